@@ -0,0 +1,13 @@
+// Raw public keys in DTLS need OpenSSL 3.2 or later, openssl-sys tells us
+// which version we're built against.
+
+use std::env;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(ossl320)");
+    if let Ok(version) = env::var("DEP_OPENSSL_VERSION_NUMBER") {
+        if u64::from_str_radix(&version, 16).map(|v| v >= 0x3020_0000).unwrap_or(false) {
+            println!("cargo:rustc-cfg=ossl320");
+        }
+    }
+}
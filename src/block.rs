@@ -0,0 +1,852 @@
+use message::{Message, MessageBuilder, Mtype, Code};
+use message::option::{self, Block};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+type Key = (SocketAddr, Code, Vec<option::Option>);
+
+struct Entry {
+    response: Message,
+    created: Instant,
+}
+
+/// Splits responses that don't fit in one datagram into Block2 blocks (RFC
+/// 7959) and keeps the full representation so that requests for the later
+/// blocks can be answered without going back to the handler.
+///
+/// Representations are keyed by the transfer, the peer and the request's
+/// cache key options, so a new one for the same request replaces the old.
+/// Every split response carries an ETag for clients to tell if it changed
+/// between blocks, but an ETag in a request is only a validator and doesn't
+/// pick which representation is served.
+pub struct Block2Cache {
+    entries: HashMap<Key, Entry>,
+    lifetime: Duration,
+    capacity: usize,
+}
+
+impl Block2Cache {
+    pub fn new(lifetime: Duration, capacity: usize) -> Block2Cache {
+        Block2Cache{
+            entries: HashMap::new(),
+            lifetime,
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drops representations that have been kept longer than the lifetime.
+    pub fn expire(&mut self) {
+        let lifetime = self.lifetime;
+        self.entries.retain(|_, entry| entry.created.elapsed() <= lifetime);
+    }
+
+    /// Answers a request for a later block of a response that has already
+    /// been split, returns `None` if it isn't cached.
+    pub fn serve(&mut self, addr: &SocketAddr, request: &Message) -> Option<Message> {
+        let block = match request.block2() {
+            Some(block) if block.num > 0 => block,
+            _ => return None,
+        };
+
+        let key = Self::key(addr, request);
+        let (response, more) = match self.entries.get(&key) {
+            Some(entry) if entry.created.elapsed() <= self.lifetime => {
+                let response = Self::block(&entry.response, block);
+                let more = response.block2().is_some_and(|b| b.more);
+                (response, more)
+            },
+            Some(_) => {
+                self.entries.remove(&key);
+                return None;
+            },
+            None => return None,
+        };
+
+        if !more {
+            self.entries.remove(&key);
+        }
+
+        let mut response = response;
+        address_reply(request, &mut response);
+        Some(response)
+    }
+
+    /// Splits `response` into blocks of at most `max_szx` if it's too large
+    /// (or the client asked for a smaller block size), returning the block
+    /// the client asked for and caching the rest.
+    pub fn split(&mut self, addr: &SocketAddr, request: &Message, mut response: Message, max_szx: u8) -> Message {
+        if response.block2().is_some() {
+            return response;
+        }
+
+        let requested = request.block2();
+        let szx = cmp::min(max_szx, requested.map_or(max_szx, |b| b.szx));
+        let num = requested.map_or(0, |b| b.num);
+        let block = Block::new(num, false, szx);
+
+        if num == 0 && response.payload.len() <= block.size() {
+            return response;
+        }
+
+        if !response.options.iter().any(|o| o.number() == 4) {
+            let etag = Self::etag(&response.payload);
+            response.options.push(option::Option::ETag(etag));
+        }
+
+        let first = Self::block(&response, block);
+
+        if first.block2().is_some_and(|b| b.more) {
+            self.insert(Self::key(addr, request), response);
+        }
+
+        first
+    }
+
+    fn insert(&mut self, key: Key, response: Message) {
+        self.expire();
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let oldest = self.entries.iter()
+                .min_by_key(|&(_, entry)| entry.created)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        if self.capacity > 0 {
+            self.entries.insert(key, Entry{response, created: Instant::now()});
+        }
+    }
+
+    // Cuts the requested block out of the full response. Asking for a block
+    // past the end is answered with 4.02 Bad Option.
+    fn block(full: &Message, block: Block) -> Message {
+        let offset = block.offset();
+        let len = full.payload.len();
+
+        if offset >= len && !(offset == 0 && len == 0) {
+            return MessageBuilder::new(full.mtype, Code::BadOption)
+                .mid(full.mid)
+                .token(&full.token)
+                .build();
+        }
+
+        let end = cmp::min(offset + block.size(), len);
+
+        let mut msg = full.clone();
+        msg.options.retain(|o| o.number() != 23 && o.number() != 28);
+        msg.options.push(option::Option::Block2(Block::new(block.num, end < len, block.szx)));
+        if block.num == 0 {
+            msg.options.push(option::Option::Size2(len as u32));
+        }
+        msg.payload = full.payload[offset..end].to_vec();
+
+        msg
+    }
+
+    // An ETag in the request is a validator for the handler, so it's left
+    // out of the key.
+    fn key(addr: &SocketAddr, request: &Message) -> Key {
+        let mut key = transfer_key(addr, request);
+        key.2.retain(|o| o.number() != 4);
+        key
+    }
+
+    fn etag(payload: &[u8]) -> Vec<u8> {
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        (0..8).map(|i| (hash >> (8 * i)) as u8).collect()
+    }
+}
+
+/// What came of passing a request through a `Block1Assembler`.
+#[derive(PartialEq, Debug)]
+pub enum Assembly {
+    /// The request (with the whole body if it was sent in blocks) is ready
+    /// for the handler. The block is the one to echo in the response.
+    Complete(Message, Option<Block>),
+    /// The request was part of a transfer, or a broken one, and should be
+    /// answered with this instead of going to the handler.
+    Reply(Message),
+}
+
+struct Upload {
+    body: Vec<u8>,
+    updated: Instant,
+}
+
+/// Reassembles request bodies sent with Block1 (RFC 7959) so the handler
+/// only sees the complete request. Intermediate blocks are answered with
+/// 2.31 Continue, bodies larger than `max_body_size` are refused with 4.13
+/// Request Entity Too Large and transfers that go quiet for `lifetime` are
+/// dropped.
+///
+/// Transfers are matched on the peer, the request's cache key options and
+/// any Request-Tag (RFC 9175), the token may change from block to block.
+pub struct Block1Assembler {
+    uploads: HashMap<Key, Upload>,
+    lifetime: Duration,
+    capacity: usize,
+    max_body_size: usize,
+    max_szx: u8,
+}
+
+impl Block1Assembler {
+    pub fn new(lifetime: Duration, capacity: usize, max_body_size: usize, max_szx: u8) -> Block1Assembler {
+        Block1Assembler{
+            uploads: HashMap::new(),
+            lifetime,
+            capacity,
+            max_body_size,
+            max_szx,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.uploads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uploads.is_empty()
+    }
+
+    /// Drops transfers that haven't seen a block within the lifetime.
+    pub fn expire(&mut self) {
+        let lifetime = self.lifetime;
+        self.uploads.retain(|_, upload| upload.updated.elapsed() <= lifetime);
+    }
+
+    pub fn handle(&mut self, addr: &SocketAddr, mut request: Message) -> Assembly {
+        let block = match request.block1() {
+            Some(block) => block,
+            None => return Assembly::Complete(request, None),
+        };
+
+        self.expire();
+
+        let key = transfer_key(addr, &request);
+
+        let announced = request.options.iter().filter_map(|o| match *o {
+            option::Option::Size1(n) => Some(n as usize),
+            _ => None
+        }).next();
+
+        if announced.is_some_and(|n| n > self.max_body_size) {
+            self.uploads.remove(&key);
+            return Assembly::Reply(self.too_large(&request));
+        }
+
+        // The last block again, because our 2.31 was lost or the client
+        // retried under a new MID, gets the same answer.
+        let repeated = self.uploads.get(&key).is_some_and(|upload| {
+            let offset = block.offset();
+            block.more && offset + request.payload.len() == upload.body.len() && upload.body[offset..] == request.payload[..]
+        });
+        if repeated {
+            self.uploads.get_mut(&key).unwrap().updated = Instant::now();
+            let ack = Block::new(block.num, true, cmp::min(block.szx, self.max_szx));
+            return Assembly::Reply(self.reply(&request, Code::Continue, Some(ack)));
+        }
+
+        if block.num == 0 {
+            if !self.uploads.contains_key(&key) && self.uploads.len() >= self.capacity {
+                let oldest = self.uploads.iter()
+                    .min_by_key(|&(_, upload)| upload.updated)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.uploads.remove(&oldest);
+                }
+            }
+            self.uploads.insert(key.clone(), Upload{body: vec![], updated: Instant::now()});
+        }
+
+        let in_order = self.uploads.get(&key).is_some_and(|upload| upload.body.len() == block.offset());
+        if !in_order || (block.more && request.payload.len() != block.size()) {
+            self.uploads.remove(&key);
+            return Assembly::Reply(self.reply(&request, Code::RequestEntityIncomplete, None));
+        }
+
+        let too_large = {
+            let upload = self.uploads.get_mut(&key).unwrap();
+            if upload.body.len() + request.payload.len() > self.max_body_size {
+                true
+            } else {
+                upload.body.extend_from_slice(&request.payload);
+                upload.updated = Instant::now();
+                false
+            }
+        };
+
+        if too_large {
+            self.uploads.remove(&key);
+            return Assembly::Reply(self.too_large(&request));
+        }
+
+        // The client has to continue with the size we answer with.
+        let szx = cmp::min(block.szx, self.max_szx);
+
+        if block.more {
+            let ack = Block::new(block.num, true, szx);
+            return Assembly::Reply(self.reply(&request, Code::Continue, Some(ack)));
+        }
+
+        let body = self.uploads.remove(&key).unwrap().body;
+        request.options.retain(|o| o.number() != 27 && o.number() != 60);
+        request.payload = body;
+
+        Assembly::Complete(request, Some(Block::new(block.num, false, szx)))
+    }
+
+    fn too_large(&mut self, request: &Message) -> Message {
+        let mut reply = self.reply(request, Code::RequestEntityTooLarge, None);
+        reply.options.push(option::Option::Size1(self.max_body_size as u32));
+        reply
+    }
+
+    fn reply(&self, request: &Message, code: Code, block: Option<Block>) -> Message {
+        let mut reply = MessageBuilder::new(Mtype::Acknowledgement, code).build();
+        if let Some(block) = block {
+            reply.options.push(option::Option::Block1(block));
+        }
+        address_reply(request, &mut reply);
+        reply
+    }
+}
+
+// Addresses `response` as the reply to `request`, piggybacked on the ACK
+// for a CON or as a NON. Like `Message::response_for` a NON keeps the
+// request's MID, which the endpoint replaces with one of its own.
+fn address_reply(request: &Message, response: &mut Message) {
+    response.token = request.token.clone();
+    response.mid = request.mid;
+    response.mtype = if request.mtype == Mtype::Confirmable { Mtype::Acknowledgement } else { Mtype::NonConfirmable };
+}
+
+fn transfer_key(addr: &SocketAddr, request: &Message) -> Key {
+    let options = request.options.iter()
+        .filter(|o| (o.is_cache_key() || o.number() == 292) && !is_transfer_option(o.number()))
+        .cloned()
+        .collect();
+
+    (*addr, request.code, options)
+}
+
+// Options that describe how a representation is transferred rather than
+// which representation it is, so aren't part of the cache key.
+fn is_transfer_option(number: u16) -> bool {
+    matches!(number, 6 | 23 | 27 | 28 | 60)
+}
+
+
+/// Why a block-wise transfer started by a client failed.
+#[derive(PartialEq, Debug)]
+pub enum TransferError {
+    /// The representation kept changing while its blocks were fetched.
+    Changed,
+    /// The server answered with a block other than the one asked for.
+    UnexpectedBlock,
+    /// The response body is larger than allowed.
+    TooLarge,
+}
+
+/// What to do next in a client's block-wise transfer.
+#[derive(PartialEq, Debug)]
+pub enum Step {
+    /// Send this request and pass its response back.
+    Request(Message),
+    /// The transfer is over, this is the response.
+    Response(Message),
+}
+
+// How often a transfer is started over before giving up.
+const MAX_RESTARTS: u32 = 3;
+
+/// Sends a request body in Block1 blocks (RFC 7959) if it doesn't fit in a
+/// single one. The server may ask for smaller blocks, and a 4.08 Request
+/// Entity Incomplete starts the upload over.
+///
+/// Like the server side this only keeps the state, the caller sends the
+/// requests and hands back the responses.
+pub struct Block1Upload {
+    request: Message,
+    body: Vec<u8>,
+    // The block that was sent last.
+    block: Block,
+    blockwise: bool,
+    restarts: u32,
+}
+
+impl Block1Upload {
+    pub fn new(mut request: Message, szx: u8) -> Block1Upload {
+        let body = ::std::mem::take(&mut request.payload);
+        let szx = cmp::min(szx, 6);
+        let blockwise = body.len() > 1 << (szx + 4);
+
+        Block1Upload{request, body, block: Block::new(0, false, szx), blockwise, restarts: 0}
+    }
+
+    /// The first request to send.
+    pub fn start(&mut self) -> Step {
+        if !self.blockwise {
+            let mut request = self.request.clone();
+            request.payload = self.body.clone();
+            return Step::Request(request);
+        }
+
+        let szx = self.block.szx;
+        Step::Request(self.block_request(0, szx))
+    }
+
+    pub fn response(&mut self, response: Message) -> Result<Step, TransferError> {
+        if !self.blockwise {
+            return Ok(Step::Response(response));
+        }
+
+        if response.code == Code::RequestEntityIncomplete && self.restarts < MAX_RESTARTS {
+            self.restarts += 1;
+            let szx = self.block.szx;
+            return Ok(Step::Request(self.block_request(0, szx)));
+        }
+
+        if response.code != Code::Continue || !self.block.more {
+            return Ok(Step::Response(response));
+        }
+
+        let ack = match response.block1() {
+            Some(ack) if ack.num == self.block.num => ack,
+            _ => return Err(TransferError::UnexpectedBlock),
+        };
+
+        // Carry on from where the last block ended, with smaller blocks if
+        // the server asked for them.
+        let szx = cmp::min(ack.szx, self.block.szx);
+        let offset = self.block.offset() + self.block.size();
+        let num = (offset >> (szx + 4)) as u32;
+
+        Ok(Step::Request(self.block_request(num, szx)))
+    }
+
+    fn block_request(&mut self, num: u32, szx: u8) -> Message {
+        let size = 1 << (szx + 4);
+        let offset = num as usize * size;
+        let end = cmp::min(offset + size, self.body.len());
+        self.block = Block::new(num, end < self.body.len(), szx);
+
+        let mut request = self.request.clone();
+        request.options.retain(|o| o.number() != 27 && o.number() != 60);
+        request.options.push(option::Option::Block1(self.block));
+        if num == 0 {
+            request.options.push(option::Option::Size1(self.body.len() as u32));
+        }
+        request.payload = self.body[offset..end].to_vec();
+        request
+    }
+}
+
+/// Fetches the rest of a response that came as the first of several Block2
+/// blocks (RFC 7959), checking its ETag stays the same. If it changes the
+/// download starts over.
+pub struct Block2Download {
+    request: Message,
+    body: Vec<u8>,
+    etag: Option<Vec<u8>>,
+    max_body_size: usize,
+    restarts: u32,
+}
+
+impl Block2Download {
+    /// `request` is the one to ask for further blocks with, any payload is
+    /// left out of those.
+    pub fn new(mut request: Message, max_body_size: usize) -> Block2Download {
+        request.payload.clear();
+        request.options.retain(|o| o.number() != 23 && o.number() != 27 && o.number() != 60);
+
+        Block2Download{request, body: vec![], etag: None, max_body_size, restarts: 0}
+    }
+
+    pub fn response(&mut self, mut response: Message) -> Result<Step, TransferError> {
+        let block = match response.block2() {
+            Some(block) if response.code.class() == 2 => block,
+            _ if self.body.is_empty() => return Ok(Step::Response(response)),
+            _ => return Err(TransferError::UnexpectedBlock),
+        };
+
+        if block.offset() != self.body.len() {
+            return Err(TransferError::UnexpectedBlock);
+        }
+
+        let etag = response.options.iter().filter_map(|o| match *o {
+            option::Option::ETag(ref etag) => Some(etag.clone()),
+            _ => None
+        }).next();
+
+        if block.num == 0 {
+            self.etag = etag;
+        } else if etag != self.etag {
+            if self.restarts >= MAX_RESTARTS {
+                return Err(TransferError::Changed);
+            }
+            self.restarts += 1;
+            self.body.clear();
+            return Ok(Step::Request(self.block_request(0, block.szx)));
+        }
+
+        if self.body.len() + response.payload.len() > self.max_body_size {
+            return Err(TransferError::TooLarge);
+        }
+        self.body.extend_from_slice(&response.payload);
+
+        if block.more {
+            if response.payload.len() != block.size() {
+                return Err(TransferError::UnexpectedBlock);
+            }
+            let num = (self.body.len() / block.size()) as u32;
+            return Ok(Step::Request(self.block_request(num, block.szx)));
+        }
+
+        response.options.retain(|o| o.number() != 23 && o.number() != 28);
+        response.payload = ::std::mem::take(&mut self.body);
+        Ok(Step::Response(response))
+    }
+
+    fn block_request(&self, num: u32, szx: u8) -> Message {
+        let mut request = self.request.clone();
+        request.options.push(option::Option::Block2(Block::new(num, false, szx)));
+        request
+    }
+}
+
+#[test]
+fn test_block_option_encoding() {
+    let block = Block::from_u32(0x2A).unwrap();
+    assert_eq!(block, Block::new(2, true, 2));
+    assert_eq!(block.size(), 64);
+    assert_eq!(block.offset(), 128);
+    assert_eq!(block.as_u32(), 0x2A);
+
+    assert_eq!(Block::from_u32(0x07), None);
+    assert_eq!(Block::szx_for_size(1152), 6);
+    assert_eq!(Block::szx_for_size(100), 2);
+
+    let msg = MessageBuilder::new(Mtype::Confirmable, Code::Get)
+        .option(option::Option::Block2(Block::new(0x1234, false, 6)))
+        .build();
+    let bin = msg.to_bytes().unwrap();
+    assert_eq!(&bin[4..], &[0xD3, 0x0A, 0x01, 0x23, 0x46]);
+    assert_eq!(Message::from_bytes(&bin).unwrap(), msg);
+}
+
+#[test]
+fn test_block2_split_and_serve() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut cache = Block2Cache::new(Duration::from_secs(60), 16);
+
+    let request = MessageBuilder::new(Mtype::Confirmable, Code::Get)
+        .mid(1)
+        .token(&[1])
+        .option(option::Option::UriPath("fw".to_string()))
+        .build();
+    let response = Message::ack_for(&request, Code::Content);
+    let mut full = response.clone();
+    full.payload = (0..100u8).collect();
+
+    let first = cache.split(&addr, &request, full, 1);
+    assert_eq!(first.block2(), Some(Block::new(0, true, 1)));
+    assert_eq!(first.payload, (0..32u8).collect::<Vec<u8>>());
+    assert!(first.options.contains(&option::Option::Size2(100)));
+    assert_eq!(cache.len(), 1);
+
+    let etag = first.options.iter().find(|o| o.number() == 4).cloned().unwrap();
+
+    let mut later = MessageBuilder::new(Mtype::Confirmable, Code::Get)
+        .mid(2)
+        .token(&[2])
+        .option(option::Option::UriPath("fw".to_string()))
+        .option(option::Option::Block2(Block::new(3, false, 1)))
+        .build();
+
+    let last = cache.serve(&addr, &later).unwrap();
+    assert_eq!(last.mtype, Mtype::Acknowledgement);
+    assert_eq!(last.mid, 2);
+    assert_eq!(last.token, [2]);
+    assert_eq!(last.block2(), Some(Block::new(3, false, 1)));
+    assert_eq!(last.payload, (96..100u8).collect::<Vec<u8>>());
+    assert!(last.options.contains(&etag));
+    assert!(cache.is_empty());
+
+    later.options = vec![option::Option::Block2(Block::new(1, false, 1))];
+    assert_eq!(cache.serve(&addr, &later), None);
+
+    let small = cache.split(&addr, &request, response.clone(), 5);
+    assert_eq!(small, response);
+}
+
+#[test]
+fn test_block2_cache_keyed_by_transfer() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let other = "127.0.0.1:5684".parse().unwrap();
+    let mut cache = Block2Cache::new(Duration::from_secs(60), 16);
+
+    let request = MessageBuilder::new(Mtype::Confirmable, Code::Get).token(&[1]).build();
+    let full = |etag: u8| MessageBuilder::new(Mtype::Acknowledgement, Code::Content)
+        .option(option::Option::ETag(vec![etag]))
+        .payload(vec![etag; 64])
+        .build();
+    let block = |num: u32, etag: ::std::option::Option<u8>| {
+        let mut later = MessageBuilder::new(Mtype::Confirmable, Code::Get)
+            .token(&[2])
+            .option(option::Option::Block2(Block::new(num, false, 1)));
+        if let Some(etag) = etag {
+            later = later.option(option::Option::ETag(vec![etag]));
+        }
+        later.build()
+    };
+
+    cache.split(&addr, &request, full(1), 1);
+    cache.split(&other, &request, full(1), 1);
+    assert_eq!(cache.len(), 2);
+
+    // A new representation for the same request replaces the old one, and
+    // is what's served even to a client revalidating with the old ETag. Its
+    // own ETag tells the client it changed.
+    cache.split(&addr, &request, full(2), 1);
+    assert_eq!(cache.len(), 2);
+    let current = cache.serve(&addr, &block(1, Some(1))).unwrap();
+    assert_eq!(current.payload, vec![2; 32]);
+    assert!(current.options.contains(&option::Option::ETag(vec![2])));
+
+    assert_eq!(cache.serve(&other, &block(1, Some(7))).unwrap().payload, vec![1; 32]);
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_block1_reassembly() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut assembler = Block1Assembler::new(Duration::from_secs(60), 16, 1024, 6);
+
+    let upload = |mid: u16, block: Block, payload: Vec<u8>| {
+        MessageBuilder::new(Mtype::Confirmable, Code::Put)
+            .mid(mid)
+            .token(&[mid as u8])
+            .option(option::Option::UriPath("fw".to_string()))
+            .option(option::Option::Block1(block))
+            .payload(payload)
+            .build()
+    };
+
+    let plain = MessageBuilder::new(Mtype::Confirmable, Code::Put).payload(vec![1]).build();
+    assert_eq!(assembler.handle(&addr, plain.clone()), Assembly::Complete(plain, None));
+
+    match assembler.handle(&addr, upload(1, Block::new(0, true, 1), vec![0; 32])) {
+        Assembly::Reply(reply) => {
+            assert_eq!(reply.code, Code::Continue);
+            assert_eq!(reply.mtype, Mtype::Acknowledgement);
+            assert_eq!(reply.mid, 1);
+            assert_eq!(reply.token, [1]);
+            assert_eq!(reply.block1(), Some(Block::new(0, true, 1)));
+        },
+        other => panic!("unexpected {:?}", other),
+    }
+
+    assert!(matches!(assembler.handle(&addr, upload(2, Block::new(1, true, 1), vec![1; 32])), Assembly::Reply(_)));
+
+    match assembler.handle(&addr, upload(3, Block::new(2, false, 1), vec![2; 5])) {
+        Assembly::Complete(request, block) => {
+            assert_eq!(block, Some(Block::new(2, false, 1)));
+            assert_eq!(request.mid, 3);
+            assert_eq!(request.block1(), None);
+            assert_eq!(request.payload.len(), 69);
+            assert_eq!(&request.payload[60..], &[1, 1, 1, 1, 2, 2, 2, 2, 2]);
+        },
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(assembler.is_empty());
+
+    match assembler.handle(&addr, upload(4, Block::new(3, true, 1), vec![0; 32])) {
+        Assembly::Reply(reply) => assert_eq!(reply.code, Code::RequestEntityIncomplete),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_block1_repeated_block() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut assembler = Block1Assembler::new(Duration::from_secs(60), 16, 1024, 6);
+
+    let upload = |mid: u16, block: Block, payload: Vec<u8>| {
+        MessageBuilder::new(Mtype::Confirmable, Code::Put)
+            .mid(mid)
+            .option(option::Option::Block1(block))
+            .payload(payload)
+            .build()
+    };
+    let code = |assembly: Assembly| match assembly {
+        Assembly::Reply(reply) => (reply.code, reply.mid, reply.block1()),
+        other => panic!("unexpected {:?}", other),
+    };
+
+    assert_eq!(code(assembler.handle(&addr, upload(1, Block::new(0, true, 1), vec![0; 32]))),
+               (Code::Continue, 1, Some(Block::new(0, true, 1))));
+    assert_eq!(code(assembler.handle(&addr, upload(2, Block::new(0, true, 1), vec![0; 32]))),
+               (Code::Continue, 2, Some(Block::new(0, true, 1))));
+    assert_eq!(code(assembler.handle(&addr, upload(3, Block::new(1, true, 1), vec![1; 32]))),
+               (Code::Continue, 3, Some(Block::new(1, true, 1))));
+    assert_eq!(code(assembler.handle(&addr, upload(4, Block::new(1, true, 1), vec![1; 32]))),
+               (Code::Continue, 4, Some(Block::new(1, true, 1))));
+
+    match assembler.handle(&addr, upload(5, Block::new(2, false, 1), vec![2; 4])) {
+        Assembly::Complete(request, _) => assert_eq!(request.payload.len(), 68),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // A repeat with different content still breaks the transfer.
+    assembler.handle(&addr, upload(6, Block::new(0, true, 1), vec![0; 32]));
+    assembler.handle(&addr, upload(7, Block::new(1, true, 1), vec![1; 32]));
+    assert_eq!(code(assembler.handle(&addr, upload(8, Block::new(1, true, 1), vec![9; 32]))).0,
+               Code::RequestEntityIncomplete);
+    assert!(assembler.is_empty());
+}
+
+#[test]
+fn test_block1_too_large() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut assembler = Block1Assembler::new(Duration::from_secs(60), 16, 40, 6);
+
+    let upload = |block: Block| {
+        MessageBuilder::new(Mtype::NonConfirmable, Code::Post)
+            .option(option::Option::Block1(block))
+            .payload(vec![0; 32])
+            .build()
+    };
+
+    assert!(matches!(assembler.handle(&addr, upload(Block::new(0, true, 1))), Assembly::Reply(_)));
+
+    match assembler.handle(&addr, upload(Block::new(1, true, 1))) {
+        Assembly::Reply(reply) => {
+            assert_eq!(reply.code, Code::RequestEntityTooLarge);
+            assert_eq!(reply.mtype, Mtype::NonConfirmable);
+            assert!(reply.options.contains(&option::Option::Size1(40)));
+        },
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(assembler.is_empty());
+}
+
+#[test]
+fn test_block1_upload() {
+    let body: Vec<u8> = (0..100u8).collect();
+    let request = MessageBuilder::new(Mtype::Confirmable, Code::Put).payload(body.clone()).build();
+    let mut upload = Block1Upload::new(request, 1);
+    let continue_ = |block: Block| MessageBuilder::new(Mtype::Acknowledgement, Code::Continue)
+        .option(option::Option::Block1(block)).build();
+
+    let first = match upload.start() {
+        Step::Request(first) => first,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(first.block1(), Some(Block::new(0, true, 1)));
+    assert!(first.options.contains(&option::Option::Size1(100)));
+    assert_eq!(first.payload, &body[..32]);
+
+    // The server asks for 16 byte blocks, so the next one is number 2.
+    let second = match upload.response(continue_(Block::new(0, true, 0))).unwrap() {
+        Step::Request(second) => second,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(second.block1(), Some(Block::new(2, true, 0)));
+    assert_eq!(second.payload, &body[32..48]);
+
+    // 4.08 starts over.
+    let incomplete = MessageBuilder::new(Mtype::Acknowledgement, Code::RequestEntityIncomplete).build();
+    match upload.response(incomplete).unwrap() {
+        Step::Request(again) => assert_eq!(again.block1(), Some(Block::new(0, true, 0))),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(upload.response(continue_(Block::new(3, true, 0))), Err(TransferError::UnexpectedBlock));
+
+    let mut num = 0;
+    let mut received = vec![];
+    let mut upload = Block1Upload::new(MessageBuilder::new(Mtype::Confirmable, Code::Put).payload(body.clone()).build(), 2);
+    let mut step = upload.start();
+    while let Step::Request(request) = step {
+        let block = request.block1().unwrap();
+        assert_eq!(block.num, num);
+        received.extend_from_slice(&request.payload);
+        num += 1;
+        let response = if block.more { continue_(block) } else {
+            MessageBuilder::new(Mtype::Acknowledgement, Code::Changed).option(option::Option::Block1(block)).build()
+        };
+        step = upload.response(response).unwrap();
+    }
+    assert_eq!(num, 2);
+    assert_eq!(received, body);
+
+    // Small bodies are sent as they are.
+    let mut upload = Block1Upload::new(MessageBuilder::new(Mtype::Confirmable, Code::Put).payload(vec![1; 16]).build(), 0);
+    match upload.start() {
+        Step::Request(request) => assert_eq!((request.block1(), request.payload.len()), (None, 16)),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn test_block2_download() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut cache = Block2Cache::new(Duration::from_secs(60), 8);
+    let body: Vec<u8> = (0..100u8).collect();
+    let request = MessageBuilder::new(Mtype::Confirmable, Code::Get).mid(1).token(&[1]).build();
+    let full = MessageBuilder::new(Mtype::Acknowledgement, Code::Content).mid(1).token(&[1]).payload(body.clone()).build();
+
+    let mut download = Block2Download::new(request.clone(), 1024);
+    let mut response = cache.split(&addr, &request, full.clone(), 1);
+    let mut requests = 0;
+    let done = loop {
+        match download.response(response).unwrap() {
+            Step::Request(next) => {
+                requests += 1;
+                response = cache.serve(&addr, &next).unwrap();
+            },
+            Step::Response(done) => break done,
+        }
+    };
+    assert_eq!(requests, 3);
+    assert_eq!(done.payload, body);
+    assert_eq!(done.block2(), None);
+
+    // A changed ETag starts over, too many changes give up.
+    let mut download = Block2Download::new(request.clone(), 1024);
+    let block = |num, etag: u8| MessageBuilder::new(Mtype::Acknowledgement, Code::Content)
+        .option(option::Option::ETag(vec![etag]))
+        .option(option::Option::Block2(Block::new(num, true, 0)))
+        .payload(vec![0; 16]).build();
+    assert!(matches!(download.response(block(0, 1)), Ok(Step::Request(_))));
+    for etag in 2..5 {
+        match download.response(block(1, etag)).unwrap() {
+            Step::Request(next) => assert_eq!(next.block2(), Some(Block::new(0, false, 0))),
+            other => panic!("{:?}", other),
+        }
+        download.response(block(0, etag)).unwrap();
+    }
+    assert_eq!(download.response(block(1, 9)), Err(TransferError::Changed));
+
+    let mut download = Block2Download::new(request.clone(), 20);
+    download.response(block(0, 1)).unwrap();
+    assert_eq!(download.response(block(1, 1)), Err(TransferError::TooLarge));
+    assert_eq!(Block2Download::new(request, 20).response(block(2, 1)), Err(TransferError::UnexpectedBlock));
+}
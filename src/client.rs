@@ -0,0 +1,329 @@
+use message::{Code, Message, Mtype};
+use uri::Uri;
+use block::{Block1Upload, Block2Download, Step, TransferError};
+use endpoint::{Config, Endpoint, Handle, MsgHandler, Observation};
+
+pub use exchange::Error;
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread::{self, JoinHandle};
+
+// The endpoint behind a client doesn't serve anything.
+struct NoResources;
+
+impl MsgHandler for NoResources {
+    fn handle_msg(&self, _addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+        match msg.mtype {
+            Mtype::Confirmable => Message::reset_for(msg).to_bytes().ok(),
+            _ => None
+        }
+    }
+}
+
+/// A blocking CoAP client.
+///
+/// Requests are sent from an `Endpoint` running on a thread of its own,
+/// which takes care of tokens, MIDs, retransmissions and separate
+/// responses. Each call waits until the response arrives or the endpoint
+/// gives up on it. Large request bodies are sent in Block1 blocks and
+/// responses split into Block2 blocks are fetched in full.
+pub struct Client {
+    handle: Handle,
+    thread: Option<JoinHandle<io::Result<()>>>,
+    block_szx: u8,
+    max_response_size: usize,
+}
+
+impl Client {
+    /// A client on an ephemeral IPv4 port.
+    pub fn new() -> io::Result<Client> {
+        Client::with_config("0.0.0.0:0".parse().unwrap(), Config::default())
+    }
+
+    pub fn with_config(local_addr: SocketAddr, config: Config) -> io::Result<Client> {
+        let (block_szx, max_response_size) = (config.block_szx, config.max_response_size);
+        let endpoint = Endpoint::with_config(local_addr, config).bind(NoResources)?;
+        let handle = endpoint.handle();
+        let thread = thread::spawn(move || endpoint.run());
+
+        Ok(Client{handle, thread: Some(thread), block_szx, max_response_size})
+    }
+
+    pub fn get(&self, uri: &str) -> Result<Message, Error> {
+        self.request(Code::Get, uri, vec![])
+    }
+
+    pub fn post(&self, uri: &str, payload: Vec<u8>) -> Result<Message, Error> {
+        self.request(Code::Post, uri, payload)
+    }
+
+    pub fn put(&self, uri: &str, payload: Vec<u8>) -> Result<Message, Error> {
+        self.request(Code::Put, uri, payload)
+    }
+
+    pub fn delete(&self, uri: &str) -> Result<Message, Error> {
+        self.request(Code::Delete, uri, vec![])
+    }
+
+    /// Sends a CON request for `uri` and waits for the response.
+    pub fn request(&self, code: Code, uri: &str, payload: Vec<u8>) -> Result<Message, Error> {
+        let addr = resolve(uri)?;
+        let mut msg = Message::request(code, uri)?;
+        msg.payload = payload;
+
+        self.send(addr, msg)
+    }
+
+    /// Observes `uri`, calling `callback` from the client's thread with the
+    /// response and every notification after it. See `Handle::observe`.
+    pub fn observe<F>(&self, uri: &str, callback: F) -> Result<Observation, Error>
+        where F: FnMut(Result<Message, Error>) + Send + 'static {
+        let addr = resolve(uri)?;
+        let msg = Message::request(Code::Get, uri)?;
+
+        Ok(self.handle.observe(addr, msg, callback)?)
+    }
+
+    /// Sends a request to `addr` and waits for the response. The request is
+    /// given a fresh MID, and a token if it doesn't have one.
+    ///
+    /// Block-wise transfers happen in here, unless the request already has
+    /// a Block1 or Block2 option, in which case it's sent as it is.
+    pub fn send(&self, addr: SocketAddr, msg: Message) -> Result<Message, Error> {
+        if msg.block1().is_some() || msg.block2().is_some() {
+            return self.exchange(addr, msg);
+        }
+
+        let mut download = Block2Download::new(msg.clone(), self.max_response_size);
+        let mut upload = Block1Upload::new(msg, self.block_szx);
+
+        let step = upload.start();
+        let response = self.transfer(addr, step, |response| upload.response(response))?;
+        let step = download.response(response)?;
+        self.transfer(addr, step, |response| download.response(response))
+    }
+
+    // Sends requests until `next` has the response.
+    fn transfer<F>(&self, addr: SocketAddr, mut step: Step, mut next: F) -> Result<Message, Error>
+        where F: FnMut(Message) -> Result<Step, TransferError> {
+        loop {
+            step = match step {
+                Step::Request(request) => next(self.exchange(addr, request)?)?,
+                Step::Response(response) => return Ok(response),
+            };
+        }
+    }
+
+    fn exchange(&self, addr: SocketAddr, msg: Message) -> Result<Message, Error> {
+        self.handle.request_future(addr, msg)?.wait()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if self.handle.shutdown().is_ok() {
+            if let Some(thread) = self.thread.take() {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+/// The address to send a request for a `coap://` URI to.
+pub fn resolve(uri: &str) -> Result<SocketAddr, Error> {
+    let uri = Uri::parse(uri)?;
+
+    match (uri.host.as_str(), uri.port).to_socket_addrs()?.next() {
+        Some(addr) => Ok(addr),
+        None => Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))),
+    }
+}
+
+
+#[test]
+fn test_client_piggybacked_and_separate_responses() {
+    use endpoint::Responder;
+    use message::MessageRef;
+    use router::uri_path;
+    use std::time::Duration;
+
+    struct Server;
+
+    impl MsgHandler for Server {
+        fn handle_msg(&self, _addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+            let mut resp = Message::response_for(msg, Code::Content);
+            resp.payload = uri_path(msg).join("/").into_bytes();
+            resp.payload.extend_from_slice(&msg.payload);
+            resp.to_bytes().ok()
+        }
+
+        fn handle_request(&self, addr: &SocketAddr, msg: &MessageRef, buf: &mut Vec<u8>, responder: Responder) {
+            let request = msg.to_owned().unwrap();
+            if uri_path(&request) != ["slow"] {
+                return self.handle_msg_into(addr, msg, buf);
+            }
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                responder.respond(Message::builder(Mtype::Confirmable, Code::Changed).payload(b"done".to_vec()).build()).unwrap();
+            });
+        }
+    }
+
+    let config = Config{ack_delay: Duration::from_millis(100), ..Config::default()};
+    let server = Endpoint::with_config("127.0.0.1:0".parse().unwrap(), config).bind(Server).unwrap();
+    let server_addr = server.local_addr();
+    let server_handle = server.handle();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let client = Client::new().unwrap();
+    let uri = |path: &str| format!("coap://127.0.0.1:{}{}", server_addr.port(), path);
+
+    let resp = client.get(&uri("/a/b")).unwrap();
+    assert_eq!((resp.mtype, resp.code), (Mtype::Acknowledgement, Code::Content));
+    assert_eq!(resp.payload, b"a/b");
+
+    let resp = client.post(&uri("/c"), b"+x".to_vec()).unwrap();
+    assert_eq!(resp.payload, b"c+x");
+
+    let resp = client.put(&uri("/slow"), vec![]).unwrap();
+    assert_eq!((resp.mtype, resp.code), (Mtype::Confirmable, Code::Changed));
+    assert_eq!(resp.payload, b"done");
+
+    match client.get("http://example.com/") {
+        Err(Error::Message(::message::Error::InvalidUri)) => (),
+        other => panic!("{:?}", other),
+    }
+
+    server_handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn test_client_times_out() {
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::time::{Duration, Instant};
+
+    // Nobody answers on this socket.
+    let silent = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let mut config = Config::default();
+    config.transmission.ack_timeout = Duration::from_millis(100);
+    config.transmission.ack_random_factor = 1.0;
+    config.transmission.max_retransmit = 1;
+    config.sweep_interval = Duration::from_millis(100);
+    let client = Client::with_config("127.0.0.1:0".parse().unwrap(), config).unwrap();
+
+    let start = Instant::now();
+    match client.get(&format!("coap://{}/x", silent.local_addr().unwrap())) {
+        Err(Error::TimedOut) => (),
+        other => panic!("{:?}", other),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_client_observe() {
+    use router::Router;
+    use std::sync::{mpsc, Arc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    let value = Arc::new(AtomicUsize::new(0));
+    let mut router = Router::new();
+    {
+        let value = value.clone();
+        router.get("/*", move |_, msg, _| {
+            let mut resp = Message::response_for(msg, Code::Content);
+            resp.payload = vec![value.load(Ordering::SeqCst) as u8];
+            resp.to_bytes().ok()
+        }).unwrap();
+    }
+
+    let server = Endpoint::new("127.0.0.1:0".parse().unwrap()).bind(router).unwrap();
+    let server_addr = server.local_addr();
+    let server_handle = server.handle();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let client = Client::new().unwrap();
+    let uri = |path: &str| format!("coap://127.0.0.1:{}{}", server_addr.port(), path);
+    let (tx, rx) = mpsc::channel::<Result<Vec<u8>, Error>>();
+    let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+
+    let observation = client.observe(&uri("/temp"), move |r: Result<Message, Error>| tx.send(r.map(|m| m.payload)).unwrap()).unwrap();
+    assert_eq!(next(), [0]);
+
+    value.store(1, Ordering::SeqCst);
+    server_handle.notify("/temp").unwrap();
+    assert_eq!(next(), [1]);
+
+    // After cancelling the server stops notifying.
+    observation.cancel().unwrap();
+    thread::sleep(Duration::from_millis(200));
+    value.store(2, Ordering::SeqCst);
+    server_handle.notify("/temp").unwrap();
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+    // A forgotten observation is ended with a RST on the next notification.
+    let (tx, rx) = mpsc::channel::<Result<Vec<u8>, Error>>();
+    let observation = client.observe(&uri("/humidity"), move |r: Result<Message, Error>| tx.send(r.map(|m| m.payload)).unwrap()).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap(), [2]);
+    observation.forget().unwrap();
+    thread::sleep(Duration::from_millis(200));
+    server_handle.notify("/humidity").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    value.store(3, Ordering::SeqCst);
+    server_handle.notify("/humidity").unwrap();
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+    server_handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn test_client_blockwise() {
+    use router::Router;
+    use std::sync::{Arc, Mutex};
+
+    let stored = Arc::new(Mutex::new(vec![]));
+    let mut router = Router::new();
+    {
+        let stored = stored.clone();
+        router.put("/fw", move |_, msg, _| {
+            *stored.lock().unwrap() = msg.payload.clone();
+            Message::response_for(msg, Code::Changed).to_bytes().ok()
+        }).unwrap();
+    }
+    {
+        let stored = stored.clone();
+        router.get("/fw", move |_, msg, _| {
+            let mut resp = Message::response_for(msg, Code::Content);
+            resp.payload = stored.lock().unwrap().clone();
+            resp.to_bytes().ok()
+        }).unwrap();
+    }
+
+    // The server takes smaller blocks than the client sends.
+    let config = Config{block_szx: 2, ..Config::default()};
+    let server = Endpoint::with_config("127.0.0.1:0".parse().unwrap(), config).bind(router).unwrap();
+    let server_addr = server.local_addr();
+    let server_handle = server.handle();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let client = Client::new().unwrap();
+    let uri = format!("coap://127.0.0.1:{}/fw", server_addr.port());
+    let image: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+    let resp = client.put(&uri, image.clone()).unwrap();
+    assert_eq!(resp.code, Code::Changed);
+    assert_eq!(*stored.lock().unwrap(), image);
+
+    let resp = client.get(&uri).unwrap();
+    assert_eq!(resp.code, Code::Content);
+    assert_eq!(resp.block2(), None);
+    assert_eq!(resp.payload, image);
+
+    server_handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -1,2 +1,4 @@
 use mio::Token;
 pub const SERVER: Token = Token(0);
+// The listener for stream connections, which take the tokens after it.
+pub const STREAMS: Token = Token(1);
@@ -0,0 +1,167 @@
+use message::Mtype;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// What `DuplicateCache::check` found out about a message.
+#[derive(PartialEq, Debug)]
+pub enum Seen<'a> {
+    /// First time we've seen this MID from this peer.
+    New,
+    /// A retransmission. For a CON this is what was sent back last time, if
+    /// anything, for a NON it's always `None`.
+    Duplicate(Option<&'a [u8]>),
+}
+
+struct Entry {
+    expires: Instant,
+    seq: u64,
+    response: Option<Vec<u8>>,
+}
+
+/// Remembers the MIDs of recently received CON and NON messages, and what
+/// was sent back, so that retransmissions aren't handled twice (RFC 7252
+/// §4.5). Entries last EXCHANGE_LIFETIME for CONs and NON_LIFETIME for NONs,
+/// or until `capacity` newer ones push them out.
+pub struct DuplicateCache {
+    entries: HashMap<(SocketAddr, u16), Entry>,
+    order: VecDeque<((SocketAddr, u16), u64)>,
+    next_seq: u64,
+    capacity: usize,
+    con_lifetime: Duration,
+    non_lifetime: Duration,
+}
+
+impl DuplicateCache {
+    pub fn new(capacity: usize, con_lifetime: Duration, non_lifetime: Duration) -> DuplicateCache {
+        DuplicateCache{
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            next_seq: 0,
+            capacity,
+            con_lifetime,
+            non_lifetime,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Checks a received message, remembering its MID if it's new. Only CON
+    /// and NON messages are tracked, anything else is always `New`.
+    pub fn check(&mut self, addr: &SocketAddr, mid: u16, mtype: Mtype) -> Seen<'_> {
+        let lifetime = match mtype {
+            Mtype::Confirmable => self.con_lifetime,
+            Mtype::NonConfirmable => self.non_lifetime,
+            _ => return Seen::New,
+        };
+
+        let key = (*addr, mid);
+        let now = Instant::now();
+
+        if self.entries.get(&key).is_some_and(|entry| entry.expires <= now) {
+            self.entries.remove(&key);
+        }
+
+        if self.entries.contains_key(&key) {
+            // Duplicate NONs are just dropped, there's nothing to acknowledge.
+            if mtype == Mtype::NonConfirmable {
+                return Seen::Duplicate(None);
+            }
+            let entry = &self.entries[&key];
+            return Seen::Duplicate(entry.response.as_ref().map(|r| &r[..]));
+        }
+
+        if self.capacity == 0 {
+            return Seen::New;
+        }
+
+        while self.entries.len() >= self.capacity {
+            if !self.pop_oldest() {
+                break;
+            }
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.entries.insert(key, Entry{expires: now + lifetime, seq, response: None});
+        self.order.push_back((key, seq));
+
+        Seen::New
+    }
+
+    /// Remembers the reply sent for a message `check` said was new, so it
+    /// can be sent again for retransmissions.
+    pub fn record(&mut self, addr: &SocketAddr, mid: u16, response: &[u8]) {
+        if let Some(entry) = self.entries.get_mut(&(*addr, mid)) {
+            entry.response = Some(response.to_vec());
+        }
+    }
+
+    /// Drops expired entries, meant to be called periodically.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.entries.retain(|_, entry| entry.expires > now);
+
+        let entries = &self.entries;
+        self.order.retain(|&(key, seq)| entries.get(&key).is_some_and(|e| e.seq == seq));
+    }
+
+    fn pop_oldest(&mut self) -> bool {
+        while let Some((key, seq)) = self.order.pop_front() {
+            if self.entries.get(&key).is_some_and(|e| e.seq == seq) {
+                self.entries.remove(&key);
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+
+#[test]
+fn test_dedup_replays_con_and_drops_non() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let other = "127.0.0.1:5684".parse().unwrap();
+    let mut cache = DuplicateCache::new(16, Duration::from_secs(247), Duration::from_secs(145));
+
+    assert_eq!(cache.check(&addr, 1, Mtype::Confirmable), Seen::New);
+    assert_eq!(cache.check(&addr, 1, Mtype::Confirmable), Seen::Duplicate(None));
+    cache.record(&addr, 1, &[0x60, 0x45, 0x00, 0x01]);
+    assert_eq!(cache.check(&addr, 1, Mtype::Confirmable), Seen::Duplicate(Some(&[0x60, 0x45, 0x00, 0x01][..])));
+
+    assert_eq!(cache.check(&other, 1, Mtype::Confirmable), Seen::New);
+
+    assert_eq!(cache.check(&addr, 2, Mtype::NonConfirmable), Seen::New);
+    cache.record(&addr, 2, &[0x50, 0x45, 0x00, 0x02]);
+    assert_eq!(cache.check(&addr, 2, Mtype::NonConfirmable), Seen::Duplicate(None));
+
+    assert_eq!(cache.check(&addr, 3, Mtype::Acknowledgement), Seen::New);
+    assert_eq!(cache.check(&addr, 3, Mtype::Acknowledgement), Seen::New);
+    assert_eq!(cache.len(), 3);
+}
+
+#[test]
+fn test_dedup_is_bounded_and_expires() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut cache = DuplicateCache::new(2, Duration::from_secs(247), Duration::from_millis(0));
+
+    assert_eq!(cache.check(&addr, 1, Mtype::Confirmable), Seen::New);
+    assert_eq!(cache.check(&addr, 2, Mtype::Confirmable), Seen::New);
+    assert_eq!(cache.check(&addr, 3, Mtype::Confirmable), Seen::New);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.check(&addr, 1, Mtype::Confirmable), Seen::New);
+    assert_eq!(cache.check(&addr, 3, Mtype::Confirmable), Seen::Duplicate(None));
+
+    assert_eq!(cache.check(&addr, 4, Mtype::NonConfirmable), Seen::New);
+    cache.expire();
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.check(&addr, 4, Mtype::NonConfirmable), Seen::New);
+}
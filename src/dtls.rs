@@ -0,0 +1,815 @@
+//! DTLS 1.2 (RFC 6347) for `coaps://`, in the security modes of RFC 7252
+//! §9.1.3: pre-shared keys, raw public keys and X.509 certificates.
+//!
+//! The protocol itself is left to OpenSSL, which is given the datagrams for
+//! each peer and hands back the ones to send. An `Endpoint` given a `Dtls`
+//! only speaks DTLS on its socket, and who the peer authenticated as is
+//! passed to `MsgHandler::handle_secure_request`. Sessions are opened to
+//! peers the endpoint sends to when it has none with them, anything sent
+//! meanwhile waits for the handshake. In the PSK mode that takes a
+//! `client_identity`.
+//!
+//! Only the cipher suites RFC 7252 makes mandatory are offered and accepted:
+//! TLS_PSK_WITH_AES_128_CCM_8 for pre-shared keys, and
+//! TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8 on P-256 otherwise. Raw public keys
+//! (RFC 7250) need OpenSSL 3.2 or later.
+
+use security::Identity;
+use socket::Socket;
+
+use foreign_types::ForeignTypeRef;
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::ssl::{ErrorCode, Ssl, SslContext, SslRef, SslContextBuilder, SslMethod, SslOptions, SslStream, SslVerifyMode, SslVersion};
+use openssl::x509::X509;
+use openssl::x509::store::X509Store;
+use openssl_sys as ffi;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::time::{Duration, Instant};
+
+// Parts of OpenSSL the openssl crate doesn't wrap: the stateless cookie
+// exchange and raw public keys.
+extern "C" {
+    fn DTLSv1_listen(ssl: *mut ffi::SSL, client: *mut c_void) -> c_int;
+    fn BIO_ADDR_new() -> *mut c_void;
+    fn BIO_ADDR_free(addr: *mut c_void);
+}
+
+#[cfg(ossl320)]
+extern "C" {
+    fn SSL_CTX_set1_client_cert_type(ctx: *mut ffi::SSL_CTX, val: *const u8, len: usize) -> c_int;
+    fn SSL_CTX_set1_server_cert_type(ctx: *mut ffi::SSL_CTX, val: *const u8, len: usize) -> c_int;
+    fn SSL_get0_peer_rpk(ssl: *const ffi::SSL) -> *mut ffi::EVP_PKEY;
+    fn X509_STORE_CTX_get0_rpk(ctx: *const ffi::X509_STORE_CTX) -> *mut ffi::EVP_PKEY;
+}
+
+// DTLSv1_handle_timeout() is a macro for this.
+const DTLS_CTRL_HANDLE_TIMEOUT: c_int = 74;
+const HANDSHAKE: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+#[cfg(ossl320)]
+const TLSEXT_CERT_TYPE_RPK: u8 = 2;
+
+// Largest datagram sent, the IPv6 minimum MTU less the IP and UDP headers.
+const MTU: u32 = 1280 - 48;
+// Largest record that can arrive.
+const MAX_RECORD: usize = 16 * 1024 + 256;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Messages held back for a session that's still being established.
+const MAX_PENDING: usize = 16;
+
+/// Looks up the pre-shared key for a PSK identity.
+pub type KeyCallback = Box<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Decides whether to accept a peer's raw public key, given as a DER
+/// SubjectPublicKeyInfo.
+pub type TrustCallback = Box<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+enum Mode {
+    Psk{keys: KeyCallback, client_identity: Option<(Vec<u8>, Vec<u8>)>},
+    #[cfg(ossl320)]
+    RawPublicKey{key: PKey<Private>, trusted: TrustCallback},
+    Certificate{cert: X509, key: PKey<Private>, trusted: X509Store},
+}
+
+/// DTLS settings for an `Endpoint`, see `Endpoint::dtls`.
+pub struct Dtls {
+    mode: Mode,
+    session_lifetime: Duration,
+    max_sessions: usize,
+}
+
+impl Dtls {
+    fn new(mode: Mode) -> Dtls {
+        Dtls{
+            mode,
+            session_lifetime: Duration::from_secs(60 * 60),
+            max_sessions: 1024,
+        }
+    }
+
+    /// Accepts peers whose PSK identity `keys` maps to a key.
+    pub fn psk<F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static>(keys: F) -> Dtls {
+        Dtls::new(Mode::Psk{keys: Box::new(keys), client_identity: None})
+    }
+
+    /// Authenticates with the P-256 key `key`, and accepts peers whose raw
+    /// public keys `trusted` accepts. Only when built against OpenSSL 3.2 or
+    /// later.
+    #[cfg(ossl320)]
+    pub fn raw_public_key<F: Fn(&[u8]) -> bool + Send + Sync + 'static>(key: PKey<Private>, trusted: F) -> Dtls {
+        Dtls::new(Mode::RawPublicKey{key, trusted: Box::new(trusted)})
+    }
+
+    /// Authenticates with `cert` and its P-256 `key`, and accepts peers with
+    /// a certificate issued by one in `trusted`. Names in the certificates
+    /// aren't checked, that's left to the handler.
+    pub fn certificate(cert: X509, key: PKey<Private>, trusted: X509Store) -> Dtls {
+        Dtls::new(Mode::Certificate{cert, key, trusted})
+    }
+
+    /// The identity and key used for sessions this endpoint starts in the
+    /// PSK mode.
+    pub fn client_identity(mut self, identity: &[u8], key: &[u8]) -> Dtls {
+        if let Mode::Psk{ref mut client_identity, ..} = self.mode {
+            *client_identity = Some((identity.to_vec(), key.to_vec()));
+        }
+        self
+    }
+
+    /// How long a session is kept without hearing from the peer, an hour by
+    /// default.
+    pub fn session_lifetime(mut self, lifetime: Duration) -> Dtls {
+        self.session_lifetime = lifetime;
+        self
+    }
+
+    /// Most sessions kept at once, 1024 by default. Handshakes from further
+    /// peers are ignored until others have ended.
+    pub fn max_sessions(mut self, max: usize) -> Dtls {
+        self.max_sessions = max;
+        self
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    Psk,
+    #[cfg_attr(not(ossl320), allow(dead_code))]
+    RawPublicKey,
+    Certificate,
+}
+
+// What OpenSSL reads and writes for a session: each read takes a whole
+// datagram that arrived, and each write is a datagram to send.
+#[derive(Default)]
+struct Datagrams {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.incoming.pop_front() {
+            Some(datagram) => {
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok(len)
+            },
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Session {
+    stream: SslStream<Datagrams>,
+    // Set once the handshake is done.
+    identity: Option<Identity>,
+    pending: Vec<Vec<u8>>,
+    started: Instant,
+    last_heard: Instant,
+}
+
+impl Session {
+    fn new(ssl: Ssl) -> Result<Session, ErrorStack> {
+        let now = Instant::now();
+        Ok(Session{
+            stream: SslStream::new(ssl, Datagrams::default())?,
+            identity: None,
+            pending: vec![],
+            started: now,
+            last_heard: now,
+        })
+    }
+
+    // Reads what the datagrams that arrived carried, which carries on the
+    // handshake as well. False if the session failed or the peer closed it.
+    fn read(&mut self, kind: Kind, buf: &mut [u8], received: &mut Vec<Vec<u8>>) -> bool {
+        loop {
+            match self.stream.ssl_read(buf) {
+                Ok(len) => received.push(buf[..len].to_vec()),
+                Err(ref e) if e.code() == ErrorCode::WANT_READ => break,
+                Err(_) => return false,
+            }
+        }
+
+        if self.identity.is_none() && self.stream.ssl().is_init_finished() {
+            self.identity = peer_identity(kind, &self.stream);
+            if self.identity.is_none() {
+                return false;
+            }
+            for pkt in self.pending.split_off(0) {
+                self.write(&pkt);
+            }
+        }
+        true
+    }
+
+    fn write(&mut self, pkt: &[u8]) {
+        // A message too large for a record is dropped, like one too large
+        // for a datagram.
+        self.stream.ssl_write(pkt).ok();
+    }
+}
+
+fn peer_identity(kind: Kind, stream: &SslStream<Datagrams>) -> Option<Identity> {
+    let ssl = stream.ssl();
+    match kind {
+        Kind::Psk => ssl.psk_identity().map(|identity| Identity::Psk(identity.to_vec())),
+        Kind::RawPublicKey => peer_public_key(ssl).map(Identity::RawPublicKey),
+        Kind::Certificate => ssl.peer_certificate().and_then(|cert| cert.to_der().ok()).map(Identity::Certificate),
+    }
+}
+
+#[cfg(ossl320)]
+fn peer_public_key(ssl: &SslRef) -> Option<Vec<u8>> {
+    public_key_der(unsafe { SSL_get0_peer_rpk(ssl.as_ptr()) })
+}
+
+#[cfg(not(ossl320))]
+fn peer_public_key(_: &SslRef) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(ossl320)]
+fn public_key_der(key: *mut ffi::EVP_PKEY) -> Option<Vec<u8>> {
+    use openssl::pkey::{PKeyRef, Public};
+
+    if key.is_null() {
+        return None;
+    }
+    let key: &PKeyRef<Public> = unsafe { PKeyRef::from_ptr(key) };
+    key.public_key_to_der().ok()
+}
+
+/// The DTLS sessions of an endpoint, by peer address.
+pub(crate) struct Sessions {
+    context: SslContext,
+    kind: Kind,
+    // Whether sessions can be opened to peers.
+    connects: bool,
+    session_lifetime: Duration,
+    max_sessions: usize,
+    addr_index: Index<Ssl, SocketAddr>,
+    sessions: HashMap<SocketAddr, Session>,
+    read_buf: Vec<u8>,
+}
+
+impl Sessions {
+    pub fn new(dtls: Dtls) -> io::Result<Sessions> {
+        let mut cookie_secret = [0; 32];
+        rand_bytes(&mut cookie_secret)?;
+        let addr_index = Ssl::new_ex_index()?;
+
+        let kind = match dtls.mode {
+            Mode::Psk{..} => Kind::Psk,
+            #[cfg(ossl320)]
+            Mode::RawPublicKey{..} => Kind::RawPublicKey,
+            Mode::Certificate{..} => Kind::Certificate,
+        };
+        let connects = match dtls.mode {
+            Mode::Psk{ref client_identity, ..} => client_identity.is_some(),
+            _ => true,
+        };
+
+        Ok(Sessions{
+            context: context(dtls.mode, cookie_secret, addr_index)?,
+            kind,
+            connects,
+            session_lifetime: dtls.session_lifetime,
+            max_sessions: dtls.max_sessions,
+            addr_index,
+            sessions: HashMap::new(),
+            read_buf: vec![0; MAX_RECORD],
+        })
+    }
+
+    /// The identity of the peer at `addr`, once its session is established.
+    pub fn identity(&self, addr: &SocketAddr) -> Option<Identity> {
+        self.sessions.get(addr).and_then(|session| session.identity.clone())
+    }
+
+    /// Handles a datagram from `addr`, answering the handshake as needed,
+    /// and returns the CoAP messages it carried.
+    pub fn receive(&mut self, sock: &mut Socket, addr: &SocketAddr, datagram: &[u8]) -> Vec<Vec<u8>> {
+        let mut out = vec![];
+        let received = self.read(addr, datagram, &mut out);
+        for datagram in out {
+            sock.send_to(&datagram, addr);
+        }
+        received
+    }
+
+    fn read(&mut self, addr: &SocketAddr, datagram: &[u8], out: &mut Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut received = vec![];
+
+        // A peer that lost its session, say by restarting, starts a new one
+        // with an unencrypted ClientHello. It replaces the old session once
+        // the cookie exchange is done.
+        let established = self.sessions.get(addr).is_some_and(|session| session.identity.is_some());
+        if !self.sessions.contains_key(addr) || established && is_client_hello(datagram) {
+            match self.listen(addr, datagram, out) {
+                Some(session) => self.sessions.insert(*addr, session),
+                None => return received,
+            };
+        } else {
+            self.sessions.get_mut(addr).unwrap().stream.get_mut().incoming.push_back(datagram.to_vec());
+        }
+
+        let session = self.sessions.get_mut(addr).unwrap();
+        session.last_heard = Instant::now();
+        let open = session.read(self.kind, &mut self.read_buf, &mut received);
+        out.append(&mut session.stream.get_mut().outgoing);
+        if !open {
+            self.sessions.remove(addr);
+        }
+        received
+    }
+
+    // A ClientHello is answered with a HelloVerifyRequest unless it carries
+    // the cookie for its address, so no state is kept for peers that can't
+    // receive at the address they claim (RFC 6347 §4.2.1).
+    fn listen(&mut self, addr: &SocketAddr, datagram: &[u8], out: &mut Vec<Vec<u8>>) -> Option<Session> {
+        if self.sessions.len() >= self.max_sessions && !self.sessions.contains_key(addr) {
+            return None;
+        }
+
+        let mut ssl = Ssl::new(&self.context).ok()?;
+        ssl.set_ex_data(self.addr_index, *addr);
+        ssl.set_mtu(MTU).ok()?;
+        ssl.set_accept_state();
+        let mut session = Session::new(ssl).ok()?;
+        session.stream.get_mut().incoming.push_back(datagram.to_vec());
+
+        let listened = unsafe {
+            let client = BIO_ADDR_new();
+            if client.is_null() {
+                return None;
+            }
+            let listened = DTLSv1_listen(session.stream.ssl().as_ptr(), client);
+            BIO_ADDR_free(client);
+            listened
+        };
+        out.append(&mut session.stream.get_mut().outgoing);
+
+        if listened == 1 {
+            Some(session)
+        } else {
+            None
+        }
+    }
+
+    /// Sends a CoAP message to `addr`, holding it back until there's a
+    /// session with the peer. In the PSK mode without a client identity
+    /// nothing can be sent to peers that haven't connected to us.
+    pub fn send(&mut self, sock: &mut Socket, addr: &SocketAddr, pkt: &[u8]) {
+        let mut out = vec![];
+        self.write(addr, pkt, &mut out);
+        for datagram in out {
+            sock.send_to(&datagram, addr);
+        }
+    }
+
+    fn write(&mut self, addr: &SocketAddr, pkt: &[u8], out: &mut Vec<Vec<u8>>) {
+        if !self.sessions.contains_key(addr) {
+            if !self.connects || self.sessions.len() >= self.max_sessions {
+                return;
+            }
+            match self.connect(addr) {
+                Some(session) => self.sessions.insert(*addr, session),
+                None => return,
+            };
+        }
+
+        let session = self.sessions.get_mut(addr).unwrap();
+        if session.identity.is_some() {
+            session.write(pkt);
+        } else if session.pending.len() < MAX_PENDING {
+            session.pending.push(pkt.to_vec());
+        }
+
+        let open = session.read(self.kind, &mut self.read_buf, &mut vec![]);
+        out.append(&mut session.stream.get_mut().outgoing);
+        if !open {
+            self.sessions.remove(addr);
+        }
+    }
+
+    fn connect(&mut self, addr: &SocketAddr) -> Option<Session> {
+        let mut ssl = Ssl::new(&self.context).ok()?;
+        ssl.set_ex_data(self.addr_index, *addr);
+        ssl.set_mtu(MTU).ok()?;
+        ssl.set_connect_state();
+        Session::new(ssl).ok()
+    }
+
+    /// Retransmits handshake flights that went unanswered, and drops
+    /// sessions that failed or went quiet.
+    pub fn sweep(&mut self, sock: &mut Socket) {
+        let mut out = vec![];
+        self.timeouts(&mut out);
+        for (addr, datagram) in out {
+            sock.send_to(&datagram, &addr);
+        }
+    }
+
+    fn timeouts(&mut self, out: &mut Vec<(SocketAddr, Vec<u8>)>) {
+        let now = Instant::now();
+        let lifetime = self.session_lifetime;
+
+        self.sessions.retain(|addr, session| {
+            if session.identity.is_none() {
+                if session.started + HANDSHAKE_TIMEOUT <= now {
+                    return false;
+                }
+                // Retransmits if OpenSSL's timer has run out, and fails once
+                // it's retransmitted too often.
+                let handled = unsafe {
+                    ffi::SSL_ctrl(session.stream.ssl().as_ptr(), DTLS_CTRL_HANDLE_TIMEOUT, 0, ptr::null_mut())
+                };
+                out.extend(session.stream.get_mut().outgoing.drain(..).map(|datagram| (*addr, datagram)));
+                if handled < 0 {
+                    return false;
+                }
+            }
+            session.last_heard + lifetime > now
+        });
+    }
+}
+
+// Whether `datagram` starts with a ClientHello record in epoch 0.
+fn is_client_hello(datagram: &[u8]) -> bool {
+    datagram.len() > 13 && datagram[0] == HANDSHAKE && datagram[3..5] == [0, 0] && datagram[13] == CLIENT_HELLO
+}
+
+fn context(mode: Mode, cookie_secret: [u8; 32], addr_index: Index<Ssl, SocketAddr>) -> Result<SslContext, ErrorStack> {
+    let mut ctx = SslContext::builder(SslMethod::dtls())?;
+    ctx.set_min_proto_version(Some(SslVersion::DTLS1_2))?;
+    ctx.set_max_proto_version(Some(SslVersion::DTLS1_2))?;
+    ctx.set_options(SslOptions::COOKIE_EXCHANGE | SslOptions::NO_QUERY_MTU | SslOptions::NO_TICKET);
+    // OpenSSL 3.2 and later only allow CCM_8 at security level 0 for its
+    // short tag. The suites, curve and signatures are pinned below instead,
+    // and certificate chains get a verification level of their own.
+    ctx.set_security_level(0);
+
+    ctx.set_cookie_generate_cb(move |ssl, buf| {
+        let cookie = cookie(&cookie_secret, ssl.ex_data(addr_index))?;
+        buf[..cookie.len()].copy_from_slice(&cookie);
+        Ok(cookie.len())
+    });
+    ctx.set_cookie_verify_cb(move |ssl, received| {
+        match cookie(&cookie_secret, ssl.ex_data(addr_index)) {
+            Ok(cookie) => cookie.len() == received.len() && memcmp::eq(&cookie, received),
+            Err(_) => false,
+        }
+    });
+
+    match mode {
+        Mode::Psk{keys, client_identity} => {
+            ctx.set_cipher_list("PSK-AES128-CCM8")?;
+            ctx.set_psk_server_callback(move |_, identity, psk| {
+                match identity.and_then(&keys) {
+                    Some(ref key) if key.len() <= psk.len() => {
+                        psk[..key.len()].copy_from_slice(key);
+                        Ok(key.len())
+                    },
+                    _ => Ok(0),
+                }
+            });
+            if let Some((identity, key)) = client_identity {
+                ctx.set_psk_client_callback(move |_, _, identity_buf, psk| {
+                    if identity.len() >= identity_buf.len() || key.len() > psk.len() {
+                        return Ok(0);
+                    }
+                    identity_buf[..identity.len()].copy_from_slice(&identity);
+                    identity_buf[identity.len()] = 0;
+                    psk[..key.len()].copy_from_slice(&key);
+                    Ok(key.len())
+                });
+            }
+        },
+        #[cfg(ossl320)]
+        Mode::RawPublicKey{key, trusted} => {
+            ecdsa(&mut ctx)?;
+            ctx.set_private_key(&key)?;
+            let types = [TLSEXT_CERT_TYPE_RPK];
+            unsafe {
+                if SSL_CTX_set1_client_cert_type(ctx.as_ptr(), types.as_ptr(), types.len()) != 1
+                    || SSL_CTX_set1_server_cert_type(ctx.as_ptr(), types.as_ptr(), types.len()) != 1 {
+                    return Err(ErrorStack::get());
+                }
+            }
+            ctx.set_verify_callback(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT, move |_, store| {
+                let key = unsafe { X509_STORE_CTX_get0_rpk(store.as_ptr()) };
+                public_key_der(key).is_some_and(|der| trusted(&der))
+            });
+        },
+        Mode::Certificate{cert, key, trusted} => {
+            ecdsa(&mut ctx)?;
+            ctx.set_certificate(&cert)?;
+            ctx.set_private_key(&key)?;
+            ctx.check_private_key()?;
+            ctx.set_cert_store(trusted);
+            // Rejects weak keys and MD5 or SHA-1 signatures in the chain.
+            ctx.verify_param_mut().set_auth_level(2);
+            ctx.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        },
+    }
+
+    Ok(ctx.build())
+}
+
+fn ecdsa(ctx: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+    ctx.set_cipher_list("ECDHE-ECDSA-AES128-CCM8")?;
+    ctx.set_groups_list("P-256")?;
+    ctx.set_sigalgs_list("ECDSA+SHA256")
+}
+
+// A HelloVerifyRequest cookie, a MAC of the address the ClientHello came
+// from.
+fn cookie(secret: &[u8], addr: Option<&SocketAddr>) -> Result<Vec<u8>, ErrorStack> {
+    let addr = addr.ok_or_else(ErrorStack::get)?;
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(addr.to_string().as_bytes())?;
+    let mut cookie = signer.sign_to_vec()?;
+    cookie.truncate(16);
+    Ok(cookie)
+}
+
+#[cfg(test)]
+fn p256_key() -> PKey<Private> {
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+// Passes datagrams between a client at `client_addr` and a server at
+// `server_addr` until neither side has anything more to say, returning what
+// each received.
+#[cfg(test)]
+fn exchange(client: &mut Sessions, server: &mut Sessions, client_addr: &SocketAddr, server_addr: &SocketAddr, mut out: Vec<Vec<u8>>) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let (mut to_client, mut to_server) = (vec![], vec![]);
+    while !out.is_empty() {
+        let mut reply = vec![];
+        for datagram in out.drain(..) {
+            to_server.extend(server.read(client_addr, &datagram, &mut reply));
+        }
+        for datagram in reply {
+            to_client.extend(client.read(server_addr, &datagram, &mut out));
+        }
+    }
+    (to_client, to_server)
+}
+
+#[test]
+fn test_dtls_handshake() {
+    let server_addr: SocketAddr = "192.0.2.1:5684".parse().unwrap();
+    let client_addr: SocketAddr = "192.0.2.2:40000".parse().unwrap();
+    let keys = |id: &[u8]| if id == b"client" { Some(b"secretPSK".to_vec()) } else { None };
+    let run = |client: &mut Sessions, server: &mut Sessions, out| exchange(client, server, &client_addr, &server_addr, out);
+
+    let mut server = Sessions::new(Dtls::psk(keys)).unwrap();
+    let mut client = Sessions::new(Dtls::psk(|_| None).client_identity(b"client", b"secretPSK")).unwrap();
+
+    // Sending starts the handshake, and the message follows it.
+    let mut out = vec![];
+    client.write(&server_addr, b"hello", &mut out);
+    assert_eq!(client.identity(&server_addr), None);
+    let (_, received) = run(&mut client, &mut server, out);
+    assert_eq!(received, [b"hello".to_vec()]);
+    assert_eq!(server.identity(&client_addr), Some(Identity::Psk(b"client".to_vec())));
+    assert_eq!(client.identity(&server_addr), Some(Identity::Psk(b"client".to_vec())));
+
+    let mut out = vec![];
+    server.write(&client_addr, b"world", &mut out);
+    assert_eq!(client.read(&server_addr, &out[0], &mut vec![]), [b"world".to_vec()]);
+
+    // The same record again is a replay, and a tampered one doesn't open.
+    assert!(client.read(&server_addr, &out[0], &mut vec![]).is_empty());
+    let last = out[0].len() - 1;
+    out[0][last] ^= 1;
+    assert!(client.read(&server_addr, &out[0], &mut vec![]).is_empty());
+    assert!(client.identity(&server_addr).is_some());
+
+    // Unknown identities and wrong keys don't get a session.
+    for &(identity, key) in &[(&b"stranger"[..], &b"secretPSK"[..]), (&b"client"[..], &b"guess"[..])] {
+        let mut server = Sessions::new(Dtls::psk(keys)).unwrap();
+        let mut client = Sessions::new(Dtls::psk(|_| None).client_identity(identity, key)).unwrap();
+        let mut out = vec![];
+        client.write(&server_addr, b"hello", &mut out);
+        let (_, received) = run(&mut client, &mut server, out);
+        assert!(received.is_empty());
+        assert_eq!(server.identity(&client_addr), None);
+        assert_eq!(client.identity(&server_addr), None);
+    }
+
+    // A client that lost its session can start a new one.
+    let mut restarted = Sessions::new(Dtls::psk(|_| None).client_identity(b"client", b"secretPSK")).unwrap();
+    let mut out = vec![];
+    restarted.write(&server_addr, b"again", &mut out);
+    let (_, received) = run(&mut restarted, &mut server, out);
+    assert_eq!(received, [b"again".to_vec()]);
+    let mut out = vec![];
+    server.write(&client_addr, b"world", &mut out);
+    assert_eq!(restarted.read(&server_addr, &out[0], &mut vec![]), [b"world".to_vec()]);
+    assert!(client.read(&server_addr, &out[0], &mut vec![]).is_empty());
+
+    // Nothing is kept for a ClientHello without a cookie.
+    let mut server = Sessions::new(Dtls::psk(keys)).unwrap();
+    let mut client = Sessions::new(Dtls::psk(|_| None).client_identity(b"client", b"secretPSK")).unwrap();
+    let mut hello = vec![];
+    client.write(&server_addr, b"hello", &mut hello);
+    let mut verify = vec![];
+    server.read(&client_addr, &hello[0], &mut verify);
+    assert_eq!(verify.len(), 1);
+    assert!(server.sessions.is_empty());
+}
+
+#[cfg(test)]
+fn handshake(client: Dtls, server: Dtls) -> (Sessions, Sessions, Vec<Vec<u8>>) {
+    let server_addr: SocketAddr = "192.0.2.1:5684".parse().unwrap();
+    let client_addr: SocketAddr = "192.0.2.2:40000".parse().unwrap();
+    let mut client = Sessions::new(client).unwrap();
+    let mut server = Sessions::new(server).unwrap();
+
+    let mut out = vec![];
+    client.write(&server_addr, b"hello", &mut out);
+    let (_, received) = exchange(&mut client, &mut server, &client_addr, &server_addr, out);
+    (client, server, received)
+}
+
+#[test]
+#[cfg(ossl320)]
+fn test_dtls_raw_public_keys() {
+    let server_addr: SocketAddr = "192.0.2.1:5684".parse().unwrap();
+    let client_addr: SocketAddr = "192.0.2.2:40000".parse().unwrap();
+    let (server_key, client_key) = (p256_key(), p256_key());
+    let server_der = server_key.public_key_to_der().unwrap();
+    let client_der = client_key.public_key_to_der().unwrap();
+
+    // Keys are accepted when the callback trusts them.
+    for &trust_client in &[true, false] {
+        let expected = client_der.clone();
+        let server = Dtls::raw_public_key(server_key.clone(), move |der| trust_client && der == &expected[..]);
+        let expected = server_der.clone();
+        let client = Dtls::raw_public_key(client_key.clone(), move |der| der == &expected[..]);
+
+        let (client, server, received) = handshake(client, server);
+        if trust_client {
+            assert_eq!(received, [b"hello".to_vec()]);
+            assert_eq!(server.identity(&client_addr), Some(Identity::RawPublicKey(client_der.clone())));
+            assert_eq!(client.identity(&server_addr), Some(Identity::RawPublicKey(server_der.clone())));
+        } else {
+            assert!(received.is_empty());
+            assert_eq!(server.identity(&client_addr), None);
+        }
+    }
+}
+
+// Issues a day's certificate for `key`, self-signed unless an issuer is given.
+#[cfg(test)]
+fn certificate(key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>, digest: MessageDigest) -> X509 {
+    use openssl::asn1::Asn1Time;
+    use openssl::x509::X509NameBuilder;
+    use openssl::x509::extension::BasicConstraints;
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", if issuer.is_some() { "leaf" } else { "root" }).unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_pubkey(key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    match issuer {
+        Some((issuer, issuer_key)) => {
+            cert.set_issuer_name(issuer.subject_name()).unwrap();
+            cert.sign(issuer_key, digest).unwrap();
+        },
+        None => {
+            cert.set_issuer_name(&name).unwrap();
+            cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+            cert.sign(key, digest).unwrap();
+        },
+    }
+    cert.build()
+}
+
+#[cfg(test)]
+fn trusting(cert: &X509) -> X509Store {
+    use openssl::x509::store::X509StoreBuilder;
+
+    let mut store = X509StoreBuilder::new().unwrap();
+    store.add_cert(cert.clone()).unwrap();
+    store.build()
+}
+
+#[test]
+fn test_dtls_certificates() {
+    let server_addr: SocketAddr = "192.0.2.1:5684".parse().unwrap();
+    let client_addr: SocketAddr = "192.0.2.2:40000".parse().unwrap();
+
+    // Certificates have to be issued by a trusted one, here they're
+    // self-signed and trusted directly.
+    let (server_key, client_key) = (p256_key(), p256_key());
+    let server_cert = certificate(&server_key, None, MessageDigest::sha256());
+    let client_cert = certificate(&client_key, None, MessageDigest::sha256());
+    let stranger_cert = certificate(&p256_key(), None, MessageDigest::sha256());
+    for &trust_client in &[true, false] {
+        let trusted = trusting(if trust_client { &client_cert } else { &stranger_cert });
+        let server = Dtls::certificate(server_cert.clone(), server_key.clone(), trusted);
+        let client = Dtls::certificate(client_cert.clone(), client_key.clone(), trusting(&server_cert));
+
+        let (client, server, received) = handshake(client, server);
+        if trust_client {
+            assert_eq!(received, [b"hello".to_vec()]);
+            assert_eq!(server.identity(&client_addr), Some(Identity::Certificate(client_cert.to_der().unwrap())));
+            assert_eq!(client.identity(&server_addr), Some(Identity::Certificate(server_cert.to_der().unwrap())));
+        } else {
+            assert!(received.is_empty());
+            assert_eq!(server.identity(&client_addr), None);
+        }
+    }
+}
+
+#[test]
+fn test_dtls_rejects_weak_chains() {
+    let client_addr: SocketAddr = "192.0.2.2:40000".parse().unwrap();
+    let (root_key, server_key, client_key) = (p256_key(), p256_key(), p256_key());
+    let root = certificate(&root_key, None, MessageDigest::sha256());
+    let server_cert = certificate(&server_key, None, MessageDigest::sha256());
+
+    // A client certificate issued by the trusted root only passes if the
+    // root signed it with SHA-256, not SHA-1.
+    for &(digest, accepted) in &[(MessageDigest::sha256(), true), (MessageDigest::sha1(), false)] {
+        let client_cert = certificate(&client_key, Some((&root, &root_key)), digest);
+        let server = Dtls::certificate(server_cert.clone(), server_key.clone(), trusting(&root));
+        let client = Dtls::certificate(client_cert.clone(), client_key.clone(), trusting(&server_cert));
+
+        let (_, server, received) = handshake(client, server);
+        if accepted {
+            assert_eq!(received, [b"hello".to_vec()]);
+            assert_eq!(server.identity(&client_addr), Some(Identity::Certificate(client_cert.to_der().unwrap())));
+        } else {
+            assert!(received.is_empty());
+            assert_eq!(server.identity(&client_addr), None);
+        }
+    }
+}
+
+#[test]
+fn test_dtls_endpoint() {
+    use endpoint::Endpoint;
+    use message::{Code, Message};
+    use testing::{spawn, Whoami};
+    use std::sync::mpsc;
+
+    let localhost = "127.0.0.1:0".parse().unwrap();
+    let server = spawn(Endpoint::new(localhost)
+        .dtls(Dtls::psk(|identity| if identity == b"sensor-7" { Some(b"0123456789abcdef".to_vec()) } else { None }))
+        .bind(Whoami).unwrap());
+    let client = spawn(Endpoint::new(localhost)
+        .dtls(Dtls::psk(|_| None).client_identity(b"sensor-7", b"0123456789abcdef"))
+        .bind(Whoami).unwrap());
+
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..2 {
+        let tx = tx.clone();
+        client.handle.request(server.addr, Message::request(Code::Get, "coaps://localhost/whoami").unwrap(), move |r| {
+            tx.send(r.map(|m| m.payload)).unwrap();
+        }).unwrap();
+    }
+    for _ in 0..2 {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap(), b"sensor-7");
+    }
+
+    client.stop();
+    server.stop();
+}
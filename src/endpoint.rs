@@ -1,30 +1,1112 @@
 use constants::*;
-use message::Message;
-use socket_handler::SocketHandler;
+use message::{Message, MessageRef};
+use random;
+use message::option::OptionRegistry;
+use socket::Socket;
+use socket_handler::{Command, SocketHandler};
+use tcp::{Framing, Streams};
+use dtls::{Dtls, Sessions};
+use oscore::Oscore;
+use security::Identity;
+use transaction::{Delivery, Params};
+use exchange::{self, ResponseFuture};
 
 use mio::*;
 use mio::udp::{UdpSocket};
+use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 
 pub trait MsgHandler {
-    fn handle_msg(&self, &SocketAddr, &Message) -> Option<Vec<u8>>;
+    fn handle_msg(&self, addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>>;
+
+    /// Application specific options to decode before calling `handle_msg`.
+    fn option_registry(&self) -> &OptionRegistry {
+        OptionRegistry::empty()
+    }
+
+    /// Called with a message borrowed from the receive buffer. The default
+    /// copies it into a `Message` for `handle_msg`, handlers that want to
+    /// avoid the per-packet allocation can override this instead.
+    fn handle_msg_ref(&self, addr: &SocketAddr, msg: &MessageRef) -> Option<Vec<u8>> {
+        match msg.to_owned_with(self.option_registry()) {
+            Ok(msg) => self.handle_msg(addr, &msg),
+            Err(_) => None
+        }
+    }
+
+    /// Called with a reusable, empty send buffer that the reply (if any)
+    /// should be written into, e.g. with `Message::encode_into_vec`. Nothing
+    /// is sent if the buffer is left empty.
+    fn handle_msg_into(&self, addr: &SocketAddr, msg: &MessageRef, buf: &mut Vec<u8>) {
+        if let Some(resp) = self.handle_msg_ref(addr, msg) {
+            buf.extend_from_slice(&resp);
+        }
+    }
+
+    /// Called first for every message, with a `Responder` that can be kept
+    /// to answer the request later instead of writing a reply into `buf`.
+    /// An observer's request is passed again for each notification, when
+    /// a kept `Responder` means none is sent. The default drops it and
+    /// calls `handle_msg_into`.
+    fn handle_request(&self, addr: &SocketAddr, msg: &MessageRef, buf: &mut Vec<u8>, _responder: Responder) {
+        self.handle_msg_into(addr, msg, buf)
+    }
+
+    /// Called instead of `handle_request` for requests that came over DTLS
+    /// or were protected with OSCORE, with who the peer authenticated as.
+    /// Notifications to such observers are made the same way. The default
+    /// ignores the identity.
+    fn handle_secure_request(&self, addr: &SocketAddr, _identity: &Identity, msg: &MessageRef, buf: &mut Vec<u8>, responder: Responder) {
+        self.handle_request(addr, msg, buf, responder)
+    }
+}
+
+/// Tuning for an `Endpoint`, the defaults should suit most servers.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Largest block size used when splitting responses, and by a `Client`
+    /// for request bodies, as an SZX (0 to 6 for 16 to 1024 byte blocks).
+    pub block_szx: u8,
+    /// How long a block-wise transfer is kept without hearing from the
+    /// client, both for split responses and for request bodies being
+    /// reassembled.
+    pub block_lifetime: Duration,
+    /// Maximum number of block-wise transfers of each kind kept at once.
+    pub block_cache_size: usize,
+    /// Largest request body that will be reassembled from Block1 blocks.
+    /// Bodies being reassembled can take up to `block_cache_size` times
+    /// this much memory, 16 MiB with the defaults.
+    pub max_body_size: usize,
+    /// Largest response body a `Client` reassembles from Block2 blocks.
+    pub max_response_size: usize,
+    /// Largest message accepted over a reliable transport, which peers are
+    /// told in a CSM.
+    pub max_message_size: usize,
+    /// Most connections open at once over a reliable transport, further ones
+    /// are closed as soon as they're accepted.
+    pub max_connections: usize,
+    /// Timing of retransmissions for confirmable messages, this also sets
+    /// how long received MIDs are remembered to detect duplicates.
+    pub transmission: Params,
+    /// Maximum number of received MIDs remembered to detect duplicates.
+    pub dedup_capacity: usize,
+    /// How often expired exchange state is cleaned up.
+    pub sweep_interval: Duration,
+    /// How long a CON request left unanswered by the handler waits for a
+    /// `Responder` before it gets an empty ACK, after which the response is
+    /// sent separately as a CON of its own.
+    pub ack_delay: Duration,
+    /// Most requests waiting on a `Responder` at once, further ones are
+    /// answered with 5.03 Service Unavailable.
+    pub max_separate_responses: usize,
+    /// How often each observer is sent a CON notification to check it's
+    /// still interested, RFC 7641 asks for at least once a day.
+    pub observe_check_interval: Duration,
+    /// Most observers kept at once, further registrations are answered
+    /// without an Observe option.
+    pub max_observers: usize,
+    /// Most observers kept for any one address.
+    pub max_observers_per_peer: usize,
+    /// How long a request sent by the endpoint waits for a separate
+    /// response once it's been acknowledged, or for any response to a NON.
+    pub response_timeout: Duration,
+    /// Most requests sent by the endpoint that may be outstanding to one
+    /// peer at a time, further ones are queued.
+    pub nstart: usize,
+    /// Largest datagram received, longer ones are dropped.
+    pub recv_buffer_size: usize,
+    /// Most datagrams held back while the socket isn't writable, further
+    /// ones are dropped.
+    pub send_queue_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config{
+            block_szx: 6,
+            block_lifetime: Duration::from_secs(60),
+            block_cache_size: 64,
+            max_body_size: 256 * 1024,
+            max_response_size: 64 * 1024 * 1024,
+            max_message_size: 64 * 1024,
+            max_connections: 1024,
+            transmission: Params::default(),
+            dedup_capacity: 16 * 1024,
+            sweep_interval: Duration::from_secs(1),
+            ack_delay: Duration::from_secs(1),
+            max_separate_responses: 1024,
+            observe_check_interval: Duration::from_secs(24 * 60 * 60),
+            max_observers: 1024,
+            max_observers_per_peer: 16,
+            response_timeout: Duration::from_secs(93),
+            nstart: 1,
+            recv_buffer_size: 64 * 1024,
+            send_queue_size: 1024,
+        }
+    }
 }
 
 pub struct Endpoint {
     local_addr: SocketAddr,
+    websocket_addr: Option<SocketAddr>,
+    dtls: Option<Dtls>,
+    oscore: Option<Oscore>,
+    config: Config,
 }
 
 impl Endpoint {
     pub fn new(local_addr: SocketAddr) -> Endpoint {
-        Endpoint{local_addr: local_addr}
+        Endpoint::with_config(local_addr, Config::default())
+    }
+
+    pub fn with_config(local_addr: SocketAddr, config: Config) -> Endpoint {
+        Endpoint{local_addr, websocket_addr: None, dtls: None, oscore: None, config}
+    }
+
+    /// Also accepts CoAP over WebSockets connections on `addr`, for the
+    /// same handler. Requests and `send`s to a peer connected that way go
+    /// over its connection, but connections to WebSocket servers aren't
+    /// opened and Observe is only sent over UDP.
+    pub fn websocket(mut self, addr: SocketAddr) -> Endpoint {
+        self.websocket_addr = Some(addr);
+        self
+    }
+
+    /// Secures the socket with DTLS, making this a `coaps` endpoint. Plain
+    /// CoAP isn't accepted on it any more.
+    pub fn dtls(mut self, dtls: Dtls) -> Endpoint {
+        self.dtls = Some(dtls);
+        self
+    }
+
+    /// Protects requests and responses end to end with OSCORE, for the
+    /// peers and key IDs `oscore` has security contexts for. Requests
+    /// without the OSCORE option are still handled as they are.
+    pub fn oscore(mut self, oscore: Oscore) -> Endpoint {
+        self.oscore = Some(oscore);
+        self
+    }
+
+    /// Binds the socket and sets up the event loop without starting it, so
+    /// that a `Handle` can be taken first. Fails if the transmission
+    /// parameters in the config aren't valid, see `Params::validate`.
+    pub fn bind<H: MsgHandler>(self, handler: H) -> io::Result<BoundEndpoint<H>> {
+        self.config.transmission.validate()?;
+        let server = UdpSocket::bound(&self.local_addr)?;
+        let local_addr = server.local_addr()?;
+
+        let mut event_loop = EventLoop::new()?;
+        event_loop.register(&server, SERVER, EventSet::readable(), PollOpt::edge())?;
+
+        let streams = match self.websocket_addr {
+            Some(addr) => Some(Streams::bind(&mut event_loop, &addr, Framing::WebSocket, &self.config)?),
+            None => None,
+        };
+        let websocket_addr = match streams {
+            Some(ref streams) => Some(streams.local_addr()?),
+            None => None,
+        };
+        let dtls = match self.dtls {
+            Some(dtls) => Some(Sessions::new(dtls)?),
+            None => None,
+        };
+
+        let handle = Handle::new(event_loop.channel());
+        let io_stats = IoStats::default();
+        let sock = Socket::new(server, self.config.send_queue_size, io_stats.clone());
+        let mut handler = SocketHandler::new(sock, dtls, self.oscore, streams, handler, self.config, handle);
+        handler.schedule_sweep(&mut event_loop);
+
+        Ok(BoundEndpoint{
+            local_addr,
+            websocket_addr,
+            io_stats,
+            event_loop,
+            handler,
+        })
     }
 
     pub fn run<H: MsgHandler>(self, handler: H) {
-        let server = UdpSocket::bound(&self.local_addr).unwrap();
+        self.bind(handler).unwrap().run().unwrap();
+    }
+}
+
+pub struct BoundEndpoint<H: MsgHandler> {
+    local_addr: SocketAddr,
+    websocket_addr: Option<SocketAddr>,
+    io_stats: IoStats,
+    event_loop: EventLoop<SocketHandler<H>>,
+    handler: SocketHandler<H>,
+}
+
+impl<H: MsgHandler> BoundEndpoint<H> {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Where WebSocket connections are accepted, if they are.
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.websocket_addr
+    }
+
+    pub fn handle(&self) -> Handle {
+        Handle::new(self.event_loop.channel())
+    }
+
+    /// Counts of the socket's errors, which keep updating while it runs.
+    pub fn io_stats(&self) -> IoStats {
+        self.io_stats.clone()
+    }
+
+    /// Runs the event loop until `Handle::shutdown` is called.
+    pub fn run(mut self) -> io::Result<()> {
+        self.event_loop.run(&mut self.handler)
+    }
+}
+
+/// How often the endpoint's UDP socket failed, readable from any thread.
+/// None of these stop the endpoint, the datagrams involved are just lost.
+#[derive(Clone, Default)]
+pub struct IoStats {
+    counts: Arc<IoCounts>,
+}
+
+#[derive(Default)]
+struct IoCounts {
+    recv_errors: AtomicUsize,
+    send_errors: AtomicUsize,
+    dropped_sends: AtomicUsize,
+    oversized_recvs: AtomicUsize,
+}
+
+impl IoStats {
+    /// Errors reading from the socket, e.g. ICMP reports of unreachable
+    /// peers.
+    pub fn recv_errors(&self) -> usize {
+        self.counts.recv_errors.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams that couldn't be sent.
+    pub fn send_errors(&self) -> usize {
+        self.counts.send_errors.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams dropped because too many were already waiting for the
+    /// socket to become writable.
+    pub fn dropped_sends(&self) -> usize {
+        self.counts.dropped_sends.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams dropped for being longer than `Config::recv_buffer_size`.
+    pub fn oversized_recvs(&self) -> usize {
+        self.counts.oversized_recvs.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn recv_error(&self) {
+        self.counts.recv_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn send_error(&self) {
+        self.counts.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped_send(&self) {
+        self.counts.dropped_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn oversized_recv(&self) {
+        self.counts.oversized_recvs.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Sends messages from the endpoint, from any thread.
+#[derive(Clone)]
+pub struct Handle {
+    sender: Sender<Command>,
+}
+
+impl Handle {
+    pub(crate) fn new(sender: Sender<Command>) -> Handle {
+        Handle{sender}
+    }
+
+    /// Sends a message. CON and NON messages are given a fresh MID, and CONs
+    /// are retransmitted until they're acknowledged or time out.
+    pub fn send(&self, addr: SocketAddr, msg: Message) -> io::Result<()> {
+        self.command(Command::Send(addr, msg, None))
+    }
+
+    /// Sends a CON, calling `callback` once it's been acknowledged, reset or
+    /// has timed out.
+    pub fn send_con<F>(&self, addr: SocketAddr, msg: Message, callback: F) -> io::Result<()>
+        where F: FnOnce(Delivery) + Send + 'static {
+        self.command(Command::Send(addr, msg, Some(Box::new(callback))))
+    }
+
+    /// Sends a request, calling `callback` with the response or why there
+    /// isn't one. The request is given a fresh MID, and a token if it
+    /// doesn't have one, and waits its turn if NSTART requests are already
+    /// outstanding to `addr`.
+    pub fn request<F>(&self, addr: SocketAddr, msg: Message, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<Message, exchange::Error>) + Send + 'static {
+        self.command(Command::Request(addr, msg, Box::new(callback)))
+    }
+
+    /// Like `request`, but the response is delivered through a future.
+    pub fn request_future(&self, addr: SocketAddr, msg: Message) -> io::Result<ResponseFuture> {
+        let (future, callback) = ResponseFuture::new();
+        self.command(Command::Request(addr, msg, callback))?;
+        Ok(future)
+    }
+
+    /// Observes the resource `msg` asks for, calling `callback` with the
+    /// response and each notification after it. Notifications that arrive
+    /// out of order are dropped, and the resource is registered for again
+    /// if nothing fresh arrives before the last one's Max-Age runs out. The
+    /// observation ends with a response that isn't a notification, e.g. an
+    /// error, or with an `Err` if registering fails.
+    pub fn observe<F>(&self, addr: SocketAddr, mut msg: Message, callback: F) -> io::Result<Observation>
+        where F: FnMut(Result<Message, exchange::Error>) + Send + 'static {
+        if msg.token.is_empty() {
+            msg.token = random::token(8);
+        }
+
+        let observation = Observation{handle: self.clone(), addr, token: msg.token.clone()};
+        self.command(Command::Observe(addr, msg, Box::new(callback)))?;
+        Ok(observation)
+    }
+
+    /// Tells the observers of the resource at `path`, e.g. `/sensors/1a`,
+    /// that it has changed. The handler is asked for each of them again
+    /// and the responses are sent as notifications.
+    pub fn notify(&self, path: &str) -> io::Result<()> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let segments = if path.is_empty() { vec![] } else { path.split('/').map(|s| s.to_string()).collect() };
+        self.command(Command::Notify(segments))
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.command(Command::Shutdown)
+    }
+
+    pub(crate) fn command(&self, cmd: Command) -> io::Result<()> {
+        self.sender.send(cmd).map_err(|e| match e {
+            NotifyError::Io(e) => e,
+            NotifyError::Full(_) => io::Error::new(io::ErrorKind::WouldBlock, "endpoint queue full"),
+            NotifyError::Closed(_) => io::Error::new(io::ErrorKind::BrokenPipe, "endpoint stopped"),
+        })
+    }
+}
+
+/// Answers a request after the handler has returned, from any thread.
+///
+/// If the response arrives within `Config::ack_delay` of a CON request it's
+/// piggybacked on the ACK, otherwise the request has already been
+/// acknowledged and the response goes out as a CON with the request's token,
+/// retransmitted until the client acknowledges it. Responses to NON requests
+/// are sent as NONs. Responding after EXCHANGE_LIFETIME, or to a request the
+/// handler already replied to, does nothing.
+///
+/// Dropping the `Responder` without responding lets the endpoint forget the
+/// request, a CON is then left for the client to retransmit.
+pub struct Responder {
+    handle: Handle,
+    id: u64,
+    addr: SocketAddr,
+    token: Vec<u8>,
+    liveness: Liveness,
+}
+
+impl Responder {
+    pub(crate) fn new(handle: Handle, id: u64, addr: SocketAddr, token: &[u8]) -> Responder {
+        let liveness = Liveness(Arc::new(AtomicUsize::new(PENDING)));
+        Responder{handle, id, addr, token: token.to_vec(), liveness}
+    }
+
+    pub(crate) fn liveness(&self) -> Liveness {
+        self.liveness.clone()
+    }
+
+    /// The client that sent the request.
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// Sends `response`. Its type, MID and token are filled in by the
+    /// endpoint.
+    pub fn respond(self, response: Message) -> io::Result<()> {
+        self.liveness.0.store(RESPONDED, Ordering::SeqCst);
+        self.handle.command(Command::Respond(self.id, response))
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        let state = &self.liveness.0;
+        if state.compare_exchange(PENDING, DROPPED, Ordering::SeqCst, Ordering::SeqCst).is_err()
+            && state.compare_exchange(REGISTERED, DROPPED, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            self.handle.command(Command::Release(self.id)).ok();
+        }
+    }
+}
+
+const PENDING: usize = 0;
+const REGISTERED: usize = 1;
+const DROPPED: usize = 2;
+const RESPONDED: usize = 3;
+
+// Where a `Responder` is at, shared with the endpoint so it only waits for
+// responses that can still come. A `Responder` that's dropped after the
+// endpoint started waiting tells it to stop.
+#[derive(Clone)]
+pub(crate) struct Liveness(Arc<AtomicUsize>);
+
+impl Liveness {
+    /// Marks the request as waiting for its response, false if the
+    /// `Responder` has already been dropped without one.
+    pub(crate) fn register(&self) -> bool {
+        match self.0.compare_exchange(PENDING, REGISTERED, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => true,
+            Err(state) => state == RESPONDED,
+        }
+    }
+}
+
+/// A resource being observed through `Handle::observe`. Dropping it doesn't
+/// end the observation.
+pub struct Observation {
+    handle: Handle,
+    addr: SocketAddr,
+    token: Vec<u8>,
+}
+
+impl Observation {
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// Stops observing and tells the server with a GET with Observe 1.
+    pub fn cancel(self) -> io::Result<()> {
+        self.handle.command(Command::Unobserve((self.addr, self.token), true))
+    }
+
+    /// Stops observing without telling the server, the next notification is
+    /// answered with a RST.
+    pub fn forget(self) -> io::Result<()> {
+        self.handle.command(Command::Unobserve((self.addr, self.token), false))
+    }
+}
+
+
+#[test]
+fn test_endpoint_retransmits_con_until_acked() {
+    use message::{Code, MessageBuilder, Mtype};
+    use nullhandler::NullHandler;
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::sync::mpsc;
+
+    let mut config = Config::default();
+    config.transmission.ack_timeout = Duration::from_millis(200);
+    config.transmission.ack_random_factor = 1.0;
+
+    let server = spawn(Endpoint::with_config("127.0.0.1:0".parse().unwrap(), config).bind(NullHandler).unwrap());
+
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let msg = MessageBuilder::new(Mtype::Confirmable, Code::Get).token(&[7]).build();
+    server.handle.send_con(peer.local_addr().unwrap(), msg, move |d| tx.send(d).unwrap()).unwrap();
+
+    let mut buf = [0; 64];
+    let (len, from) = peer.recv_from(&mut buf).unwrap();
+    assert_eq!(from, server.addr);
+    let first = Message::from_bytes(&buf[..len]).unwrap();
+    assert_eq!(first.token, [7]);
+
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    assert_eq!(Message::from_bytes(&buf[..len]).unwrap(), first);
 
-        let mut event_loop = EventLoop::new().unwrap();
-        event_loop.register(&server, SERVER, EventSet::readable(), PollOpt::edge()).unwrap();
-        event_loop.run(&mut SocketHandler::new(server, handler)).unwrap();
+    let ack = Message::ack_for(&first, Code::Content);
+    peer.send_to(&ack.to_bytes().unwrap(), server.addr).unwrap();
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(Delivery::Acknowledged(ack)));
+
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_replays_response_to_duplicate_con() {
+    use message::{Code, MessageBuilder, Mtype};
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter(Arc<AtomicUsize>);
+
+    impl MsgHandler for Counter {
+        fn handle_msg(&self, _addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+            let n = self.0.fetch_add(1, Ordering::SeqCst) as u8;
+            Message::ack_for(msg, Code::Changed).to_bytes().ok().map(|mut b| { b.push(0xFF); b.push(n); b })
+        }
+    }
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let server = spawn(Endpoint::new("127.0.0.1:0".parse().unwrap()).bind(Counter(calls.clone())).unwrap());
+
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let post = MessageBuilder::new(Mtype::Confirmable, Code::Post).mid(0x1234).token(&[1]).build();
+    let mut buf = [0; 64];
+
+    peer.send_to(&post.to_bytes().unwrap(), server.addr).unwrap();
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    let first = buf[..len].to_vec();
+
+    peer.send_to(&post.to_bytes().unwrap(), server.addr).unwrap();
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &first[..]);
+
+    let non = MessageBuilder::new(Mtype::NonConfirmable, Code::Post).mid(0x1235).build();
+    peer.send_to(&non.to_bytes().unwrap(), server.addr).unwrap();
+    peer.recv_from(&mut buf).unwrap();
+    peer.send_to(&non.to_bytes().unwrap(), server.addr).unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    assert!(peer.recv_from(&mut buf).is_err());
+
+    assert_eq!(calls.load(Ordering::SeqCst), 2, "handler calls");
+
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_sends_separate_response() {
+    use message::{Code, MessageBuilder, Mtype};
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+
+    struct Deferred(Mutex<mpsc::Sender<Responder>>);
+
+    impl MsgHandler for Deferred {
+        fn handle_msg(&self, _addr: &SocketAddr, _msg: &Message) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn handle_request(&self, _addr: &SocketAddr, _msg: &MessageRef, _buf: &mut Vec<u8>, responder: Responder) {
+            self.0.lock().unwrap().send(responder).unwrap();
+        }
+    }
+
+    let mut config = Config{ack_delay: Duration::from_millis(200), ..Config::default()};
+    config.transmission.ack_timeout = Duration::from_millis(200);
+    config.transmission.ack_random_factor = 1.0;
+
+    let (tx, rx) = mpsc::channel();
+    let server = spawn(Endpoint::with_config("127.0.0.1:0".parse().unwrap(), config).bind(Deferred(Mutex::new(tx))).unwrap());
+
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 64];
+
+    // Answered in time, so it's piggybacked.
+    let get = MessageBuilder::new(Mtype::Confirmable, Code::Get).mid(0x100).token(&[1]).build();
+    peer.send_to(&get.to_bytes().unwrap(), server.addr).unwrap();
+    let responder = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(responder.token(), [1]);
+    responder.respond(MessageBuilder::new(Mtype::Confirmable, Code::Content).payload(b"now".to_vec()).build()).unwrap();
+
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    let resp = Message::from_bytes(&buf[..len]).unwrap();
+    assert_eq!(resp.mtype, Mtype::Acknowledgement);
+    assert_eq!(resp.mid, 0x100);
+    assert_eq!(resp.token, [1]);
+    assert_eq!(resp.payload, b"now");
+
+    // Too slow, so the request is acknowledged first.
+    let get = MessageBuilder::new(Mtype::Confirmable, Code::Get).mid(0x101).token(&[2]).build();
+    peer.send_to(&get.to_bytes().unwrap(), server.addr).unwrap();
+    let responder = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    let ack = Message::from_bytes(&buf[..len]).unwrap();
+    assert_eq!(ack.mtype, Mtype::Acknowledgement);
+    assert_eq!(ack.code, Code::Empty);
+    assert_eq!(ack.mid, 0x101);
+
+    // A retransmitted request gets the empty ACK again.
+    peer.send_to(&get.to_bytes().unwrap(), server.addr).unwrap();
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    assert_eq!(Message::from_bytes(&buf[..len]).unwrap(), ack);
+
+    responder.respond(MessageBuilder::new(Mtype::Acknowledgement, Code::Content).payload(b"later".to_vec()).build()).unwrap();
+
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    let resp = Message::from_bytes(&buf[..len]).unwrap();
+    assert_eq!(resp.mtype, Mtype::Confirmable);
+    assert_eq!(resp.token, [2]);
+    assert_eq!(resp.payload, b"later");
+
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    assert_eq!(Message::from_bytes(&buf[..len]).unwrap(), resp);
+
+    peer.send_to(&Message::ack_for(&resp, Code::Empty).to_bytes().unwrap(), server.addr).unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    assert!(peer.recv_from(&mut buf).is_err());
+
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_forgets_dropped_responders() {
+    use message::{Code, MessageBuilder, Mtype};
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+
+    struct Deferred(Mutex<mpsc::Sender<Responder>>);
+
+    impl MsgHandler for Deferred {
+        fn handle_msg(&self, _addr: &SocketAddr, _msg: &Message) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn handle_request(&self, _addr: &SocketAddr, msg: &MessageRef, _buf: &mut Vec<u8>, responder: Responder) {
+            if msg.payload() != Ok(&b"drop"[..]) {
+                self.0.lock().unwrap().send(responder).unwrap();
+            }
+        }
+    }
+
+    let config = Config{ack_delay: Duration::from_millis(300), max_separate_responses: 1, ..Config::default()};
+
+    let (tx, rx) = mpsc::channel();
+    let server = spawn(Endpoint::with_config("127.0.0.1:0".parse().unwrap(), config).bind(Deferred(Mutex::new(tx))).unwrap());
+
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 64];
+    let get = |mid: u16, payload: &[u8]| {
+        MessageBuilder::new(Mtype::Confirmable, Code::Get).mid(mid).token(&[mid as u8]).payload(payload.to_vec()).build()
+    };
+
+    // Only one request may wait on its Responder.
+    peer.send_to(&get(1, b"keep").to_bytes().unwrap(), server.addr).unwrap();
+    let first = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    peer.send_to(&get(2, b"keep").to_bytes().unwrap(), server.addr).unwrap();
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    let full = Message::from_bytes(&buf[..len]).unwrap();
+    assert_eq!((full.mtype, full.code, full.mid), (Mtype::Acknowledgement, Code::ServiceUnavailable, 2));
+    drop(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+
+    // Once the Responder is dropped the request is forgotten, without an
+    // empty ACK, and makes room for the next.
+    drop(first);
+    peer.set_read_timeout(Some(Duration::from_millis(600))).unwrap();
+    assert!(peer.recv_from(&mut buf).is_err());
+
+    peer.send_to(&get(3, b"keep").to_bytes().unwrap(), server.addr).unwrap();
+    let third = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    third.respond(MessageBuilder::new(Mtype::Confirmable, Code::Content).build()).unwrap();
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    let resp = Message::from_bytes(&buf[..len]).unwrap();
+    assert_eq!((resp.mtype, resp.code, resp.mid), (Mtype::Acknowledgement, Code::Content, 3));
+
+    // A request whose Responder the handler dropped isn't acknowledged.
+    peer.send_to(&get(4, b"drop").to_bytes().unwrap(), server.addr).unwrap();
+    assert!(peer.recv_from(&mut buf).is_err());
+
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_notifies_observers() {
+    use message::{Code, MessageBuilder, Mtype};
+    use message::option::Option as CoapOption;
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    struct Counter(Arc<AtomicUsize>);
+
+    impl MsgHandler for Counter {
+        fn handle_msg(&self, _addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+            let n = self.0.load(Ordering::SeqCst) as u8;
+            let mut resp = Message::response_for(msg, Code::Content);
+            resp.options.push(CoapOption::MaxAge(if n < 2 { 3600 } else { 1 }));
+            resp.payload = vec![n];
+            resp.to_bytes().ok()
+        }
+    }
+
+    let value = Arc::new(AtomicUsize::new(0));
+    let server = spawn(Endpoint::new("127.0.0.1:0".parse().unwrap()).bind(Counter(value.clone())).unwrap());
+
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 64];
+    let mut recv = || {
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        Message::from_bytes(&buf[..len]).unwrap()
+    };
+    let observe = |msg: &Message| msg.options.iter().filter_map(|o| match *o {
+        CoapOption::Observe(seq) => Some(seq),
+        _ => None
+    }).next();
+
+    let get = MessageBuilder::new(Mtype::Confirmable, Code::Get).mid(1).token(&[5])
+        .option(CoapOption::Observe(0))
+        .option(CoapOption::UriPath("temp".to_string()))
+        .build();
+    peer.send_to(&get.to_bytes().unwrap(), server.addr).unwrap();
+    let resp = recv();
+    assert_eq!((resp.mtype, resp.code, resp.payload.as_slice()), (Mtype::Acknowledgement, Code::Content, &[0][..]));
+    let first = observe(&resp).unwrap();
+
+    // Changes to other resources don't concern it.
+    server.handle.notify("/humidity").unwrap();
+    value.store(1, Ordering::SeqCst);
+    server.handle.notify("/temp").unwrap();
+    let note = recv();
+    assert_eq!((note.mtype, note.token.as_slice(), note.payload.as_slice()), (Mtype::NonConfirmable, &[5][..], &[1][..]));
+    let second = observe(&note).unwrap();
+    assert!(second > first);
+
+    // With a Max-Age of one second it's sent again once that runs out.
+    value.store(2, Ordering::SeqCst);
+    server.handle.notify("/temp").unwrap();
+    let note = recv();
+    assert_eq!(note.payload, [2]);
+    let refreshed = recv();
+    assert_eq!(refreshed.payload, [2]);
+    assert!(observe(&refreshed).unwrap() > observe(&note).unwrap());
+
+    // A RST ends the observation.
+    let rst = Message::reset_for(&refreshed);
+    peer.send_to(&rst.to_bytes().unwrap(), server.addr).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    server.handle.notify("/temp").unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(1500))).unwrap();
+    assert!(peer.recv_from(&mut buf).is_err());
+
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_notifies_through_handle_request() {
+    use message::{Code, MessageBuilder, Mtype};
+    use message::option::Option as CoapOption;
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Only answers in `handle_request`, so `handle_msg` is never asked.
+    struct Counter(Arc<AtomicUsize>);
+
+    impl MsgHandler for Counter {
+        fn handle_msg(&self, _addr: &SocketAddr, _msg: &Message) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn handle_request(&self, _addr: &SocketAddr, msg: &MessageRef, buf: &mut Vec<u8>, _responder: Responder) {
+            let mut resp = Message::response_for(&msg.to_owned().unwrap(), Code::Content);
+            resp.payload = vec![self.0.load(Ordering::SeqCst) as u8];
+            resp.encode_into_vec(buf).unwrap();
+        }
+    }
+
+    let value = Arc::new(AtomicUsize::new(0));
+    let server = spawn(Endpoint::new("127.0.0.1:0".parse().unwrap()).bind(Counter(value.clone())).unwrap());
+
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 64];
+    let mut recv = || {
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        Message::from_bytes(&buf[..len]).unwrap()
+    };
+
+    let get = MessageBuilder::new(Mtype::Confirmable, Code::Get).mid(1).token(&[5])
+        .option(CoapOption::Observe(0))
+        .option(CoapOption::UriPath("temp".to_string()))
+        .build();
+    peer.send_to(&get.to_bytes().unwrap(), server.addr).unwrap();
+    assert_eq!(recv().payload, [0]);
+
+    value.store(1, Ordering::SeqCst);
+    server.handle.notify("/temp").unwrap();
+    let note = recv();
+    assert_eq!((note.token.as_slice(), note.payload.as_slice()), (&[5][..], &[1][..]));
+
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_is_client_and_server() {
+    use message::{Code, MessageBuilder, Mtype};
+    use message::option::Option as CoapOption;
+    use router::{uri_path, Router};
+    use testing::spawn;
+    use std::sync::mpsc;
+
+    let mut router = Router::new();
+    router.get("/echo/{n}", |_, msg, params| {
+        let mut resp = Message::response_for(msg, Code::Content);
+        resp.payload = params.get("n").unwrap().as_bytes().to_vec();
+        resp.to_bytes().ok()
+    }).unwrap();
+
+    let server = spawn(Endpoint::new("127.0.0.1:0".parse().unwrap()).bind(router).unwrap());
+
+    // The client side is itself an endpoint serving requests.
+    struct Echo;
+
+    impl MsgHandler for Echo {
+        fn handle_msg(&self, _addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+            let mut resp = Message::response_for(msg, Code::Content);
+            resp.payload = uri_path(msg).join("/").into_bytes();
+            resp.to_bytes().ok()
+        }
+    }
+
+    let config = Config{nstart: 8, ..Config::default()};
+    let client = spawn(Endpoint::with_config("127.0.0.1:0".parse().unwrap(), config).bind(Echo).unwrap());
+
+    let (tx, rx) = mpsc::channel();
+    let n = 500;
+    for i in 0..n {
+        let msg = MessageBuilder::new(Mtype::Confirmable, Code::Get)
+            .option(CoapOption::UriPath("echo".to_string()))
+            .option(CoapOption::UriPath(i.to_string()))
+            .build();
+        let tx = tx.clone();
+        client.handle.request(server.addr, msg, move |result| tx.send((i, result)).unwrap()).unwrap();
+    }
+
+    let mut seen = vec![false; n];
+    for _ in 0..n {
+        let (i, result) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(result.unwrap().payload, i.to_string().into_bytes());
+        seen[i] = true;
+    }
+    assert!(seen.iter().all(|&s| s));
+
+    // And it still answers requests itself.
+    let msg = MessageBuilder::new(Mtype::NonConfirmable, Code::Get).option(CoapOption::UriPath("hi".to_string())).build();
+    let resp = server.handle.request_future(client.addr, msg).unwrap().wait().unwrap();
+    assert_eq!((resp.mtype, resp.payload.as_slice()), (Mtype::NonConfirmable, &b"hi"[..]));
+
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_survives_bursts_and_socket_errors() {
+    use message::{Code, MessageBuilder, Mtype};
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::thread;
+    use std::time::Instant;
+
+    // Answers with the length of the request's payload.
+    struct Length;
+
+    impl MsgHandler for Length {
+        fn handle_msg(&self, _addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+            let mut resp = Message::response_for(msg, Code::Content);
+            resp.payload = msg.payload.len().to_string().into_bytes();
+            resp.to_bytes().ok()
+        }
+    }
+
+    let endpoint = Endpoint::new("127.0.0.1:0".parse().unwrap()).bind(Length).unwrap();
+    let server_addr = endpoint.local_addr();
+    let handle = endpoint.handle();
+    let stats = endpoint.io_stats();
+
+    // Everything is waiting on the socket before the endpoint first looks.
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let n = 100;
+    for mid in 0..n {
+        let msg = MessageBuilder::new(Mtype::NonConfirmable, Code::Post).mid(mid).payload(b"x".to_vec()).build();
+        peer.send_to(&msg.to_bytes().unwrap(), server_addr).unwrap();
+    }
+    let big = MessageBuilder::new(Mtype::Confirmable, Code::Post).mid(n).payload(vec![0; 5000]).build();
+    peer.send_to(&big.to_bytes().unwrap(), server_addr).unwrap();
+
+    // Port 0 can't be sent to, which mustn't take the endpoint down.
+    handle.send("127.0.0.1:0".parse().unwrap(), MessageBuilder::new(Mtype::NonConfirmable, Code::Get).build()).unwrap();
+
+    let server = spawn(endpoint);
+
+    let mut buf = [0; 64];
+    let mut lengths = vec![];
+    for _ in 0..n + 1 {
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        let resp = Message::from_bytes(&buf[..len]).unwrap();
+        lengths.push((resp.mtype, resp.mid, String::from_utf8(resp.payload).unwrap()));
     }
+    let (acks, nons): (Vec<_>, Vec<_>) = lengths.into_iter().partition(|l| l.0 == Mtype::Acknowledgement);
+    assert_eq!(nons.len(), n as usize);
+    assert!(nons.iter().all(|(_, _, l)| l == "1"));
+    assert_eq!(acks, [(Mtype::Acknowledgement, n, "5000".to_string())]);
+
+    // The send may be picked up after the datagrams.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while stats.send_errors() == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(stats.send_errors(), 1);
+    assert_eq!(stats.dropped_sends(), 0);
+
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_honours_smaller_block2_size() {
+    use message::{Code, MessageBuilder, Mtype};
+    use message::option::{self, Block};
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+
+    struct Body;
+
+    impl MsgHandler for Body {
+        fn handle_msg(&self, _addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+            let mut resp = Message::ack_for(msg, Code::Content);
+            resp.payload = (0..100u8).collect();
+            resp.to_bytes().ok()
+        }
+    }
+
+    let server = spawn(Endpoint::new("127.0.0.1:0".parse().unwrap()).bind(Body).unwrap());
+
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let mut buf = [0; 256];
+    let mut get = |mid: u16, num: u32| {
+        let request = MessageBuilder::new(Mtype::Confirmable, Code::Get)
+            .mid(mid)
+            .option(option::Option::Block2(Block::new(num, false, 1)))
+            .build();
+        peer.send_to(&request.to_bytes().unwrap(), server.addr).unwrap();
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        Message::from_bytes(&buf[..len]).unwrap()
+    };
+
+    // The body fits in one of our blocks, but not in the client's.
+    let first = get(1, 0);
+    assert_eq!(first.block2(), Some(Block::new(0, true, 1)));
+    assert_eq!(first.payload, (0..32u8).collect::<Vec<u8>>());
+
+    let last = get(2, 3);
+    assert_eq!(last.block2(), Some(Block::new(3, false, 1)));
+    assert_eq!(last.payload, (96..100u8).collect::<Vec<u8>>());
+
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_gives_non_replies_own_mids() {
+    use message::{Code, MessageBuilder, Mtype};
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+
+    struct Hello;
+
+    impl MsgHandler for Hello {
+        fn handle_msg(&self, _addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+            Message::response_for(msg, Code::Content).to_bytes().ok()
+        }
+    }
+
+    let server = spawn(Endpoint::new("127.0.0.1:0".parse().unwrap()).bind(Hello).unwrap());
+
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 64];
+    let mut get = |mid: u16| {
+        let request = MessageBuilder::new(Mtype::NonConfirmable, Code::Get).mid(mid).token(&[mid as u8]).build();
+        peer.send_to(&request.to_bytes().unwrap(), server.addr).unwrap();
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        Message::from_bytes(&buf[..len]).unwrap()
+    };
+
+    // The replies take consecutive MIDs from the endpoint, not the ones of
+    // the requests.
+    let first = get(7);
+    let second = get(100);
+    assert_eq!((first.mtype, first.token.as_slice()), (Mtype::NonConfirmable, &[7][..]));
+    assert_eq!(second.token, [100]);
+    assert_eq!(second.mid, first.mid.wrapping_add(1));
+
+    server.stop();
+}
+
+#[test]
+fn test_endpoint_gives_block_replies_own_mids() {
+    use message::{Code, MessageBuilder, Mtype};
+    use message::option::{self, Block};
+    use testing::spawn;
+    use std::net::UdpSocket as StdUdpSocket;
+
+    struct Body;
+
+    impl MsgHandler for Body {
+        fn handle_msg(&self, _addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+            let mut resp = Message::response_for(msg, Code::Content);
+            resp.payload = (0..100u8).collect();
+            resp.to_bytes().ok()
+        }
+    }
+
+    let server = spawn(Endpoint::new("127.0.0.1:0".parse().unwrap()).bind(Body).unwrap());
+
+    let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 256];
+    let mut send = |request: Message| {
+        peer.send_to(&request.to_bytes().unwrap(), server.addr).unwrap();
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        Message::from_bytes(&buf[..len]).unwrap()
+    };
+    let get = |mid: u16, num: u32| MessageBuilder::new(Mtype::NonConfirmable, Code::Get).mid(mid)
+        .option(option::Option::Block2(Block::new(num, false, 1)))
+        .build();
+
+    // The handler's reply, a block from the cache and a 2.31 Continue from
+    // the reassembly all take their MIDs from the endpoint.
+    let first = send(get(1, 0));
+    let cached = send(get(2, 1));
+    let upload = MessageBuilder::new(Mtype::NonConfirmable, Code::Post).mid(3)
+        .option(option::Option::Block1(Block::new(0, true, 1)))
+        .payload(vec![0; 32])
+        .build();
+    let cont = send(upload);
+    assert_eq!((cached.block2(), cont.code), (Some(Block::new(1, true, 1)), Code::Continue));
+    assert_eq!((cached.mid, cont.mid), (first.mid.wrapping_add(1), first.mid.wrapping_add(2)));
+
+    server.stop();
 }
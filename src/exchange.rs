@@ -0,0 +1,410 @@
+use message::{self, Message};
+use block::TransferError;
+use oscore;
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Why a request didn't get a response.
+#[derive(Debug)]
+pub enum Error {
+    /// The URI couldn't be parsed.
+    Message(message::Error),
+    /// The endpoint couldn't be reached, or the peer couldn't be resolved.
+    Io(io::Error),
+    /// The peer rejected the request with a RST.
+    Reset,
+    /// No response arrived in time.
+    TimedOut,
+    /// A block-wise transfer went wrong.
+    Transfer(TransferError),
+    /// The request couldn't be protected with OSCORE.
+    Oscore(oscore::Error),
+}
+
+impl From<message::Error> for Error {
+    fn from(e: message::Error) -> Error {
+        Error::Message(e)
+    }
+}
+
+impl From<TransferError> for Error {
+    fn from(e: TransferError) -> Error {
+        Error::Transfer(e)
+    }
+}
+
+impl From<oscore::Error> for Error {
+    fn from(e: oscore::Error) -> Error {
+        Error::Oscore(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+pub type ResponseCallback = Box<dyn FnOnce(Result<Message, Error>) + Send>;
+
+#[derive(Default)]
+struct Shared {
+    result: Option<Result<Message, Error>>,
+    waker: Option<Waker>,
+    done: bool,
+}
+
+/// The response to a request sent with `Handle::request_future`, either
+/// awaited or waited for with `wait`.
+pub struct ResponseFuture {
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+}
+
+// Completes a `ResponseFuture`, with an error if it's dropped first.
+struct Completer {
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+}
+
+impl ResponseFuture {
+    pub(crate) fn new() -> (ResponseFuture, ResponseCallback) {
+        let shared = Arc::new((Mutex::new(Shared::default()), Condvar::new()));
+        let completer = Completer{shared: shared.clone()};
+
+        (ResponseFuture{shared}, Box::new(move |result| completer.complete(result)))
+    }
+
+    /// Blocks until the response arrives.
+    pub fn wait(self) -> Result<Message, Error> {
+        let (ref lock, ref ready) = *self.shared;
+        let mut shared = lock.lock().unwrap();
+        loop {
+            if let Some(result) = shared.result.take() {
+                return result;
+            }
+            shared = ready.wait(shared).unwrap();
+        }
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Message, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.0.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Completer {
+    fn complete(&self, result: Result<Message, Error>) {
+        let (ref lock, ref ready) = *self.shared;
+        let mut shared = lock.lock().unwrap();
+        if shared.done {
+            return;
+        }
+
+        shared.done = true;
+        shared.result = Some(result);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+        ready.notify_all();
+    }
+}
+
+impl Drop for Completer {
+    fn drop(&mut self) {
+        // Only does anything if the endpoint stopped before answering.
+        self.complete(Err(Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "endpoint stopped"))));
+    }
+}
+
+struct Pending {
+    mid: u16,
+    acked: bool,
+    deadline: Instant,
+    callback: ResponseCallback,
+}
+
+/// Requests sent by the endpoint that are waiting for a response, matched up
+/// by peer and token (RFC 7252 §5.3.2).
+///
+/// A CON request waits up to MAX_TRANSMIT_WAIT for its ACK. If that's an
+/// empty ACK the response will come separately, and it waits another
+/// `response_timeout` for it.
+///
+/// No more than `nstart` requests are outstanding to a peer at once (RFC
+/// 7252 §4.7), a request is outstanding until it's acknowledged or answered.
+/// Any more are queued until one finishes.
+pub struct Exchanges {
+    pending: HashMap<(SocketAddr, Vec<u8>), Pending>,
+    outstanding: HashMap<SocketAddr, usize>,
+    queued: HashMap<SocketAddr, VecDeque<(Message, ResponseCallback)>>,
+    max_transmit_wait: Duration,
+    response_timeout: Duration,
+    nstart: usize,
+}
+
+impl Exchanges {
+    pub fn new(max_transmit_wait: Duration, response_timeout: Duration, nstart: usize) -> Exchanges {
+        Exchanges{
+            pending: HashMap::new(),
+            outstanding: HashMap::new(),
+            queued: HashMap::new(),
+            max_transmit_wait,
+            response_timeout,
+            nstart,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn contains(&self, addr: &SocketAddr, token: &[u8]) -> bool {
+        self.pending.contains_key(&(*addr, token.to_vec()))
+    }
+
+    /// Number of requests waiting to be sent because of NSTART.
+    pub fn queued(&self) -> usize {
+        self.queued.values().map(|q| q.len()).sum()
+    }
+
+    /// Whether another request can be sent to `addr` now, and there are none
+    /// queued ahead of it.
+    pub fn can_start(&self, addr: &SocketAddr) -> bool {
+        self.outstanding.get(addr).cloned().unwrap_or(0) < self.nstart && !self.queued.contains_key(addr)
+    }
+
+    /// Holds on to a request until `can_start` would allow it.
+    pub fn enqueue(&mut self, addr: SocketAddr, msg: Message, callback: ResponseCallback) {
+        self.queued.entry(addr).or_default().push_back((msg, callback));
+    }
+
+    /// A queued request that can now be sent, if there is one.
+    pub fn dequeue(&mut self) -> Option<(SocketAddr, Message, ResponseCallback)> {
+        let nstart = self.nstart;
+        let outstanding = &self.outstanding;
+        let addr = *self.queued.keys().find(|a| outstanding.get(a).cloned().unwrap_or(0) < nstart)?;
+
+        let queue = self.queued.get_mut(&addr).unwrap();
+        let (msg, callback) = queue.pop_front().unwrap();
+        if queue.is_empty() {
+            self.queued.remove(&addr);
+        }
+
+        Some((addr, msg, callback))
+    }
+
+    /// Starts waiting for the response to a request sent with `mid` and
+    /// `token`, `con` saying whether it was confirmable.
+    pub fn start(&mut self, addr: SocketAddr, token: Vec<u8>, mid: u16, con: bool, callback: ResponseCallback) {
+        let wait = if con { self.max_transmit_wait } else { self.response_timeout };
+        let old = self.pending.insert((addr, token), Pending{
+            mid,
+            acked: false,
+            deadline: Instant::now() + wait,
+            callback,
+        });
+
+        if let Some(old) = old {
+            self.finished(&addr, &old);
+        }
+        *self.outstanding.entry(addr).or_insert(0) += 1;
+    }
+
+    /// The request sent with `mid` got an empty ACK, so the response will
+    /// come separately.
+    pub fn acknowledged(&mut self, addr: &SocketAddr, mid: u16) {
+        let response_timeout = self.response_timeout;
+        let acked = match self.pending.iter_mut().find(|&(key, ref p)| key.0 == *addr && p.mid == mid) {
+            Some((_, pending)) if !pending.acked => {
+                pending.deadline = Instant::now() + response_timeout;
+                pending.acked = true;
+                true
+            },
+            _ => false
+        };
+
+        if acked {
+            self.release(addr);
+        }
+    }
+
+    /// Delivers a response, handing it back if nothing is waiting for it.
+    pub fn complete(&mut self, addr: &SocketAddr, response: Message) -> Result<u16, Message> {
+        match self.pending.remove(&(*addr, response.token.clone())) {
+            Some(pending) => {
+                self.finished(addr, &pending);
+                (pending.callback)(Ok(response));
+                Ok(pending.mid)
+            },
+            None => Err(response)
+        }
+    }
+
+    /// Fails the request a RST with `mid` rejected.
+    pub fn reset(&mut self, addr: &SocketAddr, mid: u16) -> bool {
+        let key = self.pending.iter()
+            .find(|&(key, p)| key.0 == *addr && p.mid == mid)
+            .map(|(key, _)| key.clone());
+
+        match key.and_then(|key| self.pending.remove(&key)) {
+            Some(pending) => {
+                self.finished(addr, &pending);
+                (pending.callback)(Err(Error::Reset));
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Times out requests past their deadline, meant to be called
+    /// periodically.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self.pending.iter()
+            .filter(|&(_, p)| p.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired {
+            if let Some(pending) = self.pending.remove(&key) {
+                self.finished(&key.0, &pending);
+                (pending.callback)(Err(Error::TimedOut));
+            }
+        }
+    }
+
+    fn finished(&mut self, addr: &SocketAddr, pending: &Pending) {
+        if !pending.acked {
+            self.release(addr);
+        }
+    }
+
+    // One less request is outstanding to `addr`.
+    fn release(&mut self, addr: &SocketAddr) {
+        if let Some(n) = self.outstanding.get_mut(addr) {
+            *n -= 1;
+            if *n == 0 {
+                self.outstanding.remove(addr);
+            }
+        }
+    }
+}
+
+
+#[test]
+fn test_exchanges_match_by_peer_and_token() {
+    use message::{Code, MessageBuilder, Mtype};
+    use std::sync::mpsc;
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let other = "127.0.0.1:5684".parse().unwrap();
+    let mut exchanges = Exchanges::new(Duration::from_secs(93), Duration::from_secs(93), 2);
+    let (tx, rx) = mpsc::channel();
+
+    let tx1 = tx.clone();
+    exchanges.start(addr, vec![1], 10, true, Box::new(move |r| tx1.send(r.map_err(|e| format!("{:?}", e))).unwrap()));
+    exchanges.start(addr, vec![2], 11, true, Box::new(move |r| tx.send(r.map_err(|e| format!("{:?}", e))).unwrap()));
+    assert!(exchanges.contains(&addr, &[1]));
+
+    let resp = MessageBuilder::new(Mtype::Confirmable, Code::Content).token(&[1]).build();
+    assert_eq!(exchanges.complete(&other, resp.clone()), Err(resp.clone()));
+    assert_eq!(exchanges.complete(&addr, resp.clone()), Ok(10));
+    assert_eq!(rx.try_recv(), Ok(Ok(resp.clone())));
+    assert_eq!(exchanges.complete(&addr, resp.clone()), Err(resp));
+
+    assert!(!exchanges.reset(&addr, 10));
+    assert!(exchanges.reset(&addr, 11));
+    assert_eq!(rx.try_recv(), Ok(Err("Reset".to_string())));
+    assert!(exchanges.is_empty());
+}
+
+#[test]
+fn test_exchanges_time_out() {
+    use std::sync::mpsc;
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut exchanges = Exchanges::new(Duration::from_secs(0), Duration::from_secs(93), 1);
+    let (tx, rx) = mpsc::channel();
+
+    let tx1 = tx.clone();
+    exchanges.start(addr, vec![1], 10, true, Box::new(move |r| tx1.send(r.is_err()).unwrap()));
+    exchanges.start(addr, vec![2], 11, true, Box::new(move |r| tx.send(r.is_err()).unwrap()));
+    exchanges.acknowledged(&addr, 11);
+
+    exchanges.expire();
+    assert_eq!(rx.try_recv(), Ok(true));
+    assert!(rx.try_recv().is_err());
+    assert!(exchanges.contains(&addr, &[2]));
+}
+
+#[test]
+fn test_exchanges_nstart() {
+    use message::{Code, MessageBuilder, Mtype};
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let other = "127.0.0.1:5684".parse().unwrap();
+    let mut exchanges = Exchanges::new(Duration::from_secs(93), Duration::from_secs(93), 1);
+    let request = |token: u8| MessageBuilder::new(Mtype::Confirmable, Code::Get).token(&[token]).build();
+
+    assert!(exchanges.can_start(&addr));
+    exchanges.start(addr, vec![1], 10, true, Box::new(|_| ()));
+    assert!(!exchanges.can_start(&addr));
+    assert!(exchanges.can_start(&other));
+
+    exchanges.enqueue(addr, request(2), Box::new(|_| ()));
+    exchanges.enqueue(addr, request(3), Box::new(|_| ()));
+    assert_eq!(exchanges.queued(), 2);
+    assert!(exchanges.dequeue().is_none());
+
+    // An empty ACK frees the slot even though the response is still to come.
+    exchanges.acknowledged(&addr, 10);
+    let (to, msg, _) = exchanges.dequeue().unwrap();
+    assert_eq!((to, msg.token), (addr, vec![2]));
+    exchanges.start(addr, vec![2], 11, true, Box::new(|_| ()));
+    assert!(exchanges.dequeue().is_none());
+    assert!(!exchanges.can_start(&addr));
+
+    exchanges.complete(&addr, MessageBuilder::new(Mtype::Acknowledgement, Code::Content).token(&[2]).build()).unwrap();
+    assert_eq!(exchanges.dequeue().unwrap().1.token, vec![3]);
+    assert_eq!(exchanges.queued(), 0);
+    assert!(exchanges.can_start(&addr));
+}
+
+#[test]
+fn test_response_future() {
+    use message::{Code, MessageBuilder, Mtype};
+    use std::thread;
+
+    let (future, callback) = ResponseFuture::new();
+    let resp = MessageBuilder::new(Mtype::Acknowledgement, Code::Content).build();
+    let sent = resp.clone();
+    thread::spawn(move || callback(Ok(sent)));
+    assert_eq!(future.wait().unwrap(), resp);
+
+    let (future, callback) = ResponseFuture::new();
+    drop(callback);
+    match future.wait() {
+        Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => (),
+        other => panic!("{:?}", other),
+    }
+}
@@ -1,8 +1,31 @@
+extern crate foreign_types;
+extern crate getrandom;
 extern crate mio;
+extern crate openssl;
+extern crate openssl_sys;
 
 mod constants;
+mod random;
+mod socket;
 mod socket_handler;
+#[cfg(test)]
+mod testing;
 
 pub mod message;
 pub mod endpoint;
+pub mod block;
+pub mod client;
+pub mod dedup;
+pub mod dtls;
+pub mod exchange;
+pub mod link_format;
 pub mod nullhandler;
+pub mod observe;
+pub mod oscore;
+pub mod resource;
+pub mod router;
+pub mod security;
+pub mod tcp;
+pub mod transaction;
+pub mod uri;
+pub mod websocket;
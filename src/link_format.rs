@@ -0,0 +1,291 @@
+//! CoRE Link Format (RFC 6690), as served at `/.well-known/core`.
+
+use std::fmt;
+
+/// Content-Format number of `application/link-format`.
+pub const CONTENT_FORMAT: u16 = 40;
+
+#[derive(PartialEq, Debug)]
+pub enum Error {
+    /// Something unexpected at this offset.
+    Syntax(usize),
+}
+
+/// One link, e.g. `</sensors/temp>;rt="temperature-c";if="sensor";obs`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Link {
+    pub target: String,
+    /// Attributes in order, `None` for ones without a value like `obs`.
+    pub attrs: Vec<(String, Option<String>)>,
+}
+
+impl Link {
+    pub fn new(target: &str) -> Link {
+        Link{target: target.to_string(), attrs: vec![]}
+    }
+
+    pub fn attr(mut self, name: &str, value: &str) -> Link {
+        self.attrs.push((name.to_string(), Some(value.to_string())));
+        self
+    }
+
+    /// Resource type.
+    pub fn rt(self, rt: &str) -> Link {
+        self.attr("rt", rt)
+    }
+
+    /// Interface description.
+    pub fn interface(self, interface: &str) -> Link {
+        self.attr("if", interface)
+    }
+
+    /// Content-Format.
+    pub fn ct(self, ct: u16) -> Link {
+        self.attr("ct", &ct.to_string())
+    }
+
+    /// Estimated size.
+    pub fn sz(self, sz: u32) -> Link {
+        self.attr("sz", &sz.to_string())
+    }
+
+    /// Marks the resource as observable.
+    pub fn obs(mut self) -> Link {
+        self.attrs.push(("obs".to_string(), None));
+        self
+    }
+
+    /// The value of the first attribute called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(n, v)| n == name && v.is_some()).and_then(|(_, v)| v.as_deref())
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.attrs.iter().any(|(n, _)| n == name)
+    }
+
+    /// Whether the link passes a `/.well-known/core` query filter (RFC 6690
+    /// §4.1). `href` filters on the target, a trailing `*` matches any
+    /// suffix, and space separated attributes like `rt` match if any one of
+    /// their values does.
+    pub fn matches(&self, name: &str, value: Option<&str>) -> bool {
+        let value = match value {
+            Some(value) => value,
+            None => return self.has(name),
+        };
+
+        let matches = |candidate: &str| match value.strip_suffix('*') {
+            Some(prefix) => candidate.starts_with(prefix),
+            None => candidate == value,
+        };
+
+        if name == "href" {
+            return matches(&self.target);
+        }
+
+        let is_list = name == "rt" || name == "if" || name == "rel";
+        self.attrs.iter().filter(|(n, _)| n == name).any(|(_, v)| match *v {
+            Some(ref v) if is_list => v.split(' ').any(matches),
+            Some(ref v) => matches(v),
+            None => false,
+        })
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}>", self.target)?;
+
+        for (name, value) in &self.attrs {
+            match *value {
+                None => write!(f, ";{}", name)?,
+                Some(ref v) if !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()) => write!(f, ";{}={}", name, v)?,
+                Some(ref v) => {
+                    write!(f, ";{}=\"", name)?;
+                    for c in v.chars() {
+                        if c == '"' || c == '\\' {
+                            write!(f, "\\")?;
+                        }
+                        write!(f, "{}", c)?;
+                    }
+                    write!(f, "\"")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes links as a link format document.
+pub fn serialize(links: &[Link]) -> String {
+    links.iter().map(|l| l.to_string()).collect::<Vec<_>>().join(",")
+}
+
+/// Parses a link format document.
+pub fn parse(s: &str) -> Result<Vec<Link>, Error> {
+    let mut parser = Parser{s: s.as_bytes(), pos: 0};
+    let mut links = vec![];
+
+    parser.skip_space();
+    if parser.at_end() {
+        return Ok(links);
+    }
+
+    loop {
+        links.push(parser.link()?);
+        parser.skip_space();
+        if parser.at_end() {
+            return Ok(links);
+        }
+        parser.expect(b',')?;
+        parser.skip_space();
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn at_end(&self) -> bool {
+        self.pos >= self.s.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).cloned()
+    }
+
+    fn skip_space(&mut self) {
+        while self.peek().is_some_and(|b| b == b' ' || b == b'\t' || b == b'\r' || b == b'\n') {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), Error> {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(Error::Syntax(self.pos))
+        }
+    }
+
+    // Everything up to the first byte `keep` rejects, which may be nothing.
+    fn take_while<F: Fn(u8) -> bool>(&mut self, keep: F) -> Result<String, Error> {
+        let start = self.pos;
+        while self.peek().is_some_and(&keep) {
+            self.pos += 1;
+        }
+        String::from_utf8(self.s[start..self.pos].to_vec()).map_err(|_| Error::Syntax(start))
+    }
+
+    fn link(&mut self) -> Result<Link, Error> {
+        self.expect(b'<')?;
+        let target = self.take_while(|b| b != b'>')?;
+        self.expect(b'>')?;
+
+        let mut link = Link{target, attrs: vec![]};
+        loop {
+            self.skip_space();
+            if self.peek() != Some(b';') {
+                return Ok(link);
+            }
+            self.pos += 1;
+            self.skip_space();
+
+            let start = self.pos;
+            let name = self.take_while(|b| b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~*".contains(&b))?;
+            if name.is_empty() {
+                return Err(Error::Syntax(start));
+            }
+
+            self.skip_space();
+            if self.peek() != Some(b'=') {
+                link.attrs.push((name, None));
+                continue;
+            }
+            self.pos += 1;
+            self.skip_space();
+
+            let value = if self.peek() == Some(b'"') {
+                self.quoted()?
+            } else {
+                self.take_while(|b| !b" \t\r\n,;\"".contains(&b))?
+            };
+            link.attrs.push((name, Some(value)));
+        }
+    }
+
+    fn quoted(&mut self) -> Result<String, Error> {
+        let start = self.pos;
+        self.expect(b'"')?;
+
+        let mut value = vec![];
+        loop {
+            match self.peek() {
+                None => return Err(Error::Syntax(start)),
+                Some(b'"') => break,
+                Some(b'\\') if self.pos + 1 < self.s.len() => {
+                    value.push(self.s[self.pos + 1]);
+                    self.pos += 2;
+                },
+                Some(b) => {
+                    value.push(b);
+                    self.pos += 1;
+                }
+            }
+        }
+        self.pos += 1;
+
+        String::from_utf8(value).map_err(|_| Error::Syntax(start))
+    }
+}
+
+
+#[test]
+fn test_link_format_parse() {
+    let doc = "</sensors/temp>;rt=\"temperature-c\";if=\"sensor\";obs;ct=0,\n \
+               </fw>;title=\"say \\\"hi\\\"\";sz=1024, </empty>";
+    let links = parse(doc).unwrap();
+
+    assert_eq!(links, [
+        Link::new("/sensors/temp").rt("temperature-c").interface("sensor").obs().ct(0),
+        Link::new("/fw").attr("title", "say \"hi\"").sz(1024),
+        Link::new("/empty"),
+    ]);
+    assert_eq!(parse("").unwrap(), []);
+    assert_eq!(parse("</a>;rt=\"open"), Err(Error::Syntax(8)));
+    assert_eq!(parse("</a>,"), Err(Error::Syntax(5)));
+    assert_eq!(parse("</a>;=1"), Err(Error::Syntax(5)));
+    assert_eq!(parse("/a"), Err(Error::Syntax(0)));
+}
+
+#[test]
+fn test_link_format_round_trip() {
+    let links = vec![
+        Link::new("/sensors/temp").rt("temperature-c temperature").interface("sensor").obs().ct(0),
+        Link::new("/fw").attr("title", "say \"hi\" \\o/").sz(1024),
+    ];
+
+    let doc = serialize(&links);
+    assert_eq!(doc, "</sensors/temp>;rt=\"temperature-c temperature\";if=\"sensor\";obs;ct=0,\
+                     </fw>;title=\"say \\\"hi\\\" \\\\o/\";sz=1024");
+    assert_eq!(parse(&doc).unwrap(), links);
+}
+
+#[test]
+fn test_link_format_filter() {
+    let link = Link::new("/sensors/temp").rt("temperature-c temperature").obs().ct(0);
+
+    assert!(link.matches("rt", Some("temperature")));
+    assert!(link.matches("rt", Some("temperature-c")));
+    assert!(link.matches("rt", Some("temp*")));
+    assert!(!link.matches("rt", Some("humidity")));
+    assert!(link.matches("href", Some("/sensors/*")));
+    assert!(!link.matches("href", Some("/sensors")));
+    assert!(link.matches("ct", Some("0")));
+    assert!(link.matches("obs", None));
+    assert!(!link.matches("if", None));
+}
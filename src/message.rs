@@ -1,4 +1,7 @@
-#[derive(PartialEq, Eq, Debug)]
+// The original parser tests compare against empty slices.
+#![cfg_attr(test, allow(clippy::comparison_to_empty))]
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Message {
     pub version: u8,
     pub mtype: Mtype,
@@ -13,10 +16,51 @@ pub struct Message {
 pub enum Error {
     MessageFormat,
     InvalidToken,
-    InvalidOptionNumber
+    InvalidOptionNumber,
+    // Parse errors, each carrying the byte offset into the packet at which
+    // the problem was found.
+    TruncatedHeader(usize),
+    UnsupportedVersion(usize, u8),
+    InvalidMessageType(u8),
+    InvalidTokenLength(usize, u8),
+    TruncatedToken(usize),
+    TruncatedOptionDelta(usize),
+    TruncatedOptionLength(usize),
+    TruncatedOptionValue(usize),
+    ReservedOptionDelta(usize),
+    ReservedOptionLength(usize),
+    OptionNumberOverflow(usize),
+    EmptyPayload(usize),
+    // The destination buffer needs to be at least this many bytes.
+    BufferTooSmall(usize),
+    InvalidUri,
+    // Option number and the value length that is out of range for it.
+    InvalidOptionLength(u16, usize),
+    RepeatedOption(u16),
+    InvalidOptionValue(u16),
 }
 
-#[derive(PartialEq, Eq, Debug)]
+impl Error {
+    /// Byte offset into the packet at which parsing failed, if known.
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            Error::TruncatedHeader(i) |
+            Error::UnsupportedVersion(i, _) |
+            Error::InvalidTokenLength(i, _) |
+            Error::TruncatedToken(i) |
+            Error::TruncatedOptionDelta(i) |
+            Error::TruncatedOptionLength(i) |
+            Error::TruncatedOptionValue(i) |
+            Error::ReservedOptionDelta(i) |
+            Error::ReservedOptionLength(i) |
+            Error::OptionNumberOverflow(i) |
+            Error::EmptyPayload(i) => Some(i),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 #[repr(u8)]
 pub enum Mtype {
     Confirmable,
@@ -26,13 +70,13 @@ pub enum Mtype {
 }
 
 impl Mtype {
-    pub fn from_u8(raw_mtype: u8) -> Mtype {
+    pub fn from_u8(raw_mtype: u8) -> Result<Mtype, Error> {
         match raw_mtype {
-            0 => Mtype::Confirmable,
-            1 => Mtype::NonConfirmable,
-            2 => Mtype::Acknowledgement,
-            3 => Mtype::Reset,
-            _ => panic!("bad rawtype")
+            0 => Ok(Mtype::Confirmable),
+            1 => Ok(Mtype::NonConfirmable),
+            2 => Ok(Mtype::Acknowledgement),
+            3 => Ok(Mtype::Reset),
+            _ => Err(Error::InvalidMessageType(raw_mtype))
         }
     }
 
@@ -46,18 +90,22 @@ impl Mtype {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum Code {
     Empty,
     Get,
     Post,
     Put,
     Delete,
+    Fetch,
+    Patch,
+    IPatch,
     Created,
     Deleted,
     Valid,
     Changed,
     Content,
+    Continue,
     BadRequest,
     Unauthorized,
     BadOption,
@@ -65,6 +113,7 @@ pub enum Code {
     NotFound,
     MethodNotAllowed,
     NotAcceptable,
+    RequestEntityIncomplete,
     PreconditionFailed,
     RequestEntityTooLarge,
     UnsupportedContentFormat,
@@ -74,6 +123,12 @@ pub enum Code {
     ServiceUnavailable,
     GatewayTimeout,
     ProxyingNotSupported,
+    // Signaling codes of reliable transports (RFC 8323).
+    Csm,
+    Ping,
+    Pong,
+    Release,
+    Abort,
     Unknown(u8)
 }
 
@@ -85,11 +140,15 @@ impl Code {
             2 => Code::Post,
             3 => Code::Put,
             4 => Code::Delete,
+            5 => Code::Fetch,
+            6 => Code::Patch,
+            7 => Code::IPatch,
             65 => Code::Created,
             66 => Code::Deleted,
             67 => Code::Valid,
             68 => Code::Changed,
             69 => Code::Content,
+            95 => Code::Continue,
             128 => Code::BadRequest,
             129 => Code::Unauthorized,
             130 => Code::BadOption,
@@ -97,19 +156,27 @@ impl Code {
             132 => Code::NotFound,
             133 => Code::MethodNotAllowed,
             134 => Code::NotAcceptable,
+            136 => Code::RequestEntityIncomplete,
             140 => Code::PreconditionFailed,
             141 => Code::RequestEntityTooLarge,
-            142 => Code::UnsupportedContentFormat,
+            143 => Code::UnsupportedContentFormat,
             160 => Code::InternalServerError,
             161 => Code::NotImplemented,
             162 => Code::BadGateway,
             163 => Code::ServiceUnavailable,
             164 => Code::GatewayTimeout,
             165 => Code::ProxyingNotSupported,
+            225 => Code::Csm,
+            226 => Code::Ping,
+            227 => Code::Pong,
+            228 => Code::Release,
+            229 => Code::Abort,
             _ => Code::Unknown(raw_code)
         }
     }
 
+    // Details are written the way RFC 7252 writes codes, e.g. 4.04.
+    #[allow(clippy::zero_prefixed_literal)]
     pub fn as_u8(&self) -> u8 {
         match *self{
             Code::Empty => Self::build(0,00),
@@ -117,11 +184,15 @@ impl Code {
             Code::Post => Self::build(0,02),
             Code::Put => Self::build(0,03),
             Code::Delete => Self::build(0,04),
+            Code::Fetch => Self::build(0,05),
+            Code::Patch => Self::build(0,06),
+            Code::IPatch => Self::build(0,07),
             Code::Created => Self::build(2,01),
             Code::Deleted => Self::build(2,02),
             Code::Valid => Self::build(2,03),
             Code::Changed => Self::build(2,04),
             Code::Content => Self::build(2,05),
+            Code::Continue => Self::build(2,31),
             Code::BadRequest => Self::build(4,00),
             Code::Unauthorized => Self::build(4,01),
             Code::BadOption => Self::build(4,02),
@@ -129,6 +200,7 @@ impl Code {
             Code::NotFound => Self::build(4,04),
             Code::MethodNotAllowed => Self::build(4,05),
             Code::NotAcceptable => Self::build(4,06),
+            Code::RequestEntityIncomplete => Self::build(4,08),
             Code::PreconditionFailed => Self::build(4,12),
             Code::RequestEntityTooLarge => Self::build(4,13),
             Code::UnsupportedContentFormat => Self::build(4,15),
@@ -138,6 +210,11 @@ impl Code {
             Code::ServiceUnavailable => Self::build(5,03),
             Code::GatewayTimeout => Self::build(5,04),
             Code::ProxyingNotSupported => Self::build(5,05),
+            Code::Csm => Self::build(7,01),
+            Code::Ping => Self::build(7,02),
+            Code::Pong => Self::build(7,03),
+            Code::Release => Self::build(7,04),
+            Code::Abort => Self::build(7,05),
             Code::Unknown(code) => code
         }
     }
@@ -147,17 +224,17 @@ impl Code {
         ((class & 0x07) << 5) | (detail & 0x1F)
     }
 
-    fn class(&self) -> u8 {
+    pub fn class(&self) -> u8 {
         self.as_u8() >> 5
     }
 
-    fn detail(&self) -> u8 {
+    pub fn detail(&self) -> u8 {
         self.as_u8() & 0x1F
     }
 }
 
 pub mod option {
-    #[derive(PartialEq, Eq, Debug)]
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
     pub enum Option {
         IfMatch(Vec<u8>),
         UriHost(String),
@@ -166,69 +243,226 @@ pub mod option {
         Observe(u32),
         UriPort(u16),
         LocationPath(String),
+        // The compressed COSE object of an OSCORE message (RFC 8613).
+        Oscore(Vec<u8>),
         UriPath(String),
         ContentFormat(u16),
         MaxAge(u32),
         UriQuery(String),
         Accept(u16),
         LocationQuery(String),
+        Block2(Block),
+        Block1(Block),
+        Size2(u32),
         ProxyUri(String),
         ProxyScheme(String),
         Size1(u32),
         NoResponse(u8),
+        RequestTag(Vec<u8>),
+        Custom(CustomOption),
         Unknown((u16, Vec<u8>))
     }
 
-    impl Option {
+    /// The value of a Block1 or Block2 option (RFC 7959).
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    pub struct Block {
+        pub num: u32,
+        pub more: bool,
+        pub szx: u8,
+    }
 
-        pub fn build_header(&self, last_option_number: &mut u16) -> Vec<u8> {
-            let mut header = vec![0u8];
+    impl Block {
+        pub fn new(num: u32, more: bool, szx: u8) -> Block {
+            Block{num, more, szx}
+        }
+
+        /// The SZX for the largest block size no bigger than `size`, which
+        /// is clamped to the 16 to 1024 byte range.
+        pub fn szx_for_size(size: usize) -> u8 {
+            let mut szx = 6;
+            while szx > 0 && (1 << (szx + 4)) > size {
+                szx -= 1;
+            }
+            szx
+        }
 
-            if self.number() < *last_option_number {
-                panic!("bad order");
+        /// Returns `None` for values that don't fit in the option or use the
+        /// reserved SZX of 7.
+        pub fn from_u32(value: u32) -> ::std::option::Option<Block> {
+            if value > 0xFFFFFF || value & 0x07 == 7 {
+                return None;
+            }
+
+            Some(Block{
+                num: value >> 4,
+                more: value & 0x08 != 0,
+                szx: (value & 0x07) as u8,
+            })
+        }
+
+        pub fn as_u32(&self) -> u32 {
+            (self.num << 4) | ((self.more as u32) << 3) | (self.szx as u32 & 0x07)
+        }
+
+        pub fn size(&self) -> usize {
+            1 << (self.szx + 4)
+        }
+
+        /// Byte offset of this block into the full body.
+        pub fn offset(&self) -> usize {
+            self.num as usize * self.size()
+        }
+    }
+
+    /// Describes an application specific option, see `OptionRegistry`.
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    pub struct OptionDef {
+        pub number: u16,
+        pub name: &'static str,
+        pub format: format::Format,
+        pub repeatable: bool,
+    }
+
+    /// A decoded option registered with an `OptionRegistry`.
+    #[derive(Clone, PartialEq, Eq, Hash)]
+    pub struct CustomOption {
+        pub def: OptionDef,
+        pub value: value::Value,
+    }
+
+    impl ::std::fmt::Debug for CustomOption {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            write!(f, "{}({:?})", self.def.name, self.value)
+        }
+    }
+
+    /// Application specific options that should be decoded as typed values
+    /// rather than `Option::Unknown`. The options defined by the CoAP RFCs
+    /// are always handled internally and can't be overridden.
+    #[derive(Clone, Debug, Default)]
+    pub struct OptionRegistry {
+        defs: Vec<OptionDef>,
+    }
+
+    static EMPTY_REGISTRY: OptionRegistry = OptionRegistry::new();
+
+    impl OptionRegistry {
+        pub const fn new() -> OptionRegistry {
+            OptionRegistry{defs: Vec::new()}
+        }
+
+        /// A registry with nothing registered, used when none is given.
+        pub fn empty() -> &'static OptionRegistry {
+            &EMPTY_REGISTRY
+        }
+
+        pub fn register(&mut self, def: OptionDef) -> Result<(), super::Error> {
+            if format::is_builtin(def.number) || def.number >= 65000 || self.get(def.number).is_some() {
+                return Err(super::Error::InvalidOptionNumber);
+            }
+
+            let i = self.defs.iter().position(|d| d.number > def.number).unwrap_or(self.defs.len());
+            self.defs.insert(i, def);
+            Ok(())
+        }
+
+        pub fn get(&self, number: u16) -> ::std::option::Option<&OptionDef> {
+            self.defs.binary_search_by_key(&number, |d| d.number).ok().map(|i| &self.defs[i])
+        }
+
+        pub fn get_by_name(&self, name: &str) -> ::std::option::Option<&OptionDef> {
+            self.defs.iter().find(|d| d.name == name)
+        }
+
+        /// Builds an option for a registered number, checking that the value
+        /// is of the registered type.
+        pub fn option(&self, number: u16, value: value::Value) -> Result<Option, super::Error> {
+            let def = match self.get(number) {
+                Some(def) => *def,
+                None => return Err(super::Error::InvalidOptionNumber),
+            };
+
+            match (def.format, &value) {
+                (format::Format::Empty, &value::Value::Empty) |
+                (format::Format::Opaque(..), &value::Value::Opaque(_)) |
+                (format::Format::String(..), &value::Value::String(_)) |
+                (format::Format::UInt(..), &value::Value::UInt(_)) => {
+                    Ok(Option::Custom(CustomOption{def, value}))
+                },
+                _ => Err(super::Error::InvalidOptionValue(number)),
+            }
+        }
+    }
+
+    impl Option {
+
+        /// Checks the value length against the option's format and that the
+        /// number is not in the reserved range.
+        pub fn validate(&self) -> Result<(), super::Error> {
+            let number = self.number();
+            if number >= 65000 {
+                return Err(super::Error::InvalidOptionNumber);
             }
 
-            let delta = self.number() - *last_option_number;
-            let base_delta = match delta {
-                0...12 => delta,
-                13...268 => {header.push((delta-13) as u8); 13},
-                269...64999 => {header.push(((delta-269) >> 8) as u8); header.push((delta-269) as u8); 14},
-                _ => unreachable!(),
-            } as u8;
             let length = self.value_len();
-            let base_length = match length {
-                0...12 => length,
-                13...268 => {header.push((length-13) as u8); 13},
-                269...64999 => {header.push(((length-269) >> 8) as u8); header.push((length-269) as u8); 14},
-                _ => panic!("option too big"),
-            } as u8;
+            let (min, max) = match self.format() {
+                format::Format::Empty => (0, 0),
+                format::Format::Opaque(min, max) |
+                format::Format::String(min, max) |
+                format::Format::UInt(min, max) => (min, max),
+            };
 
-            header[0] = base_delta << 4 | base_length;
+            if length < min as usize || length > max as usize {
+                return Err(super::Error::InvalidOptionLength(number, length));
+            }
 
-            *last_option_number = *last_option_number + delta;
+            Ok(())
+        }
 
-            header
+        pub fn format(&self) -> format::Format {
+            match *self {
+                Option::Custom(ref c) => c.def.format,
+                _ => format::get_by_number(self.number()),
+            }
+        }
+
+        pub fn is_repeatable(&self) -> bool {
+            match *self {
+                Option::Custom(ref c) => c.def.repeatable,
+                _ => format::is_repeatable(self.number()),
+            }
         }
 
         pub fn value_len(&self) -> usize {
             match *self {
-                Option::IfMatch(ref v) => (v).len(),
+                Option::IfMatch(ref v) => v.len(),
                 Option::UriHost(ref s) => s.len(),
                 Option::ETag(ref v) => v.len(),
                 Option::IfNoneMatch => 0,
-                Option::Observe(n) => Self::u32_as_bytes(&n).len(),
-                Option::UriPort(n) => Self::u16_as_bytes(&n).len(),
+                Option::Observe(n) => Self::integer_len(n as u64),
+                Option::UriPort(n) => Self::integer_len(n as u64),
                 Option::LocationPath(ref s) => s.len(),
                 Option::UriPath(ref s) => s.len(),
-                Option::ContentFormat(n) => Self::u16_as_bytes(&n).len(),
-                Option::MaxAge(n) => Self::u32_as_bytes(&n).len(),
+                Option::ContentFormat(n) => Self::integer_len(n as u64),
+                Option::MaxAge(n) => Self::integer_len(n as u64),
                 Option::UriQuery(ref s) => s.len(),
-                Option::Accept(n) => Self::u16_as_bytes(&n).len(),
+                Option::Accept(n) => Self::integer_len(n as u64),
                 Option::LocationQuery(ref s) => s.len(),
+                Option::Block2(b) => Self::integer_len(b.as_u32() as u64),
+                Option::Block1(b) => Self::integer_len(b.as_u32() as u64),
+                Option::Size2(n) => Self::integer_len(n as u64),
                 Option::ProxyUri(ref s) => s.len(),
                 Option::ProxyScheme(ref s) => s.len(),
-                Option::Size1(n) => Self::u32_as_bytes(&n).len(),
-                Option::NoResponse(n) => Self::u8_as_bytes(&n).len(),
+                Option::Size1(n) => Self::integer_len(n as u64),
+                Option::NoResponse(n) => Self::integer_len(n as u64),
+                Option::RequestTag(ref v) => v.len(),
+                Option::Oscore(ref v) => v.len(),
+                Option::Custom(ref c) => match c.value {
+                    value::Value::Empty => 0,
+                    value::Value::Opaque(ref v) => v.len(),
+                    value::Value::String(ref s) => s.len(),
+                    value::Value::UInt(n) => Self::integer_len(n),
+                },
                 Option::Unknown((_, ref v)) => v.len()
             }
         }
@@ -248,10 +482,21 @@ pub mod option {
                 Option::UriQuery(ref s) => s.as_bytes().to_vec(),
                 Option::Accept(ref n) => Self::integer_to_bytes(*n as u64),
                 Option::LocationQuery(ref s) => s.as_bytes().to_vec(),
+                Option::Block2(ref b) => Self::integer_to_bytes(b.as_u32() as u64),
+                Option::Block1(ref b) => Self::integer_to_bytes(b.as_u32() as u64),
+                Option::Size2(ref n) => Self::integer_to_bytes(*n as u64),
                 Option::ProxyUri(ref s) => s.as_bytes().to_vec(),
                 Option::ProxyScheme(ref s) => s.as_bytes().to_vec(),
                 Option::Size1(ref n) => Self::integer_to_bytes(*n as u64),
                 Option::NoResponse(ref n) => Self::integer_to_bytes(*n as u64),
+                Option::RequestTag(ref v) => v.to_vec(),
+                Option::Oscore(ref v) => v.to_vec(),
+                Option::Custom(ref c) => match c.value {
+                    value::Value::Empty => Vec::with_capacity(0),
+                    value::Value::Opaque(ref v) => v.to_vec(),
+                    value::Value::String(ref s) => s.as_bytes().to_vec(),
+                    value::Value::UInt(n) => Self::integer_to_bytes(n),
+                },
                 Option::Unknown((_, ref v)) => v.to_vec()
             }
         }
@@ -270,54 +515,89 @@ pub mod option {
         }
 */
 
+        /// Writes the option value into the front of `buf`, which must be at
+        /// least `value_len()` bytes long, returning the number of bytes written.
+        pub fn write_value(&self, buf: &mut [u8]) -> usize {
+            match *self {
+                Option::IfMatch(ref v) => Self::write_bytes(v, buf),
+                Option::UriHost(ref s) => Self::write_bytes(s.as_bytes(), buf),
+                Option::ETag(ref v) => Self::write_bytes(v, buf),
+                Option::IfNoneMatch => 0,
+                Option::Observe(n) => Self::write_integer(n as u64, buf),
+                Option::UriPort(n) => Self::write_integer(n as u64, buf),
+                Option::LocationPath(ref s) => Self::write_bytes(s.as_bytes(), buf),
+                Option::UriPath(ref s) => Self::write_bytes(s.as_bytes(), buf),
+                Option::ContentFormat(n) => Self::write_integer(n as u64, buf),
+                Option::MaxAge(n) => Self::write_integer(n as u64, buf),
+                Option::UriQuery(ref s) => Self::write_bytes(s.as_bytes(), buf),
+                Option::Accept(n) => Self::write_integer(n as u64, buf),
+                Option::LocationQuery(ref s) => Self::write_bytes(s.as_bytes(), buf),
+                Option::Block2(b) => Self::write_integer(b.as_u32() as u64, buf),
+                Option::Block1(b) => Self::write_integer(b.as_u32() as u64, buf),
+                Option::Size2(n) => Self::write_integer(n as u64, buf),
+                Option::ProxyUri(ref s) => Self::write_bytes(s.as_bytes(), buf),
+                Option::ProxyScheme(ref s) => Self::write_bytes(s.as_bytes(), buf),
+                Option::Size1(n) => Self::write_integer(n as u64, buf),
+                Option::NoResponse(n) => Self::write_integer(n as u64, buf),
+                Option::RequestTag(ref v) => Self::write_bytes(v, buf),
+                Option::Oscore(ref v) => Self::write_bytes(v, buf),
+                Option::Custom(ref c) => match c.value {
+                    value::Value::Empty => 0,
+                    value::Value::Opaque(ref v) => Self::write_bytes(v, buf),
+                    value::Value::String(ref s) => Self::write_bytes(s.as_bytes(), buf),
+                    value::Value::UInt(n) => Self::write_integer(n, buf),
+                },
+                Option::Unknown((_, ref v)) => Self::write_bytes(v, buf)
+            }
+        }
+
+        fn write_bytes(value: &[u8], buf: &mut [u8]) -> usize {
+            buf[..value.len()].copy_from_slice(value);
+            value.len()
+        }
+
+        fn write_integer(n: u64, buf: &mut [u8]) -> usize {
+            let len = Self::integer_len(n);
+            for (i, byte) in buf[..len].iter_mut().enumerate() {
+                *byte = (n >> (8 * (len - 1 - i))) as u8;
+            }
+
+            len
+        }
+
         fn integer_to_bytes(mut n: u64) -> Vec<u8> {
             let mut bytes = vec![];
             while n != 0 {
                 bytes.push(n as u8);
-                n = n >> 8;
+                n >>= 8;
             }
 
             bytes.reverse();
             bytes
         }
 
-        fn u32_as_bytes<'a>(n: &'a u32) -> &'a [u8] {
-            use std::mem;
-
-            let mut i = 0;
-            let bytes: &[u8; 4] = unsafe{mem::transmute((n as *const u32) as *const u8)};
-            while bytes[i] == 0 {i += 1};
+        fn integer_len(mut n: u64) -> usize {
+            let mut len = 0;
+            while n != 0 {
+                len += 1;
+                n >>= 8;
+            }
 
-            &bytes[i..]
+            len
         }
 
-        fn u16_as_bytes<'a>(n: &'a u16) -> &'a [u8] {
-            use std::mem;
-
-            let mut i = 0;
-            let bytes: &[u8 ;2] = unsafe{mem::transmute((n as *const u16) as *const u8)};
-            while bytes[i] == 0 {i += 1};
-
-            &bytes[i..]
+        pub fn from_raw(number: u16, value: &[u8]) -> Option {
+            Self::from_raw_with(number, value, OptionRegistry::empty())
         }
 
-        fn u8_as_bytes<'a>(n: &'a u8) -> &'a [u8] {
-            use std::mem;
+        /// Decodes an option, using `registry` for any numbers that aren't
+        /// defined by the CoAP RFCs.
+        pub fn from_raw_with(number: u16, value: &[u8], registry: &OptionRegistry) -> Option {
+            if let Some(def) = registry.get(number) {
+                return Self::from_raw_custom(*def, value);
+            }
 
-            let mut i = 0;
-            let bytes: &[u8; 1] = unsafe{mem::transmute((n as *const u8) as *const u8)};
-            while bytes[i] == 0 {i += 1};
-
-            &bytes[i..]
-        }
-
-        pub fn from_raw(number: u16, value: &[u8]) -> Option {
-            let parsed_value = match format::get_by_number(number) {
-                format::Format::Empty => Self::should_be_empty(value),
-                format::Format::Opaque(min, max) => Self::should_be_opaque(value, min, max),
-                format::Format::UInt(min, max) => Self::should_be_uint(value, min, max),
-                format::Format::String(min, max) => Self::should_be_string(value, min, max),
-            };
+            let parsed_value = Self::parse_value(format::get_by_number(number), value);
 
             match (number, parsed_value) {
                 (1, value::Value::Opaque(v)) => Option::IfMatch(v),
@@ -327,18 +607,47 @@ pub mod option {
                 (6, value::Value::UInt(v)) => Option::Observe(v as u32),
                 (7, value::Value::UInt(v)) => Option::UriPort(v as u16),
                 (8, value::Value::String(v)) => Option::LocationPath(v),
+                (9, value::Value::Opaque(v)) => Option::Oscore(v),
                 (11, value::Value::String(v)) => Option::UriPath(v),
                 (12, value::Value::UInt(v)) => Option::ContentFormat(v as u16),
                 (14, value::Value::UInt(v)) => Option::MaxAge(v as u32),
                 (15, value::Value::String(v)) => Option::UriQuery(v),
                 (17, value::Value::UInt(v)) => Option::Accept(v as u16),
                 (20, value::Value::String(v)) => Option::LocationQuery(v),
+                (23, value::Value::UInt(v)) if Block::from_u32(v as u32).is_some() => {
+                    Option::Block2(Block::from_u32(v as u32).unwrap())
+                },
+                (27, value::Value::UInt(v)) if Block::from_u32(v as u32).is_some() => {
+                    Option::Block1(Block::from_u32(v as u32).unwrap())
+                },
+                (28, value::Value::UInt(v)) => Option::Size2(v as u32),
                 (35, value::Value::String(v)) => Option::ProxyUri(v),
                 (39, value::Value::String(v)) => Option::ProxyScheme(v),
                 (60, value::Value::UInt(v)) => Option::Size1(v as u32),
                 (284, value::Value::UInt(v)) => Option::NoResponse(v as u8),
-                (_, value::Value::Opaque(v)) => Option::Unknown((number, v)),
-                _ => panic!("unhandled option number, format combination")
+                (292, value::Value::Opaque(v)) => Option::RequestTag(v),
+                _ => Option::Unknown((number, value.to_vec()))
+            }
+        }
+
+        fn from_raw_custom(def: OptionDef, value: &[u8]) -> Option {
+            match (def.format, Self::parse_value(def.format, value)) {
+                (format::Format::Empty, v @ value::Value::Empty) |
+                (format::Format::Opaque(..), v @ value::Value::Opaque(_)) |
+                (format::Format::String(..), v @ value::Value::String(_)) |
+                (format::Format::UInt(..), v @ value::Value::UInt(_)) => {
+                    Option::Custom(CustomOption{def, value: v})
+                },
+                _ => Option::Unknown((def.number, value.to_vec()))
+            }
+        }
+
+        fn parse_value(format: format::Format, value: &[u8]) -> value::Value {
+            match format {
+                format::Format::Empty => Self::should_be_empty(value),
+                format::Format::Opaque(min, max) => Self::should_be_opaque(value, min, max),
+                format::Format::UInt(min, max) => Self::should_be_uint(value, min, max),
+                format::Format::String(min, max) => Self::should_be_string(value, min, max),
             }
         }
 
@@ -374,7 +683,7 @@ pub mod option {
 
 
         pub fn should_be_opaque(value: &[u8], _min: u16, _max: u16) -> value::Value {
-            return value::Value::Opaque(value.to_vec());
+            value::Value::Opaque(value.to_vec())
         }
 
         pub fn number(&self) -> u16 {
@@ -386,16 +695,22 @@ pub mod option {
                 Option::Observe(_) => 6,
                 Option::UriPort(_) => 7,
                 Option::LocationPath(_) => 8,
+                Option::Oscore(_) => 9,
                 Option::UriPath(_) => 11,
                 Option::ContentFormat(_) => 12,
                 Option::MaxAge(_) => 14,
                 Option::UriQuery(_) => 15,
                 Option::Accept(_) => 17,
                 Option::LocationQuery(_) => 20,
+                Option::Block2(_) => 23,
+                Option::Block1(_) => 27,
+                Option::Size2(_) => 28,
                 Option::ProxyUri(_) => 35,
                 Option::ProxyScheme(_) => 39,
                 Option::Size1(_) => 60,
                 Option::NoResponse(_) => 284,
+                Option::RequestTag(_) => 292,
+                Option::Custom(ref c) => c.def.number,
                 Option::Unknown((n, _)) => n
             }
         }
@@ -426,6 +741,7 @@ pub mod option {
     }
 
     pub mod value {
+        #[derive(Clone, PartialEq, Eq, Hash, Debug)]
         pub enum Value {
             Empty,
             Opaque(Vec<u8>),
@@ -435,6 +751,7 @@ pub mod option {
     }
 
     pub mod format {
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
         pub enum Format {
             Empty,
             Opaque(u16,u16),
@@ -471,153 +788,566 @@ pub mod option {
             match number {
                 1 => Format::Opaque(0, 8),
                 3 => Format::String(1, 255),
-                4 => Format::Opaque(0, 0),
+                4 => Format::Opaque(1, 8),
                 5 => Format::Empty,
-                6 => Format::UInt(0, 4), //guess
+                6 => Format::UInt(0, 3),
                 7 => Format::UInt(0, 2),
                 8 => Format::String(0, 255),
+                9 => Format::Opaque(0, 255),
                 11 => Format::String(0, 255),
                 12 => Format::UInt(0, 2),
                 14 => Format::UInt(0, 4),
                 15 => Format::String(0, 255),
                 17 => Format::UInt(0, 2),
                 20 => Format::String(0, 255),
-                35 => Format::String(0, 1034),
-                39 => Format::String(0, 255),
+                23 => Format::UInt(0, 3),
+                27 => Format::UInt(0, 3),
+                28 => Format::UInt(0, 4),
+                35 => Format::String(1, 1034),
+                39 => Format::String(1, 255),
                 60 => Format::UInt(0, 4),
                 284 => Format::UInt(0, 1),
+                292 => Format::Opaque(0, 8),
                 _ => Format::Opaque(0, 65535)
             }
         }
+
+        /// Whether an option may appear more than once in a message. Unknown
+        /// options are assumed to be repeatable.
+        pub fn is_repeatable(number: u16) -> bool {
+            match number {
+                1 | 4 | 8 | 11 | 15 | 20 | 292 => true,
+                3 | 5 | 6 | 7 | 9 | 12 | 14 | 17 | 23 | 27 | 28 | 35 | 39 | 60 | 284 => false,
+                _ => true
+            }
+        }
+
+        /// Whether the option number is one defined by the CoAP RFCs.
+        pub fn is_builtin(number: u16) -> bool {
+            matches!(number, 1 | 3 | 4 | 5 | 6 | 7 | 8 | 9 | 11 | 12 | 14 | 15 | 17 | 20 | 23 | 27 | 28 | 35 | 39 | 60 | 284 | 292)
+        }
     }
 }
 
 
 impl Message {
+    pub fn builder(mtype: Mtype, code: Code) -> MessageBuilder {
+        MessageBuilder::new(mtype, code)
+    }
+
+    /// Builds a piggybacked response to `request`, with the request's MID and
+    /// token. An `Empty` code gives an empty ACK, which carries no token.
+    pub fn ack_for(request: &Message, code: Code) -> Message {
+        let builder = Message::builder(Mtype::Acknowledgement, code).mid(request.mid);
+
+        if code == Code::Empty {
+            builder.build()
+        } else {
+            builder.token(&request.token).build()
+        }
+    }
+
+    /// Builds a response to `request` with its token, piggybacked on the ACK
+    /// for a CON and as a NON otherwise. A NON response is left with the
+    /// request's MID, which an `Endpoint` replaces with one of its own when
+    /// sending it.
+    pub fn response_for(request: &Message, code: Code) -> Message {
+        match request.mtype {
+            Mtype::Confirmable => Message::ack_for(request, code),
+            _ => Message::builder(Mtype::NonConfirmable, code).mid(request.mid).token(&request.token).build(),
+        }
+    }
+
+    /// Builds the (empty) RST used to reject `request`.
+    pub fn reset_for(request: &Message) -> Message {
+        Message::builder(Mtype::Reset, Code::Empty).mid(request.mid).build()
+    }
+
+    /// Builds a confirmable request for `uri`, e.g. `coap://host/a/b?x=1`,
+    /// with the Uri-Host, Uri-Port, Uri-Path and Uri-Query options filled in
+    /// as `uri::Uri::options` does.
+    /// The MID and token are left for the caller to set.
+    pub fn request(code: Code, uri: &str) -> Result<Message, Error> {
+        let uri = ::uri::Uri::parse(uri)?;
+
+        let mut msg = Message::builder(Mtype::Confirmable, code).build();
+        msg.options = uri.options();
+        Ok(msg)
+    }
+
     pub fn from_bytes(pkt: &[u8]) -> Result<Message, Error> {
-        let mut i: usize;
+        MessageRef::from_bytes(pkt)?.to_owned()
+    }
 
-        if pkt.len() < 4 {
+    pub fn block1(&self) -> ::std::option::Option<option::Block> {
+        self.options.iter().filter_map(|o| match *o {
+            option::Option::Block1(b) => Some(b),
+            _ => None
+        }).next()
+    }
+
+    pub fn block2(&self) -> ::std::option::Option<option::Block> {
+        self.options.iter().filter_map(|o| match *o {
+            option::Option::Block2(b) => Some(b),
+            _ => None
+        }).next()
+    }
+
+    pub fn from_bytes_with(pkt: &[u8], registry: &option::OptionRegistry) -> Result<Message, Error> {
+        MessageRef::from_bytes(pkt)?.to_owned_with(registry)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut pkt = vec![];
+        self.encode_into_vec(&mut pkt)?;
+
+        Ok(pkt)
+    }
+
+    /// Number of bytes `encode_into` will write for this message. This also
+    /// validates the options, see `encode_into`.
+    pub fn encoded_len(&self) -> Result<usize, Error> {
+        self.sorted_len(&SortedOptions::new(&self.options))
+    }
+
+    fn sorted_len(&self, options: &SortedOptions) -> Result<usize, Error> {
+        if self.token.len() > 8 {
             return Err(Error::MessageFormat);
         }
 
-        let version = pkt[0] >> 6;
-        let mtype = Mtype::from_u8((pkt[0] >> 4) & 0x03);
-        let token_length = pkt[0] & 0x0F;
-        let code = Code::from_u8(pkt[1]);
-        let mid = ((pkt[2] as u16) << 8) | pkt[3] as u16;
+        let mut len = 4 + self.token.len();
+        let mut last_option_number = None;
 
-        if pkt.len() < 4 + token_length as usize {
-            return Err(Error::MessageFormat);
+        for option in options.iter() {
+            option.validate()?;
+
+            if last_option_number == Some(option.number()) && !option.is_repeatable() {
+                return Err(Error::RepeatedOption(option.number()));
+            }
+
+            let delta = (option.number() - last_option_number.unwrap_or(0)) as usize;
+            let length = option.value_len();
+
+            len += 1 + Self::option_ext_len(delta) + Self::option_ext_len(length) + length;
+            last_option_number = Some(option.number());
         }
 
-        let mut token = vec![];
-        for j in 0..token_length {
-            token.push(pkt[4+j as usize]);
+        if !self.payload.is_empty() {
+            len += 1 + self.payload.len();
         }
 
-        i = 4 + token_length as usize;
+        Ok(len)
+    }
 
-        let mut options: Vec<option::Option> = vec![];
-        let mut option_number_offset = 0u16;
+    /// Serializes the message into the front of `buf`, returning the number
+    /// of bytes written.
+    ///
+    /// Options are written ordered by number, keeping repeated options in
+    /// the order they appear in `options`; only when they're out of order is
+    /// anything allocated, to sort them. Options with lengths outside
+    /// those allowed for their number, or non-repeatable options given more
+    /// than once, are rejected.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let options = SortedOptions::new(&self.options);
+        let len = self.sorted_len(&options)?;
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall(len));
+        }
 
-        while i < pkt.len() {
-            if pkt[i] == 0xFF {
-                i+=1;
-                break;
-            }
+        Ok(self.write_sorted(&options, buf))
+    }
 
-            // Note: length errors for 13 & 14 will be caught in the check below.
-            let delta = match pkt[i] >> 4 {
-                d @ 0 ... 12 => d as u16,
-                13 => {i+=1; pkt[i] as u16 + 13},
-                14 => {i+=2; (((pkt[i-1] as u16) << 8) | pkt[i] as u16) + 269},
-                15 => panic!("message format error"),
-                _  => unreachable!(),
-            };
-            let length = match pkt[i] & 0x0F {
-                d @ 0 ... 12 => d as u16,
-                13 => {i+=1; pkt[i] as u16 + 13},
-                14 => {i+=2; ((pkt[i-1] as u16) << 8) | pkt[i] as u16 + 269},
-                15 => panic!("message format error"),
-                _  => unreachable!(),
-            };
+    /// Appends the serialized message to `buf`, growing it as needed,
+    /// returning the number of bytes written.
+    pub fn encode_into_vec(&self, buf: &mut Vec<u8>) -> Result<usize, Error> {
+        let options = SortedOptions::new(&self.options);
+        let start = buf.len();
+        buf.resize(start + self.sorted_len(&options)?, 0);
+
+        Ok(self.write_sorted(&options, &mut buf[start..]))
+    }
+
+    // Writes the message into `buf`, which `sorted_len` has checked is big
+    // enough.
+    fn write_sorted(&self, options: &SortedOptions, buf: &mut [u8]) -> usize {
+        buf[0] = (self.version << 6) | (self.mtype.as_u8() << 4) | self.token.len() as u8;
+        buf[1] = self.code.as_u8();
+        buf[2] = (self.mid >> 8) as u8;
+        buf[3] = (self.mid & 0xFF) as u8;
+
+        let mut i = 4;
+
+        buf[i..i + self.token.len()].copy_from_slice(&self.token);
+        i += self.token.len();
 
+        let mut last_option_number = 0;
+
+        for option in options.iter() {
+            let delta = (option.number() - last_option_number) as usize;
+            let length = option.value_len();
+            let header = i;
             i += 1;
+            let base_delta = Self::write_option_ext(delta, &mut buf[i..]);
+            i += Self::option_ext_len(delta);
+            let base_length = Self::write_option_ext(length, &mut buf[i..]);
+            i += Self::option_ext_len(length);
+            buf[header] = (base_delta << 4) | base_length;
+
+            i += option.write_value(&mut buf[i..]);
+            last_option_number = option.number();
+        }
 
-            let option_number = option_number_offset + delta;
-            option_number_offset = option_number;
+        if !self.payload.is_empty() {
+            buf[i] = 0xFF;
+            i += 1;
+            buf[i..i + self.payload.len()].copy_from_slice(&self.payload);
+            i += self.payload.len();
+        }
 
-            if length >= 65000 {
-                return Err(Error::MessageFormat);
-            }
+        i
+    }
 
-            if pkt.len() >= i+(length as usize) {
-                options.push(option::Option::from_raw(option_number, &pkt[i..i+(length as usize)]));
-            } else {
-                return Err(Error::MessageFormat);
-            }
+    fn option_ext_len(n: usize) -> usize {
+        match n {
+            0..=12 => 0,
+            13..=268 => 1,
+            _ => 2,
+        }
+    }
+
+    // Writes any extended delta or length bytes for `n`, returning the
+    // nibble to put in the option header.
+    fn write_option_ext(n: usize, buf: &mut [u8]) -> u8 {
+        match n {
+            0..=12 => n as u8,
+            13..=268 => {
+                buf[0] = (n - 13) as u8;
+                13
+            },
+            _ => {
+                buf[0] = ((n - 269) >> 8) as u8;
+                buf[1] = (n - 269) as u8;
+                14
+            },
+        }
+    }
+}
+
+// Options in (number, position) order. Messages are usually built with their
+// options already in order, and then nothing is copied; otherwise references
+// to them are stable-sorted once per encode.
+enum SortedOptions<'a> {
+    InOrder(&'a [option::Option]),
+    Sorted(Vec<&'a option::Option>),
+}
 
-            i += length as usize;
+impl<'a> SortedOptions<'a> {
+    fn new(options: &'a [option::Option]) -> SortedOptions<'a> {
+        if options.windows(2).all(|w| w[0].number() <= w[1].number()) {
+            return SortedOptions::InOrder(options);
         }
 
-        let payload = if i < pkt.len() {
-            pkt[i..].to_vec()
-        } else {
-            vec![]
+        let mut sorted: Vec<_> = options.iter().collect();
+        sorted.sort_by_key(|option| option.number());
+        SortedOptions::Sorted(sorted)
+    }
+
+    fn iter<'s>(&'s self) -> SortedIter<'s, 'a> {
+        SortedIter{options: self, next: 0}
+    }
+}
+
+struct SortedIter<'s, 'a: 's> {
+    options: &'s SortedOptions<'a>,
+    next: usize,
+}
+
+impl<'s, 'a> Iterator for SortedIter<'s, 'a> {
+    type Item = &'a option::Option;
+
+    fn next(&mut self) -> ::std::option::Option<&'a option::Option> {
+        let option = match *self.options {
+            SortedOptions::InOrder(options) => options.get(self.next),
+            SortedOptions::Sorted(ref options) => options.get(self.next).cloned(),
         };
+        self.next += 1;
+        option
+    }
+}
+
+/// Builds a `Message` field by field, keeping options ordered by number.
+pub struct MessageBuilder {
+    msg: Message,
+}
+
+impl MessageBuilder {
+    pub fn new(mtype: Mtype, code: Code) -> MessageBuilder {
+        MessageBuilder{
+            msg: Message{
+                version: 1,
+                mtype,
+                code,
+                mid: 0,
+                token: vec![],
+                options: vec![],
+                payload: vec![],
+            }
+        }
+    }
+
+    pub fn mid(mut self, mid: u16) -> MessageBuilder {
+        self.msg.mid = mid;
+        self
+    }
+
+    pub fn token(mut self, token: &[u8]) -> MessageBuilder {
+        self.msg.token = token.to_vec();
+        self
+    }
+
+    /// Adds an option after any already added with the same or a lower number.
+    pub fn option(mut self, option: option::Option) -> MessageBuilder {
+        let i = self.msg.options.iter()
+            .position(|o| o.number() > option.number())
+            .unwrap_or(self.msg.options.len());
+        self.msg.options.insert(i, option);
+        self
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> MessageBuilder {
+        self.msg.payload = payload;
+        self
+    }
+
+    pub fn build(self) -> Message {
+        self.msg
+    }
+}
+
+/// A message borrowed from a receive buffer.
+///
+/// Only the fixed header and token are checked up front, options are decoded
+/// lazily by `options()` and every value is a slice into the original packet.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MessageRef<'a> {
+    pkt: &'a [u8],
+    options_start: usize,
+}
+
+/// A single undecoded option, as yielded by `MessageRef::options()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RawOption<'a> {
+    pub number: u16,
+    pub value: &'a [u8],
+}
+
+pub struct Options<'a> {
+    pkt: &'a [u8],
+    i: usize,
+    last_option_number: u16,
+    done: bool,
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn from_bytes(pkt: &'a [u8]) -> Result<MessageRef<'a>, Error> {
+        if pkt.len() < 4 {
+            return Err(Error::TruncatedHeader(pkt.len()));
+        }
+
+        let version = pkt[0] >> 6;
+        let token_length = pkt[0] & 0x0F;
+
+        if version != 1 {
+            return Err(Error::UnsupportedVersion(0, version));
+        }
+
+        if token_length > 8 {
+            return Err(Error::InvalidTokenLength(0, token_length));
+        }
+
+        if pkt.len() < 4 + token_length as usize {
+            return Err(Error::TruncatedToken(pkt.len()));
+        }
+
+        Ok(MessageRef{
+            pkt,
+            options_start: 4 + token_length as usize,
+        })
+    }
+
+    pub fn version(&self) -> u8 {
+        self.pkt[0] >> 6
+    }
+
+    pub fn mtype(&self) -> Mtype {
+        match (self.pkt[0] >> 4) & 0x03 {
+            0 => Mtype::Confirmable,
+            1 => Mtype::NonConfirmable,
+            2 => Mtype::Acknowledgement,
+            _ => Mtype::Reset,
+        }
+    }
+
+    pub fn code(&self) -> Code {
+        Code::from_u8(self.pkt[1])
+    }
+
+    pub fn mid(&self) -> u16 {
+        ((self.pkt[2] as u16) << 8) | self.pkt[3] as u16
+    }
+
+    pub fn token(&self) -> &'a [u8] {
+        &self.pkt[4..self.options_start]
+    }
+
+    pub fn options(&self) -> Options<'a> {
+        Options{
+            pkt: self.pkt,
+            i: self.options_start,
+            last_option_number: 0,
+            done: false,
+        }
+    }
+
+    /// Walks the options to find the payload marker, failing if any option
+    /// before it is malformed.
+    pub fn payload(&self) -> Result<&'a [u8], Error> {
+        let mut options = self.options();
+        for option in &mut options {
+            option?;
+        }
+
+        Ok(&self.pkt[options.i..])
+    }
+
+    pub fn to_owned(&self) -> Result<Message, Error> {
+        self.to_owned_with(option::OptionRegistry::empty())
+    }
+
+    /// Copies the message into a `Message`, decoding application specific
+    /// options with `registry`.
+    pub fn to_owned_with(&self, registry: &option::OptionRegistry) -> Result<Message, Error> {
+        let mut options = vec![];
+        for option in self.options() {
+            let option = option?;
+            options.push(option::Option::from_raw_with(option.number, option.value, registry));
+        }
 
         Ok(Message{
-            version: version,
-            mtype: mtype,
-            code: code,
-            mid: mid,
-            token: token,
-            options: options,
-            payload: payload,
+            version: self.version(),
+            mtype: self.mtype(),
+            code: self.code(),
+            mid: self.mid(),
+            token: self.token().to_vec(),
+            options,
+            payload: self.payload()?.to_vec(),
         })
     }
+}
 
-    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
-        if self.token.len() > 8 {
-            return Err(Error::MessageFormat);
+impl<'a> RawOption<'a> {
+    pub fn as_str(&self) -> ::std::option::Option<&'a str> {
+        ::std::str::from_utf8(self.value).ok()
+    }
+
+    pub fn as_uint(&self) -> ::std::option::Option<u32> {
+        if self.value.len() > 4 {
+            return None;
         }
 
-        // estimate packet size
-        let mut est_pkt_size: usize = 4 + self.token.len() + 1 + 1 + self.payload.len();
+        Some(self.value.iter().fold(0, |n, byte| (n << 8) | *byte as u32))
+    }
+}
 
-        for option in &self.options {
-            est_pkt_size += 2 + option.value_len();
+impl<'a> Options<'a> {
+    fn next_option(&mut self) -> Result<RawOption<'a>, Error> {
+        let pkt = self.pkt;
 
-            if option.number() >= 65000 {
-                return Err(Error::MessageFormat);
-            }
+        let header_offset = self.i;
+        let delta_nibble = pkt[self.i] >> 4;
+        let length_nibble = pkt[self.i] & 0x0F;
+        self.i += 1;
+
+        if delta_nibble == 15 {
+            return Err(Error::ReservedOptionDelta(header_offset));
+        }
+        if length_nibble == 15 {
+            return Err(Error::ReservedOptionLength(header_offset));
         }
 
-        let mut pkt = Vec::with_capacity(est_pkt_size);
+        let delta = self.read_ext(delta_nibble, Error::TruncatedOptionDelta)?;
+        let length = self.read_ext(length_nibble, Error::TruncatedOptionLength)? as usize;
 
-        pkt.push((self.version << 6) | self.mtype.as_u8() << 4 | self.token.len() as u8);
-        pkt.push(self.code.as_u8());
-        pkt.push((self.mid >> 8) as u8);
-        pkt.push((self.mid & 0xFF) as u8);
+        let option_number = self.last_option_number as u32 + delta;
+        if option_number > u16::MAX as u32 {
+            return Err(Error::OptionNumberOverflow(header_offset));
+        }
+        self.last_option_number = option_number as u16;
 
-        for byte in &self.token {
-            pkt.push(*byte)
+        if pkt.len() < self.i + length {
+            return Err(Error::TruncatedOptionValue(self.i));
         }
 
-        let mut last_option_number = 0;
+        let value = &pkt[self.i..self.i + length];
+        self.i += length;
 
-        for option in &self.options {
-            pkt.extend(option.build_header(&mut last_option_number));
-            pkt.extend(option.value_to_bytes());
+        Ok(RawOption{
+            number: self.last_option_number,
+            value,
+        })
+    }
+
+    // Reads the extended delta or length bytes that follow an option header
+    // for nibble values 13 and 14.
+    fn read_ext(&mut self, nibble: u8, truncated: fn(usize) -> Error) -> Result<u32, Error> {
+        let pkt = self.pkt;
+
+        match nibble {
+            0..=12 => Ok(nibble as u32),
+            13 => {
+                if pkt.len() < self.i + 1 {
+                    return Err(truncated(self.i));
+                }
+                let value = pkt[self.i] as u32 + 13;
+                self.i += 1;
+                Ok(value)
+            },
+            14 => {
+                if pkt.len() < self.i + 2 {
+                    return Err(truncated(self.i));
+                }
+                let value = (((pkt[self.i] as u32) << 8) | pkt[self.i+1] as u32) + 269;
+                self.i += 2;
+                Ok(value)
+            },
+            _ => unreachable!(),
         }
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = Result<RawOption<'a>, Error>;
 
-        if self.payload.len() > 0 {
-            pkt.push(0xFF);
-            pkt.extend(&self.payload);
+    fn next(&mut self) -> ::std::option::Option<Self::Item> {
+        if self.done || self.i >= self.pkt.len() {
+            return None;
         }
 
-        Ok(pkt)
+        if self.pkt[self.i] == 0xFF {
+            self.done = true;
+            self.i += 1;
+            if self.i == self.pkt.len() {
+                return Some(Err(Error::EmptyPayload(self.i - 1)));
+            }
+            return None;
+        }
+
+        let option = self.next_option();
+        if option.is_err() {
+            self.done = true;
+        }
+
+        Some(option)
     }
 }
 
@@ -747,3 +1477,339 @@ fn test_msg_encode_get_con_with_opts() {
         assert_eq!(test_bin[i], ref_bin[i]);
     }
 }
+
+#[test]
+fn test_msg_parse_truncated_header() {
+    assert_eq!(Message::from_bytes(&[0x40,0x01,0x00]), Err(Error::TruncatedHeader(3)));
+}
+
+#[test]
+fn test_msg_parse_bad_version() {
+    assert_eq!(Message::from_bytes(&[0x80,0x01,0x00,0x37]), Err(Error::UnsupportedVersion(0, 2)));
+}
+
+#[test]
+fn test_msg_parse_bad_token_length() {
+    let ref_bin = [0x49,0x01,0x00,0x37,1,2,3,4,5,6,7,8,9];
+
+    assert_eq!(Message::from_bytes(&ref_bin), Err(Error::InvalidTokenLength(0, 9)));
+}
+
+#[test]
+fn test_msg_parse_truncated_token() {
+    assert_eq!(Message::from_bytes(&[0x42,0x01,0x00,0x37,0x99]), Err(Error::TruncatedToken(5)));
+}
+
+#[test]
+fn test_msg_parse_reserved_nibbles() {
+    assert_eq!(Message::from_bytes(&[0x40,0x01,0x00,0x37,0xF1,0x00]), Err(Error::ReservedOptionDelta(4)));
+    assert_eq!(Message::from_bytes(&[0x40,0x01,0x00,0x37,0xBF,0x00]), Err(Error::ReservedOptionLength(4)));
+}
+
+#[test]
+fn test_msg_parse_truncated_extended() {
+    assert_eq!(Message::from_bytes(&[0x40,0x01,0x00,0x37,0xD0]), Err(Error::TruncatedOptionDelta(5)));
+    assert_eq!(Message::from_bytes(&[0x40,0x01,0x00,0x37,0xE0,0x01]), Err(Error::TruncatedOptionDelta(5)));
+    assert_eq!(Message::from_bytes(&[0x40,0x01,0x00,0x37,0xBD]), Err(Error::TruncatedOptionLength(5)));
+    assert_eq!(Message::from_bytes(&[0x40,0x01,0x00,0x37,0xDE,0x01,0x00]), Err(Error::TruncatedOptionLength(6)));
+}
+
+#[test]
+fn test_msg_parse_truncated_value() {
+    assert_eq!(Message::from_bytes(&[0x40,0x01,0x00,0x37,0xb3,0x61,0x62]), Err(Error::TruncatedOptionValue(5)));
+}
+
+#[test]
+fn test_msg_parse_option_number_overflow() {
+    let ref_bin = [0x40,0x01,0x00,0x37,0xE0,0xFE,0x00,0xE0,0x00,0x00];
+
+    assert_eq!(Message::from_bytes(&ref_bin), Err(Error::OptionNumberOverflow(7)));
+}
+
+#[test]
+fn test_msg_parse_empty_payload() {
+    assert_eq!(Message::from_bytes(&[0x40,0x01,0x00,0x37,0xFF]), Err(Error::EmptyPayload(4)));
+}
+
+#[test]
+fn test_msg_parse_never_panics() {
+    let ref_bin = [0x40,0x02,0x00,0x37,0xb2,0x31,0x61,0x04,0x74,0x65,
+                   0x6d,0x70,0x4d,0x1b,0x61,0x33,0x32,0x63,0x38,0x35,
+                   0x62,0x61,0x39,0x64,0x64,0x61,0x34,0x35,0x38,0x32,
+                   0x33,0x62,0x65,0x34,0x31,0x36,0x32,0x34,0x36,0x63,
+                   0x66,0x38,0x62,0x34,0x33,0x33,0x62,0x61,0x61,0x30,
+                   0x36,0x38,0x64,0x37,0xFF,0x39,0x39];
+
+    for len in 0..ref_bin.len() {
+        let _ = Message::from_bytes(&ref_bin[..len]);
+    }
+
+    for i in 4..ref_bin.len() {
+        for byte in 0..256 {
+            let mut pkt = ref_bin.to_vec();
+            pkt[i] = byte as u8;
+            let _ = Message::from_bytes(&pkt);
+        }
+    }
+}
+
+#[test]
+fn test_msg_ref_parse_get_con_with_opts() {
+    let ref_bin = [0x40,0x02,0x00,0x37,0xb2,0x31,0x61,0x04,0x74,0x65,
+                   0x6d,0x70,0x4d,0x1b,0x61,0x33,0x32,0x63,0x38,0x35,
+                   0x62,0x61,0x39,0x64,0x64,0x61,0x34,0x35,0x38,0x32,
+                   0x33,0x62,0x65,0x34,0x31,0x36,0x32,0x34,0x36,0x63,
+                   0x66,0x38,0x62,0x34,0x33,0x33,0x62,0x61,0x61,0x30,
+                   0x36,0x38,0x64,0x37,0xFF,0x39,0x39];
+
+    let msg = MessageRef::from_bytes(&ref_bin).unwrap();
+
+    assert!(msg.version() == 1);
+    assert!(msg.mtype() == Mtype::Confirmable);
+    assert!(msg.code() == Code::Post);
+    assert!(msg.mid() == 0x0037);
+    assert!(msg.token().is_empty());
+
+    let options: Vec<RawOption> = msg.options().map(|o| o.unwrap()).collect();
+    assert_eq!(options.len(), 3);
+    assert_eq!(options[0].number, 11);
+    assert_eq!(options[0].as_str(), Some("1a"));
+    assert_eq!(options[1].as_str(), Some("temp"));
+    assert_eq!(options[2].number, 15);
+    assert_eq!(options[2].value, &ref_bin[14..54]);
+
+    assert_eq!(msg.payload().unwrap(), &[0x39, 0x39]);
+    assert_eq!(msg.to_owned().unwrap(), Message::from_bytes(&ref_bin).unwrap());
+}
+
+#[test]
+fn test_msg_ref_lazy_option_errors() {
+    let ref_bin = [0x41,0x01,0x00,0x37,0x99,0xb1,0x61,0xF1,0x00];
+
+    let msg = MessageRef::from_bytes(&ref_bin).unwrap();
+    assert!(msg.token() == [0x99]);
+
+    let mut options = msg.options();
+    assert_eq!(options.next(), Some(Ok(RawOption{number: 11, value: b"a"})));
+    assert_eq!(options.next(), Some(Err(Error::ReservedOptionDelta(7))));
+    assert_eq!(options.next(), None);
+
+    assert_eq!(msg.payload(), Err(Error::ReservedOptionDelta(7)));
+    assert_eq!(msg.to_owned(), Err(Error::ReservedOptionDelta(7)));
+}
+
+#[test]
+fn test_msg_encode_into_buffer() {
+    let msg = Message{
+        version: 1,
+        mtype: Mtype::NonConfirmable,
+        code: Code::Content,
+        mid: 0x1234,
+        token: vec![0xAB, 0xCD],
+        options: vec![
+            option::Option::ContentFormat(0),
+            option::Option::MaxAge(0x0100),
+            option::Option::Unknown((300, vec![1, 2, 3]))
+        ],
+        payload: vec![0x68, 0x69]
+    };
+
+    let ref_bin = [0x52,0x45,0x12,0x34,0xAB,0xCD,0xC0,0x22,0x01,0x00,
+                   0xE3,0x00,0x11,0x01,0x02,0x03,0xFF,0x68,0x69];
+
+    assert_eq!(msg.encoded_len(), Ok(ref_bin.len()));
+
+    let mut buf = [0u8; 64];
+    let len = msg.encode_into(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &ref_bin[..]);
+
+    let mut small = [0u8; 10];
+    assert_eq!(msg.encode_into(&mut small), Err(Error::BufferTooSmall(ref_bin.len())));
+
+    let mut vec = vec![0x00];
+    assert_eq!(msg.encode_into_vec(&mut vec), Ok(ref_bin.len()));
+    assert_eq!(&vec[1..], &ref_bin[..]);
+
+    assert_eq!(Message::from_bytes(&ref_bin).unwrap(), msg);
+}
+
+#[test]
+fn test_msg_builder_orders_options() {
+    let msg = Message::builder(Mtype::NonConfirmable, Code::Content)
+        .mid(7)
+        .token(&[1, 2])
+        .option(option::Option::ContentFormat(50))
+        .option(option::Option::UriPath("a".to_string()))
+        .option(option::Option::ETag(vec![9]))
+        .option(option::Option::UriPath("b".to_string()))
+        .payload(vec![1])
+        .build();
+
+    assert!(msg.version == 1);
+    assert!(msg.mid == 7);
+    assert!(msg.token == [1, 2]);
+    assert!(msg.options == [
+        option::Option::ETag(vec![9]),
+        option::Option::UriPath("a".to_string()),
+        option::Option::UriPath("b".to_string()),
+        option::Option::ContentFormat(50)
+    ]);
+}
+
+#[test]
+fn test_msg_ack_and_reset_for() {
+    let request = Message::builder(Mtype::Confirmable, Code::Get).mid(0x37).token(&[0x99]).build();
+
+    let ack = Message::ack_for(&request, Code::Content);
+    assert!(ack.mtype == Mtype::Acknowledgement);
+    assert!(ack.code == Code::Content);
+    assert!(ack.mid == 0x37);
+    assert!(ack.token == [0x99]);
+
+    let empty_ack = Message::ack_for(&request, Code::Empty);
+    assert!(empty_ack.token.is_empty());
+
+    let rst = Message::reset_for(&request);
+    assert_eq!(rst.to_bytes().unwrap(), [0x70, 0x00, 0x00, 0x37]);
+}
+
+#[test]
+fn test_msg_request_from_uri() {
+    let msg = Message::request(Code::Get, "coap://Example.com:61616/a/b?x=1&y").unwrap();
+
+    assert!(msg.mtype == Mtype::Confirmable);
+    assert!(msg.code == Code::Get);
+    assert!(msg.options == [
+        option::Option::UriHost("example.com".to_string()),
+        option::Option::UriPort(61616),
+        option::Option::UriPath("a".to_string()),
+        option::Option::UriPath("b".to_string()),
+        option::Option::UriQuery("x=1".to_string()),
+        option::Option::UriQuery("y".to_string())
+    ]);
+
+    let msg = Message::request(Code::Post, "coaps://[::1]:5684").unwrap();
+    assert!(msg.options.is_empty());
+
+    assert_eq!(Message::request(Code::Get, "http://example.com/"), Err(Error::InvalidUri));
+    assert_eq!(Message::request(Code::Get, "coap://host:port/"), Err(Error::InvalidUri));
+}
+
+#[test]
+fn test_msg_request_decodes_uri() {
+    let uri = "coap://example.com/a%2Fb/%C3%A9t%C3%A9?q=a%26b";
+    let msg = Message::request(Code::Get, uri).unwrap();
+
+    assert_eq!(msg.options, ::uri::Uri::parse(uri).unwrap().options());
+    assert!(msg.options == [
+        option::Option::UriHost("example.com".to_string()),
+        option::Option::UriPath("a/b".to_string()),
+        option::Option::UriPath("\u{e9}t\u{e9}".to_string()),
+        option::Option::UriQuery("q=a&b".to_string())
+    ]);
+
+    assert_eq!(Message::request(Code::Get, "coap://example.com/%zz"), Err(Error::InvalidUri));
+}
+
+#[test]
+fn test_msg_encode_sorts_options() {
+    let ref_bin = [0x40,0x02,0x00,0x37,0xb2,0x31,0x61,0x04,0x74,0x65,
+                   0x6d,0x70,0x4d,0x1b,0x61,0x33,0x32,0x63,0x38,0x35,
+                   0x62,0x61,0x39,0x64,0x64,0x61,0x34,0x35,0x38,0x32,
+                   0x33,0x62,0x65,0x34,0x31,0x36,0x32,0x34,0x36,0x63,
+                   0x66,0x38,0x62,0x34,0x33,0x33,0x62,0x61,0x61,0x30,
+                   0x36,0x38,0x64,0x37,0xFF,0x39,0x39];
+    let msg = Message{
+        version: 1,
+        mtype: Mtype::Confirmable,
+        code: Code::Post,
+        mid: 0x0037,
+        token: vec![],
+        options: vec![
+            option::Option::UriQuery("a32c85ba9dda45823be416246cf8b433baa068d7".to_string()),
+            option::Option::UriPath("1a".to_string()),
+            option::Option::UriPath("temp".to_string())
+        ],
+        payload: vec![0x39, 0x39]
+    };
+
+    assert_eq!(msg.to_bytes().unwrap(), &ref_bin[..]);
+}
+
+#[test]
+fn test_msg_encode_rejects_bad_options() {
+    let mut msg = Message::builder(Mtype::Confirmable, Code::Get).build();
+
+    msg.options = vec![option::Option::ContentFormat(0), option::Option::ContentFormat(50)];
+    assert_eq!(msg.to_bytes(), Err(Error::RepeatedOption(12)));
+
+    msg.options = vec![option::Option::ETag(vec![])];
+    assert_eq!(msg.to_bytes(), Err(Error::InvalidOptionLength(4, 0)));
+
+    msg.options = vec![option::Option::UriHost("a".repeat(256))];
+    assert_eq!(msg.to_bytes(), Err(Error::InvalidOptionLength(3, 256)));
+
+    msg.options = vec![option::Option::Observe(0x01000000)];
+    assert_eq!(msg.to_bytes(), Err(Error::InvalidOptionLength(6, 4)));
+
+    msg.options = vec![option::Option::Unknown((65000, vec![]))];
+    assert_eq!(msg.to_bytes(), Err(Error::InvalidOptionNumber));
+
+    msg.options = vec![option::Option::Unknown((2000, vec![0; 65535]))];
+    assert_eq!(msg.to_bytes().map(|b| b.len()), Ok(4 + 5 + 65535));
+}
+
+#[test]
+fn test_option_registry_custom_options() {
+    use self::option::{OptionDef, OptionRegistry};
+    use self::option::format::Format;
+    use self::option::value::Value;
+
+    let mut registry = OptionRegistry::new();
+    registry.register(OptionDef{number: 2048, name: "DeviceId", format: Format::String(1, 16), repeatable: false}).unwrap();
+    registry.register(OptionDef{number: 2050, name: "Priority", format: Format::UInt(0, 1), repeatable: false}).unwrap();
+
+    assert_eq!(registry.register(OptionDef{number: 11, name: "Path", format: Format::Opaque(0, 1), repeatable: true}),
+               Err(Error::InvalidOptionNumber));
+    assert_eq!(registry.register(OptionDef{number: 2048, name: "Dup", format: Format::Empty, repeatable: true}),
+               Err(Error::InvalidOptionNumber));
+    assert_eq!(registry.option(2050, Value::String("high".to_string())), Err(Error::InvalidOptionValue(2050)));
+
+    let msg = Message::builder(Mtype::NonConfirmable, Code::Post)
+        .option(registry.option(2050, Value::UInt(3)).unwrap())
+        .option(registry.option(2048, Value::String("abc".to_string())).unwrap())
+        .build();
+
+    let bin = msg.to_bytes().unwrap();
+
+    let parsed = Message::from_bytes_with(&bin, &registry).unwrap();
+    assert_eq!(parsed, msg);
+    assert_eq!(format!("{:?}", parsed.options[0]), "Custom(DeviceId(String(\"abc\")))");
+
+    let unregistered = Message::from_bytes(&bin).unwrap();
+    assert!(unregistered.options[0] == option::Option::Unknown((2048, b"abc".to_vec())));
+
+    let mut too_long = msg.clone();
+    too_long.options.push(registry.option(2048, Value::String("a".repeat(17))).unwrap());
+    assert_eq!(too_long.to_bytes(), Err(Error::InvalidOptionLength(2048, 17)));
+
+    let mut repeated = msg.clone();
+    repeated.options.push(registry.option(2050, Value::UInt(1)).unwrap());
+    assert_eq!(repeated.to_bytes(), Err(Error::RepeatedOption(2050)));
+}
+
+#[test]
+fn test_code_round_trip() {
+    for raw in 0..=255u8 {
+        assert_eq!(Code::from_u8(raw).as_u8(), raw);
+    }
+
+    assert_eq!(Code::from_u8(5), Code::Fetch);
+    assert_eq!(Code::from_u8(6), Code::Patch);
+    assert_eq!(Code::from_u8(7), Code::IPatch);
+    assert_eq!(Code::from_u8(225), Code::Csm);
+    assert_eq!(Code::from_u8(229), Code::Abort);
+    assert_eq!((Code::NotFound.class(), Code::NotFound.detail()), (4, 4));
+    assert_eq!(Code::from_u8(143), Code::UnsupportedContentFormat);
+    assert_eq!(Code::UnsupportedContentFormat.as_u8(), 143);
+}
@@ -11,15 +11,7 @@ impl MsgHandler for NullHandler {
         // todo: would it be better to use an ack w/ error code?
         match request.mtype {
             Mtype::Confirmable | Mtype::NonConfirmable => {
-                let reply = Message{
-                    version: 1,
-                    mtype: Mtype::Reset,
-                    code: Code::Empty,
-                    mid: request.mid,
-                    token: request.token.clone(),
-                    options: vec![],
-                    payload: vec![]
-                };
+                let reply = Message::reset_for(request);
 
                 Some(reply.to_bytes().unwrap())
             },
@@ -0,0 +1,486 @@
+use message::Message;
+use message::option::Option as CoapOption;
+use exchange::Error;
+use oscore::Protected;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// An observer is identified by its address and the token of its request.
+pub type ObserverKey = (SocketAddr, Vec<u8>);
+
+/// A client observing a resource (RFC 7641).
+pub struct Observer {
+    /// Uri-Path of the observed resource, what `Observers::matching` looks
+    /// for.
+    pub path: Vec<String>,
+    /// The registering GET, handled again to produce each notification.
+    pub request: Vec<u8>,
+    // What notifications are protected with, if it registered with OSCORE.
+    pub(crate) protected: Option<Protected>,
+    last_mid: Option<u16>,
+    last_con: Instant,
+    fresh_until: Option<Instant>,
+}
+
+/// Keeps track of observers and the sequence numbers for notifications.
+///
+/// Like `Transactions` this only keeps the state, the caller produces and
+/// sends the notifications.
+pub struct Observers {
+    observers: HashMap<ObserverKey, Observer>,
+    next_seq: u32,
+    check_interval: Duration,
+    capacity: usize,
+    peer_capacity: usize,
+}
+
+impl Observers {
+    /// `check_interval` is how often each observer gets a CON notification
+    /// to check it's still there. At most `capacity` observers are kept, and
+    /// `peer_capacity` for any one address.
+    pub fn new(check_interval: Duration, capacity: usize, peer_capacity: usize) -> Observers {
+        Observers{
+            observers: HashMap::new(),
+            next_seq: 0,
+            check_interval,
+            capacity,
+            peer_capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.observers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    /// The next 24-bit value for an Observe option.
+    pub fn next_seq(&mut self) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = (self.next_seq + 1) & 0xFF_FFFF;
+        seq
+    }
+
+    /// Whether `key` can be registered, either because it already is or
+    /// because neither its address nor the total is at its limit.
+    pub fn has_room(&self, key: &ObserverKey) -> bool {
+        if self.observers.contains_key(key) {
+            return true;
+        }
+
+        let peer = self.observers.keys().filter(|k| k.0 == key.0).count();
+        self.observers.len() < self.capacity && peer < self.peer_capacity
+    }
+
+    /// Adds an observer, replacing any with the same address and token.
+    pub fn register(&mut self, key: ObserverKey, path: Vec<String>, request: Vec<u8>) {
+        self.observers.insert(key, Observer{
+            path,
+            request,
+            protected: None,
+            last_mid: None,
+            last_con: Instant::now(),
+            fresh_until: None,
+        });
+    }
+
+    // Protects the notifications to a registered observer with OSCORE.
+    pub(crate) fn protect(&mut self, key: &ObserverKey, protected: Protected) {
+        if let Some(o) = self.observers.get_mut(key) {
+            o.protected = Some(protected);
+        }
+    }
+
+    pub fn deregister(&mut self, key: &ObserverKey) -> bool {
+        self.observers.remove(key).is_some()
+    }
+
+    /// Removes the observer a notification with `mid` was sent to, for when
+    /// it's rejected with a RST.
+    pub fn reset(&mut self, addr: &SocketAddr, mid: u16) -> bool {
+        let key = self.observers.iter()
+            .find(|&(key, o)| key.0 == *addr && o.last_mid == Some(mid))
+            .map(|(key, _)| key.clone());
+
+        match key {
+            Some(key) => self.deregister(&key),
+            None => false
+        }
+    }
+
+    pub fn get(&self, key: &ObserverKey) -> Option<&Observer> {
+        self.observers.get(key)
+    }
+
+    /// Observers of the resource at `path`.
+    pub fn matching(&self, path: &[String]) -> Vec<ObserverKey> {
+        self.observers.iter().filter(|&(_, o)| o.path == path).map(|(key, _)| key.clone()).collect()
+    }
+
+    /// Observers that need a notification without the resource changing,
+    /// because the last one is no longer fresh or a CON is due.
+    pub fn due(&self) -> Vec<ObserverKey> {
+        let now = Instant::now();
+        self.observers.iter()
+            .filter(|&(_, o)| o.fresh_until.is_some_and(|t| t <= now) || o.last_con + self.check_interval <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Whether the next notification to `key` should be a CON.
+    pub fn con_due(&self, key: &ObserverKey) -> bool {
+        self.observers.get(key).is_some_and(|o| o.last_con + self.check_interval <= Instant::now())
+    }
+
+    /// Records a notification that was sent, `max_age` being its Max-Age.
+    /// Once that has passed the observer is `due` a fresh one, unless it was
+    /// zero.
+    pub fn sent(&mut self, key: &ObserverKey, mid: u16, con: bool, max_age: u32) {
+        if let Some(o) = self.observers.get_mut(key) {
+            let now = Instant::now();
+            o.last_mid = Some(mid);
+            if con {
+                o.last_con = now;
+            }
+            o.fresh_until = match max_age {
+                0 => None,
+                secs => Some(now + Duration::from_secs(secs as u64)),
+            };
+        }
+    }
+}
+
+
+/// Whether a notification with sequence number `v2` received at `t2` is
+/// newer than the last one, `v1` received at `t1` (RFC 7641 §3.4).
+pub fn is_fresh(v1: u32, t1: Instant, v2: u32, t2: Instant) -> bool {
+    (v1 < v2 && v2 - v1 < 1 << 23) ||
+        (v1 > v2 && v1 - v2 > 1 << 23) ||
+        t2 > t1 + Duration::from_secs(128)
+}
+
+pub type NotificationCallback = Box<dyn FnMut(Result<Message, Error>) + Send>;
+
+struct Subscription {
+    request: Message,
+    callback: NotificationCallback,
+    last: Option<(u32, Instant)>,
+    mid: u16,
+    refresh_at: Instant,
+    forgotten: bool,
+}
+
+/// What `Subscriptions::notification` made of a response.
+#[derive(PartialEq, Debug)]
+pub enum Notified {
+    /// It was passed on.
+    Delivered,
+    /// It was older than one already passed on, and dropped.
+    Stale,
+    /// The observation was forgotten, so it should be answered with a RST.
+    Forgotten,
+    /// It isn't for any of our observations.
+    Unknown,
+}
+
+/// Resources this endpoint is observing as a client.
+///
+/// Notifications go to the callback until one comes without an Observe
+/// option or with an error code, which ends the observation. If no fresh
+/// notification arrives before the last one's Max-Age runs out the
+/// observation is `due` to be registered again.
+pub struct Subscriptions {
+    subs: HashMap<ObserverKey, Subscription>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Subscriptions {
+        Subscriptions::new()
+    }
+}
+
+impl Subscriptions {
+    pub fn new() -> Subscriptions {
+        Subscriptions{subs: HashMap::new()}
+    }
+
+    pub fn len(&self) -> usize {
+        self.subs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subs.is_empty()
+    }
+
+    pub fn contains(&self, addr: &SocketAddr, token: &[u8]) -> bool {
+        self.subs.contains_key(&(*addr, token.to_vec()))
+    }
+
+    /// Records a registration that was just sent with `mid`, `refresh_at`
+    /// being when to give up waiting and register again.
+    pub fn start(&mut self, key: ObserverKey, request: Message, mid: u16, refresh_at: Instant, callback: NotificationCallback) {
+        self.subs.insert(key, Subscription{
+            request,
+            callback,
+            last: None,
+            mid,
+            refresh_at,
+            forgotten: false,
+        });
+    }
+
+    /// Records a re-registration sent with `mid`.
+    pub fn registered(&mut self, key: &ObserverKey, mid: u16, refresh_at: Instant) {
+        if let Some(sub) = self.subs.get_mut(key) {
+            sub.mid = mid;
+            sub.refresh_at = refresh_at;
+        }
+    }
+
+    /// Stops an observation without telling the server, the next
+    /// notification is rejected instead.
+    pub fn forget(&mut self, key: &ObserverKey) {
+        if let Some(sub) = self.subs.get_mut(key) {
+            sub.forgotten = true;
+        }
+    }
+
+    /// The MID the latest registration was sent with.
+    pub fn mid(&self, key: &ObserverKey) -> Option<u16> {
+        self.subs.get(key).map(|s| s.mid)
+    }
+
+    /// The registering request.
+    pub fn request(&self, key: &ObserverKey) -> Option<&Message> {
+        self.subs.get(key).map(|s| &s.request)
+    }
+
+    pub fn remove(&mut self, key: &ObserverKey) -> bool {
+        self.subs.remove(key).is_some()
+    }
+
+    /// Ends an observation with an error.
+    pub fn fail(&mut self, key: &ObserverKey, error: Error) {
+        if let Some(mut sub) = self.subs.remove(key) {
+            if !sub.forgotten {
+                (sub.callback)(Err(error));
+            }
+        }
+    }
+
+    /// Fails the observation whose registration a RST with `mid` rejected.
+    pub fn reset(&mut self, addr: &SocketAddr, mid: u16) -> bool {
+        let key = self.subs.iter()
+            .find(|&(key, s)| key.0 == *addr && s.mid == mid)
+            .map(|(key, _)| key.clone());
+
+        match key {
+            Some(key) => {
+                self.fail(&key, Error::Reset);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Handles a response with the token of one of our observations.
+    pub fn notification(&mut self, addr: &SocketAddr, msg: Message) -> Notified {
+        let key = (*addr, msg.token.clone());
+        let now = Instant::now();
+
+        let seq = msg.options.iter().filter_map(|o| match *o {
+            CoapOption::Observe(seq) => Some(seq),
+            _ => None
+        }).next();
+        let max_age = msg.options.iter().filter_map(|o| match *o {
+            CoapOption::MaxAge(secs) => Some(secs),
+            _ => None
+        }).next().unwrap_or(60);
+
+        let sub = match self.subs.get_mut(&key) {
+            Some(sub) => sub,
+            None => return Notified::Unknown
+        };
+
+        if sub.forgotten {
+            self.subs.remove(&key);
+            return Notified::Forgotten;
+        }
+
+        let seq = match seq {
+            Some(seq) if msg.code.class() == 2 => seq,
+            _ => {
+                // The last response of the observation.
+                let mut sub = self.subs.remove(&key).unwrap();
+                (sub.callback)(Ok(msg));
+                return Notified::Delivered;
+            }
+        };
+
+        if let Some((v1, t1)) = sub.last {
+            if !is_fresh(v1, t1, seq, now) {
+                return Notified::Stale;
+            }
+        }
+
+        sub.last = Some((seq, now));
+        sub.refresh_at = now + Duration::from_secs(max_age as u64);
+        (sub.callback)(Ok(msg));
+        Notified::Delivered
+    }
+
+    /// Observations to register again, with the requests to send.
+    pub fn due(&self) -> Vec<(ObserverKey, Message)> {
+        let now = Instant::now();
+        self.subs.iter()
+            .filter(|&(_, s)| !s.forgotten && s.refresh_at <= now)
+            .map(|(key, s)| (key.clone(), s.request.clone()))
+            .collect()
+    }
+}
+
+#[test]
+fn test_observers_register_and_deregister() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut observers = Observers::new(Duration::from_secs(86400), 16, 16);
+    let path = vec!["temp".to_string()];
+
+    observers.register((addr, vec![1]), path.clone(), vec![]);
+    observers.register((addr, vec![2]), path.clone(), vec![]);
+    observers.register((addr, vec![2]), path.clone(), vec![]);
+    observers.register((addr, vec![3]), vec!["other".to_string()], vec![]);
+    assert_eq!(observers.len(), 3);
+
+    let mut matching = observers.matching(&path);
+    matching.sort();
+    assert_eq!(matching, [(addr, vec![1]), (addr, vec![2])]);
+
+    observers.sent(&(addr, vec![1]), 40, false, 60);
+    assert!(!observers.reset(&addr, 41));
+    assert!(observers.reset(&addr, 40));
+    assert!(observers.deregister(&(addr, vec![2])));
+    assert!(!observers.deregister(&(addr, vec![2])));
+    assert_eq!(observers.len(), 1);
+}
+
+#[test]
+fn test_observers_due_and_seq() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut observers = Observers::new(Duration::from_secs(86400), 16, 16);
+
+    observers.register((addr, vec![1]), vec![], vec![]);
+    observers.register((addr, vec![2]), vec![], vec![]);
+    assert!(observers.due().is_empty());
+    assert!(!observers.con_due(&(addr, vec![1])));
+
+    observers.sent(&(addr, vec![1]), 1, false, 0);
+    observers.sent(&(addr, vec![2]), 2, false, 0);
+    assert!(observers.due().is_empty());
+
+    let mut checked = Observers::new(Duration::from_secs(0), 16, 16);
+    checked.register((addr, vec![1]), vec![], vec![]);
+    assert_eq!(checked.due(), [(addr, vec![1])]);
+    assert!(checked.con_due(&(addr, vec![1])));
+
+    observers.next_seq = 0xFF_FFFF;
+    assert_eq!(observers.next_seq(), 0xFF_FFFF);
+    assert_eq!(observers.next_seq(), 0);
+}
+
+#[test]
+fn test_observers_limits() {
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let other = "127.0.0.1:5684".parse().unwrap();
+    let mut observers = Observers::new(Duration::from_secs(86400), 3, 2);
+
+    for token in 1..3 {
+        assert!(observers.has_room(&(addr, vec![token])));
+        observers.register((addr, vec![token]), vec![], vec![]);
+    }
+    assert!(!observers.has_room(&(addr, vec![3])));
+    assert!(observers.has_room(&(addr, vec![2])));
+
+    assert!(observers.has_room(&(other, vec![1])));
+    observers.register((other, vec![1]), vec![], vec![]);
+    assert!(!observers.has_room(&(other, vec![2])));
+
+    observers.deregister(&(addr, vec![1]));
+    assert!(observers.has_room(&(addr, vec![3])));
+}
+
+#[test]
+fn test_notification_freshness() {
+    let t1 = Instant::now();
+    let t2 = t1 + Duration::from_secs(1);
+
+    assert!(is_fresh(1, t1, 2, t2));
+    assert!(!is_fresh(2, t1, 1, t2));
+    assert!(!is_fresh(5, t1, 5, t2));
+    assert!(is_fresh(0xFF_FFFF, t1, 0, t2));
+    assert!(!is_fresh(0, t1, 0xFF_FFFF, t2));
+    assert!(!is_fresh(0, t1, 1 << 23, t2));
+    assert!(is_fresh(2, t1, 1, t1 + Duration::from_secs(129)));
+}
+
+#[test]
+fn test_subscriptions_deliver_and_end() {
+    use message::{Code, MessageBuilder, Mtype};
+    use std::sync::mpsc;
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut subs = Subscriptions::new();
+    let (tx, rx) = mpsc::channel();
+
+    let request = MessageBuilder::new(Mtype::Confirmable, Code::Get).token(&[1]).option(CoapOption::Observe(0)).build();
+    let soon = Instant::now() + Duration::from_secs(60);
+    subs.start((addr, vec![1]), request, 7, soon, Box::new(move |r| tx.send(r.map(|m| m.payload)).unwrap()));
+
+    let note = |seq: std::option::Option<u32>, code, payload: u8| {
+        let mut builder = MessageBuilder::new(Mtype::NonConfirmable, code).token(&[1]).payload(vec![payload]);
+        if let Some(seq) = seq {
+            builder = builder.option(CoapOption::Observe(seq));
+        }
+        builder.build()
+    };
+
+    assert_eq!(subs.notification(&addr, note(Some(5), Code::Content, 1)), Notified::Delivered);
+    assert_eq!(subs.notification(&addr, note(Some(4), Code::Content, 2)), Notified::Stale);
+    assert_eq!(subs.notification(&addr, note(Some(6), Code::Content, 3)), Notified::Delivered);
+    assert_eq!(rx.try_recv().unwrap().unwrap(), [1]);
+    assert_eq!(rx.try_recv().unwrap().unwrap(), [3]);
+    assert!(rx.try_recv().is_err());
+    assert!(subs.due().is_empty());
+
+    assert_eq!(subs.notification(&addr, note(None, Code::NotFound, 4)), Notified::Delivered);
+    assert_eq!(rx.try_recv().unwrap().unwrap(), [4]);
+    assert!(subs.is_empty());
+    assert_eq!(subs.notification(&addr, note(Some(7), Code::Content, 5)), Notified::Unknown);
+}
+
+#[test]
+fn test_subscriptions_forget_reset_and_refresh() {
+    use message::{Code, MessageBuilder, Mtype};
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut subs = Subscriptions::new();
+    let request = MessageBuilder::new(Mtype::Confirmable, Code::Get).token(&[1]).build();
+    let now = Instant::now();
+
+    subs.start((addr, vec![1]), request.clone(), 7, now, Box::new(|_| ()));
+    subs.start((addr, vec![2]), request.clone(), 8, now + Duration::from_secs(60), Box::new(|_| ()));
+    assert_eq!(subs.due(), [((addr, vec![1]), request.clone())]);
+    subs.registered(&(addr, vec![1]), 9, now + Duration::from_secs(60));
+    assert!(subs.due().is_empty());
+
+    assert!(!subs.reset(&addr, 7));
+    assert!(subs.reset(&addr, 9));
+
+    subs.forget(&(addr, vec![2]));
+    let note = MessageBuilder::new(Mtype::Confirmable, Code::Content).token(&[2]).option(CoapOption::Observe(1)).build();
+    assert_eq!(subs.notification(&addr, note), Notified::Forgotten);
+    assert!(subs.is_empty());
+}
@@ -0,0 +1,1017 @@
+//! Object Security for Constrained RESTful Environments (RFC 8613).
+//!
+//! OSCORE protects requests and responses end to end, by encrypting the
+//! code, most options and the payload into a COSE object carried as the
+//! payload of an outer message. Only the options a proxy needs (Uri-Host,
+//! Uri-Port, Proxy-Uri, Proxy-Scheme and Observe) stay outside, along with
+//! the header and token.
+//!
+//! An `Endpoint` given an `Oscore` unprotects requests carrying the OSCORE
+//! option before its handler sees them and protects the replies, passing
+//! the sender ID to `MsgHandler::handle_secure_request`. Requests sent to a
+//! peer registered with `Oscore::peer` are protected, and their responses
+//! unprotected, on the way through. Observations work both ways: each
+//! notification gets a sequence number of its own, and the client drops
+//! any older than the last it accepted (RFC 8613 §4.1.3.5).
+//!
+//! Only the mandatory AES-CCM-16-64-128 algorithm and HKDF-SHA256 are
+//! supported, both done by OpenSSL. A nonce must never be used twice with
+//! the same key, so a context writes its sender sequence number ahead to
+//! storage before using it (RFC 8613 Appendix B.1.1), and has to be set up
+//! again from the stored value after a restart. The highest sequence number
+//! accepted from the peer is stored too, before the request is handed on,
+//! so a restarted context refuses everything up to it (Appendix B.1.2).
+
+use message::{self, Code, Message, Mtype};
+use message::option::Option as CoapOption;
+use observe::ObserverKey;
+use security::{Identity, Replay};
+
+use openssl::cipher::Cipher;
+use openssl::cipher_ctx::CipherCtx;
+use openssl::error::ErrorStack;
+use openssl::md::Md;
+use openssl::pkey::Id;
+use openssl::pkey_ctx::PkeyCtx;
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// AES-CCM-16-64-128 (COSE algorithm 10).
+const AES_CCM_16_64_128: u8 = 10;
+const KEY_LEN: usize = 16;
+const NONCE_LEN: usize = 13;
+const TAG_LEN: usize = 8;
+const MAX_ID_LEN: usize = NONCE_LEN - 6;
+const MAX_ID_CONTEXT_LEN: usize = 255;
+const MAX_SEQUENCE: u64 = (1 << 40) - 1;
+
+// How far ahead the sender sequence number is stored, trading how many are
+// skipped after a restart for how often the store is written.
+const SEQUENCE_CHUNK: u64 = 256;
+
+const OPTION_NUMBER: u16 = 9;
+const OBSERVE: u16 = 6;
+const KID_FLAG: u8 = 0x08;
+const KID_CONTEXT_FLAG: u8 = 0x10;
+
+/// Stores the sequence numbers a context needs after a restart, see
+/// `SecurityContext::new`.
+pub type SequenceStore = Box<dyn FnMut(Stored) -> io::Result<()> + Send>;
+
+/// The sequence numbers a context stores, and is set up again from after a
+/// restart. A new context starts from `Stored::default()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Stored {
+    /// The sender sequence numbers below this may be used.
+    pub sender_sequence: u64,
+    /// The highest sequence number accepted from the peer. Requests up to
+    /// it are refused as replays.
+    pub recipient_sequence: Option<u64>,
+}
+
+/// Why a message couldn't be protected or unprotected.
+#[derive(PartialEq, Debug)]
+pub enum Error {
+    /// The OSCORE option is missing or malformed.
+    BadOption,
+    /// No security context matches the request's key ID.
+    UnknownContext,
+    /// The request's sequence number has been seen before, or may have been
+    /// before a restart.
+    Replay,
+    /// The message didn't authenticate, or its plaintext isn't valid.
+    Decryption,
+    /// The sender sequence numbers are used up and the context has to be
+    /// replaced.
+    SequenceExhausted,
+    /// The sequence numbers couldn't be stored, so the next sender ones
+    /// aren't used or the request isn't accepted.
+    SequenceNotStored,
+    /// OpenSSL failed to encrypt the message.
+    Encryption,
+    Message(message::Error),
+}
+
+impl Error {
+    /// The code of the unprotected error response RFC 8613 §8.2 calls for.
+    pub fn response_code(&self) -> Code {
+        match *self {
+            Error::BadOption => Code::BadOption,
+            Error::UnknownContext | Error::Replay => Code::Unauthorized,
+            Error::Decryption | Error::Message(_) => Code::BadRequest,
+            Error::SequenceExhausted | Error::SequenceNotStored | Error::Encryption => Code::InternalServerError,
+        }
+    }
+
+    /// The diagnostic payload that goes with `response_code`.
+    pub fn diagnostic(&self) -> &'static str {
+        match *self {
+            Error::BadOption => "Bad OSCORE option",
+            Error::UnknownContext => "Security context not found",
+            Error::Replay => "Replay detected",
+            Error::Decryption | Error::Message(_) => "Decryption failed",
+            Error::SequenceExhausted => "Sequence numbers exhausted",
+            Error::SequenceNotStored => "Sequence number not stored",
+            Error::Encryption => "Encryption failed",
+        }
+    }
+}
+
+impl From<message::Error> for Error {
+    fn from(e: message::Error) -> Error {
+        Error::Message(e)
+    }
+}
+
+/// The request a response is bound to: the key ID and partial IV it was
+/// protected with, which go into the response's nonce and AAD.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RequestBinding {
+    kid: Vec<u8>,
+    piv: Vec<u8>,
+}
+
+/// One side of an OSCORE security context (RFC 8613 §3), derived from a
+/// master secret shared with a single peer.
+pub struct SecurityContext {
+    sender_id: Vec<u8>,
+    recipient_id: Vec<u8>,
+    id_context: Option<Vec<u8>>,
+    sender_key: [u8; KEY_LEN],
+    recipient_key: [u8; KEY_LEN],
+    common_iv: [u8; NONCE_LEN],
+    sender_seq: u64,
+    // Sequence numbers below this have been stored and can be used.
+    stored_seq: u64,
+    store: SequenceStore,
+    replay: Replay,
+}
+
+impl SecurityContext {
+    /// Derives the sender and recipient keys and the common IV. The master
+    /// salt may be empty.
+    ///
+    /// `stored` is what `store` was last called with, or the default for a
+    /// new context. Before using sender sequence numbers past the stored
+    /// one, and before accepting a request numbered above the stored
+    /// recipient one, the context calls `store` with the new values, which
+    /// must only return once they've been written somewhere that survives a
+    /// restart. If it fails the context protects or accepts nothing that
+    /// needed it until it succeeds.
+    ///
+    /// Fails with `InvalidInput` if either ID is longer than 7 bytes, the
+    /// most the nonce has room for, or the ID context is longer than the
+    /// 255 bytes the OSCORE option has room for.
+    pub fn new<F>(master_secret: &[u8], master_salt: &[u8], sender_id: &[u8], recipient_id: &[u8],
+                  id_context: Option<&[u8]>, stored: Stored, store: F) -> io::Result<SecurityContext>
+        where F: FnMut(Stored) -> io::Result<()> + Send + 'static
+    {
+        if sender_id.len() > MAX_ID_LEN || recipient_id.len() > MAX_ID_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "OSCORE IDs are at most 7 bytes"));
+        }
+        if id_context.is_some_and(|c| c.len() > MAX_ID_CONTEXT_LEN) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "OSCORE ID contexts are at most 255 bytes"));
+        }
+
+        let derive = |id: &[u8], kind: &str, out: &mut [u8]| {
+            let mut info = vec![0x85];
+            cbor_bytes(&mut info, 0x40, id);
+            match id_context {
+                Some(id_context) => cbor_bytes(&mut info, 0x40, id_context),
+                None => info.push(0xF6),
+            }
+            info.push(AES_CCM_16_64_128);
+            cbor_bytes(&mut info, 0x60, kind.as_bytes());
+            info.push(out.len() as u8);
+            hkdf(master_salt, master_secret, &info, out)
+        };
+
+        let mut sender_key = [0; KEY_LEN];
+        derive(sender_id, "Key", &mut sender_key)?;
+        let mut recipient_key = [0; KEY_LEN];
+        derive(recipient_id, "Key", &mut recipient_key)?;
+        let mut common_iv = [0; NONCE_LEN];
+        derive(&[], "IV", &mut common_iv)?;
+
+        Ok(SecurityContext{
+            sender_id: sender_id.to_vec(),
+            recipient_id: recipient_id.to_vec(),
+            id_context: id_context.map(|c| c.to_vec()),
+            sender_key,
+            recipient_key,
+            common_iv,
+            sender_seq: stored.sender_sequence,
+            stored_seq: stored.sender_sequence,
+            store: Box::new(store),
+            replay: Replay::after(stored.recipient_sequence),
+        })
+    }
+
+    /// The sender sequence number the next request or notification will
+    /// use.
+    pub fn next_sender_sequence(&self) -> u64 {
+        self.sender_seq
+    }
+
+    pub fn sender_id(&self) -> &[u8] {
+        &self.sender_id
+    }
+
+    pub fn recipient_id(&self) -> &[u8] {
+        &self.recipient_id
+    }
+
+    pub fn sender_key(&self) -> &[u8] {
+        &self.sender_key
+    }
+
+    pub fn recipient_key(&self) -> &[u8] {
+        &self.recipient_key
+    }
+
+    pub fn common_iv(&self) -> &[u8] {
+        &self.common_iv
+    }
+
+    /// Protects a request with the next sender sequence number, returning
+    /// the outer message and what its response has to be bound to.
+    pub fn protect_request(&mut self, request: &Message) -> Result<(Message, RequestBinding), Error> {
+        let piv = self.next_partial_iv()?;
+
+        let mut value = vec![piv.len() as u8 | KID_FLAG];
+        value.extend_from_slice(&piv);
+        if let Some(ref id_context) = self.id_context {
+            value[0] |= KID_CONTEXT_FLAG;
+            value.push(id_context.len() as u8);
+            value.extend_from_slice(id_context);
+        }
+        value.extend_from_slice(&self.sender_id);
+
+        let binding = RequestBinding{kid: self.sender_id.clone(), piv};
+        let code = if has_observe(request) { Code::Fetch } else { Code::Post };
+        let nonce = self.nonce(&binding.kid, &binding.piv);
+        let protected = seal(&self.sender_key, &nonce, &binding, request, code, value)?;
+        Ok((protected, binding))
+    }
+
+    /// Verifies and decrypts a request, rejecting any this context has
+    /// already accepted, and storing its sequence number first if it's the
+    /// highest yet.
+    pub fn unprotect_request(&mut self, request: &Message) -> Result<(Message, RequestBinding), Error> {
+        let value = OptionValue::parse(oscore_option(request).ok_or(Error::BadOption)?)?;
+        let (kid, piv) = match (value.kid, value.piv) {
+            (Some(kid), Some(piv)) => (kid, piv),
+            _ => return Err(Error::BadOption),
+        };
+        if !self.matches(kid, value.kid_context) {
+            return Err(Error::UnknownContext);
+        }
+
+        let seq = sequence(piv);
+        if !self.replay.is_new(seq) {
+            return Err(Error::Replay);
+        }
+
+        let binding = RequestBinding{kid: kid.to_vec(), piv: piv.to_vec()};
+        let nonce = self.nonce(&binding.kid, &binding.piv);
+        let unprotected = open(&self.recipient_key, &nonce, &binding, request)?;
+
+        if self.replay.top().is_none_or(|top| seq > top) {
+            let stored = Stored{recipient_sequence: Some(seq), ..self.stored()};
+            (self.store)(stored).map_err(|_| Error::SequenceNotStored)?;
+        }
+        self.replay.mark(seq);
+        Ok((unprotected, binding))
+    }
+
+    /// Protects a response to the request `binding` came from, reusing the
+    /// request's nonce.
+    pub fn protect_response(&self, response: &Message, binding: &RequestBinding) -> Result<Message, Error> {
+        let nonce = self.nonce(&binding.kid, &binding.piv);
+        seal(&self.sender_key, &nonce, binding, response, response_code(response), vec![])
+    }
+
+    /// Protects a notification to the observation `binding` came from, with
+    /// the next sender sequence number as its partial IV.
+    pub fn protect_notification(&mut self, notification: &Message, binding: &RequestBinding) -> Result<Message, Error> {
+        let piv = self.next_partial_iv()?;
+        let nonce = self.nonce(&self.sender_id, &piv);
+        let mut value = vec![piv.len() as u8];
+        value.extend_from_slice(&piv);
+        seal(&self.sender_key, &nonce, binding, notification, response_code(notification), value)
+    }
+
+    /// Verifies and decrypts the response to a request this context
+    /// protected.
+    pub fn unprotect_response(&self, response: &Message, binding: &RequestBinding) -> Result<Message, Error> {
+        let value = OptionValue::parse(oscore_option(response).ok_or(Error::BadOption)?)?;
+        let nonce = match value.piv {
+            Some(piv) => self.nonce(&self.recipient_id, piv),
+            None => self.nonce(&binding.kid, &binding.piv),
+        };
+        open(&self.recipient_key, &nonce, binding, response)
+    }
+
+    // The next sender sequence number as a partial IV, storing the ones
+    // after it first if they haven't been.
+    fn next_partial_iv(&mut self) -> Result<Vec<u8>, Error> {
+        if self.sender_seq > MAX_SEQUENCE {
+            return Err(Error::SequenceExhausted);
+        }
+        if self.sender_seq >= self.stored_seq {
+            let stored = Stored{sender_sequence: cmp::min(self.sender_seq + SEQUENCE_CHUNK, MAX_SEQUENCE + 1), ..self.stored()};
+            (self.store)(stored).map_err(|_| Error::SequenceNotStored)?;
+            self.stored_seq = stored.sender_sequence;
+        }
+
+        let piv = partial_iv(self.sender_seq);
+        self.sender_seq += 1;
+        Ok(piv)
+    }
+
+    fn stored(&self) -> Stored {
+        Stored{sender_sequence: self.stored_seq, recipient_sequence: self.replay.top()}
+    }
+
+    fn matches(&self, kid: &[u8], kid_context: Option<&[u8]>) -> bool {
+        kid == &self.recipient_id[..] && kid_context.is_none_or(|c| self.id_context.as_ref().is_some_and(|own| own[..] == *c))
+    }
+
+    // RFC 8613 §5.2: the ID's length, the ID and the partial IV, padded to
+    // fixed positions and mixed with the common IV.
+    fn nonce(&self, id: &[u8], piv: &[u8]) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[0] = id.len() as u8;
+        nonce[1 + MAX_ID_LEN - id.len()..1 + MAX_ID_LEN].copy_from_slice(id);
+        nonce[NONCE_LEN - piv.len()..].copy_from_slice(piv);
+        for (n, iv) in nonce.iter_mut().zip(self.common_iv.iter()) {
+            *n ^= iv;
+        }
+        nonce
+    }
+}
+
+/// The security contexts of an `Endpoint`, see `Endpoint::oscore`.
+#[derive(Default)]
+pub struct Oscore {
+    contexts: Vec<SecurityContext>,
+    peers: HashMap<SocketAddr, usize>,
+    // Requests we protected, by peer and token, waiting for their response.
+    requests: HashMap<ObserverKey, (usize, RequestBinding, Instant)>,
+    // Observations we registered, by peer and token, with the partial IV of
+    // the last notification accepted.
+    observations: HashMap<ObserverKey, (usize, RequestBinding, Option<u64>)>,
+}
+
+// Which context unprotected a request, for protecting its response.
+#[derive(Clone)]
+pub(crate) struct Protected {
+    context: usize,
+    binding: RequestBinding,
+}
+
+impl Protected {
+    pub(crate) fn identity(&self) -> Identity {
+        Identity::Oscore(self.binding.kid.clone())
+    }
+}
+
+impl Oscore {
+    pub fn new() -> Oscore {
+        Oscore::default()
+    }
+
+    /// Accepts requests protected with `context`.
+    pub fn context(mut self, context: SecurityContext) -> Oscore {
+        self.contexts.push(context);
+        self
+    }
+
+    /// Protects requests sent to `addr` with `context`, which also accepts
+    /// requests like any other.
+    pub fn peer(mut self, addr: SocketAddr, context: SecurityContext) -> Oscore {
+        self.peers.insert(addr, self.contexts.len());
+        self.contexts.push(context);
+        self
+    }
+
+    pub(crate) fn unprotect_request(&mut self, request: &Message) -> Result<(Message, Protected), Error> {
+        let value = OptionValue::parse(oscore_option(request).ok_or(Error::BadOption)?)?;
+        let kid = value.kid.ok_or(Error::BadOption)?;
+        let context = self.contexts.iter().position(|c| c.matches(kid, value.kid_context)).ok_or(Error::UnknownContext)?;
+
+        let (unprotected, binding) = self.contexts[context].unprotect_request(request)?;
+        Ok((unprotected, Protected{context, binding}))
+    }
+
+    pub(crate) fn protect_response(&self, protected: &Protected, response: &Message) -> Result<Message, Error> {
+        self.contexts[protected.context].protect_response(response, &protected.binding)
+    }
+
+    pub(crate) fn protect_notification(&mut self, protected: &Protected, notification: &Message) -> Result<Message, Error> {
+        self.contexts[protected.context].protect_notification(notification, &protected.binding)
+    }
+
+    // Protects a request if there's a context for its destination, leaving
+    // it alone otherwise.
+    pub(crate) fn protect_request(&mut self, addr: &SocketAddr, request: Message) -> Result<Message, Error> {
+        let context = match self.peers.get(addr) {
+            Some(&context) => context,
+            None => return Ok(request),
+        };
+
+        let (protected, binding) = self.contexts[context].protect_request(&request)?;
+        self.requests.insert((*addr, request.token), (context, binding, Instant::now()));
+        Ok(protected)
+    }
+
+    // Like `protect_request`, for a request registering an observation,
+    // whose notifications stay bound to it.
+    pub(crate) fn protect_registration(&mut self, addr: &SocketAddr, request: Message) -> Result<Message, Error> {
+        let context = match self.peers.get(addr) {
+            Some(&context) => context,
+            None => return Ok(request),
+        };
+
+        let (protected, binding) = self.contexts[context].protect_request(&request)?;
+        self.observations.insert((*addr, request.token), (context, binding, None));
+        Ok(protected)
+    }
+
+    // Unprotects the response to a request `protect_request` protected.
+    // Error responses the peer couldn't protect come through as they are,
+    // while ones that don't authenticate are dropped.
+    pub(crate) fn unprotect_response(&mut self, addr: &SocketAddr, response: Message) -> Option<Message> {
+        let (context, binding, _) = match self.requests.remove(&(*addr, response.token.clone())) {
+            Some(request) => request,
+            None => return Some(response),
+        };
+
+        if oscore_option(&response).is_none() {
+            return Some(response);
+        }
+        self.contexts[context].unprotect_response(&response, &binding).ok()
+    }
+
+    // Unprotects a notification for an observation `protect_registration`
+    // protected, like `unprotect_response`. Only the first can reuse the
+    // request's nonce, and later ones have to be newer than the last.
+    pub(crate) fn unprotect_notification(&mut self, addr: &SocketAddr, notification: Message) -> Option<Message> {
+        let &mut (context, ref binding, ref mut last) = match self.observations.get_mut(&(*addr, notification.token.clone())) {
+            Some(observation) => observation,
+            None => return Some(notification),
+        };
+
+        let value = match oscore_option(&notification) {
+            Some(value) => OptionValue::parse(value).ok()?,
+            None => return Some(notification),
+        };
+        let seq = value.piv.map(sequence);
+        if last.is_some() && seq <= *last {
+            return None;
+        }
+
+        let unprotected = self.contexts[context].unprotect_response(&notification, binding).ok()?;
+        if seq.is_some() {
+            *last = seq;
+        }
+        Some(unprotected)
+    }
+
+    // Forgets requests that are past waiting for a response, and
+    // observations `observing` says have ended.
+    pub(crate) fn sweep<F: Fn(&SocketAddr, &[u8]) -> bool>(&mut self, lifetime: Duration, observing: F) {
+        self.requests.retain(|_, &mut (_, _, sent)| sent.elapsed() < lifetime);
+        self.observations.retain(|key, _| observing(&key.0, &key.1));
+    }
+}
+
+// The fields of an OSCORE option value (RFC 8613 §6.1).
+#[derive(Default)]
+struct OptionValue<'a> {
+    piv: Option<&'a [u8]>,
+    kid_context: Option<&'a [u8]>,
+    kid: Option<&'a [u8]>,
+}
+
+impl<'a> OptionValue<'a> {
+    fn parse(value: &'a [u8]) -> Result<OptionValue<'a>, Error> {
+        let mut parsed = OptionValue::default();
+        let (&flags, mut rest) = match value.split_first() {
+            Some(split) => split,
+            None => return Ok(parsed),
+        };
+
+        let n = (flags & 0x07) as usize;
+        if flags & 0xE0 != 0 || n > 5 || rest.len() < n {
+            return Err(Error::BadOption);
+        }
+        if n > 0 {
+            parsed.piv = Some(&rest[..n]);
+        }
+        rest = &rest[n..];
+
+        if flags & KID_CONTEXT_FLAG != 0 {
+            let s = *rest.first().ok_or(Error::BadOption)? as usize;
+            if rest.len() < 1 + s {
+                return Err(Error::BadOption);
+            }
+            parsed.kid_context = Some(&rest[1..1 + s]);
+            rest = &rest[1 + s..];
+        }
+
+        if flags & KID_FLAG != 0 {
+            parsed.kid = Some(rest);
+        } else if !rest.is_empty() {
+            return Err(Error::BadOption);
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn oscore_option(msg: &Message) -> Option<&[u8]> {
+    msg.options.iter().filter_map(|o| match *o {
+        CoapOption::Oscore(ref value) => Some(&value[..]),
+        _ => None
+    }).next()
+}
+
+fn has_observe(msg: &Message) -> bool {
+    msg.options.iter().any(|o| o.number() == OBSERVE)
+}
+
+// The outer code of a protected response (RFC 8613 §4.2).
+fn response_code(response: &Message) -> Code {
+    if has_observe(response) { Code::Content } else { Code::Changed }
+}
+
+// Class U options, which stay in the outer message. Anything else,
+// including options we don't know, is encrypted. Observe is both, see
+// `seal`.
+fn is_outer(number: u16) -> bool {
+    matches!(number, 3 | OBSERVE | 7 | OPTION_NUMBER | 35 | 39)
+}
+
+// The sequence number in as few bytes as possible, but at least one.
+fn partial_iv(seq: u64) -> Vec<u8> {
+    let bytes = seq.to_be_bytes();
+    let skip = bytes.iter().take(7).take_while(|&&b| b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+fn sequence(piv: &[u8]) -> u64 {
+    piv.iter().fold(0, |seq, &b| (seq << 8) | b as u64)
+}
+
+// HKDF-SHA256 (RFC 5869), filling `out`.
+fn hkdf(salt: &[u8], secret: &[u8], info: &[u8], out: &mut [u8]) -> Result<(), ErrorStack> {
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(secret)?;
+    if !salt.is_empty() {
+        ctx.set_hkdf_salt(salt)?;
+    }
+    ctx.add_hkdf_info(info)?;
+    ctx.derive(Some(out))?;
+    Ok(())
+}
+
+// AES-CCM-16-64-128, with the tag appended to the ciphertext. OpenSSL
+// only produces a short tag if its length is set before the key.
+fn encrypt(key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let mut ctx = CipherCtx::new()?;
+    ctx.encrypt_init(Some(Cipher::aes_128_ccm()), None, None)?;
+    ctx.set_iv_length(NONCE_LEN)?;
+    ctx.set_tag_length(TAG_LEN)?;
+    ctx.encrypt_init(None, Some(key), Some(nonce))?;
+    ctx.set_data_len(plaintext.len())?;
+    ctx.cipher_update(aad, None)?;
+
+    let mut out = vec![];
+    ctx.cipher_update_vec(plaintext, &mut out)?;
+    ctx.cipher_final_vec(&mut out)?;
+    let mut tag = [0; TAG_LEN];
+    ctx.tag(&mut tag)?;
+    out.extend_from_slice(&tag);
+    Ok(out)
+}
+
+// Checks the tag at the end of `ciphertext` and decrypts the rest, which
+// for CCM all happens in the one update.
+fn decrypt(key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_LEN);
+    let mut ctx = CipherCtx::new()?;
+    ctx.decrypt_init(Some(Cipher::aes_128_ccm()), None, None)?;
+    ctx.set_iv_length(NONCE_LEN)?;
+    ctx.set_tag(tag)?;
+    ctx.decrypt_init(None, Some(key), Some(nonce))?;
+    ctx.set_data_len(ciphertext.len())?;
+    ctx.cipher_update(aad, None)?;
+
+    let mut out = vec![];
+    ctx.cipher_update_vec(ciphertext, &mut out)?;
+    Ok(out)
+}
+
+// Appends a CBOR byte or text string, `major` being 0x40 or 0x60.
+fn cbor_bytes(out: &mut Vec<u8>, major: u8, bytes: &[u8]) {
+    match bytes.len() {
+        len @ 0..=23 => out.push(major | len as u8),
+        len @ 24..=255 => out.extend_from_slice(&[major | 24, len as u8]),
+        len => {
+            out.push(major | 25);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(bytes);
+}
+
+// The COSE Enc_structure (RFC 8152 §5.3) used as AAD, with the external AAD
+// of RFC 8613 §5.4 binding the message to its request.
+fn aad(binding: &RequestBinding) -> Vec<u8> {
+    let mut external = vec![0x85, 0x01, 0x81, AES_CCM_16_64_128];
+    cbor_bytes(&mut external, 0x40, &binding.kid);
+    cbor_bytes(&mut external, 0x40, &binding.piv);
+    external.push(0x40);
+
+    let mut aad = vec![0x83];
+    cbor_bytes(&mut aad, 0x60, b"Encrypt0");
+    aad.push(0x40);
+    cbor_bytes(&mut aad, 0x40, &external);
+    aad
+}
+
+// Encrypts the code, Class E options and payload of `msg` into the payload
+// of an outer message with the given code and OSCORE option value.
+//
+// Observe goes both inside and out (RFC 8613 §4.1.3.5): a request carries
+// the same value in both, while a notification's inner one is empty and the
+// outer one orders it.
+fn seal(key: &[u8], nonce: &[u8], binding: &RequestBinding, msg: &Message, code: Code, value: Vec<u8>) -> Result<Message, Error> {
+    let (mut outer, mut inner): (Vec<_>, Vec<_>) = msg.options.iter()
+        .filter(|o| o.number() != OPTION_NUMBER).cloned()
+        .partition(|o| is_outer(o.number()));
+    for o in &outer {
+        if let CoapOption::Observe(n) = *o {
+            inner.push(CoapOption::Observe(if msg.code.class() == 0 { n } else { 0 }));
+        }
+    }
+
+    // The plaintext is the code followed by the options and payload, as
+    // they'd be encoded in a message without a token.
+    let inner = Message{
+        version: 1,
+        mtype: Mtype::Confirmable,
+        code: msg.code,
+        mid: 0,
+        token: vec![],
+        options: inner,
+        payload: msg.payload.clone(),
+    };
+    let mut plaintext = inner.to_bytes()?;
+    plaintext.drain(..1);
+    plaintext.drain(1..3);
+
+    let payload = encrypt(key, nonce, &aad(binding), &plaintext).map_err(|_| Error::Encryption)?;
+    outer.push(CoapOption::Oscore(value));
+    Ok(Message{
+        version: msg.version,
+        mtype: msg.mtype,
+        code,
+        mid: msg.mid,
+        token: msg.token.clone(),
+        options: outer,
+        payload,
+    })
+}
+
+// Decrypts what `seal` produced, putting the outer options back with the
+// inner ones. A request's Observe is taken from inside, a response's from
+// outside.
+fn open(key: &[u8], nonce: &[u8], binding: &RequestBinding, msg: &Message) -> Result<Message, Error> {
+    if msg.payload.len() <= TAG_LEN {
+        return Err(Error::Decryption);
+    }
+    let plaintext = decrypt(key, nonce, &aad(binding), &msg.payload).map_err(|_| Error::Decryption)?;
+    let (&code, rest) = plaintext.split_first().ok_or(Error::Decryption)?;
+
+    let mut pkt = vec![0x40, code, 0, 0];
+    pkt.extend_from_slice(rest);
+    let inner = Message::from_bytes(&pkt).map_err(|_| Error::Decryption)?;
+
+    let is_request = inner.code.class() == 0;
+    let mut options: Vec<_> = msg.options.iter()
+        .filter(|o| is_outer(o.number()) && o.number() != OPTION_NUMBER && !(is_request && o.number() == OBSERVE))
+        .cloned().collect();
+    options.extend(inner.options.into_iter().filter(|o| !is_outer(o.number()) || (is_request && o.number() == OBSERVE)));
+
+    Ok(Message{
+        version: msg.version,
+        mtype: msg.mtype,
+        code: inner.code,
+        mid: msg.mid,
+        token: msg.token.clone(),
+        options,
+        payload: inner.payload,
+    })
+}
+
+
+// A store for tests, which don't restart.
+#[cfg(test)]
+fn unstored(_: Stored) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+}
+
+#[test]
+fn test_oscore_context_derivation() {
+    // RFC 8613 Appendix C.1 to C.3, from the client's side.
+    let secret = hex("0102030405060708090a0b0c0d0e0f10");
+    let salt = hex("9e7ca92223786340");
+
+    let c1 = SecurityContext::new(&secret, &salt, &[], &[0x01], None, Stored::default(), unstored).unwrap();
+    assert_eq!(c1.sender_key(), &hex("f0910ed7295e6ad4b54fc793154302ff")[..]);
+    assert_eq!(c1.recipient_key(), &hex("ffb14e093c94c9cac9471648b4f98710")[..]);
+    assert_eq!(c1.common_iv(), &hex("4622d4dd6d944168eefb54987c")[..]);
+    assert_eq!(&c1.nonce(&[], &[0])[..], &hex("4622d4dd6d944168eefb54987c")[..]);
+    assert_eq!(&c1.nonce(&[0x01], &[0])[..], &hex("4722d4dd6d944169eefb54987c")[..]);
+
+    let c2 = SecurityContext::new(&secret, &[], &[0x00], &[0x01], None, Stored::default(), unstored).unwrap();
+    assert_eq!(c2.sender_key(), &hex("321b26943253c7ffb6003b0b64d74041")[..]);
+    assert_eq!(c2.recipient_key(), &hex("e57b5635815177cd679ab4bcec9d7dda")[..]);
+    assert_eq!(c2.common_iv(), &hex("be35ae297d2dace910c52e99f9")[..]);
+
+    let c3 = SecurityContext::new(&secret, &salt, &[], &[0x01], Some(&hex("37cbf3210017a2d3")), Stored::default(), unstored).unwrap();
+    assert_eq!(c3.sender_key(), &hex("af2a1300a5e95788b356336eeecd2b92")[..]);
+    assert_eq!(c3.recipient_key(), &hex("e39a0c7c77b43f03b4b39ab9a268699f")[..]);
+    assert_eq!(c3.common_iv(), &hex("2ca58fb85ff1b81c0b7181b85e")[..]);
+
+    // IDs and ID contexts have to fit the nonce and the OSCORE option.
+    assert!(SecurityContext::new(&secret, &[], &[0; 7], &[0; 7], Some(&[0; 255]), Stored::default(), unstored).is_ok());
+    for &(sender_id, id_context) in &[(&[0; 8][..], None), (&[0; 7][..], Some(&[0; 256][..]))] {
+        let e = SecurityContext::new(&secret, &[], sender_id, &[], id_context, Stored::default(), unstored).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn test_oscore_protect() {
+    // RFC 8613 Appendix C.4 to C.8.
+    let secret = hex("0102030405060708090a0b0c0d0e0f10");
+    let salt = hex("9e7ca92223786340");
+    let request = Message::from_bytes(&hex("44015d1f00003974396c6f63616c686f737483747631")).unwrap();
+    let response = Message::from_bytes(&hex("64455d1f00003974ff48656c6c6f20576f726c6421")).unwrap();
+
+    let mut client = SecurityContext::new(&secret, &salt, &[], &[0x01], None, Stored{sender_sequence: 20, ..Stored::default()}, unstored).unwrap();
+    let (protected, binding) = client.protect_request(&request).unwrap();
+    assert_eq!(protected.to_bytes().unwrap(), hex("44025d1f00003974396c6f63616c686f7374620914ff612f1092f1776f1c1668b3825e"));
+    assert_eq!(client.next_sender_sequence(), 21);
+
+    // The server gets the original request back, once.
+    let mut server = SecurityContext::new(&secret, &salt, &[0x01], &[], None, Stored::default(), unstored).unwrap();
+    let (unprotected, server_binding) = server.unprotect_request(&protected).unwrap();
+    assert_eq!(unprotected.to_bytes().unwrap(), request.to_bytes().unwrap());
+    assert_eq!(server_binding, binding);
+    assert_eq!(server.unprotect_request(&protected), Err(Error::Replay));
+
+    let protected_response = server.protect_response(&response, &binding).unwrap();
+    assert_eq!(protected_response.to_bytes().unwrap(), hex("64445d1f0000397490ffdbaad1e9a7e7b2a813d3c31524378303cdafae119106"));
+    let unprotected = client.unprotect_response(&protected_response, &binding).unwrap();
+    assert_eq!(unprotected.to_bytes().unwrap(), response.to_bytes().unwrap());
+
+    // A response with its own partial IV.
+    let with_piv = Message::from_bytes(&hex("64445d1f00003974920100ff4d4c13669384b67354b2b6175ff4b8658c666a6cf88e")).unwrap();
+    let unprotected = client.unprotect_response(&with_piv, &binding).unwrap();
+    assert_eq!(unprotected.to_bytes().unwrap(), response.to_bytes().unwrap());
+
+    // Without a master salt, and with an ID context.
+    let request = Message::from_bytes(&hex("440171c30000b932396c6f63616c686f737483747631")).unwrap();
+    let mut client = SecurityContext::new(&secret, &[], &[0x00], &[0x01], None, Stored{sender_sequence: 20, ..Stored::default()}, unstored).unwrap();
+    let (protected, _) = client.protect_request(&request).unwrap();
+    assert_eq!(protected.to_bytes().unwrap(), hex("440271c30000b932396c6f63616c686f737463091400ff4ed339a5a379b0b8bc731fffb0"));
+
+    let request = Message::from_bytes(&hex("44012f8eef9bbf7a396c6f63616c686f737483747631")).unwrap();
+    let id_context = hex("37cbf3210017a2d3");
+    let mut client = SecurityContext::new(&secret, &salt, &[], &[0x01], Some(&id_context), Stored{sender_sequence: 20, ..Stored::default()}, unstored).unwrap();
+    let (protected, _) = client.protect_request(&request).unwrap();
+    assert_eq!(protected.to_bytes().unwrap(), hex("44022f8eef9bbf7a396c6f63616c686f73746b19140837cbf3210017a2d3ff72cd7273fd331ac45cffbe55c3"));
+}
+
+#[test]
+fn test_oscore_rejects() {
+    let secret = hex("0102030405060708090a0b0c0d0e0f10");
+    let request = Message::from_bytes(&hex("44015d1f00003974396c6f63616c686f737483747631")).unwrap();
+
+    let mut client = SecurityContext::new(&secret, &[], b"c", b"s", None, Stored::default(), unstored).unwrap();
+    let mut oscore = Oscore::new().context(SecurityContext::new(&secret, &[], b"s", b"c", None, Stored::default(), unstored).unwrap());
+
+    let (protected, _) = client.protect_request(&request).unwrap();
+    let mut tampered = protected.clone();
+    tampered.payload[0] ^= 1;
+    assert_eq!(oscore.unprotect_request(&tampered).err(), Some(Error::Decryption));
+    assert!(oscore.unprotect_request(&protected).is_ok());
+    assert_eq!(oscore.unprotect_request(&protected).err(), Some(Error::Replay));
+
+    let (protected, _) = SecurityContext::new(&secret, &[], b"x", b"s", None, Stored::default(), unstored).unwrap().protect_request(&request).unwrap();
+    assert_eq!(oscore.unprotect_request(&protected).err(), Some(Error::UnknownContext));
+    assert_eq!(oscore.unprotect_request(&request).err(), Some(Error::BadOption));
+
+    let mut bad_option = protected;
+    bad_option.options.retain(|o| o.number() != OPTION_NUMBER);
+    bad_option.options.push(CoapOption::Oscore(vec![0x0f]));
+    assert_eq!(oscore.unprotect_request(&bad_option).err(), Some(Error::BadOption));
+}
+
+#[test]
+fn test_oscore_sender_sequence() {
+    use std::sync::{Arc, Mutex};
+
+    let secret = hex("0102030405060708090a0b0c0d0e0f10");
+    let request = Message::from_bytes(&hex("44015d1f00003974396c6f63616c686f737483747631")).unwrap();
+
+    // Sequence numbers are stored a chunk ahead, before any of them is used.
+    let stored = Arc::new(Mutex::new(vec![]));
+    let log = stored.clone();
+    let mut client = SecurityContext::new(&secret, &[], b"c", b"s", None, Stored{sender_sequence: 5, ..Stored::default()}, move |stored| {
+        log.lock().unwrap().push(stored.sender_sequence);
+        Ok(())
+    }).unwrap();
+    client.protect_request(&request).unwrap();
+    assert_eq!(*stored.lock().unwrap(), [5 + SEQUENCE_CHUNK]);
+    for _ in 1..SEQUENCE_CHUNK {
+        client.protect_request(&request).unwrap();
+    }
+    assert_eq!(stored.lock().unwrap().len(), 1);
+    client.protect_request(&request).unwrap();
+    assert_eq!(*stored.lock().unwrap(), [5 + SEQUENCE_CHUNK, 5 + 2 * SEQUENCE_CHUNK]);
+
+    // Nothing is protected with numbers that couldn't be stored.
+    let mut failing = SecurityContext::new(&secret, &[], b"c", b"s", None, Stored::default(), |_| Err(io::Error::other("read-only"))).unwrap();
+    assert_eq!(failing.protect_request(&request).err(), Some(Error::SequenceNotStored));
+    assert_eq!(failing.next_sender_sequence(), 0);
+
+    // The last number can be used, but then the context needs replacing.
+    let mut last = SecurityContext::new(&secret, &[], b"c", b"s", None, Stored{sender_sequence: MAX_SEQUENCE, ..Stored::default()}, |stored| {
+        assert_eq!(stored.sender_sequence, MAX_SEQUENCE + 1);
+        Ok(())
+    }).unwrap();
+    assert!(last.protect_request(&request).is_ok());
+    assert_eq!(last.protect_request(&request).err(), Some(Error::SequenceExhausted));
+}
+
+#[test]
+fn test_oscore_replay_after_restart() {
+    use std::sync::{Arc, Mutex};
+
+    let secret = hex("0102030405060708090a0b0c0d0e0f10");
+    let request = Message::from_bytes(&hex("44015d1f00003974396c6f63616c686f737483747631")).unwrap();
+    let mut client = SecurityContext::new(&secret, &[], b"c", b"s", None, Stored::default(), unstored).unwrap();
+    let requests: Vec<_> = (0..4).map(|_| client.protect_request(&request).unwrap().0).collect();
+
+    // The highest sequence number accepted is stored before the request is.
+    let stored = Arc::new(Mutex::new(vec![]));
+    let log = stored.clone();
+    let mut server = SecurityContext::new(&secret, &[], b"s", b"c", None, Stored::default(), move |stored| {
+        log.lock().unwrap().push(stored.recipient_sequence);
+        Ok(())
+    }).unwrap();
+    for i in &[1, 0, 2] {
+        assert!(server.unprotect_request(&requests[*i]).is_ok());
+    }
+    assert_eq!(*stored.lock().unwrap(), [Some(1), Some(2)]);
+
+    // After a restart nothing up to it is accepted again.
+    let last = Stored{recipient_sequence: Some(2), ..Stored::default()};
+    let mut restarted = SecurityContext::new(&secret, &[], b"s", b"c", None, last, unstored).unwrap();
+    for i in &[0, 2] {
+        assert_eq!(restarted.unprotect_request(&requests[*i]).err(), Some(Error::Replay));
+    }
+    assert!(restarted.unprotect_request(&requests[3]).is_ok());
+
+    // Nor is a request whose number couldn't be stored.
+    let mut failing = SecurityContext::new(&secret, &[], b"s", b"c", None, last, |_| Err(io::Error::other("read-only"))).unwrap();
+    for _ in 0..2 {
+        assert_eq!(failing.unprotect_request(&requests[3]).err(), Some(Error::SequenceNotStored));
+    }
+}
+
+#[test]
+fn test_oscore_notifications() {
+    let secret = hex("0102030405060708090a0b0c0d0e0f10");
+    let server_addr = "192.0.2.1:5683".parse().unwrap();
+    let observe = |msg: &Message| msg.options.iter().filter_map(|o| match *o {
+        CoapOption::Observe(seq) => Some(seq),
+        _ => None
+    }).next();
+
+    let mut client = Oscore::new().peer(server_addr, SecurityContext::new(&secret, &[], b"c", b"s", None, Stored::default(), unstored).unwrap());
+    let mut server = Oscore::new().context(SecurityContext::new(&secret, &[], b"s", b"c", None, Stored::default(), unstored).unwrap());
+
+    let mut get = Message::request(Code::Get, "coap://localhost/temp").unwrap();
+    get.token = vec![1, 2];
+    get.options.push(CoapOption::Observe(0));
+    let registration = client.protect_registration(&server_addr, get).unwrap();
+    assert_eq!((registration.code, observe(&registration)), (Code::Fetch, Some(0)));
+
+    // The server sees the Observe option that was protected, not the outer
+    // one.
+    let mut stripped = registration.clone();
+    stripped.options.retain(|o| o.number() != OBSERVE);
+    let (request, protected) = server.unprotect_request(&stripped).unwrap();
+    assert_eq!((request.code, observe(&request)), (Code::Get, Some(0)));
+
+    let notification = |n: u32| {
+        let mut resp = Message::response_for(&request, Code::Content);
+        resp.options.push(CoapOption::Observe(n));
+        resp.payload = vec![n as u8];
+        resp
+    };
+
+    // The first can reuse the request's nonce, later ones have their own.
+    let first = server.protect_response(&protected, &notification(3)).unwrap();
+    let second = server.protect_notification(&protected, &notification(4)).unwrap();
+    let third = server.protect_notification(&protected, &notification(5)).unwrap();
+    assert_eq!((second.code, observe(&second)), (Code::Content, Some(4)));
+
+    let first = client.unprotect_notification(&server_addr, first).unwrap();
+    assert_eq!((first.code, observe(&first), first.payload), (Code::Content, Some(3), vec![3]));
+    let third = client.unprotect_notification(&server_addr, third.clone()).unwrap();
+    assert_eq!((observe(&third), third.payload), (Some(5), vec![5]));
+
+    // Anything older than the last one is dropped, as are forgeries.
+    assert!(client.unprotect_notification(&server_addr, second).is_none());
+    let mut forged = server.protect_notification(&protected, &notification(6)).unwrap();
+    forged.options.retain(|o| o.number() != OBSERVE);
+    forged.options.push(CoapOption::Observe(7));
+    forged.payload[0] ^= 1;
+    assert!(client.unprotect_notification(&server_addr, forged).is_none());
+
+    // Once the observation has ended they aren't recognised any more.
+    client.sweep(Duration::from_secs(0), |_, _| false);
+    let late = server.protect_notification(&protected, &notification(8)).unwrap();
+    assert_eq!(client.unprotect_notification(&server_addr, late.clone()), Some(late));
+}
+
+#[test]
+fn test_oscore_endpoint() {
+    use endpoint::Endpoint;
+    use testing::{spawn, Whoami};
+    use std::net::UdpSocket;
+    use std::sync::mpsc;
+
+    let secret = b"0123456789abcdef";
+    let localhost = "127.0.0.1:0".parse().unwrap();
+    let server = spawn(Endpoint::new(localhost)
+        .oscore(Oscore::new().context(SecurityContext::new(secret, &[], b"srv", b"dev-1", None, Stored::default(), unstored).unwrap()))
+        .bind(Whoami).unwrap());
+    let client = spawn(Endpoint::new(localhost)
+        .oscore(Oscore::new().peer(server.addr, SecurityContext::new(secret, &[], b"dev-1", b"srv", None, Stored::default(), unstored).unwrap()))
+        .bind(Whoami).unwrap());
+
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..2 {
+        let tx = tx.clone();
+        client.handle.request(server.addr, Message::request(Code::Get, "coap://localhost/whoami").unwrap(), move |r| {
+            tx.send(r.map(|m| (m.code, m.payload))).unwrap();
+        }).unwrap();
+    }
+    for _ in 0..2 {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap(), (Code::Content, b"dev-1".to_vec()));
+    }
+
+    // Notifications are protected too, and still say who's observing.
+    let (tx, rx) = mpsc::channel();
+    let observation = client.handle.observe(server.addr, Message::request(Code::Get, "coap://localhost/whoami").unwrap(), move |r| {
+        tx.send(r.map(|m| (m.code, m.payload))).unwrap();
+    }).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap(), (Code::Content, b"dev-1".to_vec()));
+    server.handle.notify("/whoami").unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap(), (Code::Content, b"dev-1".to_vec()));
+    observation.cancel().unwrap();
+
+    // A key ID the server doesn't know gets an unprotected error.
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut request = Message::request(Code::Get, "coap://localhost/whoami").unwrap();
+    request.mid = 7;
+    let (protected, _) = SecurityContext::new(secret, &[], b"dev-2", b"srv", None, Stored::default(), unstored).unwrap().protect_request(&request).unwrap();
+    sock.send_to(&protected.to_bytes().unwrap(), server.addr).unwrap();
+    let mut buf = [0; 1152];
+    let len = sock.recv(&mut buf).unwrap();
+    let resp = Message::from_bytes(&buf[..len]).unwrap();
+    assert_eq!((resp.mtype, resp.mid, resp.code), (Mtype::Acknowledgement, 7, Code::Unauthorized));
+    assert_eq!(resp.payload, b"Security context not found");
+
+    client.stop();
+    server.stop();
+}
@@ -0,0 +1,51 @@
+use getrandom;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `len` bytes from the OS random number generator, for tokens. RFC 7252
+/// asks for tokens an off-path attacker can't guess, which rules out the
+/// generator below.
+pub fn token(len: usize) -> Vec<u8> {
+    let mut token = vec![0; len];
+    getrandom::getrandom(&mut token).expect("OS random number generator failed");
+    token
+}
+
+// Small xorshift generator for MIDs and retransmission jitter. Neither needs
+// to be unpredictable, just unlikely to repeat across restarts.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new() -> Random {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() ^ (d.subsec_nanos() as u64) << 32)
+            .unwrap_or(0);
+
+        Random::with_seed(nanos ^ ((&nanos as *const u64) as u64))
+    }
+
+    pub fn with_seed(seed: u64) -> Random {
+        Random{state: seed | 1}
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// Uniformly distributed in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[test]
+fn test_random_tokens() {
+    assert_eq!(token(0), []);
+    assert_eq!(token(8).len(), 8);
+    assert_ne!(token(8), token(8));
+}
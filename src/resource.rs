@@ -0,0 +1,277 @@
+use message::{Code, Message, Mtype};
+use message::option::Option as CoapOption;
+use endpoint::MsgHandler;
+use router::Params;
+
+use std::net::SocketAddr;
+
+/// A request as seen by a `Resource`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Request {
+    pub peer: SocketAddr,
+    pub method: Code,
+    pub path: Vec<String>,
+    /// Uri-Query arguments split at the first `=`, `None` for arguments
+    /// without one.
+    pub queries: Vec<(String, Option<String>)>,
+    pub content_format: Option<u16>,
+    pub accept: Option<u16>,
+    pub payload: Vec<u8>,
+    /// Path parameters when the resource was reached through a `Router`.
+    pub params: Params,
+    /// Every option of the request, for anything not covered above.
+    pub options: Vec<CoapOption>,
+}
+
+impl Request {
+    pub fn from_message(peer: &SocketAddr, msg: &Message) -> Request {
+        let mut request = Request{
+            peer: *peer,
+            method: msg.code,
+            path: vec![],
+            queries: vec![],
+            content_format: None,
+            accept: None,
+            payload: msg.payload.clone(),
+            params: Params::default(),
+            options: msg.options.clone(),
+        };
+
+        for option in &msg.options {
+            match *option {
+                CoapOption::UriPath(ref s) => request.path.push(s.clone()),
+                CoapOption::UriQuery(ref s) => request.queries.push(split_query(s)),
+                CoapOption::ContentFormat(n) => request.content_format = Some(n),
+                CoapOption::Accept(n) => request.accept = Some(n),
+                _ => ()
+            }
+        }
+
+        request
+    }
+
+    /// The value of the first `name=value` query argument.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.queries.iter().find(|(n, v)| n == name && v.is_some()).and_then(|(_, v)| v.as_deref())
+    }
+
+    /// Whether there's a query argument called `name`, with or without a
+    /// value.
+    pub fn has_query(&self, name: &str) -> bool {
+        self.queries.iter().any(|(n, _)| n == name)
+    }
+}
+
+// Splits a Uri-Query argument at the first `=`.
+pub(crate) fn split_query(arg: &str) -> (String, Option<String>) {
+    match arg.find('=') {
+        Some(i) => (arg[..i].to_string(), Some(arg[i+1..].to_string())),
+        None => (arg.to_string(), None),
+    }
+}
+
+/// A response from a `Resource`, the type, MID and token are filled in from
+/// the request it answers.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Response {
+    pub code: Code,
+    pub content_format: Option<u16>,
+    pub payload: Vec<u8>,
+    /// Any other options to send.
+    pub options: Vec<CoapOption>,
+}
+
+impl Response {
+    pub fn new(code: Code) -> Response {
+        Response{code, content_format: None, payload: vec![], options: vec![]}
+    }
+
+    /// A 2.05 Content response.
+    pub fn content(content_format: u16, payload: Vec<u8>) -> Response {
+        Response{code: Code::Content, content_format: Some(content_format), payload, options: vec![]}
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> Response {
+        self.payload = payload;
+        self
+    }
+
+    pub fn content_format(mut self, content_format: u16) -> Response {
+        self.content_format = Some(content_format);
+        self
+    }
+
+    pub fn option(mut self, option: CoapOption) -> Response {
+        self.options.push(option);
+        self
+    }
+
+    /// Builds the message answering `request`, piggybacked on the ACK for a
+    /// CON and as a NON otherwise.
+    pub fn into_message(self, request: &Message) -> Message {
+        let mut msg = Message::response_for(request, self.code);
+        msg.options = self.options;
+        if let Some(n) = self.content_format {
+            msg.options.push(CoapOption::ContentFormat(n));
+        }
+        msg.options.sort_by_key(|o| o.number());
+        msg.payload = self.payload;
+        msg
+    }
+}
+
+/// Something that can be requested, with a method for each request code.
+/// Methods that aren't implemented answer 4.05 Method Not Allowed.
+pub trait Resource {
+    fn get(&self, _request: &Request) -> Response {
+        Response::new(Code::MethodNotAllowed)
+    }
+
+    fn post(&self, _request: &Request) -> Response {
+        Response::new(Code::MethodNotAllowed)
+    }
+
+    fn put(&self, _request: &Request) -> Response {
+        Response::new(Code::MethodNotAllowed)
+    }
+
+    fn delete(&self, _request: &Request) -> Response {
+        Response::new(Code::MethodNotAllowed)
+    }
+
+    fn fetch(&self, _request: &Request) -> Response {
+        Response::new(Code::MethodNotAllowed)
+    }
+
+    fn patch(&self, _request: &Request) -> Response {
+        Response::new(Code::MethodNotAllowed)
+    }
+
+    fn ipatch(&self, _request: &Request) -> Response {
+        Response::new(Code::MethodNotAllowed)
+    }
+
+    /// Attributes to list the resource with in `/.well-known/core`, like
+    /// `rt` or `obs`.
+    fn link_attributes(&self) -> Vec<(String, Option<String>)> {
+        vec![]
+    }
+
+    /// Calls the method for the request's code.
+    fn handle(&self, request: &Request) -> Response {
+        match request.method {
+            Code::Get => self.get(request),
+            Code::Post => self.post(request),
+            Code::Put => self.put(request),
+            Code::Delete => self.delete(request),
+            Code::Fetch => self.fetch(request),
+            Code::Patch => self.patch(request),
+            Code::IPatch => self.ipatch(request),
+            _ => Response::new(Code::MethodNotAllowed)
+        }
+    }
+}
+
+/// Answers `msg` with `resource`, returning the encoded reply. Only requests
+/// are answered, and CON pings get a RST.
+pub fn respond<R: Resource + ?Sized>(resource: &R, addr: &SocketAddr, msg: &Message, params: &Params) -> Option<Vec<u8>> {
+    if msg.code == Code::Empty {
+        return match msg.mtype {
+            Mtype::Confirmable => Message::reset_for(msg).to_bytes().ok(),
+            _ => None
+        };
+    }
+
+    if msg.code.class() != 0 {
+        return None;
+    }
+
+    let mut request = Request::from_message(addr, msg);
+    request.params = params.clone();
+
+    resource.handle(&request).into_message(msg).to_bytes().ok()
+}
+
+/// Serves a single `Resource` for every path.
+pub struct ResourceHandler<R>(pub R);
+
+impl<R: Resource> MsgHandler for ResourceHandler<R> {
+    fn handle_msg(&self, addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+        respond(&self.0, addr, msg, &Params::default())
+    }
+}
+
+
+#[test]
+fn test_request_from_message() {
+    use message::MessageBuilder;
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let msg = MessageBuilder::new(Mtype::Confirmable, Code::Put)
+        .option(CoapOption::UriPath("sensors".to_string()))
+        .option(CoapOption::UriPath("1a".to_string()))
+        .option(CoapOption::ContentFormat(50))
+        .option(CoapOption::UriQuery("unit=C".to_string()))
+        .option(CoapOption::UriQuery("verbose".to_string()))
+        .option(CoapOption::UriQuery("eq=a=b".to_string()))
+        .option(CoapOption::Accept(60))
+        .payload(b"{}".to_vec())
+        .build();
+
+    let request = Request::from_message(&addr, &msg);
+    assert_eq!(request.peer, addr);
+    assert_eq!(request.method, Code::Put);
+    assert_eq!(request.path, ["sensors", "1a"]);
+    assert_eq!(request.query("unit"), Some("C"));
+    assert_eq!(request.query("eq"), Some("a=b"));
+    assert_eq!(request.query("verbose"), None);
+    assert!(request.has_query("verbose"));
+    assert!(!request.has_query("missing"));
+    assert_eq!(request.content_format, Some(50));
+    assert_eq!(request.accept, Some(60));
+    assert_eq!(request.payload, b"{}");
+}
+
+#[test]
+fn test_resource_dispatch_and_defaults() {
+    use message::MessageBuilder;
+
+    struct Temp;
+
+    impl Resource for Temp {
+        fn get(&self, request: &Request) -> Response {
+            let unit = request.query("unit").unwrap_or("C");
+            Response::content(0, format!("21 {}", unit).into_bytes())
+        }
+
+        fn fetch(&self, _request: &Request) -> Response {
+            Response::new(Code::Content).option(CoapOption::MaxAge(30))
+        }
+    }
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let handler = ResourceHandler(Temp);
+    let handle = |msg: &Message| Message::from_bytes(&handler.handle_msg(&addr, msg).unwrap()).unwrap();
+
+    let get = MessageBuilder::new(Mtype::Confirmable, Code::Get).mid(3).token(&[9])
+        .option(CoapOption::UriQuery("unit=F".to_string())).build();
+    let resp = handle(&get);
+    assert_eq!((resp.mtype, resp.code, resp.mid), (Mtype::Acknowledgement, Code::Content, 3));
+    assert_eq!(resp.token, [9]);
+    assert_eq!(resp.options, [CoapOption::ContentFormat(0)]);
+    assert_eq!(resp.payload, b"21 F");
+
+    let fetch = MessageBuilder::new(Mtype::NonConfirmable, Code::Fetch).mid(4).token(&[8]).build();
+    let resp = handle(&fetch);
+    assert_eq!((resp.mtype, resp.code), (Mtype::NonConfirmable, Code::Content));
+    assert_eq!(resp.token, [8]);
+    assert_eq!(resp.options, [CoapOption::MaxAge(30)]);
+
+    for &code in &[Code::Post, Code::Put, Code::Delete, Code::Patch, Code::IPatch, Code::Unknown(8)] {
+        let resp = handle(&MessageBuilder::new(Mtype::Confirmable, code).token(&[1]).build());
+        assert_eq!(resp.code, Code::MethodNotAllowed);
+        assert_eq!(resp.token, [1]);
+    }
+
+    assert!(handler.handle_msg(&addr, &MessageBuilder::new(Mtype::Acknowledgement, Code::Content).build()).is_none());
+}
@@ -0,0 +1,450 @@
+use message::{Code, Message, Mtype};
+use message::option::Option as CoapOption;
+use endpoint::MsgHandler;
+use resource::{self, Resource};
+use link_format::{self, Link};
+
+use std::net::SocketAddr;
+
+/// Something wrong with a route being added to a `Router`.
+#[derive(PartialEq, Debug)]
+pub enum Error {
+    /// The pattern couldn't be parsed, e.g. an unclosed `{` or a `*` that
+    /// isn't the last segment.
+    InvalidPattern(String),
+    /// The code isn't a request method.
+    NotAMethod(Code),
+}
+
+/// The parts of the path matched by a route's `{name}` and `*` segments.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Params {
+    named: Vec<(String, String)>,
+    rest: Vec<String>,
+}
+
+impl Params {
+    /// The segment matched by `{name}`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.named.iter().find(|(n, _)| n == name).map(|(_, v)| &v[..])
+    }
+
+    /// The segments matched by a trailing `*`, possibly none.
+    pub fn rest(&self) -> &[String] {
+        &self.rest
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard,
+}
+
+/// A parsed path pattern like `/sensors/{id}/temp` or `/fw/*`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Pattern, Error> {
+        let invalid = || Error::InvalidPattern(pattern.to_string());
+
+        let path = pattern.strip_prefix('/').unwrap_or(pattern);
+        if path.is_empty() {
+            return Ok(Pattern{segments: vec![]});
+        }
+
+        let mut segments = vec![];
+        for part in path.split('/') {
+            if segments.last() == Some(&Segment::Wildcard) {
+                return Err(invalid());
+            }
+
+            let segment = if part == "*" {
+                Segment::Wildcard
+            } else if let Some(name) = part.strip_prefix('{') {
+                match name.strip_suffix('}') {
+                    Some(name) if !name.is_empty() && !name.contains(['{', '}']) => Segment::Param(name.to_string()),
+                    _ => return Err(invalid())
+                }
+            } else if part.contains(['{', '}', '*']) {
+                return Err(invalid());
+            } else {
+                Segment::Literal(part.to_string())
+            };
+
+            segments.push(segment);
+        }
+
+        Ok(Pattern{segments})
+    }
+
+    /// The path the pattern matches, if it's made up only of literals.
+    pub fn literal_path(&self) -> Option<String> {
+        let mut path = String::new();
+        for segment in &self.segments {
+            match *segment {
+                Segment::Literal(ref s) => {
+                    path.push('/');
+                    path.push_str(s);
+                },
+                _ => return None
+            }
+        }
+
+        if path.is_empty() {
+            path.push('/');
+        }
+        Some(path)
+    }
+
+    /// Matches the Uri-Path segments of a request.
+    pub fn matches<S: AsRef<str>>(&self, path: &[S]) -> Option<Params> {
+        let mut params = Params::default();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match *segment {
+                Segment::Wildcard => {
+                    params.rest = path[i..].iter().map(|s| s.as_ref().to_string()).collect();
+                    return Some(params);
+                },
+                Segment::Literal(ref literal) => match path.get(i) {
+                    Some(s) if s.as_ref() == literal => (),
+                    _ => return None
+                },
+                Segment::Param(ref name) => match path.get(i) {
+                    Some(s) => params.named.push((name.clone(), s.as_ref().to_string())),
+                    None => return None
+                }
+            }
+        }
+
+        if path.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+pub type RouteHandler = Box<dyn Fn(&SocketAddr, &Message, &Params) -> Option<Vec<u8>> + Send>;
+
+struct Route {
+    // `None` for a `Resource`, which answers every method itself.
+    method: Option<Code>,
+    pattern: Pattern,
+    handler: RouteHandler,
+    link_attrs: Vec<(String, Option<String>)>,
+}
+
+/// Dispatches requests to handlers by method and Uri-Path.
+///
+/// Routes are tried in the order they were added and the first to match is
+/// used. A request whose path matches no route gets 4.04 Not Found, and one
+/// whose path only matches routes for other methods gets 4.05 Method Not
+/// Allowed.
+///
+/// Unless a route is added for it, GET `/.well-known/core` lists the routes
+/// whose patterns are plain paths in link format (RFC 6690), along with any
+/// links added with `link`.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    links: Vec<Link>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router{routes: vec![], links: vec![]}
+    }
+
+    /// Lists a link in `/.well-known/core`, replacing the one generated for
+    /// a route with the same target.
+    pub fn link(&mut self, link: Link) {
+        self.links.push(link);
+    }
+
+    /// Adds a route for `method` requests to paths matching `pattern`.
+    /// Segments of the pattern are either literals, `{name}` to match any
+    /// one segment, or a final `*` to match whatever is left of the path.
+    pub fn add<F>(&mut self, method: Code, pattern: &str, handler: F) -> Result<(), Error>
+        where F: Fn(&SocketAddr, &Message, &Params) -> Option<Vec<u8>> + Send + 'static {
+        if method.class() != 0 || method == Code::Empty {
+            return Err(Error::NotAMethod(method));
+        }
+
+        self.routes.push(Route{
+            method: Some(method),
+            pattern: Pattern::parse(pattern)?,
+            handler: Box::new(handler),
+            link_attrs: vec![],
+        });
+
+        Ok(())
+    }
+
+    /// Adds a route sending requests of any method to `resource`, with the
+    /// path parameters in `Request::params`.
+    pub fn resource<R>(&mut self, pattern: &str, resource: R) -> Result<(), Error>
+        where R: Resource + Send + 'static {
+        self.routes.push(Route{
+            method: None,
+            pattern: Pattern::parse(pattern)?,
+            link_attrs: resource.link_attributes(),
+            handler: Box::new(move |addr, msg, params| resource::respond(&resource, addr, msg, params)),
+        });
+
+        Ok(())
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> Result<(), Error>
+        where F: Fn(&SocketAddr, &Message, &Params) -> Option<Vec<u8>> + Send + 'static {
+        self.add(Code::Get, pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> Result<(), Error>
+        where F: Fn(&SocketAddr, &Message, &Params) -> Option<Vec<u8>> + Send + 'static {
+        self.add(Code::Post, pattern, handler)
+    }
+
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> Result<(), Error>
+        where F: Fn(&SocketAddr, &Message, &Params) -> Option<Vec<u8>> + Send + 'static {
+        self.add(Code::Put, pattern, handler)
+    }
+
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> Result<(), Error>
+        where F: Fn(&SocketAddr, &Message, &Params) -> Option<Vec<u8>> + Send + 'static {
+        self.add(Code::Delete, pattern, handler)
+    }
+
+    /// The links listed in `/.well-known/core`.
+    pub fn links(&self) -> Vec<Link> {
+        let mut links: Vec<Link> = vec![];
+
+        for route in &self.routes {
+            let target = match route.pattern.literal_path() {
+                Some(target) => target,
+                None => continue
+            };
+            if self.links.iter().any(|l| l.target == target) {
+                continue;
+            }
+
+            match links.iter_mut().find(|l| l.target == target) {
+                Some(link) => link.attrs.extend(route.link_attrs.iter().cloned()),
+                None => links.push(Link{target, attrs: route.link_attrs.clone()}),
+            }
+        }
+
+        links.extend(self.links.iter().cloned());
+        links
+    }
+
+    fn well_known_core(&self, msg: &Message) -> Message {
+        if msg.code != Code::Get {
+            return Message::response_for(msg, Code::MethodNotAllowed);
+        }
+
+        let filters: Vec<_> = msg.options.iter().filter_map(|o| match *o {
+            CoapOption::UriQuery(ref s) => Some(resource::split_query(s)),
+            _ => None
+        }).collect();
+
+        let links: Vec<_> = self.links().into_iter()
+            .filter(|l| filters.iter().all(|(name, value)| l.matches(name, value.as_deref())))
+            .collect();
+
+        let mut resp = Message::response_for(msg, Code::Content);
+        resp.options.push(CoapOption::ContentFormat(link_format::CONTENT_FORMAT));
+        resp.payload = link_format::serialize(&links).into_bytes();
+        resp
+    }
+}
+
+/// The Uri-Path segments of a request.
+pub fn uri_path(msg: &Message) -> Vec<&str> {
+    msg.options.iter().filter_map(|o| match *o {
+        CoapOption::UriPath(ref s) => Some(&s[..]),
+        _ => None
+    }).collect()
+}
+
+impl MsgHandler for Router {
+    fn handle_msg(&self, addr: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+        if msg.code == Code::Empty {
+            // A CON without a request in it is a ping.
+            return match msg.mtype {
+                Mtype::Confirmable => Message::reset_for(msg).to_bytes().ok(),
+                _ => None
+            };
+        }
+
+        if msg.code.class() != 0 {
+            return None;
+        }
+
+        let path = uri_path(msg);
+        let mut path_found = false;
+
+        for route in &self.routes {
+            if let Some(params) = route.pattern.matches(&path) {
+                if route.method.is_none_or(|method| method == msg.code) {
+                    return (route.handler)(addr, msg, &params);
+                }
+                path_found = true;
+            }
+        }
+
+        if !path_found && path == [".well-known", "core"] {
+            return self.well_known_core(msg).to_bytes().ok();
+        }
+
+        let code = if path_found { Code::MethodNotAllowed } else { Code::NotFound };
+        Message::response_for(msg, code).to_bytes().ok()
+    }
+}
+
+
+#[test]
+fn test_pattern_parse() {
+    assert_eq!(Pattern::parse("/").unwrap().segments, vec![]);
+    assert_eq!(Pattern::parse("/sensors/{id}/temp").unwrap().segments, vec![
+        Segment::Literal("sensors".to_string()),
+        Segment::Param("id".to_string()),
+        Segment::Literal("temp".to_string()),
+    ]);
+    assert_eq!(Pattern::parse("fw/*").unwrap().segments, vec![Segment::Literal("fw".to_string()), Segment::Wildcard]);
+
+    for bad in &["/fw/*/x", "/a/{id", "/a/{}", "/a/b{c}", "/a/x*"] {
+        assert_eq!(Pattern::parse(bad), Err(Error::InvalidPattern(bad.to_string())));
+    }
+}
+
+#[test]
+fn test_pattern_matches() {
+    let temp = Pattern::parse("/sensors/{id}/temp").unwrap();
+    let params = temp.matches(&["sensors", "1a", "temp"]).unwrap();
+    assert_eq!(params.get("id"), Some("1a"));
+    assert_eq!(params.get("other"), None);
+    assert!(temp.matches(&["sensors", "1a"]).is_none());
+    assert!(temp.matches(&["sensors", "1a", "temp", "x"]).is_none());
+    assert!(temp.matches(&["sensor", "1a", "temp"]).is_none());
+
+    let fw = Pattern::parse("/fw/*").unwrap();
+    assert_eq!(fw.matches(&["fw"]).unwrap().rest(), &[] as &[String]);
+    assert_eq!(fw.matches(&["fw", "a", "b"]).unwrap().rest(), &["a".to_string(), "b".to_string()]);
+    assert!(fw.matches(&["fx"]).is_none());
+
+    let root = Pattern::parse("/").unwrap();
+    assert!(root.matches::<&str>(&[]).is_some());
+    assert!(root.matches(&["a"]).is_none());
+}
+
+#[test]
+fn test_router_dispatch() {
+    use message::MessageBuilder;
+
+    let mut router = Router::new();
+    router.get("/sensors/{id}/temp", |_, msg, params| {
+        let mut resp = Message::response_for(msg, Code::Content);
+        resp.payload = params.get("id").unwrap().as_bytes().to_vec();
+        resp.to_bytes().ok()
+    }).unwrap();
+    router.put("/fw/*", |_, msg, params| {
+        let mut resp = Message::response_for(msg, Code::Changed);
+        resp.payload = params.rest().join("/").into_bytes();
+        resp.to_bytes().ok()
+    }).unwrap();
+    assert_eq!(router.add(Code::Content, "/", |_, _, _| None), Err(Error::NotAMethod(Code::Content)));
+
+    struct Sensor;
+
+    impl Resource for Sensor {
+        fn delete(&self, request: &resource::Request) -> resource::Response {
+            resource::Response::new(Code::Deleted).payload(request.params.get("id").unwrap().as_bytes().to_vec())
+        }
+    }
+    router.resource("/sensors/{id}", Sensor).unwrap();
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let request = |mtype, code, path: &[&str]| {
+        let mut builder = MessageBuilder::new(mtype, code).mid(7).token(&[1, 2]);
+        for segment in path {
+            builder = builder.option(CoapOption::UriPath(segment.to_string()));
+        }
+        builder.build()
+    };
+    let handle = |msg: &Message| Message::from_bytes(&router.handle_msg(&addr, msg).unwrap()).unwrap();
+
+    let resp = handle(&request(Mtype::Confirmable, Code::Get, &["sensors", "1a", "temp"]));
+    assert_eq!((resp.mtype, resp.code, resp.mid), (Mtype::Acknowledgement, Code::Content, 7));
+    assert_eq!(resp.token, [1, 2]);
+    assert_eq!(resp.payload, b"1a");
+
+    let resp = handle(&request(Mtype::NonConfirmable, Code::Put, &["fw", "v2", "image"]));
+    assert_eq!((resp.mtype, resp.code), (Mtype::NonConfirmable, Code::Changed));
+    assert_eq!(resp.payload, b"v2/image");
+
+    let resp = handle(&request(Mtype::Confirmable, Code::Post, &["sensors", "1a", "temp"]));
+    assert_eq!(resp.code, Code::MethodNotAllowed);
+    assert_eq!(resp.token, [1, 2]);
+
+    let resp = handle(&request(Mtype::Confirmable, Code::Get, &["sensors"]));
+    assert_eq!(resp.code, Code::NotFound);
+
+    let resp = handle(&request(Mtype::Confirmable, Code::Delete, &["sensors", "2b"]));
+    assert_eq!((resp.code, resp.token.as_slice(), resp.payload.as_slice()), (Code::Deleted, &[1, 2][..], &b"2b"[..]));
+
+    let resp = handle(&request(Mtype::Confirmable, Code::Get, &["sensors", "2b"]));
+    assert_eq!(resp.code, Code::MethodNotAllowed);
+
+    let resp = handle(&request(Mtype::Confirmable, Code::Empty, &[]));
+    assert_eq!((resp.mtype, resp.code), (Mtype::Reset, Code::Empty));
+
+    assert!(router.handle_msg(&addr, &request(Mtype::Acknowledgement, Code::Content, &[])).is_none());
+}
+
+#[test]
+fn test_router_well_known_core() {
+    use message::MessageBuilder;
+
+    struct Temp;
+
+    impl Resource for Temp {
+        fn link_attributes(&self) -> Vec<(String, Option<String>)> {
+            vec![("rt".to_string(), Some("temperature".to_string())), ("obs".to_string(), None)]
+        }
+    }
+
+    let mut router = Router::new();
+    router.resource("/sensors/temp", Temp).unwrap();
+    router.get("/sensors/{id}", |_, _, _| None).unwrap();
+    router.get("/fw", |_, _, _| None).unwrap();
+    router.put("/fw", |_, _, _| None).unwrap();
+    router.get("/hidden", |_, _, _| None).unwrap();
+    router.link(Link::new("/hidden").rt("secret").ct(0));
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let get = |queries: &[&str]| {
+        let mut builder = MessageBuilder::new(Mtype::Confirmable, Code::Get)
+            .option(CoapOption::UriPath(".well-known".to_string()))
+            .option(CoapOption::UriPath("core".to_string()));
+        for query in queries {
+            builder = builder.option(CoapOption::UriQuery(query.to_string()));
+        }
+        Message::from_bytes(&router.handle_msg(&addr, &builder.build()).unwrap()).unwrap()
+    };
+
+    let resp = get(&[]);
+    assert_eq!(resp.code, Code::Content);
+    assert_eq!(resp.options, [CoapOption::ContentFormat(link_format::CONTENT_FORMAT)]);
+    assert_eq!(String::from_utf8(resp.payload).unwrap(),
+               "</sensors/temp>;rt=\"temperature\";obs,</fw>,</hidden>;rt=\"secret\";ct=0");
+
+    assert_eq!(get(&["rt=temperature"]).payload, b"</sensors/temp>;rt=\"temperature\";obs");
+    assert_eq!(get(&["href=/f*"]).payload, b"</fw>");
+    assert_eq!(get(&["rt=humidity"]).payload, b"");
+}
@@ -0,0 +1,77 @@
+//! What the secured transports have in common: who a peer authenticated as,
+//! and the replay window for sequence numbers.
+
+/// Who the peer authenticated as, over DTLS or with OSCORE.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Identity {
+    /// The PSK identity the DTLS session's key was looked up with.
+    Psk(Vec<u8>),
+    /// The peer's raw public key, as a DER SubjectPublicKeyInfo.
+    RawPublicKey(Vec<u8>),
+    /// The peer's certificate, DER encoded.
+    Certificate(Vec<u8>),
+    /// The OSCORE sender ID the request was protected with.
+    Oscore(Vec<u8>),
+}
+
+// The sliding window of RFC 6347 §4.1.2.6, which RFC 8613 §7.4 suggests for
+// OSCORE sequence numbers as well.
+#[derive(Default)]
+pub(crate) struct Replay {
+    top: u64,
+    bitmap: u64,
+}
+
+impl Replay {
+    // A window that has seen everything up to `top`, for after a restart.
+    pub(crate) fn after(top: Option<u64>) -> Replay {
+        match top {
+            Some(top) => Replay{top, bitmap: !0},
+            None => Replay::default(),
+        }
+    }
+
+    // The highest sequence number marked.
+    pub(crate) fn top(&self) -> Option<u64> {
+        if self.bitmap == 0 { None } else { Some(self.top) }
+    }
+
+    pub(crate) fn is_new(&self, seq: u64) -> bool {
+        if seq > self.top || self.bitmap == 0 {
+            return true;
+        }
+        let age = self.top - seq;
+        age < 64 && self.bitmap & (1 << age) == 0
+    }
+
+    pub(crate) fn mark(&mut self, seq: u64) {
+        if self.bitmap == 0 {
+            self.top = seq;
+            self.bitmap = 1;
+        } else if seq > self.top {
+            let shift = seq - self.top;
+            self.bitmap = if shift < 64 { self.bitmap << shift } else { 0 } | 1;
+            self.top = seq;
+        } else {
+            self.bitmap |= 1 << (self.top - seq);
+        }
+    }
+}
+
+#[test]
+fn test_replay_window() {
+    let mut replay = Replay::default();
+    assert!(replay.is_new(5));
+    replay.mark(5);
+    assert!(!replay.is_new(5));
+    assert!(replay.is_new(4));
+    replay.mark(70);
+    assert!(!replay.is_new(5) && !replay.is_new(6) && replay.is_new(7) && replay.is_new(69));
+    replay.mark(69);
+    assert!(!replay.is_new(69) && !replay.is_new(70) && replay.is_new(71));
+    assert_eq!(replay.top(), Some(70));
+
+    let restarted = Replay::after(replay.top());
+    assert!(!restarted.is_new(7) && !restarted.is_new(69) && !restarted.is_new(70) && restarted.is_new(71));
+    assert_eq!(Replay::after(None).top(), None);
+}
@@ -0,0 +1,193 @@
+// The endpoint's UDP socket. Reads are drained until the socket would block,
+// since it's registered edge triggered, and errors are counted rather than
+// taken as fatal: on an unconnected socket they're mostly ICMP reports about
+// some earlier datagram. Sends that would block are queued until the socket
+// is writable again.
+
+use constants::SERVER;
+use endpoint::IoStats;
+
+use mio::{EventLoop, EventSet, Handler, PollOpt};
+use mio::udp::UdpSocket;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+// Errors in a row after which a readiness event stops reading, so a socket
+// stuck in an error state can't spin the event loop. The next datagram to
+// arrive wakes it up again.
+const MAX_ERRORS_IN_A_ROW: usize = 16;
+
+pub struct Socket {
+    sock: UdpSocket,
+    queue: VecDeque<(SocketAddr, Vec<u8>)>,
+    max_queued: usize,
+    // Whether the socket is registered for writable events.
+    writable: bool,
+    stats: IoStats,
+}
+
+impl Socket {
+    pub fn new(sock: UdpSocket, max_queued: usize, stats: IoStats) -> Socket {
+        Socket{
+            sock,
+            queue: VecDeque::new(),
+            max_queued,
+            writable: false,
+            stats,
+        }
+    }
+
+    /// Reads the next datagram into `buf`, `None` once there's nothing left
+    /// to read. A datagram that fills `buf` may have been cut short, so it's
+    /// dropped: `buf` should be one byte longer than the largest datagram
+    /// wanted.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let mut errors = 0;
+        loop {
+            match self.sock.recv_from(buf) {
+                Ok(Some((len, _))) if len == buf.len() => self.stats.oversized_recv(),
+                Ok(Some((len, addr))) => return Some((len, addr)),
+                Ok(None) => return None,
+                Err(_) => {
+                    self.stats.recv_error();
+                    errors += 1;
+                    if errors == MAX_ERRORS_IN_A_ROW {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a datagram, or queues it if the socket would block or earlier
+    /// ones are still waiting. It's dropped if the queue is full.
+    pub fn send_to(&mut self, pkt: &[u8], addr: &SocketAddr) {
+        if self.queue.is_empty() {
+            match self.sock.send_to(pkt, addr) {
+                Ok(Some(_)) => return,
+                Ok(None) => (),
+                Err(_) => {
+                    // UDP is best-effort, and the peer will retransmit.
+                    self.stats.send_error();
+                    return;
+                }
+            }
+        }
+
+        if self.queue.len() < self.max_queued {
+            self.queue.push_back((*addr, pkt.to_vec()));
+        } else {
+            self.stats.dropped_send();
+        }
+    }
+
+    /// Sends queued datagrams until the socket would block again.
+    pub fn flush(&mut self) {
+        while let Some(&(addr, ref pkt)) = self.queue.front() {
+            match self.sock.send_to(pkt, &addr) {
+                Ok(Some(_)) => (),
+                Ok(None) => return,
+                Err(_) => self.stats.send_error(),
+            }
+            self.queue.pop_front();
+        }
+    }
+
+    /// Registers for writable events while anything is queued, and stops
+    /// once the queue has emptied.
+    pub fn update_interest<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) {
+        let writable = !self.queue.is_empty();
+        if writable == self.writable {
+            return;
+        }
+
+        let interest = if writable { EventSet::readable() | EventSet::writable() } else { EventSet::readable() };
+        if event_loop.reregister(&self.sock, SERVER, interest, PollOpt::edge()).is_ok() {
+            self.writable = writable;
+        }
+    }
+}
+
+#[cfg(test)]
+fn bound_pair(max_queued: usize) -> (Socket, ::std::net::UdpSocket, IoStats) {
+    use std::time::Duration;
+
+    let stats = IoStats::default();
+    let sock = UdpSocket::bound(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let peer = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    (Socket::new(sock, max_queued, stats.clone()), peer, stats)
+}
+
+#[test]
+fn test_socket_drops_oversized_datagrams() {
+    use std::thread;
+    use std::time::Duration;
+
+    let (mut sock, peer, stats) = bound_pair(4);
+    let addr = sock.sock.local_addr().unwrap();
+
+    peer.send_to(&[1; 9], addr).unwrap();
+    peer.send_to(&[2; 8], addr).unwrap();
+    peer.send_to(&[3; 10], addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let mut buf = [0; 9];
+    let (len, from) = sock.recv_from(&mut buf).unwrap();
+    assert_eq!((&buf[..len], from), (&[2; 8][..], peer.local_addr().unwrap()));
+    assert_eq!(sock.recv_from(&mut buf), None);
+    assert_eq!(stats.oversized_recvs(), 2);
+}
+
+#[test]
+fn test_socket_queues_sends() {
+    use mio::Token;
+
+    struct Events(Vec<EventSet>);
+
+    impl Handler for Events {
+        type Timeout = ();
+        type Message = ();
+
+        fn ready(&mut self, _event_loop: &mut EventLoop<Events>, _token: Token, events: EventSet) {
+            self.0.push(events);
+        }
+    }
+
+    let (mut sock, peer, stats) = bound_pair(2);
+    let to = peer.local_addr().unwrap();
+    let mut buf = [0; 16];
+
+    // Nothing queued, so it goes straight out.
+    sock.send_to(&[1], &to);
+    assert_eq!(peer.recv_from(&mut buf).unwrap().0, 1);
+    assert!(sock.queue.is_empty());
+
+    // Once something is queued, as when the socket would block, later
+    // datagrams wait behind it and the overflow is dropped.
+    sock.queue.push_back((to, vec![2]));
+    sock.send_to(&[3], &to);
+    sock.send_to(&[4], &to);
+    assert_eq!(sock.queue.len(), 2);
+    assert_eq!(stats.dropped_sends(), 1);
+
+    let mut event_loop = EventLoop::new().unwrap();
+    event_loop.register(&sock.sock, SERVER, EventSet::readable(), PollOpt::edge()).unwrap();
+    sock.update_interest(&mut event_loop);
+    assert!(sock.writable);
+
+    let mut events = Events(vec![]);
+    event_loop.run_once(&mut events, Some(1000)).unwrap();
+    assert!(events.0.iter().any(|e| e.is_writable()));
+
+    sock.flush();
+    for expected in &[2, 3] {
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[*expected]);
+    }
+    assert!(sock.queue.is_empty());
+
+    sock.update_interest(&mut event_loop);
+    assert!(!sock.writable);
+    assert_eq!(stats.send_errors(), 0);
+}
@@ -1,54 +1,945 @@
 use constants::*;
-use message::Message;
-use endpoint::MsgHandler;
+use block::{Assembly, Block1Assembler, Block2Cache};
+use message::{Code, Message, MessageBuilder, MessageRef, Mtype};
+use message::option;
+use endpoint::{Config, Handle, Liveness, MsgHandler, Responder};
+use dedup::{DuplicateCache, Seen};
+use dtls::Sessions;
+use exchange::{self, Exchanges, ResponseCallback};
+use observe::{NotificationCallback, Notified, ObserverKey, Observers, Subscriptions};
+use oscore::{self, Oscore, Protected};
+use random;
+use security::Identity;
+use socket::Socket;
+use tcp::Streams;
+use transaction::{Callback, Delivery, Transactions};
 
 use mio::*;
-use mio::udp::{UdpSocket};
+use std::cmp;
+use std::collections::HashMap;
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 pub struct SocketHandler<H>{
-    sock: UdpSocket,
-    handler: H
+    sock: Socket,
+    handler: H,
+    config: Config,
+    recv_buf: Vec<u8>,
+    send_buf: Vec<u8>,
+    request_buf: Vec<u8>,
+    block1: Block1Assembler,
+    block2: Block2Cache,
+    dedup: DuplicateCache,
+    transactions: Transactions,
+    observers: Observers,
+    subscriptions: Subscriptions,
+    exchanges: Exchanges,
+    handle: Handle,
+    separate: HashMap<u64, Separate>,
+    next_responder: u64,
+    // WebSocket connections, when they're served alongside UDP.
+    streams: Option<Streams>,
+    // DTLS sessions, when the socket is secured.
+    dtls: Option<Sessions>,
+    // OSCORE security contexts, when messages are protected end to end.
+    oscore: Option<Oscore>,
+}
+
+// A request the handler didn't reply to straight away, which may still be
+// answered through its `Responder`.
+struct Separate {
+    addr: SocketAddr,
+    mtype: Mtype,
+    mid: u16,
+    token: Vec<u8>,
+    acked: bool,
+    expires: Instant,
+    // How to protect the response if the request came with OSCORE.
+    protected: Option<Protected>,
+}
+
+pub enum Timer {
+    Retransmit(u64),
+    // Time's up for piggybacking the response to a CON request.
+    AckDelay(u64),
+    // Periodic cleanup of expired exchange state.
+    Sweep,
+}
+
+pub enum Command {
+    Send(SocketAddr, Message, Option<Callback>),
+    Respond(u64, Message),
+    // A `Responder` was dropped without responding.
+    Release(u64),
+    Request(SocketAddr, Message, ResponseCallback),
+    Notify(Vec<String>),
+    // An observer stopped acknowledging its notifications.
+    Forget(ObserverKey),
+    Observe(SocketAddr, Message, NotificationCallback),
+    // Stops observing, with a deregistering GET if the flag is set and by
+    // rejecting the next notification otherwise.
+    Unobserve(ObserverKey, bool),
+    // A registration went unacknowledged or was reset.
+    ObserveFailed(ObserverKey, exchange::Error),
+    Shutdown,
 }
 
 impl<H: MsgHandler>  SocketHandler<H> {
-    pub fn new(sock: UdpSocket, handler: H) -> SocketHandler<H> {
+    pub fn new(sock: Socket, dtls: Option<Sessions>, oscore: Option<Oscore>, streams: Option<Streams>, handler: H, config: Config, handle: Handle) -> SocketHandler<H> {
         SocketHandler{
-            sock: sock,
-            handler: handler
+            sock,
+            handler,
+            recv_buf: vec![0; config.recv_buffer_size + 1],
+            send_buf: Vec::with_capacity(2048),
+            request_buf: vec![],
+            block1: Block1Assembler::new(config.block_lifetime, config.block_cache_size,
+                                         config.max_body_size, config.block_szx),
+            block2: Block2Cache::new(config.block_lifetime, config.block_cache_size),
+            dedup: DuplicateCache::new(config.dedup_capacity, config.transmission.exchange_lifetime(),
+                                       config.transmission.non_lifetime()),
+            transactions: Transactions::new(config.transmission.clone()),
+            observers: Observers::new(config.observe_check_interval, config.max_observers, config.max_observers_per_peer),
+            subscriptions: Subscriptions::new(),
+            exchanges: Exchanges::new(config.transmission.max_transmit_wait(), config.response_timeout, config.nstart),
+            handle,
+            separate: HashMap::new(),
+            next_responder: 0,
+            streams,
+            dtls,
+            oscore,
+            config
+        }
+    }
+
+    // Handles everything that's arrived on the socket. It's edge triggered,
+    // so it's read until it would block.
+    fn receive(&mut self, event_loop: &mut EventLoop<Self>) {
+        let mut buf = mem::take(&mut self.recv_buf);
+        while let Some((len, addr)) = self.sock.recv_from(&mut buf) {
+            let pkts = match self.dtls {
+                Some(ref mut dtls) => dtls.receive(&mut self.sock, &addr, &buf[..len]),
+                None => {
+                    self.handle_pkt(event_loop, &addr, &buf[..len]);
+                    continue;
+                }
+            };
+            for pkt in pkts {
+                self.handle_pkt(event_loop, &addr, &pkt);
+            }
+        }
+        self.recv_buf = buf;
+    }
+
+    fn handle_pkt(&mut self, event_loop: &mut EventLoop<Self>, addr: &SocketAddr, pkt: &[u8]) {
+        let msg = match MessageRef::from_bytes(pkt) {
+            Ok(msg) => msg,
+            Err(_) => return
+        };
+
+        self.send_buf.clear();
+
+        if msg.mtype() == Mtype::Acknowledgement || msg.mtype() == Mtype::Reset {
+            let reply = match msg.to_owned_with(self.handler.option_registry()) {
+                Ok(reply) => reply,
+                Err(_) => return
+            };
+
+            if reply.mtype == Mtype::Reset {
+                self.exchanges.reset(addr, reply.mid);
+            } else if reply.code == Code::Empty {
+                self.exchanges.acknowledged(addr, reply.mid);
+            } else if self.exchanges.contains(addr, &reply.token) {
+                // A piggybacked response to one of our requests.
+                self.transactions.cancel(addr, reply.mid);
+                if let Some(reply) = self.unprotect_response(addr, reply) {
+                    self.exchanges.complete(addr, reply).ok();
+                }
+                return;
+            } else if self.subscriptions.contains(addr, &reply.token) {
+                // A piggybacked response to one of our observations.
+                self.transactions.cancel(addr, reply.mid);
+                if let Some(reply) = self.unprotect_notification(addr, reply) {
+                    self.subscriptions.notification(addr, reply);
+                }
+                return;
+            }
+
+            match self.transactions.handle_reply(addr, reply) {
+                Ok(()) => return,
+                Err(reply) => if reply.mtype == Mtype::Reset && self.observers.reset(addr, reply.mid) {
+                    return;
+                }
+            }
+        }
+
+        // Retransmissions get the same reply as the original, if any.
+        match self.dedup.check(addr, msg.mid(), msg.mtype()) {
+            Seen::New => (),
+            Seen::Duplicate(Some(resp)) => {
+                transmit(&mut self.sock, &mut self.dtls, resp, addr);
+                return;
+            },
+            Seen::Duplicate(None) => return
+        }
+
+        let is_ours = self.exchanges.contains(addr, msg.token()) || self.subscriptions.contains(addr, msg.token());
+        if msg.code().class() >= 2 && is_ours {
+            self.handle_response(addr, &msg);
+            return;
+        }
+
+        // OSCORE requests are handled unprotected, and refused with an
+        // unprotected error if they don't verify.
+        let inner_pkt;
+        let mut protected = None;
+        let msg = match self.unprotect_request(&msg) {
+            None => msg,
+            Some(Ok((pkt, p))) => {
+                inner_pkt = pkt;
+                protected = Some(p);
+                match MessageRef::from_bytes(&inner_pkt) {
+                    Ok(msg) => msg,
+                    Err(_) => return
+                }
+            },
+            Some(Err(e)) => {
+                let mtype = if msg.mtype() == Mtype::Confirmable { Mtype::Acknowledgement } else { Mtype::NonConfirmable };
+                let mut resp = MessageBuilder::new(mtype, e.response_code()).mid(msg.mid()).token(msg.token()).build();
+                resp.payload = e.diagnostic().as_bytes().to_vec();
+                resp.encode_into_vec(&mut self.send_buf).unwrap_or(0);
+                if msg.mtype() == Mtype::Confirmable {
+                    self.dedup.record(addr, msg.mid(), &self.send_buf);
+                }
+                self.send(addr);
+                return;
+            }
+        };
+
+        let identity = match protected {
+            Some(ref protected) => Some(protected.identity()),
+            None => self.dtls.as_ref().and_then(|dtls| dtls.identity(addr)),
+        };
+
+        let id = self.next_responder;
+        self.next_responder += 1;
+        let responder = Responder::new(self.handle.clone(), id, *addr, msg.token());
+        let liveness = responder.liveness();
+
+        self.dispatch(addr, &msg, identity.as_ref(), responder);
+
+        if self.send_buf.is_empty() {
+            self.await_response(event_loop, id, addr, &msg, protected, liveness);
+            return;
+        }
+
+        self.observe(addr, &msg, protected.as_ref());
+        if let Some(ref protected) = protected {
+            self.protect_reply(protected);
+        }
+
+        if msg.mtype() == Mtype::Confirmable {
+            self.dedup.record(addr, msg.mid(), &self.send_buf);
+        }
+
+        self.send(addr);
+    }
+
+    // Works out the reply to a request and leaves it in the send buffer.
+    fn dispatch(&mut self, addr: &SocketAddr, msg: &MessageRef, identity: Option<&Identity>, responder: Responder) {
+        let is_blockwise = msg.options().any(|o| o.is_ok_and(|o| o.number == 23 || o.number == 27));
+        if !is_blockwise {
+            call_handler(&self.handler, identity, addr, msg, &mut self.send_buf, responder);
+            if self.needs_split(None) {
+                if let Ok(request) = msg.to_owned_with(self.handler.option_registry()) {
+                    self.split(addr, &request, None);
+                }
+            }
+            return;
+        }
+
+        let request = match msg.to_owned_with(self.handler.option_registry()) {
+            Ok(request) => request,
+            Err(_) => return
+        };
+
+        // Later blocks of a split response are served from the cache.
+        if let Some(resp) = self.block2.serve(addr, &request) {
+            resp.encode_into_vec(&mut self.send_buf).unwrap_or(0);
+            return;
+        }
+
+        match self.block1.handle(addr, request) {
+            Assembly::Reply(resp) => {
+                resp.encode_into_vec(&mut self.send_buf).unwrap_or(0);
+            },
+            Assembly::Complete(request, block1) => {
+                // The handler is only called with a borrowed message, so the
+                // reassembled request is encoded again for it.
+                self.request_buf.clear();
+                if request.encode_into_vec(&mut self.request_buf).is_err() {
+                    return;
+                }
+                if let Ok(msg) = MessageRef::from_bytes(&self.request_buf) {
+                    call_handler(&self.handler, identity, addr, &msg, &mut self.send_buf, responder);
+                }
+                if block1.is_some() || self.needs_split(request.block2()) {
+                    self.split(addr, &request, block1);
+                }
+            }
         }
     }
+
+    // Whether the handler's reply has to be cut down to the block asked for
+    // by `requested`, or to a single block because it's too big for one. The
+    // smaller of the client's and our block size is used.
+    fn needs_split(&self, requested: Option<option::Block>) -> bool {
+        let resp = match MessageRef::from_bytes(&self.send_buf) {
+            Ok(resp) => resp,
+            Err(_) => return false
+        };
+        if resp.options().any(|o| o.is_ok_and(|o| o.number == 23)) {
+            return false;
+        }
+
+        let szx = requested.map_or(self.config.block_szx, |b| cmp::min(b.szx, self.config.block_szx));
+        let max_block_size = 1 << (szx + 4);
+        match resp.payload() {
+            Ok(payload) => payload.len() > max_block_size || requested.is_some_and(|b| b.num > 0),
+            Err(_) => false
+        }
+    }
+
+    // Rewrites the reply in the send buffer as its first block, echoing the
+    // Block1 option of the request it completes if there is one.
+    fn split(&mut self, addr: &SocketAddr, request: &Message, block1: Option<option::Block>) {
+        let mut resp = match Message::from_bytes_with(&self.send_buf, self.handler.option_registry()) {
+            Ok(resp) => resp,
+            Err(_) => return
+        };
+
+        if let Some(block1) = block1 {
+            resp.options.push(option::Option::Block1(block1));
+        }
+
+        let resp = self.block2.split(addr, request, resp, self.config.block_szx);
+        self.send_buf.clear();
+        resp.encode_into_vec(&mut self.send_buf).unwrap_or(0);
+    }
+
+    // Unprotects an OSCORE request, returning it encoded again along with
+    // what its response has to be protected with. `None` if it isn't one.
+    fn unprotect_request(&mut self, msg: &MessageRef) -> Option<Result<(Vec<u8>, Protected), oscore::Error>> {
+        let oscore = self.oscore.as_mut()?;
+        let is_request = msg.code().class() == 0 && msg.code() != Code::Empty;
+        if !is_request || !msg.options().any(|o| o.is_ok_and(|o| o.number == 9)) {
+            return None;
+        }
+
+        let request = match msg.to_owned_with(self.handler.option_registry()) {
+            Ok(request) => request,
+            Err(e) => return Some(Err(e.into()))
+        };
+        Some(oscore.unprotect_request(&request).and_then(|(request, protected)| Ok((request.to_bytes()?, protected))))
+    }
+
+    // Replaces the reply to an OSCORE request in the send buffer with its
+    // protected form. Empty ACKs and RSTs are left alone.
+    fn protect_reply(&mut self, protected: &Protected) {
+        let oscore = match self.oscore {
+            Some(ref oscore) => oscore,
+            None => return
+        };
+        let reply = match Message::from_bytes_with(&self.send_buf, self.handler.option_registry()) {
+            Ok(reply) => reply,
+            Err(_) => return
+        };
+        if reply.code.class() < 2 {
+            return;
+        }
+
+        self.send_buf.clear();
+        if let Ok(reply) = oscore.protect_response(protected, &reply) {
+            reply.encode_into_vec(&mut self.send_buf).unwrap_or(0);
+        }
+    }
+
+    // Unprotects a response to a request we protected, `None` if it doesn't
+    // verify.
+    fn unprotect_response(&mut self, addr: &SocketAddr, resp: Message) -> Option<Message> {
+        match self.oscore {
+            Some(ref mut oscore) => oscore.unprotect_response(addr, resp),
+            None => Some(resp)
+        }
+    }
+
+    // Unprotects a notification for an observation we registered with
+    // OSCORE, `None` if it doesn't verify or is older than the last one.
+    fn unprotect_notification(&mut self, addr: &SocketAddr, resp: Message) -> Option<Message> {
+        match self.oscore {
+            Some(ref mut oscore) => oscore.unprotect_notification(addr, resp),
+            None => Some(resp)
+        }
+    }
+
+    // Delivers a separate response or notification to one of our requests,
+    // acknowledging it if it's a CON. Notifications for a forgotten
+    // observation are rejected with a RST.
+    fn handle_response(&mut self, addr: &SocketAddr, msg: &MessageRef) {
+        let resp = match msg.to_owned_with(self.handler.option_registry()) {
+            Ok(resp) => resp,
+            Err(_) => return
+        };
+
+        let (mtype, mid) = (resp.mtype, resp.mid);
+        let reply = if self.subscriptions.contains(addr, &resp.token) {
+            if let Some(request_mid) = self.subscriptions.mid(&(*addr, resp.token.clone())) {
+                // The ACK to the registration may have been lost.
+                self.transactions.cancel(addr, request_mid);
+            }
+            match self.unprotect_notification(addr, resp).map(|resp| self.subscriptions.notification(addr, resp)) {
+                Some(Notified::Forgotten) => Mtype::Reset,
+                _ => Mtype::Acknowledgement,
+            }
+        } else {
+            if let Some(resp) = self.unprotect_response(addr, resp) {
+                if let Ok(request_mid) = self.exchanges.complete(addr, resp) {
+                    // The ACK to the request may have been lost.
+                    self.transactions.cancel(addr, request_mid);
+                }
+            }
+            Mtype::Acknowledgement
+        };
+
+        if mtype == Mtype::Confirmable || reply == Mtype::Reset {
+            let reply = MessageBuilder::new(reply, Code::Empty).mid(mid).build();
+            self.send_buf.clear();
+            if reply.encode_into_vec(&mut self.send_buf).is_ok() {
+                self.dedup.record(addr, mid, &self.send_buf);
+                self.send(addr);
+            }
+        }
+    }
+
+    // Starts observing a resource, registering with a GET with Observe 0.
+    fn observe_resource(&mut self, event_loop: &mut EventLoop<Self>, addr: SocketAddr, mut msg: Message, mut callback: NotificationCallback) {
+        while msg.token.is_empty() || self.subscriptions.contains(&addr, &msg.token) {
+            msg.token = random::token(8);
+        }
+        msg.options.retain(|o| o.number() != 6);
+        msg.options.push(option::Option::Observe(0));
+
+        if let Err(e) = msg.encoded_len() {
+            callback(Err(e.into()));
+            return;
+        }
+
+        let key = (addr, msg.token.clone());
+        match self.register(event_loop, key.clone(), msg.clone()) {
+            Ok(Some(mid)) => self.subscriptions.start(key, msg, mid, self.registration_deadline(), callback),
+            Ok(None) => (),
+            Err(e) => callback(Err(e.into())),
+        }
+    }
+
+    // Sends an observation's registering request, protected if the peer
+    // has an OSCORE context, returning its MID.
+    fn register(&mut self, event_loop: &mut EventLoop<Self>, key: ObserverKey, msg: Message) -> Result<Option<u16>, oscore::Error> {
+        let handle = self.handle.clone();
+        let addr = key.0;
+        let callback: Callback = Box::new(move |delivery| {
+            let error = match delivery {
+                Delivery::Acknowledged(_) => return,
+                Delivery::Reset => exchange::Error::Reset,
+                Delivery::TimedOut => exchange::Error::TimedOut,
+            };
+            handle.command(Command::ObserveFailed(key, error)).ok();
+        });
+
+        let msg = match self.oscore {
+            Some(ref mut oscore) => oscore.protect_registration(&addr, msg)?,
+            None => msg
+        };
+        Ok(self.send_msg(event_loop, addr, msg, Some(callback)))
+    }
+
+    // When to register again if no response to a registration turns up.
+    fn registration_deadline(&self) -> Instant {
+        Instant::now() + self.config.transmission.max_transmit_wait() + self.config.response_timeout
+    }
+
+    fn unobserve(&mut self, event_loop: &mut EventLoop<Self>, key: ObserverKey, deregister: bool) {
+        if !deregister {
+            self.subscriptions.forget(&key);
+            return;
+        }
+
+        let mut msg = match self.subscriptions.request(&key) {
+            Some(request) => request.clone(),
+            None => return
+        };
+        self.subscriptions.remove(&key);
+
+        msg.options.retain(|o| o.number() != 6);
+        msg.options.push(option::Option::Observe(1));
+        self.request(event_loop, key.0, msg, Box::new(|_| ()));
+    }
+
+    // Sends a request on behalf of the application, or queues it if NSTART
+    // requests to the peer are already outstanding.
+    fn request(&mut self, event_loop: &mut EventLoop<Self>, addr: SocketAddr, msg: Message, callback: ResponseCallback) {
+        if self.exchanges.can_start(&addr) {
+            self.start_request(event_loop, addr, msg, callback);
+        } else {
+            self.exchanges.enqueue(addr, msg, callback);
+        }
+    }
+
+    // Sends queued requests that no longer have to wait.
+    fn start_queued(&mut self, event_loop: &mut EventLoop<Self>) {
+        while let Some((addr, msg, callback)) = self.exchanges.dequeue() {
+            self.start_request(event_loop, addr, msg, callback);
+        }
+    }
+
+    // Sends a request and waits for the response. It gets a random token
+    // unless it already has one.
+    fn start_request(&mut self, event_loop: &mut EventLoop<Self>, addr: SocketAddr, mut msg: Message, callback: ResponseCallback) {
+        while msg.token.is_empty() || self.exchanges.contains(&addr, &msg.token) {
+            msg.token = random::token(4);
+        }
+
+        if let Err(e) = msg.encoded_len() {
+            callback(Err(e.into()));
+            return;
+        }
+
+        let msg = match self.oscore {
+            Some(ref mut oscore) => match oscore.protect_request(&addr, msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    callback(Err(e.into()));
+                    return;
+                }
+            },
+            None => msg
+        };
+
+        let token = msg.token.clone();
+        let con = msg.mtype == Mtype::Confirmable;
+        if let Some(mid) = self.send_msg(event_loop, addr, msg, None) {
+            self.exchanges.start(addr, token, mid, con, callback);
+        }
+    }
+
+    // Registers or deregisters an observer for a GET with an Observe option,
+    // adding the sequence number to a successful response. The response
+    // to an OSCORE request is protected afterwards, and the notifications
+    // with `protected`.
+    fn observe(&mut self, addr: &SocketAddr, msg: &MessageRef, protected: Option<&Protected>) {
+        if msg.code() != Code::Get && msg.code() != Code::Fetch {
+            return;
+        }
+
+        let observe = msg.options().filter_map(|o| o.ok()).find(|o| o.number == 6).and_then(|o| o.as_uint());
+        let key = (*addr, msg.token().to_vec());
+
+        match observe {
+            Some(0) => (),
+            Some(1) => {
+                self.observers.deregister(&key);
+                return;
+            },
+            _ => return
+        }
+
+        let registry = self.handler.option_registry();
+        let (request, mut resp) = match (msg.to_owned_with(registry), Message::from_bytes_with(&self.send_buf, registry)) {
+            (Ok(request), Ok(resp)) => (request, resp),
+            _ => return
+        };
+
+        if resp.code.class() != 2 {
+            self.observers.deregister(&key);
+            return;
+        }
+
+        // Without room the response goes out without an Observe option,
+        // which tells the client it isn't being notified.
+        if !self.observers.has_room(&key) {
+            return;
+        }
+
+        let path = request.options.iter().filter_map(|o| match *o {
+            option::Option::UriPath(ref s) => Some(s.clone()),
+            _ => None
+        }).collect();
+        let pkt = match request.to_bytes() {
+            Ok(pkt) => pkt,
+            Err(_) => return
+        };
+
+        resp.options.retain(|o| o.number() != 6);
+        resp.options.push(option::Option::Observe(self.observers.next_seq()));
+        self.send_buf.clear();
+        if resp.encode_into_vec(&mut self.send_buf).is_ok() {
+            self.observers.register(key.clone(), path, pkt);
+            if let Some(protected) = protected {
+                self.observers.protect(&key, protected.clone());
+            }
+            self.observers.sent(&key, resp.mid, false, max_age(&resp));
+        }
+    }
+
+    // Asks the handler for the current state of an observed resource and
+    // sends it to the observer. A CON is sent now and then to make sure it's
+    // still listening, and an error ends the observation.
+    fn send_notification(&mut self, event_loop: &mut EventLoop<Self>, key: ObserverKey) {
+        let (addr, request, protected) = match self.observers.get(&key) {
+            Some(observer) => (key.0, observer.request.clone(), observer.protected.clone()),
+            None => return
+        };
+        let identity = match protected {
+            Some(ref protected) => Some(protected.identity()),
+            None => self.dtls.as_ref().and_then(|dtls| dtls.identity(&addr)),
+        };
+
+        // The request is handled again the way it came in, including who it
+        // came from, but there's no one to answer a kept `Responder`.
+        self.send_buf.clear();
+        if let Ok(msg) = MessageRef::from_bytes(&request) {
+            let responder = Responder::new(self.handle.clone(), self.next_responder, addr, &key.1);
+            self.next_responder += 1;
+            call_handler(&self.handler, identity.as_ref(), &addr, &msg, &mut self.send_buf, responder);
+        }
+        let mut resp = match Message::from_bytes_with(&self.send_buf, self.handler.option_registry()) {
+            Ok(resp) => resp,
+            Err(_) => return
+        };
+
+        resp.token = key.1.clone();
+        resp.options.retain(|o| o.number() != 6);
+
+        if resp.code.class() != 2 {
+            self.observers.deregister(&key);
+            resp.mtype = Mtype::Confirmable;
+            if let Some(resp) = self.protect_notification(protected.as_ref(), resp) {
+                self.send_msg(event_loop, addr, resp, None);
+            }
+            return;
+        }
+
+        resp.options.push(option::Option::Observe(self.observers.next_seq()));
+        let con = self.observers.con_due(&key);
+        let max_age = max_age(&resp);
+
+        let callback: Option<Callback> = if con {
+            resp.mtype = Mtype::Confirmable;
+            let handle = self.handle.clone();
+            let key = key.clone();
+            Some(Box::new(move |delivery| if let Delivery::Acknowledged(_) = delivery {} else {
+                handle.command(Command::Forget(key)).ok();
+            }))
+        } else {
+            resp.mtype = Mtype::NonConfirmable;
+            None
+        };
+
+        let resp = match self.protect_notification(protected.as_ref(), resp) {
+            Some(resp) => resp,
+            None => {
+                // Without a sequence number it can't be notified securely.
+                self.observers.deregister(&key);
+                return;
+            }
+        };
+        if let Some(mid) = self.send_msg(event_loop, addr, resp, callback) {
+            self.observers.sent(&key, mid, con, max_age);
+        }
+    }
+
+    // Protects a notification to an observer that registered with OSCORE,
+    // `None` if that fails.
+    fn protect_notification(&mut self, protected: Option<&Protected>, resp: Message) -> Option<Message> {
+        match (protected, self.oscore.as_mut()) {
+            (Some(protected), Some(oscore)) => oscore.protect_notification(protected, &resp).ok(),
+            _ => Some(resp)
+        }
+    }
+
+    // Remembers a request the handler left unanswered while it still has the
+    // `Responder`. CONs get an empty ACK if nothing turns up in time.
+    fn await_response(&mut self, event_loop: &mut EventLoop<Self>, id: u64, addr: &SocketAddr, msg: &MessageRef, protected: Option<Protected>, liveness: Liveness) {
+        let is_request = msg.code().class() == 0 && msg.code() != Code::Empty;
+        let mtype = msg.mtype();
+        if !is_request || (mtype != Mtype::Confirmable && mtype != Mtype::NonConfirmable) || !liveness.register() {
+            return;
+        }
+
+        if self.separate.len() >= self.config.max_separate_responses {
+            let mtype = if mtype == Mtype::Confirmable { Mtype::Acknowledgement } else { Mtype::NonConfirmable };
+            let resp = MessageBuilder::new(mtype, Code::ServiceUnavailable).mid(msg.mid()).token(msg.token()).build();
+            if resp.encode_into_vec(&mut self.send_buf).is_ok() {
+                if msg.mtype() == Mtype::Confirmable {
+                    self.dedup.record(addr, msg.mid(), &self.send_buf);
+                }
+                self.send(addr);
+            }
+            return;
+        }
+
+        self.separate.insert(id, Separate{
+            addr: *addr,
+            mtype,
+            mid: msg.mid(),
+            token: msg.token().to_vec(),
+            acked: false,
+            expires: Instant::now() + self.config.transmission.exchange_lifetime(),
+            protected,
+        });
+
+        if mtype == Mtype::Confirmable && event_loop.timeout_ms(Timer::AckDelay(id), as_ms(self.config.ack_delay)).is_err() {
+            self.send_empty_ack(id);
+        }
+    }
+
+    fn send_empty_ack(&mut self, id: u64) {
+        let (addr, mid) = match self.separate.get_mut(&id) {
+            Some(s) if !s.acked => {
+                s.acked = true;
+                (s.addr, s.mid)
+            },
+            _ => return
+        };
+
+        let ack = MessageBuilder::new(Mtype::Acknowledgement, Code::Empty).mid(mid).build();
+        self.send_buf.clear();
+        if ack.encode_into_vec(&mut self.send_buf).is_ok() {
+            self.dedup.record(&addr, mid, &self.send_buf);
+            self.send(&addr);
+        }
+    }
+
+    // Sends a response handed to a `Responder`, piggybacked on the ACK if
+    // the request hasn't been acknowledged yet and separately otherwise.
+    fn respond(&mut self, event_loop: &mut EventLoop<Self>, id: u64, mut msg: Message) {
+        let separate = match self.separate.remove(&id) {
+            Some(separate) => separate,
+            None => return
+        };
+
+        msg.token = separate.token;
+        if let (Some(protected), Some(oscore)) = (separate.protected.as_ref(), self.oscore.as_ref()) {
+            msg = match oscore.protect_response(protected, &msg) {
+                Ok(msg) => msg,
+                Err(_) => return
+            };
+        }
+
+        if separate.mtype == Mtype::Confirmable && !separate.acked {
+            msg.mtype = Mtype::Acknowledgement;
+            msg.mid = separate.mid;
+            self.send_buf.clear();
+            if msg.encode_into_vec(&mut self.send_buf).is_ok() {
+                self.dedup.record(&separate.addr, separate.mid, &self.send_buf);
+                self.send(&separate.addr);
+            }
+        } else {
+            msg.mtype = separate.mtype;
+            self.send_msg(event_loop, separate.addr, msg, None);
+        }
+    }
+
+    // Sends the reply in the send buffer. A NON reply gets a fresh MID of
+    // ours whatever it was built with, since MIDs belong to their sender
+    // (RFC 7252 §4.4).
+    fn send(&mut self, addr: &SocketAddr) {
+        if self.send_buf.len() >= 4 && (self.send_buf[0] >> 4) & 0x03 == 1 {
+            let mid = self.transactions.next_mid();
+            self.send_buf[2..4].copy_from_slice(&mid.to_be_bytes());
+        }
+        if !self.send_buf.is_empty() {
+            transmit(&mut self.sock, &mut self.dtls, &self.send_buf, addr);
+        }
+    }
+
+    // Sends a message on behalf of the application, returning the MID it was
+    // sent with. CON and NON messages get a fresh MID, and CONs are
+    // retransmitted until they're acknowledged.
+    fn send_msg(&mut self, event_loop: &mut EventLoop<Self>, addr: SocketAddr, mut msg: Message, callback: Option<Callback>) -> Option<u16> {
+        if msg.mtype == Mtype::Confirmable || msg.mtype == Mtype::NonConfirmable {
+            msg.mid = self.transactions.next_mid();
+        }
+
+        let pkt = match msg.to_bytes() {
+            Ok(pkt) => pkt,
+            Err(_) => return None
+        };
+
+        transmit(&mut self.sock, &mut self.dtls, &pkt, &addr);
+
+        if msg.mtype == Mtype::Confirmable {
+            let callback = callback.unwrap_or_else(|| Box::new(|_| ()));
+            let (id, wait) = self.transactions.start(addr, msg.mid, pkt, callback);
+            self.schedule_retransmit(event_loop, id, wait);
+        }
+
+        Some(msg.mid)
+    }
+
+    pub fn schedule_sweep(&mut self, event_loop: &mut EventLoop<Self>) {
+        // If this fails the caches still expire entries as they're used.
+        event_loop.timeout_ms(Timer::Sweep, as_ms(self.config.sweep_interval)).ok();
+    }
+
+    fn schedule_retransmit(&mut self, event_loop: &mut EventLoop<Self>, id: u64, wait: Duration) {
+        if event_loop.timeout_ms(Timer::Retransmit(id), as_ms(wait)).is_err() {
+            // Without a timer it would never be retransmitted or time out.
+            self.transactions.abandon(id);
+        }
+    }
+}
+
+// Max-Age of a response in seconds, 60 if it doesn't say.
+fn max_age(msg: &Message) -> u32 {
+    msg.options.iter().filter_map(|o| match *o {
+        option::Option::MaxAge(secs) => Some(secs),
+        _ => None
+    }).next().unwrap_or(60)
+}
+
+// Sends a datagram, through the peer's DTLS session if the socket is secured.
+fn transmit(sock: &mut Socket, dtls: &mut Option<Sessions>, pkt: &[u8], addr: &SocketAddr) {
+    match *dtls {
+        Some(ref mut dtls) => dtls.send(sock, addr, pkt),
+        None => sock.send_to(pkt, addr),
+    }
+}
+
+// Requests from an authenticated peer are handed over with its identity.
+fn call_handler<H: MsgHandler>(handler: &H, identity: Option<&Identity>, addr: &SocketAddr, msg: &MessageRef, buf: &mut Vec<u8>, responder: Responder) {
+    match identity {
+        Some(identity) => handler.handle_secure_request(addr, identity, msg, buf, responder),
+        None => handler.handle_request(addr, msg, buf, responder),
+    }
+}
+
+// mio takes timeouts in milliseconds.
+pub(crate) fn as_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
 }
 
 impl<H: MsgHandler> Handler for SocketHandler<H> {
-    type Timeout = ();
-    type Message = ();
+    type Timeout = Timer;
+    type Message = Command;
 
-    fn ready(&mut self, _event_loop: &mut EventLoop<SocketHandler<H>>, token: Token, _: EventSet) {
+    fn ready(&mut self, event_loop: &mut EventLoop<SocketHandler<H>>, token: Token, events: EventSet) {
         match token {
             SERVER => {
-                let mut buf: [u8; 2048] = [0; 2048];
-                let (len, addr) = self.sock.recv_from(&mut buf).unwrap().unwrap();
-
-                let pkt = &buf[..len];
-
-                match Message::from_bytes(pkt) {
-                    Ok(msg) => {
-                        match (self.handler).handle_msg(&addr, &msg) {
-                            Some(resp) => {
-                                self.sock.send_to(&resp, &addr).unwrap_or(None); // UDP is best-effort, right?
-                            },
-                            None => ()
-                        }
-                    },
-                    Err(_) => ()
+                if events.is_writable() {
+                    self.sock.flush();
+                }
+                if events.is_readable() || events.is_error() {
+                    self.receive(event_loop);
                 }
+                self.start_queued(event_loop);
             }
-            _ => panic!("unexpected token"),
+            _ => match self.streams {
+                Some(ref mut streams) => streams.ready(event_loop, token, events, &self.handler, &self.handle),
+                None => panic!("unexpected token"),
+            },
         }
+        self.sock.update_interest(event_loop);
     }
 
-    fn notify(&mut self, _event_loop: &mut EventLoop<Self>, _msg: Self::Message) {
-        println!("notify");
+    fn notify(&mut self, event_loop: &mut EventLoop<Self>, cmd: Command) {
+        match cmd {
+            // Peers connected over WebSockets are sent to on their
+            // connection, everyone else over UDP.
+            Command::Send(addr, msg, callback) => match self.streams {
+                Some(ref mut streams) if streams.is_connected(&addr) => streams.deliver(event_loop, addr, &msg, callback),
+                _ => {
+                    self.send_msg(event_loop, addr, msg, callback);
+                }
+            },
+            Command::Respond(id, msg) => match self.streams {
+                Some(ref mut streams) if streams.is_responding(id) => streams.respond(event_loop, id, msg),
+                _ => self.respond(event_loop, id, msg),
+            },
+            Command::Release(id) => match self.streams {
+                Some(ref mut streams) if streams.is_responding(id) => streams.release(id),
+                _ => {
+                    self.separate.remove(&id);
+                }
+            },
+            Command::Request(addr, msg, callback) => match self.streams {
+                Some(ref mut streams) if streams.is_connected(&addr) => streams.request(event_loop, addr, msg, callback),
+                _ => self.request(event_loop, addr, msg, callback),
+            },
+            Command::Notify(path) => for key in self.observers.matching(&path) {
+                self.send_notification(event_loop, key);
+            },
+            Command::Forget(key) => {
+                self.observers.deregister(&key);
+            },
+            Command::Observe(addr, msg, callback) => self.observe_resource(event_loop, addr, msg, callback),
+            Command::Unobserve(key, deregister) => self.unobserve(event_loop, key, deregister),
+            Command::ObserveFailed(key, error) => self.subscriptions.fail(&key, error),
+            Command::Shutdown => event_loop.shutdown(),
+        }
+        self.sock.update_interest(event_loop);
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, timer: Timer) {
+        match timer {
+            Timer::Retransmit(id) => {
+                let next = match self.transactions.timeout(id) {
+                    Some((addr, pkt, wait)) => {
+                        transmit(&mut self.sock, &mut self.dtls, pkt, &addr);
+                        Some(wait)
+                    },
+                    None => None
+                };
+                if let Some(wait) = next {
+                    self.schedule_retransmit(event_loop, id, wait);
+                }
+            },
+            Timer::AckDelay(id) => self.send_empty_ack(id),
+            Timer::Sweep => {
+                let now = Instant::now();
+                self.separate.retain(|_, s| s.expires > now);
+                self.exchanges.expire();
+                self.start_queued(event_loop);
+                self.dedup.expire();
+                self.block1.expire();
+                self.block2.expire();
+                if let Some(ref mut streams) = self.streams {
+                    streams.sweep();
+                }
+                if let Some(ref mut dtls) = self.dtls {
+                    dtls.sweep(&mut self.sock);
+                }
+                if let Some(ref mut oscore) = self.oscore {
+                    let subscriptions = &self.subscriptions;
+                    oscore.sweep(self.config.transmission.exchange_lifetime(), |addr, token| subscriptions.contains(addr, token));
+                }
+                for key in self.observers.due() {
+                    self.send_notification(event_loop, key);
+                }
+                for (key, request) in self.subscriptions.due() {
+                    match self.register(event_loop, key.clone(), request) {
+                        Ok(Some(mid)) => {
+                            let deadline = self.registration_deadline();
+                            self.subscriptions.registered(&key, mid, deadline);
+                        },
+                        Ok(None) => (),
+                        Err(e) => self.subscriptions.fail(&key, e.into()),
+                    }
+                }
+                self.schedule_sweep(event_loop);
+            }
+        }
+        self.sock.update_interest(event_loop);
     }
 
     fn interrupted(&mut self, _event_loop: &mut EventLoop<Self>) {
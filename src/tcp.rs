@@ -0,0 +1,1132 @@
+//! CoAP over TCP (RFC 8323).
+//!
+//! Messages are framed with their length instead of being sent as
+//! datagrams, and have no type or MID since the transport is reliable. Each
+//! side starts a connection with a CSM (Capabilities and Settings Message)
+//! and may Ping, Release or Abort it with the other 7.xx signaling codes.
+//!
+//! The framing is the same over TLS (`coaps+tcp`), which `TcpEndpoint`
+//! speaks when given an OpenSSL acceptor for the connections it accepts, a
+//! connector for those it opens, or both. The same connection handling also
+//! serves CoAP over WebSockets, see `websocket`.
+
+use constants::*;
+use message::{Code, Error, Message, MessageBuilder, MessageRef, Mtype};
+use message::option::Option as CoapOption;
+use endpoint::{Config, Handle, MsgHandler, Responder};
+use exchange::{self, ResponseCallback};
+use random;
+use socket_handler::{as_ms, Command};
+use transaction::{Callback, Delivery};
+use websocket::WebSocket;
+
+use mio::*;
+use mio::tcp::{TcpListener, TcpStream};
+use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslStream};
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Max-Message-Size a peer is assumed to accept until its CSM says
+/// otherwise.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1152;
+
+// Signaling option numbers, which only mean something with their code.
+const MAX_MESSAGE_SIZE: u16 = 2;
+const BLOCK_WISE_TRANSFER: u16 = 4;
+const BAD_CSM_OPTION: u16 = 2;
+
+/// Appends `msg` to `buf` as a frame, returning the frame's length. The type
+/// and MID are left out.
+pub fn encode(msg: &Message, buf: &mut Vec<u8>) -> Result<usize, Error> {
+    let pkt = msg.to_bytes()?;
+    Ok(datagram_to_frame(&pkt, buf))
+}
+
+/// Length of the frame at the start of `buf`, or `None` if not enough of it
+/// has arrived to tell.
+pub fn frame_len(buf: &[u8]) -> Result<Option<usize>, Error> {
+    let first = match buf.first() {
+        Some(&first) => first,
+        None => return Ok(None),
+    };
+
+    let tkl = (first & 0x0F) as usize;
+    if tkl > 8 {
+        return Err(Error::InvalidTokenLength(0, tkl as u8));
+    }
+
+    let (ext, offset) = match first >> 4 {
+        13 => (1, 13),
+        14 => (2, 269),
+        15 => (4, 65805),
+        len => (0, len as usize),
+    };
+    if buf.len() < 1 + ext {
+        return Ok(None);
+    }
+
+    let len = buf[1..1 + ext].iter().fold(0, |n, &b| (n << 8) | b as usize) + offset;
+    Ok(Some(1 + ext + 1 + tkl + len))
+}
+
+/// Decodes the frame at the start of `buf`, returning the message and the
+/// length of the frame, or `None` if it hasn't all arrived yet. Messages come
+/// out as NONs with MID 0.
+pub fn decode(buf: &[u8]) -> Result<Option<(Message, usize)>, Error> {
+    let len = match frame_len(buf)? {
+        Some(len) if len <= buf.len() => len,
+        _ => return Ok(None),
+    };
+
+    let mut pkt = vec![];
+    frame_to_datagram(&buf[..len], &mut pkt)?;
+    Ok(Some((Message::from_bytes(&pkt)?, len)))
+}
+
+// Rewrites an encoded datagram as a frame.
+fn datagram_to_frame(pkt: &[u8], buf: &mut Vec<u8>) -> usize {
+    let tkl = pkt[0] & 0x0F;
+    let rest = &pkt[4 + tkl as usize..];
+    let len = rest.len();
+    let start = buf.len();
+
+    if len < 13 {
+        buf.push((len as u8) << 4 | tkl);
+    } else if len < 269 {
+        buf.push(13 << 4 | tkl);
+        buf.push((len - 13) as u8);
+    } else if len < 65805 {
+        buf.push(14 << 4 | tkl);
+        buf.extend_from_slice(&((len - 269) as u16).to_be_bytes());
+    } else {
+        buf.push(15 << 4 | tkl);
+        buf.extend_from_slice(&((len - 65805) as u32).to_be_bytes());
+    }
+
+    buf.push(pkt[1]);
+    buf.extend_from_slice(&pkt[4..4 + tkl as usize]);
+    buf.extend_from_slice(rest);
+
+    buf.len() - start
+}
+
+// Rewrites a complete frame as a NON datagram with MID 0, so it can be
+// parsed and handled like one.
+fn frame_to_datagram(frame: &[u8], pkt: &mut Vec<u8>) -> Result<(), Error> {
+    let tkl = (frame[0] & 0x0F) as usize;
+    let ext = match frame[0] >> 4 {
+        13 => 1,
+        14 => 2,
+        15 => 4,
+        _ => 0,
+    };
+
+    let code = 1 + ext;
+    if frame.len() < code + 1 + tkl {
+        return Err(Error::TruncatedHeader(frame.len()));
+    }
+
+    pkt.push(0x40 | Mtype::NonConfirmable.as_u8() << 4 | tkl as u8);
+    pkt.push(frame[code]);
+    pkt.extend_from_slice(&[0, 0]);
+    pkt.extend_from_slice(&frame[code + 1..]);
+    Ok(())
+}
+
+fn uint_bytes(n: u32) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+/// An endpoint accepting CoAP over TCP connections, with the same handler
+/// API as the UDP `Endpoint`.
+///
+/// Its `Handle` sends on an existing connection to the address, or opens
+/// one. There's nothing to acknowledge on a reliable transport, so `send_con`
+/// callbacks hear the message was acknowledged once it's been queued and
+/// requests only wait for their response. Observe isn't supported over TCP
+/// yet, and neither are block-wise transfers since messages can be as large
+/// as the peers agree on.
+pub struct TcpEndpoint {
+    local_addr: SocketAddr,
+    config: Config,
+    acceptor: Option<SslAcceptor>,
+    connector: Option<SslConnector>,
+}
+
+impl TcpEndpoint {
+    pub fn new(local_addr: SocketAddr) -> TcpEndpoint {
+        TcpEndpoint::with_config(local_addr, Config::default())
+    }
+
+    pub fn with_config(local_addr: SocketAddr, config: Config) -> TcpEndpoint {
+        TcpEndpoint{local_addr, config, acceptor: None, connector: None}
+    }
+
+    /// Speaks TLS on the connections it accepts, with the certificate and
+    /// settings of `acceptor`.
+    pub fn tls_acceptor(mut self, acceptor: SslAcceptor) -> TcpEndpoint {
+        self.acceptor = Some(acceptor);
+        self
+    }
+
+    /// Speaks TLS on the connections it opens, checking the peer's
+    /// certificate is for its IP address.
+    pub fn tls_connector(mut self, connector: SslConnector) -> TcpEndpoint {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// Binds the listener and sets up the event loop without starting it, so
+    /// that a `Handle` can be taken first.
+    pub fn bind<H: MsgHandler>(self, handler: H) -> io::Result<BoundTcpEndpoint<H>> {
+        self.config.transmission.validate()?;
+        let mut event_loop = EventLoop::new()?;
+        let mut streams = Streams::bind(&mut event_loop, &self.local_addr, Framing::Tcp, &self.config)?;
+        streams.acceptor = self.acceptor;
+        streams.connector = self.connector;
+        let local_addr = streams.local_addr()?;
+
+        let handle = Handle::new(event_loop.channel());
+        let handler = TcpHandler{
+            streams,
+            handler,
+            handle,
+            sweep_interval: self.config.sweep_interval,
+        };
+        event_loop.timeout_ms(Timer::Sweep, as_ms(handler.sweep_interval)).ok();
+
+        Ok(BoundTcpEndpoint{local_addr, event_loop, handler})
+    }
+}
+
+pub struct BoundTcpEndpoint<H: MsgHandler> {
+    local_addr: SocketAddr,
+    event_loop: EventLoop<TcpHandler<H>>,
+    handler: TcpHandler<H>,
+}
+
+impl<H: MsgHandler> BoundTcpEndpoint<H> {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn handle(&self) -> Handle {
+        Handle::new(self.event_loop.channel())
+    }
+
+    /// Runs the event loop until `Handle::shutdown` is called.
+    pub fn run(mut self) -> io::Result<()> {
+        self.event_loop.run(&mut self.handler)
+    }
+}
+
+/// How messages are carried on a stream.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Framing {
+    Tcp,
+    WebSocket,
+}
+
+/// What came in on a connection.
+pub(crate) enum Incoming {
+    // The WebSocket handshake is done and CoAP can start.
+    Opened,
+    // A message, rewritten as a NON datagram with MID 0.
+    Message(Vec<u8>),
+    // The peer broke the protocol, the connection is aborted with this
+    // diagnostic.
+    Abort(&'static str),
+    // Close once everything queued has been written.
+    Close,
+}
+
+// Room in a connection's read buffer beyond the largest message accepted,
+// for frame headers and the WebSocket handshake.
+const READ_OVERHEAD: usize = 1024;
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match *self {
+            Stream::Plain(ref stream) => stream,
+            Stream::Tls(ref stream) => stream.get_ref(),
+        }
+    }
+}
+
+// Reads and writes on a TLS stream carry on its handshake, and would block
+// while it's waiting on the peer.
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    addr: SocketAddr,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    // Outgoing connections can't be written to until they're writable.
+    connected: bool,
+    // Close once everything queued has been written.
+    closing: bool,
+    peer_max_message_size: usize,
+    ws: Option<WebSocket>,
+}
+
+struct Pending {
+    callback: ResponseCallback,
+    deadline: Instant,
+}
+
+// A request the handler didn't answer straight away.
+struct Separate {
+    connection: Token,
+    token: Vec<u8>,
+    expires: Instant,
+}
+
+// Responder ids handed out for stream requests start here, so they can't be
+// mistaken for those of UDP requests on the same endpoint.
+const FIRST_RESPONDER: u64 = 1 << 63;
+
+/// The connections accepted on a listener, and opened to peers, with the
+/// exchanges on them. Kept apart from the event loop's `Handler` so the UDP
+/// `Endpoint` can serve WebSockets with it too. The listener has the
+/// `STREAMS` token and connections the ones after it.
+pub(crate) struct Streams {
+    listener: TcpListener,
+    framing: Framing,
+    max_message_size: usize,
+    response_timeout: Duration,
+    exchange_lifetime: Duration,
+    max_separate_responses: usize,
+    max_connections: usize,
+    acceptor: Option<SslAcceptor>,
+    connector: Option<SslConnector>,
+    connections: HashMap<Token, Connection>,
+    peers: HashMap<SocketAddr, Token>,
+    next_connection: usize,
+    pending: HashMap<(Token, Vec<u8>), Pending>,
+    separate: HashMap<u64, Separate>,
+    next_responder: u64,
+    send_buf: Vec<u8>,
+}
+
+impl Streams {
+    pub fn bind<T: Handler>(event_loop: &mut EventLoop<T>, addr: &SocketAddr, framing: Framing, config: &Config) -> io::Result<Streams> {
+        let listener = TcpListener::bind(addr)?;
+        event_loop.register(&listener, STREAMS, EventSet::readable(), PollOpt::edge())?;
+
+        Ok(Streams{
+            listener,
+            framing,
+            max_message_size: config.max_message_size,
+            response_timeout: config.response_timeout,
+            exchange_lifetime: config.transmission.exchange_lifetime(),
+            max_separate_responses: config.max_separate_responses,
+            max_connections: config.max_connections,
+            acceptor: None,
+            connector: None,
+            connections: HashMap::new(),
+            peers: HashMap::new(),
+            next_connection: STREAMS.as_usize() + 1,
+            pending: HashMap::new(),
+            separate: HashMap::new(),
+            next_responder: FIRST_RESPONDER,
+            send_buf: Vec::with_capacity(2048),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Whether there's a connection to `addr` that messages can be sent on.
+    pub fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.peers.contains_key(addr)
+    }
+
+    /// Whether `id` is the `Responder` of a request received here.
+    pub fn is_responding(&self, id: u64) -> bool {
+        self.separate.contains_key(&id)
+    }
+
+    pub fn ready<T: Handler, H: MsgHandler>(&mut self, event_loop: &mut EventLoop<T>, token: Token, events: EventSet, handler: &H, handle: &Handle) {
+        if token == STREAMS {
+            self.accept(event_loop);
+            return;
+        }
+
+        if events.is_error() || events.is_hup() {
+            self.read(event_loop, token, handler, handle);
+            self.close(event_loop, token);
+            return;
+        }
+
+        if events.is_writable() {
+            if let Some(conn) = self.connections.get_mut(&token) {
+                conn.connected = true;
+            }
+            self.flush(event_loop, token);
+        }
+
+        if events.is_readable() {
+            self.read(event_loop, token, handler, handle);
+        }
+    }
+
+    fn add_connection<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, stream: TcpStream, addr: SocketAddr, connected: bool) -> io::Result<Token> {
+        stream.set_nodelay(true).ok();
+
+        let ssl = match (connected, self.acceptor.as_ref(), self.connector.as_ref()) {
+            (true, Some(acceptor), _) => {
+                let mut ssl = Ssl::new(acceptor.context())?;
+                ssl.set_accept_state();
+                Some(ssl)
+            },
+            (false, _, Some(connector)) => {
+                let mut ssl = connector.configure()?.into_ssl(&addr.ip().to_string())?;
+                ssl.set_connect_state();
+                Some(ssl)
+            },
+            _ => None
+        };
+        let stream = match ssl {
+            Some(ssl) => Stream::Tls(SslStream::new(ssl, stream)?),
+            None => Stream::Plain(stream),
+        };
+
+        let token = Token(self.next_connection);
+        self.next_connection += 1;
+        let interest = EventSet::readable() | EventSet::writable() | EventSet::hup() | EventSet::error();
+        event_loop.register(stream.tcp(), token, interest, PollOpt::edge())?;
+
+        let ws = match self.framing {
+            Framing::Tcp => None,
+            Framing::WebSocket => Some(WebSocket::default()),
+        };
+        self.connections.insert(token, Connection{
+            stream,
+            addr,
+            read_buf: vec![],
+            write_buf: vec![],
+            connected,
+            closing: false,
+            peer_max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            ws,
+        });
+        self.peers.insert(addr, token);
+
+        // Over WebSockets the CSM waits for the handshake.
+        if self.framing == Framing::Tcp {
+            self.send_csm(token);
+        }
+
+        Ok(token)
+    }
+
+    fn send_csm(&mut self, token: Token) {
+        let csm = MessageBuilder::new(Mtype::NonConfirmable, Code::Csm)
+            .option(CoapOption::Unknown((MAX_MESSAGE_SIZE, uint_bytes(self.max_message_size as u32))))
+            .build();
+        self.queue(token, &csm).ok();
+    }
+
+    // The connection to `addr`, opened if there isn't one. Only plain TCP
+    // connections are opened, WebSocket clients have to connect to us.
+    fn connection<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, addr: SocketAddr) -> io::Result<Token> {
+        if let Some(&token) = self.peers.get(&addr) {
+            return Ok(token);
+        }
+        if self.framing == Framing::WebSocket {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "no WebSocket connection to the peer"));
+        }
+        if self.connections.len() >= self.max_connections {
+            return Err(io::Error::other("too many connections"));
+        }
+
+        let stream = TcpStream::connect(&addr)?;
+        self.add_connection(event_loop, stream, addr, false)
+    }
+
+    fn accept<T: Handler>(&mut self, event_loop: &mut EventLoop<T>) {
+        while let Ok(Some((stream, addr))) = self.listener.accept() {
+            // Past the limit connections are closed as soon as they're
+            // accepted.
+            if self.connections.len() < self.max_connections {
+                self.add_connection(event_loop, stream, addr, true).ok();
+            }
+        }
+    }
+
+    fn read<T: Handler, H: MsgHandler>(&mut self, event_loop: &mut EventLoop<T>, token: Token, handler: &H, handle: &Handle) {
+        let mut incoming = vec![];
+        let mut closed = false;
+
+        if let Some(conn) = self.connections.get_mut(&token) {
+            // Reads stop once a whole message could be in the buffer, and
+            // carry on after what's there has been parsed.
+            let limit = self.max_message_size + READ_OVERHEAD;
+            let mut buf = [0; 4096];
+            loop {
+                let mut full = false;
+                loop {
+                    let room = cmp::min(buf.len(), limit - conn.read_buf.len());
+                    if room == 0 {
+                        full = true;
+                        break;
+                    }
+                    match conn.stream.read(&mut buf[..room]) {
+                        Ok(0) => {
+                            closed = true;
+                            break;
+                        },
+                        Ok(len) => conn.read_buf.extend_from_slice(&buf[..len]),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(_) => {
+                            closed = true;
+                            break;
+                        }
+                    }
+                }
+
+                let buffered = conn.read_buf.len();
+                match conn.ws {
+                    Some(ref mut ws) => incoming.extend(ws.read(&mut conn.read_buf, &mut conn.write_buf, self.max_message_size)),
+                    None => loop {
+                        match frame_len(&conn.read_buf) {
+                            Ok(Some(len)) if len > self.max_message_size => {
+                                incoming.push(Incoming::Abort("message too large"));
+                                break;
+                            },
+                            Ok(Some(len)) if len <= conn.read_buf.len() => {
+                                let mut pkt = vec![];
+                                incoming.push(match frame_to_datagram(&conn.read_buf[..len], &mut pkt) {
+                                    Ok(()) => Incoming::Message(pkt),
+                                    Err(_) => Incoming::Abort("malformed message"),
+                                });
+                                conn.read_buf.drain(..len);
+                            },
+                            Ok(_) => break,
+                            Err(_) => {
+                                incoming.push(Incoming::Abort("malformed message"));
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                if !full || closed || incoming.iter().any(|i| matches!(*i, Incoming::Abort(_) | Incoming::Close)) {
+                    break;
+                }
+                if conn.read_buf.len() == buffered {
+                    incoming.push(Incoming::Abort("message too large"));
+                    break;
+                }
+            }
+        }
+
+        for incoming in incoming {
+            match incoming {
+                Incoming::Opened => self.send_csm(token),
+                Incoming::Message(pkt) => self.handle_datagram(event_loop, token, &pkt, handler, handle),
+                Incoming::Abort(diagnostic) => {
+                    self.abort(event_loop, token, diagnostic, None);
+                    return;
+                },
+                Incoming::Close => {
+                    if let Some(conn) = self.connections.get_mut(&token) {
+                        conn.closing = true;
+                    }
+                    break;
+                }
+            }
+        }
+
+        if closed {
+            self.close(event_loop, token);
+        } else {
+            self.flush(event_loop, token);
+        }
+    }
+
+    fn handle_datagram<T: Handler, H: MsgHandler>(&mut self, event_loop: &mut EventLoop<T>, token: Token, pkt: &[u8], handler: &H, handle: &Handle) {
+        let addr = match self.connections.get(&token) {
+            Some(conn) if !conn.closing => conn.addr,
+            _ => return
+        };
+
+        let msg = match MessageRef::from_bytes(pkt) {
+            Ok(msg) => msg,
+            Err(_) => {
+                self.abort(event_loop, token, "malformed message", None);
+                return;
+            }
+        };
+
+        let code = msg.code();
+        if code.class() == 7 {
+            self.signal(event_loop, token, &msg);
+            return;
+        }
+
+        // Empty messages are only there to keep the connection alive.
+        if code == Code::Empty {
+            return;
+        }
+
+        let key = (token, msg.token().to_vec());
+        if code.class() >= 2 {
+            if let Some(pending) = self.pending.remove(&key) {
+                (pending.callback)(msg.to_owned_with(handler.option_registry()).map_err(exchange::Error::from));
+            }
+            return;
+        }
+
+        let id = self.next_responder;
+        self.next_responder += 1;
+        let responder = Responder::new(handle.clone(), id, addr, msg.token());
+        let liveness = responder.liveness();
+
+        self.send_buf.clear();
+        handler.handle_request(&addr, &msg, &mut self.send_buf, responder);
+
+        if self.send_buf.is_empty() {
+            if !liveness.register() {
+                return;
+            }
+            if self.separate.len() < self.max_separate_responses {
+                self.separate.insert(id, Separate{
+                    connection: token,
+                    token: key.1,
+                    expires: Instant::now() + self.exchange_lifetime,
+                });
+                return;
+            }
+            MessageBuilder::new(Mtype::NonConfirmable, Code::ServiceUnavailable).token(&key.1).build()
+                .encode_into_vec(&mut self.send_buf).ok();
+        }
+
+        let reply = match Message::from_bytes_with(&self.send_buf, handler.option_registry()) {
+            Ok(reply) => reply,
+            Err(_) => return
+        };
+        if self.queue(token, &reply).is_err() {
+            let error = MessageBuilder::new(Mtype::NonConfirmable, Code::InternalServerError).token(&key.1).build();
+            self.queue(token, &error).ok();
+        }
+        self.flush(event_loop, token);
+    }
+
+    fn signal<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, token: Token, msg: &MessageRef) {
+        match msg.code() {
+            Code::Csm => {
+                for option in msg.options().filter_map(|o| o.ok()) {
+                    match option.number {
+                        MAX_MESSAGE_SIZE => if let (Some(size), Some(conn)) = (option.as_uint(), self.connections.get_mut(&token)) {
+                            conn.peer_max_message_size = size as usize;
+                        },
+                        BLOCK_WISE_TRANSFER => (),
+                        // Critical options we don't know end the connection.
+                        n if n & 1 == 1 => {
+                            self.abort(event_loop, token, "unsupported critical CSM option", Some(n));
+                            return;
+                        },
+                        _ => ()
+                    }
+                }
+            },
+            Code::Ping => {
+                let pong = MessageBuilder::new(Mtype::NonConfirmable, Code::Pong).token(msg.token()).build();
+                self.queue(token, &pong).ok();
+                self.flush(event_loop, token);
+            },
+            Code::Release => {
+                if let Some(conn) = self.connections.get_mut(&token) {
+                    conn.closing = true;
+                }
+                self.flush(event_loop, token);
+            },
+            Code::Abort => self.close(event_loop, token),
+            _ => ()
+        }
+    }
+
+    // Adds `msg` to what's to be written to the connection, unless it's
+    // larger than the peer accepts.
+    fn queue(&mut self, token: Token, msg: &Message) -> Result<(), exchange::Error> {
+        let conn = match self.connections.get_mut(&token) {
+            Some(conn) if conn.ws.as_ref().is_none_or(|ws| ws.is_open()) => conn,
+            _ => return Err(io::Error::new(io::ErrorKind::NotConnected, "connection closed").into()),
+        };
+
+        let pkt = msg.to_bytes()?;
+        let mut frame = vec![];
+        let len = match conn.ws {
+            Some(ref ws) => ws.write(&pkt, &mut frame),
+            None => datagram_to_frame(&pkt, &mut frame),
+        };
+        if len > conn.peer_max_message_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "larger than the peer's Max-Message-Size").into());
+        }
+
+        conn.write_buf.extend_from_slice(&frame);
+        Ok(())
+    }
+
+    fn flush<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, token: Token) {
+        let mut failed = false;
+        let done = match self.connections.get_mut(&token) {
+            Some(conn) if conn.connected => {
+                while !conn.write_buf.is_empty() {
+                    match conn.stream.write(&conn.write_buf) {
+                        Ok(0) => {
+                            failed = true;
+                            break;
+                        },
+                        Ok(len) => {
+                            conn.write_buf.drain(..len);
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(_) => {
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+                conn.closing && conn.write_buf.is_empty()
+            },
+            _ => false
+        };
+
+        if failed || done {
+            self.close(event_loop, token);
+        }
+    }
+
+    // Tells the peer why the connection is being closed, and closes it.
+    fn abort<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, token: Token, diagnostic: &str, bad_option: Option<u16>) {
+        let mut abort = MessageBuilder::new(Mtype::NonConfirmable, Code::Abort)
+            .payload(diagnostic.as_bytes().to_vec())
+            .build();
+        if let Some(n) = bad_option {
+            abort.options.push(CoapOption::Unknown((BAD_CSM_OPTION, uint_bytes(n as u32))));
+        }
+
+        if self.queue(token, &abort).is_ok() {
+            if let Some(conn) = self.connections.get_mut(&token) {
+                conn.closing = true;
+            }
+            self.flush(event_loop, token);
+        } else {
+            self.close(event_loop, token);
+        }
+    }
+
+    fn close<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, token: Token) {
+        let conn = match self.connections.remove(&token) {
+            Some(conn) => conn,
+            None => return
+        };
+
+        event_loop.deregister(conn.stream.tcp()).ok();
+        if let Stream::Tls(mut stream) = conn.stream {
+            stream.shutdown().ok();
+        }
+        if self.peers.get(&conn.addr) == Some(&token) {
+            self.peers.remove(&conn.addr);
+        }
+
+        let keys: Vec<_> = self.pending.keys().filter(|k| k.0 == token).cloned().collect();
+        for key in keys {
+            let pending = self.pending.remove(&key).unwrap();
+            (pending.callback)(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed").into()));
+        }
+        self.separate.retain(|_, s| s.connection != token);
+    }
+
+    fn send<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, addr: SocketAddr, msg: &Message) -> Result<Token, exchange::Error> {
+        let token = self.connection(event_loop, addr)?;
+        self.queue(token, msg)?;
+        self.flush(event_loop, token);
+        Ok(token)
+    }
+
+    /// Sends a message for `Handle::send`, which counts as acknowledged once
+    /// it's been queued.
+    pub fn deliver<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, addr: SocketAddr, msg: &Message, callback: Option<Callback>) {
+        let delivery = match self.send(event_loop, addr, msg) {
+            Ok(_) => Delivery::Acknowledged(MessageBuilder::new(Mtype::Acknowledgement, Code::Empty).build()),
+            Err(_) => Delivery::Reset,
+        };
+        if let Some(callback) = callback {
+            callback(delivery);
+        }
+    }
+
+    pub fn request<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, addr: SocketAddr, mut msg: Message, callback: ResponseCallback) {
+        let connection = match self.connection(event_loop, addr) {
+            Ok(connection) => connection,
+            Err(e) => return callback(Err(e.into())),
+        };
+
+        while msg.token.is_empty() || self.pending.contains_key(&(connection, msg.token.clone())) {
+            msg.token = random::token(4);
+        }
+
+        match self.send(event_loop, addr, &msg) {
+            Ok(connection) => {
+                let deadline = Instant::now() + self.response_timeout;
+                self.pending.insert((connection, msg.token), Pending{callback, deadline});
+            },
+            Err(e) => callback(Err(e)),
+        }
+    }
+
+    pub fn respond<T: Handler>(&mut self, event_loop: &mut EventLoop<T>, id: u64, mut msg: Message) {
+        let separate = match self.separate.remove(&id) {
+            Some(separate) => separate,
+            None => return
+        };
+
+        msg.token = separate.token;
+        if self.queue(separate.connection, &msg).is_ok() {
+            self.flush(event_loop, separate.connection);
+        }
+    }
+
+    /// Forgets a request whose `Responder` was dropped without responding.
+    pub fn release(&mut self, id: u64) {
+        self.separate.remove(&id);
+    }
+
+    pub fn sweep(&mut self) {
+        let now = Instant::now();
+        self.separate.retain(|_, s| s.expires > now);
+
+        let expired: Vec<_> = self.pending.iter().filter(|&(_, p)| p.deadline <= now).map(|(k, _)| k.clone()).collect();
+        for key in expired {
+            let pending = self.pending.remove(&key).unwrap();
+            (pending.callback)(Err(exchange::Error::TimedOut));
+        }
+    }
+}
+
+enum Timer {
+    Sweep,
+}
+
+struct TcpHandler<H> {
+    streams: Streams,
+    handler: H,
+    handle: Handle,
+    sweep_interval: Duration,
+}
+
+impl<H: MsgHandler> Handler for TcpHandler<H> {
+    type Timeout = Timer;
+    type Message = Command;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
+        self.streams.ready(event_loop, token, events, &self.handler, &self.handle);
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<Self>, cmd: Command) {
+        match cmd {
+            Command::Send(addr, msg, callback) => self.streams.deliver(event_loop, addr, &msg, callback),
+            Command::Respond(id, msg) => self.streams.respond(event_loop, id, msg),
+            Command::Release(id) => self.streams.release(id),
+            Command::Request(addr, msg, callback) => self.streams.request(event_loop, addr, msg, callback),
+            Command::Observe(_, _, mut callback) => {
+                callback(Err(io::Error::new(io::ErrorKind::Unsupported, "observe isn't supported over TCP").into()));
+            },
+            Command::Notify(_) | Command::Forget(_) | Command::Unobserve(..) | Command::ObserveFailed(..) => (),
+            Command::Shutdown => event_loop.shutdown(),
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, timer: Timer) {
+        match timer {
+            Timer::Sweep => {
+                self.streams.sweep();
+                event_loop.timeout_ms(Timer::Sweep, as_ms(self.sweep_interval)).ok();
+            }
+        }
+    }
+}
+
+
+#[test]
+fn test_tcp_framing() {
+    // A GET with no token, options or payload is two bytes.
+    let get = MessageBuilder::new(Mtype::NonConfirmable, Code::Get).build();
+    let mut buf = vec![];
+    assert_eq!(encode(&get, &mut buf).unwrap(), 2);
+    assert_eq!(buf, [0x00, 0x01]);
+
+    for &len in &[0, 12, 13, 268, 269, 1000, 65804, 65805, 70000] {
+        let msg = MessageBuilder::new(Mtype::NonConfirmable, Code::Content)
+            .token(&[1, 2, 3])
+            .option(CoapOption::ContentFormat(42))
+            .payload(vec![7; len])
+            .build();
+
+        let mut buf = vec![9];
+        let frame_len = encode(&msg, &mut buf).unwrap();
+        let frame = &buf[1..];
+        assert_eq!(frame.len(), frame_len);
+
+        for partial in &[0, 1, frame_len - 1] {
+            assert_eq!(decode(&frame[..*partial]).unwrap(), None);
+        }
+
+        let (decoded, used) = decode(frame).unwrap().unwrap();
+        assert_eq!(used, frame_len);
+        assert_eq!(decoded, msg);
+    }
+
+    assert_eq!(frame_len(&[0x0F]), Err(Error::InvalidTokenLength(0, 15)));
+    assert_eq!(frame_len(&[0xD1]).unwrap(), None);
+    assert_eq!(frame_len(&[0xD1, 0x00]).unwrap(), Some(17));
+}
+
+#[test]
+fn test_tcp_endpoint() {
+    use router::{uri_path, Router};
+    use testing::spawn_tcp;
+    use std::net::TcpStream as StdTcpStream;
+    use std::sync::mpsc;
+
+    let mut router = Router::new();
+    router.get("/hello/*", |_, msg, _| {
+        let mut resp = Message::response_for(msg, Code::Content);
+        resp.payload = uri_path(msg).join("/").into_bytes();
+        resp.to_bytes().ok()
+    }).unwrap();
+
+    let server = spawn_tcp(TcpEndpoint::new("127.0.0.1:0".parse().unwrap()).bind(router).unwrap());
+
+    let mut peer = StdTcpStream::connect(server.addr).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut received = vec![];
+    let mut recv = |peer: &mut StdTcpStream| loop {
+        if let Some((msg, len)) = decode(&received).unwrap() {
+            received.drain(..len);
+            return msg;
+        }
+        let mut buf = [0; 1024];
+        let len = peer.read(&mut buf).unwrap();
+        assert!(len > 0, "connection closed");
+        received.extend_from_slice(&buf[..len]);
+    };
+    let send = |peer: &mut StdTcpStream, msg: &Message| {
+        let mut buf = vec![];
+        encode(msg, &mut buf).unwrap();
+        peer.write_all(&buf).unwrap();
+    };
+
+    let csm = recv(&mut peer);
+    assert_eq!(csm.code, Code::Csm);
+    assert!(csm.options.contains(&CoapOption::Unknown((MAX_MESSAGE_SIZE, uint_bytes(64 * 1024)))));
+    send(&mut peer, &MessageBuilder::new(Mtype::NonConfirmable, Code::Csm).build());
+
+    let get = Message::request(Code::Get, "coap://localhost/hello/tcp").map(|mut m| { m.token = vec![4, 2]; m }).unwrap();
+    send(&mut peer, &get);
+    let resp = recv(&mut peer);
+    assert_eq!((resp.code, resp.token.as_slice(), resp.payload.as_slice()), (Code::Content, &[4, 2][..], &b"hello/tcp"[..]));
+
+    send(&mut peer, &MessageBuilder::new(Mtype::NonConfirmable, Code::Ping).token(&[9]).build());
+    let pong = recv(&mut peer);
+    assert_eq!((pong.code, pong.token.as_slice()), (Code::Pong, &[9][..]));
+
+    // The server can send requests over TCP as well, here to a second
+    // endpoint it connects to.
+    let mut router = Router::new();
+    router.get("/back", |_, msg, _| Message::response_for(msg, Code::Content).to_bytes().ok()).unwrap();
+    let client = spawn_tcp(TcpEndpoint::new("127.0.0.1:0".parse().unwrap()).bind(router).unwrap());
+
+    let (tx, rx) = mpsc::channel();
+    server.handle.request(client.addr, Message::request(Code::Get, "coap://localhost/back").unwrap(), move |r| {
+        tx.send(r.map(|m| m.code)).unwrap();
+    }).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap(), Code::Content);
+
+    // A critical CSM option it doesn't know aborts the connection.
+    send(&mut peer, &MessageBuilder::new(Mtype::NonConfirmable, Code::Csm).option(CoapOption::Unknown((9, vec![]))).build());
+    let abort = recv(&mut peer);
+    assert_eq!(abort.code, Code::Abort);
+    assert!(abort.options.contains(&CoapOption::Unknown((BAD_CSM_OPTION, vec![9]))));
+    assert_eq!(peer.read(&mut [0; 16]).unwrap(), 0);
+
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn test_tcp_endpoint_limits() {
+    use router::Router;
+    use testing::spawn_tcp;
+    use std::net::TcpStream as StdTcpStream;
+
+    let mut router = Router::new();
+    router.get("/echo", |_, msg, _| {
+        let mut resp = Message::response_for(msg, Code::Content);
+        resp.payload = msg.token.clone();
+        resp.to_bytes().ok()
+    }).unwrap();
+
+    let config = Config{max_connections: 1, max_message_size: 64, ..Config::default()};
+    let server = spawn_tcp(TcpEndpoint::with_config("127.0.0.1:0".parse().unwrap(), config).bind(router).unwrap());
+
+    let connect = || {
+        let peer = StdTcpStream::connect(server.addr).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        peer
+    };
+    let recv = |peer: &mut StdTcpStream, received: &mut Vec<u8>| loop {
+        if let Some((msg, len)) = decode(received).unwrap() {
+            received.drain(..len);
+            return msg;
+        }
+        let mut buf = [0; 1024];
+        let len = peer.read(&mut buf).unwrap();
+        assert!(len > 0, "connection closed");
+        received.extend_from_slice(&buf[..len]);
+    };
+
+    let mut peer = connect();
+    let mut received = vec![];
+    assert_eq!(recv(&mut peer, &mut received).code, Code::Csm);
+
+    // A second connection is over the limit.
+    let mut second = connect();
+    assert_eq!(second.read(&mut [0; 16]).unwrap(), 0);
+
+    // Many more messages than fit in the read buffer at once are all
+    // answered.
+    let mut buf = vec![];
+    for i in 0..1000u16 {
+        let get = Message::request(Code::Get, "coap://localhost/echo").map(|mut m| { m.token = i.to_be_bytes().to_vec(); m }).unwrap();
+        encode(&get, &mut buf).unwrap();
+    }
+    assert!(buf.len() > 64 + READ_OVERHEAD);
+    peer.write_all(&buf).unwrap();
+    for i in 0..1000u16 {
+        let resp = recv(&mut peer, &mut received);
+        assert_eq!((resp.code, resp.payload), (Code::Content, i.to_be_bytes().to_vec()));
+    }
+
+    // A message larger than the server accepts aborts the connection.
+    let big = MessageBuilder::new(Mtype::NonConfirmable, Code::Post).payload(vec![0; 65]).build();
+    encode(&big, &mut buf).unwrap();
+    peer.write_all(&buf).unwrap();
+    let abort = loop {
+        let msg = recv(&mut peer, &mut received);
+        if msg.code == Code::Abort {
+            break msg;
+        }
+    };
+    assert_eq!(abort.payload, b"message too large");
+
+    server.stop();
+}
+
+#[test]
+fn test_tls_endpoint() {
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::ssl::{SslMethod, SslVerifyMode};
+    use openssl::x509::X509;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use router::Router;
+    use testing::spawn_tcp;
+    use std::sync::mpsc;
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = SubjectAlternativeName::new().ip("127.0.0.1").build(&cert.x509v3_context(None, None)).unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    let mut router = Router::new();
+    router.get("/secret", |_, msg, _| {
+        let mut resp = Message::response_for(msg, Code::Content);
+        resp.payload = b"over tls".to_vec();
+        resp.to_bytes().ok()
+    }).unwrap();
+    let server = spawn_tcp(TcpEndpoint::new("127.0.0.1:0".parse().unwrap()).tls_acceptor(acceptor.build()).bind(router).unwrap());
+
+    let client = |trusted: bool| {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        if trusted {
+            connector.cert_store_mut().add_cert(cert.clone()).unwrap();
+        }
+        connector.set_verify(SslVerifyMode::PEER);
+        spawn_tcp(TcpEndpoint::new("127.0.0.1:0".parse().unwrap()).tls_connector(connector.build()).bind(Router::new()).unwrap())
+    };
+
+    for &trusted in &[true, false] {
+        let client = client(trusted);
+        let (tx, rx) = mpsc::channel();
+        client.handle.request(server.addr, Message::request(Code::Get, "coap://localhost/secret").unwrap(), move |r| {
+            tx.send(r.map(|m| (m.code, m.payload))).unwrap();
+        }).unwrap();
+
+        let resp = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        if trusted {
+            assert_eq!(resp.unwrap(), (Code::Content, b"over tls".to_vec()));
+        } else {
+            // The server's certificate isn't trusted, so the handshake
+            // fails and the request with it.
+            assert!(resp.is_err());
+        }
+
+        client.stop();
+    }
+
+    server.stop();
+}
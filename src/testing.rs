@@ -0,0 +1,60 @@
+//! What the endpoint tests share: a handler saying who the peer
+//! authenticated as, and running an endpoint on a thread of its own.
+
+use endpoint::{BoundEndpoint, Handle, MsgHandler, Responder};
+use message::{Code, Message, MessageRef};
+use security::Identity;
+use tcp::BoundTcpEndpoint;
+
+use std::io;
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+
+// Answers with who the peer authenticated as, and refuses requests that
+// weren't secured.
+pub(crate) struct Whoami;
+
+impl MsgHandler for Whoami {
+    fn handle_msg(&self, _: &SocketAddr, msg: &Message) -> Option<Vec<u8>> {
+        Message::response_for(msg, Code::Forbidden).to_bytes().ok()
+    }
+
+    fn handle_secure_request(&self, _: &SocketAddr, identity: &Identity, msg: &MessageRef, buf: &mut Vec<u8>, _: Responder) {
+        let msg = msg.to_owned().unwrap();
+        let mut resp = Message::response_for(&msg, Code::Content);
+        resp.payload = match *identity {
+            Identity::Psk(ref identity) | Identity::Oscore(ref identity) => identity.clone(),
+            Identity::RawPublicKey(ref der) | Identity::Certificate(ref der) => der.clone(),
+        };
+        resp.encode_into_vec(buf).unwrap();
+    }
+}
+
+// An endpoint running until `stop` is called.
+pub(crate) struct Running {
+    pub(crate) addr: SocketAddr,
+    pub(crate) handle: Handle,
+    thread: JoinHandle<()>,
+}
+
+impl Running {
+    fn start<F>(addr: SocketAddr, handle: Handle, run: F) -> Running
+        where F: FnOnce() -> io::Result<()> + Send + 'static {
+        Running{addr, handle, thread: thread::spawn(move || run().unwrap())}
+    }
+
+    pub(crate) fn stop(self) {
+        self.handle.shutdown().unwrap();
+        self.thread.join().unwrap();
+    }
+}
+
+pub(crate) fn spawn<H: MsgHandler>(endpoint: BoundEndpoint<H>) -> Running
+    where BoundEndpoint<H>: Send + 'static {
+    Running::start(endpoint.local_addr(), endpoint.handle(), move || endpoint.run())
+}
+
+pub(crate) fn spawn_tcp<H: MsgHandler>(endpoint: BoundTcpEndpoint<H>) -> Running
+    where BoundTcpEndpoint<H>: Send + 'static {
+    Running::start(endpoint.local_addr(), endpoint.handle(), move || endpoint.run())
+}
@@ -0,0 +1,331 @@
+use message::{Message, Mtype};
+use random::Random;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How a confirmable message that was sent turned out.
+#[derive(PartialEq, Debug)]
+pub enum Delivery {
+    /// The peer acknowledged it, the ACK may carry a piggybacked response.
+    Acknowledged(Message),
+    /// The peer rejected it with a RST.
+    Reset,
+    /// No ACK or RST arrived after `max_retransmit` retransmissions.
+    TimedOut,
+}
+
+pub type Callback = Box<dyn FnOnce(Delivery) + Send>;
+
+// The most retransmissions `Params::validate` accepts, already past a month
+// of backing off from the default ACK timeout.
+const MAX_RETRANSMIT: u32 = 20;
+
+/// Message transmission parameters (RFC 7252 §4.8).
+#[derive(Clone, Debug)]
+pub struct Params {
+    pub ack_timeout: Duration,
+    pub ack_random_factor: f64,
+    pub max_retransmit: u32,
+    pub max_latency: Duration,
+    pub processing_delay: Duration,
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params{
+            ack_timeout: Duration::from_secs(2),
+            ack_random_factor: 1.5,
+            max_retransmit: 4,
+            max_latency: Duration::from_secs(100),
+            processing_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Params {
+    /// Checks the parameters can be used, which endpoints do when they're
+    /// bound: `max_retransmit` can be at most 20, `ack_random_factor` has to
+    /// be at least 1, and the times derived from them have to fit in a
+    /// `Duration`.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if self.max_retransmit > MAX_RETRANSMIT {
+            return invalid("max_retransmit is too large");
+        }
+        if !(self.ack_random_factor >= 1.0 && self.ack_random_factor.is_finite()) {
+            return invalid("ack_random_factor must be at least 1");
+        }
+
+        let backoff = ((1u64 << (self.max_retransmit + 1)) - 1) as f64;
+        let lifetime = Duration::try_from_secs_f64(self.ack_timeout.as_secs_f64() * backoff * self.ack_random_factor).ok()
+            .and_then(|wait| wait.checked_add(self.max_latency.checked_mul(2)?))
+            .and_then(|d| d.checked_add(self.processing_delay));
+        match lifetime {
+            Some(_) => Ok(()),
+            None => invalid("transmission times too long"),
+        }
+    }
+
+    /// Time from the first transmission of a CON to its last retransmission.
+    pub fn max_transmit_span(&self) -> Duration {
+        self.ack_timeout.mul_f64(((1u64 << self.max_retransmit) - 1) as f64 * self.ack_random_factor)
+    }
+
+    /// Time from the first transmission of a CON to when the sender gives
+    /// up waiting for an ACK.
+    pub fn max_transmit_wait(&self) -> Duration {
+        self.ack_timeout.mul_f64(((1u64 << (self.max_retransmit + 1)) - 1) as f64 * self.ack_random_factor)
+    }
+
+    /// How long a CON's MID may still be seen, and so must be remembered.
+    pub fn exchange_lifetime(&self) -> Duration {
+        self.max_transmit_span() + self.max_latency * 2 + self.processing_delay
+    }
+
+    /// How long a NON's MID may still be seen.
+    pub fn non_lifetime(&self) -> Duration {
+        self.max_transmit_span() + self.max_latency
+    }
+}
+
+struct Pending {
+    addr: SocketAddr,
+    mid: u16,
+    pkt: Vec<u8>,
+    retransmits: u32,
+    timeout: Duration,
+    callback: Callback,
+}
+
+/// Tracks outgoing confirmable messages until they're acknowledged, reset or
+/// given up on (RFC 7252 §4.2).
+///
+/// This only keeps the state, the caller does the sending and arranges to
+/// call `timeout` with the id after the returned delay.
+pub struct Transactions {
+    params: Params,
+    pending: HashMap<u64, Pending>,
+    by_mid: HashMap<(SocketAddr, u16), u64>,
+    next_id: u64,
+    next_mid: u16,
+    random: Random,
+}
+
+impl Transactions {
+    pub fn new(params: Params) -> Transactions {
+        Transactions::with_random(params, Random::new())
+    }
+
+    pub(crate) fn with_random(params: Params, mut random: Random) -> Transactions {
+        Transactions{
+            params,
+            pending: HashMap::new(),
+            by_mid: HashMap::new(),
+            next_id: 0,
+            next_mid: random.next_u64() as u16,
+            random,
+        }
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn next_mid(&mut self) -> u16 {
+        let mid = self.next_mid;
+        self.next_mid = self.next_mid.wrapping_add(1);
+        mid
+    }
+
+    /// Starts tracking a CON that has just been sent as `pkt` with `mid`,
+    /// returning the id to pass to `timeout` and how long to wait before
+    /// doing so.
+    pub fn start(&mut self, addr: SocketAddr, mid: u16, pkt: Vec<u8>, callback: Callback) -> (u64, Duration) {
+        let factor = 1.0 + (self.params.ack_random_factor - 1.0) * self.random.next_f64();
+        let timeout = self.params.ack_timeout.mul_f64(factor);
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        if let Some(old) = self.by_mid.insert((addr, mid), id) {
+            self.pending.remove(&old);
+        }
+
+        self.pending.insert(id, Pending{
+            addr,
+            mid,
+            pkt,
+            retransmits: 0,
+            timeout,
+            callback,
+        });
+
+        (id, timeout)
+    }
+
+    /// Called when the wait for `id` is over. Returns what to retransmit and
+    /// when to call this again, or `None` if it's already finished or has
+    /// now timed out.
+    pub fn timeout(&mut self, id: u64) -> Option<(SocketAddr, &[u8], Duration)> {
+        let exhausted = match self.pending.get(&id) {
+            Some(pending) => pending.retransmits >= self.params.max_retransmit,
+            None => return None,
+        };
+
+        if exhausted {
+            let pending = self.pending.remove(&id).unwrap();
+            self.by_mid.remove(&(pending.addr, pending.mid));
+            (pending.callback)(Delivery::TimedOut);
+            return None;
+        }
+
+        let pending = self.pending.get_mut(&id).unwrap();
+        pending.retransmits += 1;
+        pending.timeout *= 2;
+
+        Some((pending.addr, &pending.pkt, pending.timeout))
+    }
+
+    /// Matches an incoming ACK or RST to the CON it answers, reporting the
+    /// delivery. If it isn't one we're waiting on `msg` is handed back.
+    pub fn handle_reply(&mut self, addr: &SocketAddr, msg: Message) -> Result<(), Message> {
+        if msg.mtype != Mtype::Acknowledgement && msg.mtype != Mtype::Reset {
+            return Err(msg);
+        }
+
+        let id = match self.by_mid.remove(&(*addr, msg.mid)) {
+            Some(id) => id,
+            None => return Err(msg),
+        };
+
+        let pending = self.pending.remove(&id).unwrap();
+        if msg.mtype == Mtype::Reset {
+            (pending.callback)(Delivery::Reset);
+        } else {
+            (pending.callback)(Delivery::Acknowledged(msg));
+        }
+
+        Ok(())
+    }
+
+    /// Stops waiting for `id`, reporting it as timed out.
+    pub fn abandon(&mut self, id: u64) {
+        if let Some(pending) = self.pending.remove(&id) {
+            self.by_mid.remove(&(pending.addr, pending.mid));
+            (pending.callback)(Delivery::TimedOut);
+        }
+    }
+
+    /// Stops waiting for the CON sent with `mid` without calling its
+    /// callback, for when its answer turned up some other way.
+    pub fn cancel(&mut self, addr: &SocketAddr, mid: u16) {
+        if let Some(id) = self.by_mid.remove(&(*addr, mid)) {
+            self.pending.remove(&id);
+        }
+    }
+
+    /// Whether a CON with this MID is still waiting for an ACK.
+    pub fn is_pending(&self, addr: &SocketAddr, mid: u16) -> bool {
+        self.by_mid.contains_key(&(*addr, mid))
+    }
+}
+
+
+#[test]
+fn test_params_derived_times() {
+    let params = Params::default();
+
+    assert_eq!(params.max_transmit_span(), Duration::from_secs(45));
+    assert_eq!(params.max_transmit_wait(), Duration::from_secs(93));
+    assert_eq!(params.exchange_lifetime(), Duration::from_secs(247));
+    assert_eq!(params.non_lifetime(), Duration::from_secs(145));
+}
+
+#[test]
+fn test_params_validate() {
+    assert!(Params::default().validate().is_ok());
+
+    let params = |max_retransmit, ack_random_factor, ack_timeout| Params{
+        max_retransmit,
+        ack_random_factor,
+        ack_timeout: Duration::from_secs(ack_timeout),
+        ..Params::default()
+    };
+    assert!(params(20, 1.0, 2).validate().is_ok());
+    assert!(params(21, 1.5, 2).validate().is_err());
+    assert!(params(63, 1.5, 2).validate().is_err());
+    assert!(params(4, 0.5, 2).validate().is_err());
+    assert!(params(4, f64::NAN, 2).validate().is_err());
+    assert!(params(4, f64::INFINITY, 2).validate().is_err());
+    assert!(params(20, 1.5, u64::MAX / 2).validate().is_err());
+}
+
+#[test]
+fn test_transaction_retransmits_then_times_out() {
+    use std::sync::mpsc;
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let mut transactions = Transactions::with_random(Params::default(), Random::with_seed(1));
+    let (tx, rx) = mpsc::channel();
+
+    let mid = transactions.next_mid();
+    let (id, first) = transactions.start(addr, mid, vec![0x40, 0x01], Box::new(move |d| tx.send(d).unwrap()));
+    assert!(first >= Duration::from_secs(2) && first <= Duration::from_secs(3));
+    assert!(transactions.is_pending(&addr, mid));
+
+    let mut wait = first;
+    for _ in 0..4 {
+        let (to, pkt, next) = transactions.timeout(id).unwrap();
+        assert_eq!(to, addr);
+        assert_eq!(pkt, &[0x40, 0x01]);
+        assert_eq!(next, wait * 2);
+        wait = next;
+    }
+
+    assert!(transactions.timeout(id).is_none());
+    assert_eq!(rx.try_recv(), Ok(Delivery::TimedOut));
+    assert!(transactions.is_empty());
+    assert!(transactions.timeout(id).is_none());
+}
+
+#[test]
+fn test_transaction_matches_ack_and_rst() {
+    use message::{Code, MessageBuilder};
+    use std::sync::mpsc;
+
+    let addr = "127.0.0.1:5683".parse().unwrap();
+    let other = "127.0.0.1:5684".parse().unwrap();
+    let mut transactions = Transactions::with_random(Params::default(), Random::with_seed(1));
+    let (tx, rx) = mpsc::channel();
+
+    let tx1 = tx.clone();
+    let (id, _) = transactions.start(addr, 10, vec![], Box::new(move |d| tx1.send(d).unwrap()));
+    transactions.start(addr, 11, vec![], Box::new(move |d| tx.send(d).unwrap()));
+
+    let ack = MessageBuilder::new(Mtype::Acknowledgement, Code::Content).mid(10).build();
+    assert_eq!(transactions.handle_reply(&other, ack.clone()), Err(ack.clone()));
+
+    let con = MessageBuilder::new(Mtype::Confirmable, Code::Get).mid(10).build();
+    assert_eq!(transactions.handle_reply(&addr, con.clone()), Err(con));
+
+    assert_eq!(transactions.handle_reply(&addr, ack.clone()), Ok(()));
+    assert_eq!(rx.try_recv(), Ok(Delivery::Acknowledged(ack.clone())));
+    assert_eq!(transactions.handle_reply(&addr, ack.clone()), Err(ack));
+    assert!(transactions.timeout(id).is_none());
+
+    let rst = MessageBuilder::new(Mtype::Reset, Code::Empty).mid(11).build();
+    assert_eq!(transactions.handle_reply(&addr, rst), Ok(()));
+    assert_eq!(rx.try_recv(), Ok(Delivery::Reset));
+    assert!(transactions.is_empty());
+}
@@ -0,0 +1,294 @@
+//! `coap://` and `coaps://` URIs, and the Uri-Host, Uri-Port, Uri-Path and
+//! Uri-Query options they stand for in a request (RFC 7252 §6).
+
+use message::{Error, Message};
+use message::option::Option as CoapOption;
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+pub const DEFAULT_PORT: u16 = 5683;
+pub const DEFAULT_SECURE_PORT: u16 = 5684;
+
+/// A parsed `coap://` or `coaps://` URI, with the host, path segments and
+/// query arguments percent-decoded.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Uri {
+    /// `coaps` rather than `coap`.
+    pub secure: bool,
+    /// A lowercase registered name, or an IP address (IPv6 without the
+    /// brackets).
+    pub host: String,
+    pub port: u16,
+    pub path: Vec<String>,
+    pub query: Vec<String>,
+}
+
+impl Uri {
+    /// Parses an absolute URI following RFC 7252 §6.4. Relative references,
+    /// other schemes, fragments and broken percent-encodings are refused.
+    pub fn parse(s: &str) -> Result<Uri, Error> {
+        let (secure, rest) = match s.find("://") {
+            Some(i) if s[..i].eq_ignore_ascii_case("coap") => (false, &s[i+3..]),
+            Some(i) if s[..i].eq_ignore_ascii_case("coaps") => (true, &s[i+3..]),
+            _ => return Err(Error::InvalidUri),
+        };
+
+        if rest.contains('#') {
+            return Err(Error::InvalidUri);
+        }
+
+        let (authority, rest) = match rest.find(['/', '?']) {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+
+        if authority.contains('@') {
+            return Err(Error::InvalidUri);
+        }
+
+        let (host, port) = if authority.starts_with('[') {
+            match authority.find(']') {
+                Some(i) => {
+                    let literal = &authority[1..i];
+                    if literal.parse::<::std::net::Ipv6Addr>().is_err() {
+                        return Err(Error::InvalidUri);
+                    }
+                    (literal.to_lowercase(), &authority[i+1..])
+                },
+                None => return Err(Error::InvalidUri),
+            }
+        } else {
+            let (host, port) = match authority.rfind(':') {
+                Some(i) => (&authority[..i], &authority[i..]),
+                None => (authority, ""),
+            };
+            (decode(&host.to_lowercase())?, port)
+        };
+
+        if host.is_empty() {
+            return Err(Error::InvalidUri);
+        }
+
+        let default_port = if secure { DEFAULT_SECURE_PORT } else { DEFAULT_PORT };
+        let port = match port {
+            "" | ":" => default_port,
+            p if p.starts_with(':') && p[1..].bytes().all(|b| b.is_ascii_digit()) => {
+                p[1..].parse().map_err(|_| Error::InvalidUri)?
+            },
+            _ => return Err(Error::InvalidUri),
+        };
+
+        let (path, query) = match rest.find('?') {
+            Some(i) => (&rest[..i], Some(&rest[i+1..])),
+            None => (rest, None),
+        };
+
+        let path = match path {
+            "" | "/" => vec![],
+            path => path[1..].split('/').map(decode).collect::<Result<_, _>>()?,
+        };
+
+        let query = match query {
+            None | Some("") => vec![],
+            Some(query) => query.split('&').map(decode).collect::<Result<_, _>>()?,
+        };
+
+        Ok(Uri{secure, host, port, path, query})
+    }
+
+    /// Composes the URI a request was sent to from its options (RFC 7252
+    /// §6.5). The host and port fall back to `destination`, the address the
+    /// request was received on.
+    pub fn from_options(options: &[CoapOption], destination: &SocketAddr, secure: bool) -> Uri {
+        let mut uri = Uri{
+            secure,
+            host: destination.ip().to_string(),
+            port: destination.port(),
+            path: vec![],
+            query: vec![],
+        };
+
+        for option in options {
+            match *option {
+                CoapOption::UriHost(ref host) => uri.host = host.clone(),
+                CoapOption::UriPort(port) => uri.port = port,
+                CoapOption::UriPath(ref segment) => uri.path.push(segment.clone()),
+                CoapOption::UriQuery(ref arg) => uri.query.push(arg.clone()),
+                _ => ()
+            }
+        }
+
+        uri
+    }
+
+    pub fn from_message(msg: &Message, destination: &SocketAddr, secure: bool) -> Uri {
+        Uri::from_options(&msg.options, destination, secure)
+    }
+
+    pub fn default_port(&self) -> u16 {
+        if self.secure { DEFAULT_SECURE_PORT } else { DEFAULT_PORT }
+    }
+
+    /// The options standing for this URI in a request, in order. Uri-Host
+    /// is left out for IP addresses and Uri-Port for the default port.
+    pub fn options(&self) -> Vec<CoapOption> {
+        let mut options = vec![];
+
+        if self.host.parse::<IpAddr>().is_err() {
+            options.push(CoapOption::UriHost(self.host.clone()));
+        }
+        if self.port != self.default_port() {
+            options.push(CoapOption::UriPort(self.port));
+        }
+        options.extend(self.path.iter().map(|s| CoapOption::UriPath(s.clone())));
+        options.extend(self.query.iter().map(|s| CoapOption::UriQuery(s.clone())));
+
+        options
+    }
+}
+
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://", if self.secure { "coaps" } else { "coap" })?;
+
+        if self.host.parse::<::std::net::Ipv6Addr>().is_ok() {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            write!(f, "{}", encode(&self.host, b"!$&'()*+,;="))?;
+        }
+
+        if self.port != self.default_port() {
+            write!(f, ":{}", self.port)?;
+        }
+
+        if self.path.is_empty() {
+            write!(f, "/")?;
+        }
+        for segment in &self.path {
+            write!(f, "/{}", encode(segment, b"!$&'()*+,;=:@"))?;
+        }
+
+        for (i, arg) in self.query.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { '?' } else { '&' }, encode(arg, b"!$'()*+,;=:@/?"))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Percent-decodes a URI component, which has to come out as UTF-8.
+fn decode(s: &str) -> Result<String, Error> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i+1..i+3).ok_or(Error::InvalidUri)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(Error::InvalidUri);
+            }
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| Error::InvalidUri)?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| Error::InvalidUri)
+}
+
+// Percent-encodes everything but unreserved characters and `allowed`.
+fn encode(s: &str, allowed: &[u8]) -> String {
+    let mut encoded = String::with_capacity(s.len());
+
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || allowed.contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+
+    encoded
+}
+
+
+#[test]
+fn test_uri_decompose() {
+    let uri = Uri::parse("COAP://Example.COM:61616/a%20b/%C3%A4/?x=1&y&q=%26").unwrap();
+    assert_eq!(uri, Uri{
+        secure: false,
+        host: "example.com".to_string(),
+        port: 61616,
+        path: vec!["a b".to_string(), "ä".to_string(), "".to_string()],
+        query: vec!["x=1".to_string(), "y".to_string(), "q=&".to_string()],
+    });
+    assert_eq!(uri.options(), [
+        CoapOption::UriHost("example.com".to_string()),
+        CoapOption::UriPort(61616),
+        CoapOption::UriPath("a b".to_string()),
+        CoapOption::UriPath("ä".to_string()),
+        CoapOption::UriPath("".to_string()),
+        CoapOption::UriQuery("x=1".to_string()),
+        CoapOption::UriQuery("y".to_string()),
+        CoapOption::UriQuery("q=&".to_string()),
+    ]);
+
+    let uri = Uri::parse("coaps://[2001:DB8::1]").unwrap();
+    assert_eq!((uri.host.as_str(), uri.port), ("2001:db8::1", 5684));
+    assert!(uri.options().is_empty());
+
+    let uri = Uri::parse("coap://192.0.2.1:5684/").unwrap();
+    assert_eq!(uri.options(), [CoapOption::UriPort(5684)]);
+
+    for bad in &["http://example.com/", "/relative", "coap://", "coap://host:port/", "coap://h/#frag",
+                 "coap://h/%zz", "coap://h/%4", "coap://h/%FF", "coap://[::1/", "coap://[nope]/", "coap://u@h/"] {
+        assert_eq!(Uri::parse(bad), Err(Error::InvalidUri), "{}", bad);
+    }
+}
+
+#[test]
+fn test_uri_recompose() {
+    use message::{Code, MessageBuilder, Mtype};
+
+    let destination: SocketAddr = "[2001:db8::1]:5683".parse().unwrap();
+    let msg = MessageBuilder::new(Mtype::Confirmable, Code::Get)
+        .option(CoapOption::UriPath("sensors".to_string()))
+        .option(CoapOption::UriPath("a/b c".to_string()))
+        .option(CoapOption::UriQuery("q=a&b".to_string()))
+        .build();
+
+    let uri = Uri::from_message(&msg, &destination, false);
+    assert_eq!(uri.to_string(), "coap://[2001:db8::1]/sensors/a%2Fb%20c?q=a%26b");
+
+    let msg = MessageBuilder::new(Mtype::Confirmable, Code::Get)
+        .option(CoapOption::UriHost("example.net".to_string()))
+        .option(CoapOption::UriPort(1234))
+        .build();
+    assert_eq!(Uri::from_message(&msg, &destination, true).to_string(), "coaps://example.net:1234/");
+
+    let destination: SocketAddr = "192.0.2.1:61616".parse().unwrap();
+    let msg = MessageBuilder::new(Mtype::Confirmable, Code::Get).build();
+    assert_eq!(Uri::from_message(&msg, &destination, false).to_string(), "coap://192.0.2.1:61616/");
+}
+
+#[test]
+fn test_uri_round_trip() {
+    let destination: SocketAddr = "192.0.2.1:5683".parse().unwrap();
+
+    for s in &["coap://example.com/", "coaps://example.com:1234/a/b/?x=1&y",
+               "coap://[::1]:61616/%C3%A4%20/x%2Fy?a=%26&b=/?", "coap://192.0.2.1/.well-known/core?rt=temp*"] {
+        let uri = Uri::parse(s).unwrap();
+        assert_eq!(uri.to_string(), *s);
+
+        // Through the options and back again.
+        let recomposed = Uri::from_options(&uri.options(), &destination, uri.secure);
+        assert_eq!(recomposed.path, uri.path);
+        assert_eq!(recomposed.query, uri.query);
+        assert_eq!(recomposed.port, uri.port);
+        assert_eq!(Uri::parse(&recomposed.to_string()).unwrap(), recomposed);
+    }
+}
@@ -0,0 +1,496 @@
+//! CoAP over WebSockets (RFC 8323 §4).
+//!
+//! Clients connect to `/.well-known/coap` asking for the `coap` subprotocol,
+//! and each binary WebSocket message then carries one CoAP message, framed
+//! like over TCP but without a length. `Endpoint::websocket` serves it
+//! alongside UDP with the same handler.
+
+use message::{Error, Message};
+use tcp::Incoming;
+
+use openssl::base64::encode_block;
+use openssl::sha::sha1;
+use std::str;
+
+/// Where CoAP is served over WebSockets.
+pub const PATH: &str = "/.well-known/coap";
+/// The WebSocket subprotocol clients have to ask for.
+pub const SUBPROTOCOL: &str = "coap";
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_SIZE: usize = 8192;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+const PROTOCOL_ERROR: u16 = 1002;
+const UNSUPPORTED_DATA: u16 = 1003;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Appends `msg` to `buf` as the payload of a WebSocket message, returning
+/// its length. The type and MID are left out.
+pub fn encode(msg: &Message, buf: &mut Vec<u8>) -> Result<usize, Error> {
+    let pkt = msg.to_bytes()?;
+    Ok(datagram_to_message(&pkt, buf))
+}
+
+/// Decodes the payload of a WebSocket message. Messages come out as NONs
+/// with MID 0.
+pub fn decode(payload: &[u8]) -> Result<Message, Error> {
+    let mut pkt = vec![];
+    message_to_datagram(payload, &mut pkt)?;
+    Message::from_bytes(&pkt)
+}
+
+pub(crate) fn datagram_to_message(pkt: &[u8], buf: &mut Vec<u8>) -> usize {
+    let tkl = (pkt[0] & 0x0F) as usize;
+    let start = buf.len();
+
+    buf.push(tkl as u8);
+    buf.push(pkt[1]);
+    buf.extend_from_slice(&pkt[4..]);
+
+    buf.len() - start
+}
+
+// The Len nibble should be zero, but since the WebSocket message already
+// has a length it's ignored.
+pub(crate) fn message_to_datagram(payload: &[u8], pkt: &mut Vec<u8>) -> Result<(), Error> {
+    if payload.len() < 2 {
+        return Err(Error::TruncatedHeader(payload.len()));
+    }
+
+    let tkl = payload[0] & 0x0F;
+    pkt.push(0x50 | tkl);
+    pkt.push(payload[1]);
+    pkt.extend_from_slice(&[0, 0]);
+    pkt.extend_from_slice(&payload[2..]);
+    Ok(())
+}
+
+/// Per connection state, from the opening handshake on.
+#[derive(Default)]
+pub(crate) struct WebSocket {
+    open: bool,
+    // A message arriving in fragments.
+    fragments: Option<Vec<u8>>,
+}
+
+impl WebSocket {
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Handles what has arrived in `read_buf`, writing handshake responses
+    /// and replies to control frames to `write_buf`.
+    pub fn read(&mut self, read_buf: &mut Vec<u8>, write_buf: &mut Vec<u8>, max_message_size: usize) -> Vec<Incoming> {
+        let mut incoming = vec![];
+
+        if !self.open {
+            match handshake(read_buf) {
+                Handshake::Incomplete => return incoming,
+                Handshake::Rejected(response) => {
+                    write_buf.extend_from_slice(response.as_bytes());
+                    read_buf.clear();
+                    incoming.push(Incoming::Close);
+                    return incoming;
+                },
+                Handshake::Accepted(len, response) => {
+                    write_buf.extend_from_slice(response.as_bytes());
+                    read_buf.drain(..len);
+                    self.open = true;
+                    incoming.push(Incoming::Opened);
+                }
+            }
+        }
+
+        loop {
+            let (frame, len) = match read_frame(read_buf, max_message_size) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(code) => {
+                    close(write_buf, code, &mut incoming, read_buf);
+                    break;
+                }
+            };
+            read_buf.drain(..len);
+
+            let message = match frame.opcode {
+                BINARY if self.fragments.is_none() => {
+                    if frame.fin {
+                        Some(frame.payload)
+                    } else {
+                        self.fragments = Some(frame.payload);
+                        None
+                    }
+                },
+                CONTINUATION if self.fragments.is_some() => {
+                    let mut fragments = self.fragments.take().unwrap();
+                    fragments.extend_from_slice(&frame.payload);
+                    if fragments.len() > max_message_size {
+                        close(write_buf, MESSAGE_TOO_BIG, &mut incoming, read_buf);
+                        break;
+                    }
+                    if frame.fin {
+                        Some(fragments)
+                    } else {
+                        self.fragments = Some(fragments);
+                        None
+                    }
+                },
+                TEXT => {
+                    close(write_buf, UNSUPPORTED_DATA, &mut incoming, read_buf);
+                    break;
+                },
+                CLOSE => {
+                    write_frame(CLOSE, &frame.payload[..frame.payload.len().min(2)], write_buf);
+                    read_buf.clear();
+                    incoming.push(Incoming::Close);
+                    break;
+                },
+                PING => {
+                    write_frame(PONG, &frame.payload, write_buf);
+                    None
+                },
+                PONG => None,
+                _ => {
+                    close(write_buf, PROTOCOL_ERROR, &mut incoming, read_buf);
+                    break;
+                }
+            };
+
+            if let Some(message) = message {
+                let mut pkt = vec![];
+                incoming.push(match message_to_datagram(&message, &mut pkt) {
+                    Ok(()) => Incoming::Message(pkt),
+                    Err(_) => Incoming::Abort("malformed message"),
+                });
+            }
+        }
+
+        incoming
+    }
+
+    /// Appends a binary message carrying `pkt`, an encoded datagram.
+    pub fn write(&self, pkt: &[u8], buf: &mut Vec<u8>) -> usize {
+        let mut message = vec![];
+        let len = datagram_to_message(pkt, &mut message);
+        write_frame(BINARY, &message, buf);
+        len
+    }
+}
+
+fn close(write_buf: &mut Vec<u8>, code: u16, incoming: &mut Vec<Incoming>, read_buf: &mut Vec<u8>) {
+    write_frame(CLOSE, &code.to_be_bytes(), write_buf);
+    read_buf.clear();
+    incoming.push(Incoming::Close);
+}
+
+enum Handshake {
+    Incomplete,
+    // The length of the request and the response to send.
+    Accepted(usize, String),
+    Rejected(String),
+}
+
+// Answers the HTTP request that opens a WebSocket.
+fn handshake(buf: &[u8]) -> Handshake {
+    let len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => i + 4,
+        None if buf.len() > MAX_HANDSHAKE_SIZE => return Handshake::Rejected(status("431 Request Header Fields Too Large")),
+        None => return Handshake::Incomplete,
+    };
+
+    let request = match str::from_utf8(&buf[..len]) {
+        Ok(request) => request,
+        Err(_) => return Handshake::Rejected(status("400 Bad Request")),
+    };
+    let mut lines = request.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, target) = (request_line.next().unwrap_or(""), request_line.next().unwrap_or(""));
+    if method != "GET" {
+        return Handshake::Rejected(status("405 Method Not Allowed"));
+    }
+    if target.split('?').next() != Some(PATH) {
+        return Handshake::Rejected(status("404 Not Found"));
+    }
+
+    let headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.find(':').map(|i| (line[..i].trim(), line[i+1..].trim())))
+        .collect();
+    let header = |name: &str| headers.iter().find(|h| h.0.eq_ignore_ascii_case(name)).map(|h| h.1);
+    let has_token = |name: &str, token: &str| header(name).is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Handshake::Rejected(status("400 Bad Request"));
+    }
+    if header("Sec-WebSocket-Version") != Some("13") {
+        return Handshake::Rejected("HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\n\r\n".to_string());
+    }
+    if !has_token("Sec-WebSocket-Protocol", SUBPROTOCOL) {
+        return Handshake::Rejected(status("400 Bad Request"));
+    }
+    let key = match header("Sec-WebSocket-Key") {
+        Some(key) if !key.is_empty() => key,
+        _ => return Handshake::Rejected(status("400 Bad Request")),
+    };
+
+    Handshake::Accepted(len, format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\nSec-WebSocket-Protocol: {}\r\n\r\n",
+        accept_key(key), SUBPROTOCOL))
+}
+
+fn status(status: &str) -> String {
+    format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status)
+}
+
+fn accept_key(key: &str) -> String {
+    encode_block(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Reads a frame from a client, which has to be masked. Errors are the
+// status code to close the connection with.
+fn read_frame(buf: &[u8], max_message_size: usize) -> Result<Option<(Frame, usize)>, u16> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    if buf[0] & 0x70 != 0 || buf[1] & 0x80 == 0 {
+        return Err(PROTOCOL_ERROR);
+    }
+
+    let (len, mut pos) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(bytes), 10)
+        },
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+
+    if opcode >= CLOSE && (len > 125 || !fin) {
+        return Err(PROTOCOL_ERROR);
+    }
+    if len > max_message_size as u64 {
+        return Err(MESSAGE_TOO_BIG);
+    }
+
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+    let payload = buf[pos..pos + len].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+
+    Ok(Some((Frame{fin, opcode, payload}, pos + len)))
+}
+
+// Writes an unmasked frame, as servers send them.
+fn write_frame(opcode: u8, payload: &[u8], buf: &mut Vec<u8>) {
+    buf.push(0x80 | opcode);
+
+    let len = payload.len();
+    if len < 126 {
+        buf.push(len as u8);
+    } else if len <= 0xFFFF {
+        buf.push(126);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(127);
+        buf.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    buf.extend_from_slice(payload);
+}
+
+
+#[test]
+fn test_websocket_handshake() {
+    // The example from RFC 6455 §1.3.
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+    let request = "GET /.well-known/coap HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+                   Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                   Sec-WebSocket-Protocol: coap\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    match handshake(&request.as_bytes()[..40]) {
+        Handshake::Incomplete => (),
+        _ => panic!("handshake should be incomplete"),
+    }
+    match handshake(request.as_bytes()) {
+        Handshake::Accepted(len, response) => {
+            assert_eq!(len, request.len());
+            assert!(response.starts_with("HTTP/1.1 101 "));
+            assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+            assert!(response.contains("Sec-WebSocket-Protocol: coap\r\n"));
+        },
+        _ => panic!("handshake should be accepted"),
+    }
+
+    for (from, to, status) in &[("/.well-known/coap", "/chat", "404"), ("coap\r\n", "mqtt\r\n", "400"), (": 13", ": 8", "426")] {
+        match handshake(request.replace(from, to).as_bytes()) {
+            Handshake::Rejected(response) => assert!(response.starts_with(&format!("HTTP/1.1 {}", status)), "{}", response),
+            _ => panic!("{} should be rejected", to),
+        }
+    }
+}
+
+#[test]
+fn test_websocket_frames() {
+    use message::{Code, MessageBuilder, Mtype};
+    use message::option::Option as CoapOption;
+
+    let msg = MessageBuilder::new(Mtype::NonConfirmable, Code::Get).token(&[1, 2])
+        .option(CoapOption::UriPath("a".to_string())).build();
+    let mut payload = vec![];
+    encode(&msg, &mut payload).unwrap();
+    assert_eq!(payload, [0x02, 0x01, 1, 2, 0xB1, b'a']);
+    assert_eq!(decode(&payload).unwrap(), msg);
+
+    // A masked client frame, sent in two fragments with a ping between.
+    let masked = |fin: bool, opcode: u8, payload: &[u8]| {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![(fin as u8) << 7 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    };
+
+    let mut ws = WebSocket{open: true, fragments: None};
+    let mut read_buf = masked(false, BINARY, &payload[..3]);
+    read_buf.extend(masked(true, PING, b"hi"));
+    read_buf.extend(masked(true, CONTINUATION, &payload[3..]));
+    let mut write_buf = vec![];
+
+    let incoming = ws.read(&mut read_buf[..5].to_vec(), &mut write_buf, 1024);
+    assert!(incoming.is_empty());
+
+    let incoming = ws.read(&mut read_buf, &mut write_buf, 1024);
+    assert_eq!(write_buf, [0x80 | PONG, 2, b'h', b'i']);
+    match incoming.as_slice() {
+        [Incoming::Message(pkt)] => assert_eq!(Message::from_bytes(pkt).unwrap(), msg),
+        _ => panic!("expected one message"),
+    }
+    assert!(read_buf.is_empty());
+
+    // Unmasked frames and text aren't allowed.
+    let mut write_buf = vec![];
+    let incoming = ws.read(&mut vec![0x82, 0x00], &mut write_buf, 1024);
+    assert!(matches!(incoming.as_slice(), [Incoming::Close]));
+    assert_eq!(write_buf, [0x80 | CLOSE, 2, 0x03, 0xEA]);
+
+    let mut write_buf = vec![];
+    ws.read(&mut masked(true, TEXT, b"{}"), &mut write_buf, 1024);
+    assert_eq!(write_buf, [0x80 | CLOSE, 2, 0x03, 0xEB]);
+}
+
+#[test]
+fn test_websocket_endpoint() {
+    use endpoint::Endpoint;
+    use message::{Code, MessageBuilder, Mtype};
+    use router::Router;
+    use testing::spawn;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let mut router = Router::new();
+    router.get("/hello", |_, msg, _| {
+        let mut resp = Message::response_for(msg, Code::Content);
+        resp.payload = b"over websockets".to_vec();
+        resp.to_bytes().ok()
+    }).unwrap();
+
+    let localhost = "127.0.0.1:0".parse().unwrap();
+    let server = Endpoint::new(localhost).websocket(localhost).bind(router).unwrap();
+    let ws_addr = server.websocket_addr().unwrap();
+    let server = spawn(server);
+
+    let mut peer = TcpStream::connect(ws_addr).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    peer.write_all(b"GET /.well-known/coap HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                     Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                     Sec-WebSocket-Protocol: coap\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+    let mut received = vec![];
+    let fill = |peer: &mut TcpStream, received: &mut Vec<u8>| {
+        let mut buf = [0; 1024];
+        let len = peer.read(&mut buf).unwrap();
+        assert!(len > 0, "connection closed");
+        received.extend_from_slice(&buf[..len]);
+    };
+
+    while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+        fill(&mut peer, &mut received);
+    }
+    let end = received.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let response = String::from_utf8(received.drain(..end).collect()).unwrap();
+    assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+
+    // Server frames are unmasked and short here.
+    let mut recv = |peer: &mut TcpStream| loop {
+        if received.len() >= 2 && received.len() >= 2 + received[1] as usize {
+            assert_eq!(received[0], 0x80 | BINARY);
+            let len = received[1] as usize;
+            let frame: Vec<u8> = received.drain(..2 + len).collect();
+            return decode(&frame[2..]).unwrap();
+        }
+        fill(peer, &mut received);
+    };
+    let send = |peer: &mut TcpStream, msg: &Message| {
+        let mut payload = vec![];
+        encode(msg, &mut payload).unwrap();
+        let mask = [0xA5, 0x5A, 0x0F, 0xF0];
+        let mut frame = vec![0x80 | BINARY, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        peer.write_all(&frame).unwrap();
+    };
+
+    assert_eq!(recv(&mut peer).code, Code::Csm);
+    send(&mut peer, &MessageBuilder::new(Mtype::NonConfirmable, Code::Csm).build());
+
+    let get = Message::request(Code::Get, "coap://localhost/hello").map(|mut m| { m.token = vec![7]; m }).unwrap();
+    send(&mut peer, &get);
+    let resp = recv(&mut peer);
+    assert_eq!((resp.code, resp.token.as_slice(), resp.payload.as_slice()), (Code::Content, &[7][..], &b"over websockets"[..]));
+
+    // Requests to a peer connected over WebSockets go over its connection.
+    let (tx, rx) = mpsc::channel();
+    let peer_addr = peer.local_addr().unwrap();
+    server.handle.request(peer_addr, Message::request(Code::Get, "coap://localhost/back").unwrap(), move |r| {
+        tx.send(r.map(|m| m.payload)).unwrap();
+    }).unwrap();
+    let request = recv(&mut peer);
+    assert_eq!(request.code, Code::Get);
+    let mut resp = Message::response_for(&request, Code::Content);
+    resp.payload = b"back".to_vec();
+    send(&mut peer, &resp);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap(), b"back");
+
+    // A close is echoed, and the server closes the connection.
+    peer.write_all(&[0x80 | CLOSE, 0x82, 0, 0, 0, 0, 0x03, 0xE8]).unwrap();
+    let mut rest = vec![];
+    peer.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, [0x80 | CLOSE, 2, 0x03, 0xE8]);
+
+    server.stop();
+}